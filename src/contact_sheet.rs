@@ -0,0 +1,192 @@
+use image::{imageops, ImageOutputFormat, Rgba, RgbaImage};
+
+use crate::data::Error;
+
+// Each image gets shrunk to fit in a square this big.
+const CELL: u32 = 256;
+// Space between the cells, and around the edge.
+const GAP: u32 = 8;
+// Wider than this and the thumbnails get too small to pick from on a phone.
+const MAX_COLUMNS: u32 = 4;
+// How many pixels make up one dot of the digits.
+const DOT: u32 = 4;
+const BACKGROUND: Rgba<u8> = Rgba([32, 34, 37, 255]);
+const LABEL_BACKGROUND: Rgba<u8> = Rgba([0, 0, 0, 200]);
+const LABEL: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+// 3x5 digits, a row per byte, with the top three bits of each row being the dots.
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+// How many thumbnails go across and down for `count` images. As square as it can be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Layout {
+    pub(crate) columns: u32,
+    pub(crate) rows: u32,
+}
+impl Layout {
+    pub(crate) fn for_images(count: u32) -> Self {
+        let mut columns = 1;
+        while columns * columns < count && columns < MAX_COLUMNS {
+            columns += 1;
+        }
+        Layout {
+            columns,
+            rows: count.div_ceil(columns).max(1),
+        }
+    }
+
+    fn size(self) -> (u32, u32) {
+        (
+            self.columns * CELL + (self.columns + 1) * GAP,
+            self.rows * CELL + (self.rows + 1) * GAP,
+        )
+    }
+
+    // The top left of the `i`th cell.
+    fn origin(self, i: u32) -> (u32, u32) {
+        (
+            GAP + (i % self.columns) * (CELL + GAP),
+            GAP + (i / self.columns) * (CELL + GAP),
+        )
+    }
+}
+
+// One PNG with a numbered thumbnail of each of `images`, in order, so people can pick
+// out their favourites without opening every one. Decoding and resizing is slow, so run
+// this off the async threads.
+pub(crate) fn build(images: &[Vec<u8>]) -> Result<Vec<u8>, Error> {
+    let layout = Layout::for_images(images.len() as u32);
+    let (width, height) = layout.size();
+    let mut sheet = RgbaImage::from_pixel(width, height, BACKGROUND);
+    for (i, bytes) in images.iter().enumerate() {
+        let thumbnail = image::load_from_memory(bytes)?
+            .thumbnail(CELL, CELL)
+            .to_rgba8();
+        let (x, y) = layout.origin(i as u32);
+        // centred in its cell, since wide and tall images don't fill it
+        let x = x + (CELL - thumbnail.width()) / 2;
+        let y = y + (CELL - thumbnail.height()) / 2;
+        imageops::overlay(&mut sheet, &thumbnail, x as i64, y as i64);
+        label(&mut sheet, x, y, i + 1);
+    }
+    let mut png = std::io::Cursor::new(Vec::new());
+    sheet.write_to(&mut png, ImageOutputFormat::Png)?;
+    Ok(png.into_inner())
+}
+
+// Writes `number` in the top left corner at (x, y), on a dark box so it shows up on
+// anything.
+fn label(sheet: &mut RgbaImage, x: u32, y: u32, number: usize) {
+    let digits: Vec<usize> = number
+        .to_string()
+        .bytes()
+        .map(|b| (b - b'0') as usize)
+        .collect();
+    let width = (digits.len() as u32 * 4 + 1) * DOT;
+    let height = 7 * DOT;
+    for dy in 0..height {
+        for dx in 0..width {
+            blend(sheet, x + dx, y + dy, LABEL_BACKGROUND);
+        }
+    }
+    for (i, &digit) in digits.iter().enumerate() {
+        let left = x + DOT + i as u32 * 4 * DOT;
+        for (row, bits) in DIGITS[digit].iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..DOT {
+                    for dx in 0..DOT {
+                        let px = left + column * DOT + dx;
+                        let py = y + DOT + row as u32 * DOT + dy;
+                        blend(sheet, px, py, LABEL);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn blend(sheet: &mut RgbaImage, x: u32, y: u32, colour: Rgba<u8>) {
+    if x < sheet.width() && y < sheet.height() {
+        image::Pixel::blend(sheet.get_pixel_mut(x, y), &colour);
+    }
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32, colour: [u8; 4]) -> Vec<u8> {
+        let mut bytes = std::io::Cursor::new(Vec::new());
+        RgbaImage::from_pixel(width, height, Rgba(colour))
+            .write_to(&mut bytes, ImageOutputFormat::Png)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn test_layout() {
+        let layouts: Vec<(u32, u32)> = [1, 2, 3, 4, 5, 9, 10]
+            .iter()
+            .map(|&n| {
+                let layout = Layout::for_images(n);
+                (layout.columns, layout.rows)
+            })
+            .collect();
+        assert_eq!(
+            layouts,
+            vec![(1, 1), (2, 1), (2, 2), (2, 2), (3, 2), (3, 3), (4, 3)]
+        );
+    }
+
+    #[test]
+    fn test_sheet_of_four() {
+        let images = [
+            png(1024, 1024, [255, 0, 0, 255]),
+            png(1024, 1024, [0, 255, 0, 255]),
+            png(1792, 1024, [0, 0, 255, 255]),
+            png(1024, 1792, [255, 255, 0, 255]),
+        ];
+        let layout = Layout::for_images(images.len() as u32);
+        assert_eq!(layout.columns * layout.rows, 4);
+        let sheet = image::load_from_memory(&build(&images).unwrap())
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(sheet.dimensions(), (2 * CELL + 3 * GAP, 2 * CELL + 3 * GAP));
+        // the middle of each cell is its image
+        let centre = |i: u32| {
+            let (x, y) = layout.origin(i);
+            *sheet.get_pixel(x + CELL / 2, y + CELL / 2)
+        };
+        assert_eq!(centre(0), Rgba([255, 0, 0, 255]));
+        assert_eq!(centre(1), Rgba([0, 255, 0, 255]));
+        assert_eq!(centre(2), Rgba([0, 0, 255, 255]));
+        assert_eq!(centre(3), Rgba([255, 255, 0, 255]));
+        // the wide one leaves room above it
+        let (x, y) = layout.origin(2);
+        assert_eq!(*sheet.get_pixel(x + CELL / 2, y + 1), BACKGROUND);
+        // and each one's numbered, the top of the 1 being its middle dot
+        let (x, y) = layout.origin(0);
+        assert_eq!(*sheet.get_pixel(x + 2 * DOT, y + DOT), LABEL);
+        assert_ne!(*sheet.get_pixel(x + DOT, y + DOT), LABEL);
+    }
+
+    #[test]
+    fn test_garbage_is_an_error() {
+        assert!(build(&[vec![1, 2, 3]]).is_err());
+    }
+}
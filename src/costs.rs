@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+
+use crate::data::{Context, Error};
+use crate::info::format_millicents;
+use crate::settings;
+
+// Like roll history, this only lives in memory. Enough to spot someone going wild this
+// week, not an audit trail.
+const MAX_COST_RECORDS: usize = 2000;
+// Long prompts get cut down to this many characters in the listing.
+const PROMPT_PREVIEW_LEN: usize = 60;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct CostRecord {
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) user: String,
+    pub(crate) prompt: String,
+    pub(crate) images: u8,
+    // what the user was charged, after COST_MULTIPLIER
+    pub(crate) cost_millicents: u128,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct CostLog {
+    records: VecDeque<CostRecord>,
+}
+impl CostLog {
+    pub(crate) fn record(&mut self, record: CostRecord) {
+        if self.records.len() >= MAX_COST_RECORDS {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub(crate) fn top(&self, n: usize) -> Vec<CostRecord> {
+        top_n(self.records.iter(), n).into_iter().cloned().collect()
+    }
+}
+
+// The `n` priciest records, most expensive first. Ties go to the earlier one.
+fn top_n<'a>(records: impl Iterator<Item = &'a CostRecord>, n: usize) -> Vec<&'a CostRecord> {
+    let mut records: Vec<&CostRecord> = records.collect();
+    // stable, so equal costs stay in the order they happened
+    records.sort_by_key(|record| std::cmp::Reverse(record.cost_millicents));
+    records.truncate(n);
+    records
+}
+
+// Lists the most expensive generations since the bot woke up, to spot abuse.
+#[poise::command(slash_command)]
+pub async fn expensive(
+    ctx: Context<'_>,
+    #[description = "How many generations to list, 10 by default"] count: Option<u8>,
+) -> Result<(), Error> {
+    if !settings::ensure_admin(ctx).await? {
+        return Ok(());
+    }
+    let records = ctx
+        .data()
+        .costs
+        .lock()
+        .await
+        .top(count.unwrap_or(10) as usize);
+    let response = if records.is_empty() {
+        "Nobody's generated anything since I last woke up.".to_string()
+    } else {
+        describe(&records)
+    };
+    crate::dice_common::say_chunked(ctx, &response, true).await?;
+    Ok(())
+}
+
+fn describe(records: &[CostRecord]) -> String {
+    let mut s = String::new();
+    for (i, record) in records.iter().enumerate() {
+        s += &format!(
+            "{}. {} for {} image{} by {} at {}: {}\n",
+            i + 1,
+            format_millicents(record.cost_millicents.min(i64::MAX as u128) as i64),
+            record.images,
+            if record.images == 1 { "" } else { "s" },
+            record.user,
+            record.timestamp.format("%Y-%m-%d %H:%M UTC"),
+            preview(&record.prompt)
+        );
+    }
+    s
+}
+
+fn preview(prompt: &str) -> String {
+    let prompt = prompt.replace('\n', " ");
+    if prompt.chars().count() <= PROMPT_PREVIEW_LEN {
+        return prompt;
+    }
+    let cut: String = prompt.chars().take(PROMPT_PREVIEW_LEN - 1).collect();
+    format!("{}…", cut)
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(user: &str, cost_millicents: u128) -> CostRecord {
+        CostRecord {
+            timestamp: Utc::now(),
+            user: user.to_string(),
+            prompt: format!("{}'s picture", user),
+            images: 1,
+            cost_millicents,
+        }
+    }
+
+    #[test]
+    fn test_top_three() {
+        let records = [
+            record("a", 4_000),
+            record("b", 12_000),
+            record("c", 8_000),
+            record("d", 16_000),
+            record("e", 12_000),
+            record("f", 500),
+        ];
+        let users: Vec<&str> = top_n(records.iter(), 3)
+            .iter()
+            .map(|r| r.user.as_str())
+            .collect();
+        assert_eq!(users, vec!["d", "b", "e"]);
+        assert_eq!(top_n(records.iter(), 10).len(), 6);
+        assert!(top_n(records.iter(), 0).is_empty());
+    }
+
+    #[test]
+    fn test_log_is_bounded() {
+        let mut log = CostLog::default();
+        for i in 0..MAX_COST_RECORDS + 5 {
+            log.record(record(&i.to_string(), i as u128));
+        }
+        assert_eq!(log.records.len(), MAX_COST_RECORDS);
+        assert_eq!(log.records.front().unwrap().user, "5");
+        assert_eq!(
+            log.top(1)[0].cost_millicents,
+            (MAX_COST_RECORDS + 4) as u128
+        );
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview("a cat\nin a hat"), "a cat in a hat");
+        let long = "x".repeat(100);
+        let cut = preview(&long);
+        assert_eq!(cut.chars().count(), PROMPT_PREVIEW_LEN);
+        assert!(cut.ends_with('…'));
+    }
+}
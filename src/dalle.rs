@@ -1,9 +1,23 @@
-use crate::data::{Context, Cost, Error};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+use crate::contact_sheet;
+use crate::costs::CostRecord;
+use crate::data::{Billing, Context, Cost, Error, Payer};
+use crate::dice_common::MAX_MESSAGE_LEN;
+use crate::keys::ApiKey;
+use crate::preferences::Flavor;
+use crate::settings;
+use crate::webhook::{self, GenerationSummary};
 use base64::Engine;
-use futures::future::join_all;
+use futures::StreamExt;
 use poise::serenity_prelude as serenity;
 use serde_json::json;
 
+// Every option is its own slash command argument, so there's one for each.
+#[allow(clippy::too_many_arguments)]
 #[poise::command(slash_command)]
 pub async fn gen(
     ctx: Context<'_>,
@@ -14,82 +28,504 @@ pub async fn gen(
     #[description = "Should the image be super colorful or are more muted colors ok?"]
     style: Option<Style>,
     #[description = "The quality of the image that will be generated."] quality: Option<Quality>,
+    #[description = "Which DALL-E to ask, DALL-E 3 by default"] model: Option<Model>,
+    #[description = "Numbered thumbnails of the whole batch, to pick favourites from"]
+    contact_sheet: Option<ContactSheet>,
+    #[description = "What kind of file to send, PNG by default"] format: Option<Format>,
 ) -> Result<(), Error> {
-    let user = ctx.author();
-    let num = num.unwrap_or(4);
-    if num > 10 {
-        ctx.reply("This mortal frame can't handle such treasures. Ten is the max at once, chum")
-            .await?;
+    let options = GenOptions {
+        num,
+        size,
+        style,
+        quality,
+        model,
+        contact_sheet,
+        format,
+    };
+    generate(ctx, description, options).await
+}
+
+#[derive(Debug, Default)]
+struct GenOptions {
+    num: Option<u8>,
+    size: Option<Dimensions>,
+    style: Option<Style>,
+    quality: Option<Quality>,
+    model: Option<Model>,
+    contact_sheet: Option<ContactSheet>,
+    format: Option<Format>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum ContactSheet {
+    #[name = "As well as the full size images"]
+    Also,
+    #[name = "Instead of the full size images"]
+    Instead,
+}
+
+// `!gen` with no prompt, as a reply to someone else's message, draws whatever that
+// message describes. main.rs grafts this onto `gen` as its prefix implementation, since
+// a #[rest] prompt has to be the last argument and slash commands want it first.
+#[poise::command(prefix_command)]
+pub async fn gen_prefix(
+    ctx: Context<'_>,
+    #[rest] description: Option<String>,
+) -> Result<(), Error> {
+    let referenced = match ctx {
+        poise::Context::Prefix(prefix) => prefix.msg.referenced_message.as_deref(),
+        poise::Context::Application(_) => None,
+    };
+    let prompt = prompt_from_reference(
+        description.as_deref(),
+        referenced.map(|msg| msg.content.as_str()),
+    );
+    match prompt {
+        Err(err) => {
+            ctx.reply(err).await?;
+            Ok(())
+        }
+        Ok(prompt) => generate(ctx, prompt, GenOptions::default()).await,
+    }
+}
+
+// Why a request can't be for that many images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum BadImageCount {
+    Zero,
+    // more than the max, which is included
+    TooMany(u8),
+}
+impl BadImageCount {
+    fn message(self, flavor: Flavor) -> String {
+        match (self, flavor) {
+            (BadImageCount::Zero, Flavor::Flavorful) => {
+                "Getting philosophical with us eh? Here's zero images for you:".to_string()
+            }
+            (BadImageCount::Zero, Flavor::Terse) => "Zero images, done.".to_string(),
+            (BadImageCount::TooMany(max), Flavor::Flavorful) => format!(
+                "This mortal frame can't handle such treasures. {} is the max at once, chum",
+                max
+            ),
+            (BadImageCount::TooMany(max), Flavor::Terse) => {
+                format!("At most {} images at once.", max)
+            }
+        }
+    }
+}
+
+// Whether `num` images is something we'd generate, with at most `max` at once.
+pub(crate) fn validate_request(num: u8, max: u8) -> Result<(), BadImageCount> {
+    if num > max {
+        Err(BadImageCount::TooMany(max))
+    } else if num == 0 {
+        Err(BadImageCount::Zero)
+    } else {
+        Ok(())
+    }
+}
+
+// What they're told when billing turns them down, or None if it didn't.
+fn refusal(billing: &Billing, flavor: Flavor) -> Option<&'static str> {
+    Some(match (billing, flavor) {
+        (Billing::OwnKey(_) | Billing::Credit, _) => return None,
+        (Billing::Refused, Flavor::Flavorful) => {
+            "Limit reached. Ping rictic and ask him to update your limits."
+        }
+        (Billing::Refused, Flavor::Terse) => "Out of credit.",
+        (Billing::OverSelfLimit, Flavor::Flavorful) => {
+            "That would take you past the monthly spending limit you set. `/set_limit` changes it."
+        }
+        (Billing::OverSelfLimit, Flavor::Terse) => "Over your monthly limit, see `/set_limit`.",
+    })
+}
+
+// Anything that costs more than this has to be confirmed before we spend the money.
+const CONFIRM_OVER_CENTS: u64 = 50;
+// How long the Confirm button waits before giving up.
+const CONFIRM_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+fn needs_confirmation(cost: Cost) -> bool {
+    cost.millicents() > Cost::cents(CONFIRM_OVER_CENTS).millicents()
+}
+
+// Asks whether `cost` is alright with a pair of buttons. Returns whether they said yes,
+// having told them nothing was charged if they didn't.
+async fn confirm_cost(ctx: Context<'_>, cost: Cost, num: u8) -> Result<bool, Error> {
+    let confirm_id = format!("{}-confirm", ctx.id());
+    let cancel_id = format!("{}-cancel", ctx.id());
+    let question = format!(
+        "That's {} for {} image{}. Go ahead?",
+        crate::info::format_millicents(cost.millicents().min(i64::MAX as u128) as i64),
+        num,
+        if num == 1 { "" } else { "s" }
+    );
+    let reply = ctx
+        .send(|m| {
+            m.content(question).reply(true).components(|c| {
+                c.create_action_row(|row| {
+                    row.create_button(|b| {
+                        b.custom_id(&confirm_id)
+                            .label("Confirm")
+                            .style(serenity::ButtonStyle::Success)
+                    })
+                    .create_button(|b| {
+                        b.custom_id(&cancel_id)
+                            .label("Cancel")
+                            .style(serenity::ButtonStyle::Secondary)
+                    })
+                })
+            })
+        })
+        .await?;
+    let ids = (confirm_id.clone(), cancel_id);
+    let press = serenity::CollectComponentInteraction::new(ctx)
+        .author_id(ctx.author().id)
+        .channel_id(ctx.channel_id())
+        .filter(move |press| press.data.custom_id == ids.0 || press.data.custom_id == ids.1)
+        .timeout(CONFIRM_TIMEOUT)
+        .await;
+    let (confirmed, answer) = match &press {
+        Some(press) if press.data.custom_id == confirm_id => (true, "Confirmed."),
+        Some(_) => (false, "Cancelled. Nothing was charged."),
+        None => (
+            false,
+            "No answer, so I didn't make anything. Nothing was charged.",
+        ),
+    };
+    // the press needs answering, or Discord shows it as failed. The answer itself goes
+    // in an edit, like any other reply.
+    if let Some(press) = press {
+        press.defer(ctx).await?;
+    }
+    reply
+        .edit(ctx, |m| m.content(answer).components(|c| c))
+        .await?;
+    Ok(confirmed)
+}
+
+fn prompt_from_reference(
+    description: Option<&str>,
+    referenced: Option<&str>,
+) -> Result<String, &'static str> {
+    if let Some(description) = description.map(str::trim).filter(|d| !d.is_empty()) {
+        return Ok(description.to_string());
+    }
+    match referenced.map(str::trim) {
+        None => Err("Tell me what to draw, or reply to someone's message with `!gen` and I'll draw that."),
+        Some("") => Err("There's no text in that message for me to work with. Try replying to one that describes something."),
+        Some(content) => Ok(content.to_string()),
+    }
+}
+
+// Notes what a generation ended up costing for /expensive, once any refunds are taken off.
+// `images` are the ones that were paid for.
+async fn record_cost(ctx: Context<'_>, summary: &GenerationSummary, images: u8) {
+    ctx.data().costs.lock().await.record(CostRecord {
+        timestamp: Utc::now(),
+        user: summary.user.clone(),
+        prompt: summary.prompt.clone(),
+        images,
+        cost_millicents: summary.cost_millicents,
+    });
+}
+
+// Who pays for what the author asks for.
+fn payer_for(ctx: Context<'_>) -> Payer<'_> {
+    match settings::is_automation(ctx.author()) {
+        true => Payer::Automation,
+        false => Payer::for_user(ctx.author(), ctx.guild_id()),
+    }
+}
+
+// The key bill_request will have them use, if they've brought their own.
+async fn own_key_for(ctx: Context<'_>, payer: Payer<'_>) -> Option<ApiKey> {
+    match payer {
+        Payer::Automation => None,
+        _ => ctx.data().user_key(ctx.author().id.0).await,
+    }
+}
+
+// Bills `request` to `payer`, telling them if it's refused. None means it doesn't go
+// ahead.
+async fn bill(
+    ctx: Context<'_>,
+    payer: Payer<'_>,
+    flavor: Flavor,
+    request: &ImageRequest,
+) -> Result<Option<Billing>, Error> {
+    let billing = crate::data::bill_request(ctx.data(), payer, ctx.author().id.0, request).await?;
+    let content = match refusal(&billing, flavor) {
+        Some(content) => content,
+        None => return Ok(Some(billing)),
+    };
+    ctx.send(|m| m.content(content).reply(true).ephemeral(true))
+        .await?;
+    Ok(None)
+}
+
+async fn generate(ctx: Context<'_>, description: String, options: GenOptions) -> Result<(), Error> {
+    let payer = payer_for(ctx);
+    let num = options.num.unwrap_or(4);
+    let flavor = settings::flavor_for(ctx.data(), ctx.author().id.0).await;
+    let max_images = ctx.data().settings.lock().await.max_images();
+    if let Err(bad) = validate_request(num, max_images) {
+        ctx.reply(bad.message(flavor)).await?;
         return Ok(());
     }
-    if num == 0 {
-        ctx.reply("Getting philosophical with us eh? Here's zero images for you:")
-            .await?;
+    let model = options.model.unwrap_or_default();
+    let dimensions = options.size.unwrap_or(Dimensions::Square);
+    let quality = options.quality.unwrap_or(Quality::Standard);
+    if let Err(unsupported) = model.check(dimensions, quality) {
+        ctx.reply(unsupported).await?;
         return Ok(());
     }
     let request = ImageRequest {
         description,
         num,
-        dimensions: size.unwrap_or(Dimensions::Square),
-        style: style.unwrap_or(Style::Vivid),
-        quality: quality.unwrap_or(Quality::Standard),
+        model,
+        dimensions,
+        style: options.style.unwrap_or(Style::Vivid),
+        quality,
     };
-    let permitted = crate::data::debit_for_request(ctx.data(), user, &request).await?;
-    if permitted == crate::data::RequestPermitted::No {
-        ctx.send(|m| {
-            m.content("Limit reached. Ping rictic and ask him to to update your limits.")
-                .reply(true)
-                .ephemeral(true)
-        })
-        .await?;
-        return Ok(());
-    }
-    let reply = if num == 1 {
-        ctx.reply("Generating image...").await?
+    // a local server doesn't cost anything, so there's nothing to bill
+    let free = ctx.data().image_backend.is_free();
+    let confirm = async {
+        // automation has nobody to press the button
+        if free || matches!(payer, Payer::Automation) {
+            return Ok(true);
+        }
+        // someone with their own key pays OpenAI's price, not ours
+        let quoted = match ctx.data().user_key(ctx.author().id.0).await {
+            Some(_) => request.cost(),
+            None => {
+                let multiplier = ctx.data().settings.lock().await.cost_multiplier();
+                request.cost().scaled(multiplier)
+            }
+        };
+        match needs_confirmation(quoted) {
+            true => confirm_cost(ctx, quoted, num).await,
+            false => Ok(true),
+        }
+    };
+    let _slot = match admit(ctx, payer, flavor, confirm).await? {
+        Some(slot) => slot,
+        None => return Ok(()),
+    };
+    // before billing, so they're never charged for something that can't even start
+    let image_gen = Generator::new(&ctx.data().image_backend, own_key_for(ctx, payer).await)?;
+    let billing = match free {
+        true => Billing::Credit,
+        false => match bill(ctx, payer, flavor, &request).await? {
+            Some(billing) => billing,
+            None => return Ok(()),
+        },
+    };
+    let own_key = match billing {
+        Billing::OwnKey(key) => Some(key),
+        _ => None,
+    };
+    let generating = if num == 1 {
+        "Generating image...".to_string()
     } else {
-        ctx.reply(format!("Generating {} images...", num)).await?
+        format!("Generating {} images...", num)
     };
+    let reply = ctx.reply(generating).await?;
     let reply_message = reply.message().await.ok();
-    let images = OpenAIImageGen::new()?.create_image(request).await?;
-    let mut failures = 0;
-    let mut actual_images = Vec::new();
-    for image in images.into_iter() {
-        match image {
-            Ok(image) => {
-                actual_images.push(image);
+    let typing = crate::typing::start(ctx);
+    let multiplier = ctx.data().settings.lock().await.cost_multiplier();
+    let mut summary = GenerationSummary {
+        user_id: ctx.author().id.0,
+        user: format!("{}#{}", ctx.author().name, ctx.author().discriminator),
+        prompt: request.description.clone(),
+        requested: num,
+        generated: 0,
+        failed: 0,
+        // it's on their own OpenAI bill, not ours, or it didn't cost anyone anything
+        cost_millicents: match own_key {
+            _ if free => 0,
+            Some(_) => 0,
+            None => request.cost().scaled(multiplier).millicents(),
+        },
+        success: false,
+    };
+    let (progress, mut updates) = tokio::sync::mpsc::unbounded_channel();
+    let show_progress = async {
+        let mut done = 0;
+        while let Some(images) = updates.recv().await {
+            done += images;
+            // the last one gets the real summary below
+            if done < num {
+                let content = format!("Generated {}/{}...", done, num);
+                // best effort, the images matter more than the count
+                let _ = reply.edit(ctx, |m| m.content(content)).await;
             }
-            Err(err) => {
-                failures += 1;
-                println!("Failed to generate image: {}", err);
+        }
+    };
+    let (tally, ()) = tokio::join!(
+        run_generation(&image_gen, request.clone(), progress),
+        show_progress
+    );
+    if let Some(rate_limit) = image_gen.rate_limit() {
+        *ctx.data().rate_limit.lock().await = Some(rate_limit);
+    }
+    let Tally {
+        images: actual_images,
+        failures,
+        rejected,
+        timed_out,
+    } = match tally {
+        Err(err) => {
+            summary.failed = num as usize;
+            record_cost(ctx, &summary, num).await;
+            if !ctx.data().dry_run() {
+                webhook::notify(summary);
             }
+            return Err(err);
         }
+        Ok(tally) => tally,
+    };
+    // best effort, nobody's waiting on it
+    if let Some(dir) = gallery_dir().filter(|_| !ctx.data().dry_run()) {
+        let images = actual_images.clone();
+        let user_id = ctx.author().id.0;
+        let prompt = request.description.clone();
+        tokio::task::spawn_blocking(move || {
+            let now = Utc::now();
+            for image in images.iter() {
+                if let Err(err) = save_to_gallery(&dir, user_id, now, &prompt, image) {
+                    crate::throttled_log::log(format!("Failed to save to the gallery: {}", err));
+                }
+            }
+        });
     }
+    // nothing got made, so nothing should be paid for
+    if rejected + timed_out > 0 && summary.cost_millicents > 0 {
+        let refund = ImageRequest {
+            num: rejected + timed_out,
+            ..request.clone()
+        };
+        let refunded = crate::data::refund_for_request(ctx.data(), payer, &refund).await?;
+        summary.cost_millicents = summary.cost_millicents.saturating_sub(refunded as u128);
+    }
+    record_cost(ctx, &summary, num - rejected - timed_out).await;
 
-    ctx.channel_id()
-        .send_files(
-            ctx.http(),
-            actual_images
-                .into_iter()
-                .map(|image| serenity::AttachmentType::Bytes {
-                    data: std::borrow::Cow::Owned(image.bytes),
-                    filename: format!(
-                        "{}.png",
-                        image.revised_prompt.unwrap_or("image".to_string())
-                    )
-                    .to_string(),
-                }),
-            |f| match reply_message {
-                Some(msg) => f.reference_message((ctx.channel_id(), msg.id)),
-                None => f,
-            },
-        )
+    summary.generated = actual_images.len();
+    summary.failed = failures + rejected as usize + timed_out as usize;
+    summary.success = !actual_images.is_empty();
+    if !ctx.data().dry_run() {
+        webhook::notify(summary);
+    }
+    if actual_images.is_empty() && rejected + timed_out > 0 {
+        let _ = typing.send(());
+        let response = if rejected > 0 {
+            "That prompt was rejected by OpenAI's content filter, so there's nothing to show. You haven't been charged for it."
+        } else {
+            "The image service took too long to answer, so there's nothing to show. You haven't been charged for it. Try again in a bit?"
+        };
+        reply.edit(ctx, |m| m.content(response)).await?;
+        return Ok(());
+    }
+
+    // one image doesn't need picking from
+    let sheet = match options.contact_sheet {
+        Some(mode) if actual_images.len() > 1 => {
+            let images: Vec<Vec<u8>> = actual_images.iter().map(|i| i.bytes.clone()).collect();
+            match tokio::task::spawn_blocking(move || contact_sheet::build(&images)).await? {
+                Ok(sheet) => Some((mode, sheet)),
+                Err(err) => {
+                    // they still get the images themselves
+                    crate::throttled_log::log(format!("Failed to make a contact sheet: {}", err));
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let format = options.format.unwrap_or_default();
+    let mut delivery =
+        tokio::task::spawn_blocking(move || Delivery::new(actual_images, format)).await?;
+    if let Some((mode, sheet)) = sheet {
+        delivery.add_contact_sheet(sheet, mode);
+    }
+    let destination = match ctx.channel_id().to_channel(ctx).await {
+        // there's nowhere to use /gen in a forum but inside one of its posts, which are
+        // threads
+        Ok(serenity::Channel::Guild(channel)) if is_thread(channel.kind) => {
+            let parent = match channel.parent_id {
+                Some(parent) => parent.to_channel(ctx).await.ok(),
+                None => None,
+            };
+            match parent {
+                Some(serenity::Channel::Guild(parent)) => Destination::for_parent(
+                    parent.id,
+                    parent.kind,
+                    parent.flags,
+                    &parent.available_tags,
+                ),
+                _ => Destination::Channel,
+            }
+        }
+        _ => Destination::Channel,
+    };
+    let target = match destination {
+        Destination::Channel => ctx.channel_id(),
+        Destination::ForumPost {
+            forum,
+            tag,
+            missing_tag,
+        } => match create_forum_post(ctx, forum, &request.description, tag).await {
+            Ok(post) => post,
+            Err(_) if missing_tag => {
+                let _ = typing.send(());
+                let content = "That forum wants a tag on every post, but only mods can use the ones it has, so I couldn't post these. Ask a mod to add a tag anyone can use.";
+                reply.edit(ctx, |m| m.content(content)).await?;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        },
+    };
+    let _ = typing.send(());
+    target
+        .send_files(ctx.http(), delivery.attachments, |f| {
+            if !delivery.prompts.is_empty() {
+                f.embed(|e| {
+                    for (filename, prompt) in delivery.prompts.iter() {
+                        e.field(filename, prompt, false);
+                    }
+                    e
+                });
+            }
+            match reply_message {
+                // a forum post is somewhere else, there's nothing there to reply to
+                Some(msg) if target == ctx.channel_id() => {
+                    f.reference_message((ctx.channel_id(), msg.id))
+                }
+                _ => f,
+            }
+        })
         .await?;
+    let mut response = "Generated!".to_string();
+    if target != ctx.channel_id() {
+        response = format!("{} Posted in <#{}>.", response, target.0);
+    }
+    if failures > 0 {
+        response = format!("{} ({} failed)", response, failures);
+    }
+    if rejected > 0 {
+        response = format!(
+            "{} ({} rejected by the content filter, and not charged for)",
+            response, rejected
+        );
+    }
+    if timed_out > 0 {
+        response = format!(
+            "{} ({} timed out, and not charged for)",
+            response, timed_out
+        );
+    }
     reply
         .edit(ctx, |m| {
-            let mut response = "Generated!".to_string();
-            if failures > 0 {
-                response = format!("{} ({} failed)", response, failures);
-            }
             let m = m.content(response);
             // for (name, image) in files.iter() {
             //     m = m.attachment(serenity::AttachmentType::File {
@@ -103,7 +539,960 @@ pub async fn gen(
     Ok(())
 }
 
-const OPENAI_IMAGE_GEN_URL: &'static str = "https://api.openai.com/v1/images/generations";
+// Why a generation isn't going ahead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Held {
+    // they cancelled, or never answered, and confirm_cost has already said so
+    Declined,
+    // they're over GEN_PER_MINUTE, for this much longer
+    RateLimited(std::time::Duration),
+    // the channel already has max_per_channel going
+    ChannelBusy,
+}
+
+// Takes a generation past `confirm`, the author's GEN_PER_MINUTE and a slot in the
+// channel, in that order. Confirming goes first so that cancelling, or leaving the
+// buttons to time out, doesn't use up either of the others. Automation isn't held to
+// per-user limits.
+async fn try_admit<'a>(
+    data: &'a crate::data::Data,
+    payer: Payer<'_>,
+    user_id: u64,
+    channel: u64,
+    confirm: impl std::future::Future<Output = Result<bool, Error>>,
+) -> Result<Result<ChannelSlot<'a>, Held>, Error> {
+    if !confirm.await? {
+        return Ok(Err(Held::Declined));
+    }
+    if !matches!(payer, Payer::Automation) {
+        let allowed = data.gen_rate_limits.lock().await.try_record(
+            user_id,
+            std::time::Instant::now(),
+            gen_per_minute(),
+        );
+        if let Err(wait) = allowed {
+            return Ok(Err(Held::RateLimited(wait)));
+        }
+    }
+    let max_per_channel = data.settings.lock().await.max_per_channel();
+    Ok(data
+        .channel_slots
+        .try_start(channel, max_per_channel as usize)
+        .ok_or(Held::ChannelBusy))
+}
+
+// try_admit, telling them why when it doesn't go ahead.
+async fn admit<'a>(
+    ctx: Context<'a>,
+    payer: Payer<'_>,
+    flavor: Flavor,
+    confirm: impl std::future::Future<Output = Result<bool, Error>>,
+) -> Result<Option<ChannelSlot<'a>>, Error> {
+    let held = match try_admit(
+        ctx.data(),
+        payer,
+        ctx.author().id.0,
+        ctx.channel_id().0,
+        confirm,
+    )
+    .await?
+    {
+        Ok(slot) => return Ok(Some(slot)),
+        Err(held) => held,
+    };
+    match held {
+        Held::Declined => {}
+        Held::RateLimited(wait) => {
+            let seconds = wait.as_secs().max(1);
+            let content = match flavor {
+                Flavor::Flavorful => format!(
+                    "Slow down, chum! That's a lot of pictures in a minute. Try again in {}s.",
+                    seconds
+                ),
+                Flavor::Terse => format!("Too many generations, try again in {}s.", seconds),
+            };
+            ctx.send(|m| m.content(content).reply(true).ephemeral(true))
+                .await?;
+        }
+        Held::ChannelBusy => {
+            let content = match flavor {
+                Flavor::Flavorful => "I've already got my hands full in this channel, chum. Give me a sec to finish up.",
+                Flavor::Terse => "Too many generations running in this channel, try again shortly.",
+            };
+            ctx.reply(content).await?;
+        }
+    }
+    Ok(None)
+}
+
+// OpenAI won't take anything bigger for a variation.
+const MAX_VARIATION_UPLOAD_BYTES: u64 = 4 * 1024 * 1024;
+
+// Variations of an image someone uploads. Only DALL-E 2 does these, always at 1024x1024.
+#[poise::command(slash_command)]
+pub async fn vary(
+    ctx: Context<'_>,
+    #[description = "A square PNG, under 4 MB"] image: serenity::Attachment,
+    #[description = "The number of variations to make"] num: Option<u8>,
+) -> Result<(), Error> {
+    let payer = payer_for(ctx);
+    let num = num.unwrap_or(4);
+    let flavor = settings::flavor_for(ctx.data(), ctx.author().id.0).await;
+    let max_images = ctx.data().settings.lock().await.max_images();
+    if let Err(bad) = validate_request(num, max_images) {
+        ctx.reply(bad.message(flavor)).await?;
+        return Ok(());
+    }
+    if image.size > MAX_VARIATION_UPLOAD_BYTES {
+        ctx.reply("That image is too big, it needs to be under 4 MB.")
+            .await?;
+        return Ok(());
+    }
+    let png = image.download().await?;
+    if let Err(problem) = check_upload(&png) {
+        ctx.reply(problem).await?;
+        return Ok(());
+    }
+    let _slot = match admit(ctx, payer, flavor, async { Ok(true) }).await? {
+        Some(slot) => slot,
+        None => return Ok(()),
+    };
+    // only OpenAI does variations. Set it up before billing, so nobody pays for one that
+    // can't even start.
+    let image_gen = match ctx.data().image_backend {
+        Backend::DryRun => None,
+        _ => match OpenAIImageGen::new(own_key_for(ctx, payer).await) {
+            Ok(image_gen) => Some(image_gen),
+            Err(err) => {
+                crate::throttled_log::log(format!("Can't make variations: {}", err));
+                ctx.reply("This bot isn't set up to make variations, sorry!")
+                    .await?;
+                return Ok(());
+            }
+        },
+    };
+    let request = ImageRequest::variation(&image.filename, num);
+    let charged = match bill(ctx, payer, flavor, &request).await? {
+        Some(billing) => billing == Billing::Credit,
+        None => return Ok(()),
+    };
+    let reply = ctx.reply("Making variations...").await?;
+    let typing = crate::typing::start(ctx);
+    let images = match &image_gen {
+        None => Ok(PlaceholderGen.images(num)),
+        Some(image_gen) => {
+            let images = image_gen.create_variation(png, num).await;
+            if let Some(rate_limit) = image_gen.rate_limit() {
+                *ctx.data().rate_limit.lock().await = Some(rate_limit);
+            }
+            images
+        }
+    };
+    let images = match images {
+        Ok(images) => images,
+        // none of them got made
+        Err(err) => {
+            let _ = typing.send(());
+            if charged {
+                crate::data::refund_for_request(ctx.data(), payer, &request).await?;
+            }
+            return Err(err);
+        }
+    };
+    let _ = typing.send(());
+    let mut actual_images = Vec::new();
+    let mut not_made = 0;
+    for image in images {
+        match image {
+            Ok(image) => actual_images.push(image),
+            Err(err) if rejected_by_content_filter(&err) || is_timeout(&err) => not_made += 1,
+            Err(err) => {
+                crate::throttled_log::log(format!("Failed to make a variation: {}", err));
+            }
+        }
+    }
+    // same as /gen, nothing got made so nothing should be paid for
+    if not_made > 0 && charged {
+        let refund = ImageRequest::variation(&image.filename, not_made);
+        crate::data::refund_for_request(ctx.data(), payer, &refund).await?;
+    }
+    if actual_images.is_empty() {
+        let response = "Couldn't make any variations of that, sorry!";
+        reply.edit(ctx, |m| m.content(response)).await?;
+        return Ok(());
+    }
+    let delivery = Delivery::new(actual_images, Format::Png);
+    ctx.channel_id()
+        .send_files(ctx.http(), delivery.attachments, |f| f)
+        .await?;
+    let response = "Varied!";
+    reply.edit(ctx, |m| m.content(response)).await?;
+    Ok(())
+}
+
+// What's wrong with an upload that's meant to be varied, if anything.
+fn check_upload(bytes: &[u8]) -> Result<(), &'static str> {
+    if !bytes.starts_with(PNG_SIGNATURE) || bytes.get(12..16) != Some(b"IHDR") {
+        return Err("That isn't a PNG. OpenAI can only make variations of PNGs.");
+    }
+    let dimension = |at: usize| {
+        bytes
+            .get(at..at + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    };
+    if dimension(16) != dimension(20) {
+        return Err("That image isn't square. OpenAI can only make variations of square images.");
+    }
+    Ok(())
+}
+
+fn is_thread(kind: serenity::ChannelType) -> bool {
+    matches!(
+        kind,
+        serenity::ChannelType::PublicThread | serenity::ChannelType::PrivateThread
+    )
+}
+
+// Where the images go. Forums only take new posts, so /gen in one of a forum's posts
+// gets a new post of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Destination {
+    Channel,
+    ForumPost {
+        forum: serenity::ChannelId,
+        // the tag to put on it, when the forum won't take a post without one
+        tag: Option<serenity::ForumTagId>,
+        // the forum wants a tag, but they're all moderated, so it'll likely refuse
+        missing_tag: bool,
+    },
+}
+impl Destination {
+    // For a thread whose parent channel is `parent`.
+    fn for_parent(
+        parent: serenity::ChannelId,
+        kind: serenity::ChannelType,
+        flags: serenity::ChannelFlags,
+        tags: &[serenity::ForumTag],
+    ) -> Self {
+        if kind != serenity::ChannelType::Forum {
+            return Destination::Channel;
+        }
+        let required = flags.contains(serenity::ChannelFlags::REQUIRE_TAG);
+        // moderated tags need Manage Threads, so only ever use one anyone can
+        let tag = match required {
+            false => None,
+            true => tags.iter().find(|tag| !tag.moderated).map(|tag| tag.id),
+        };
+        Destination::ForumPost {
+            forum: parent,
+            tag,
+            missing_tag: required && tag.is_none(),
+        }
+    }
+}
+
+// Discord won't name a thread (and so a forum post) anything longer than this.
+const MAX_POST_TITLE_LEN: usize = 100;
+
+// The prompt on one line, cut short to fit.
+fn post_title(prompt: &str) -> String {
+    let title = prompt.split_whitespace().collect::<Vec<_>>().join(" ");
+    if title.chars().count() <= MAX_POST_TITLE_LEN {
+        return title;
+    }
+    title
+        .chars()
+        .take(MAX_POST_TITLE_LEN - 1)
+        .collect::<String>()
+        + "…"
+}
+
+// Starts a post in `forum`, with the prompt as its first message. Returns the post, which
+// is a thread, to send the images to.
+async fn create_forum_post(
+    ctx: Context<'_>,
+    forum: serenity::ChannelId,
+    prompt: &str,
+    tag: Option<serenity::ForumTagId>,
+) -> Result<serenity::ChannelId, Error> {
+    let content: String = prompt.chars().take(MAX_MESSAGE_LEN).collect();
+    let mut post = serde_json::Map::new();
+    post.insert("name".to_string(), json!(post_title(prompt)));
+    post.insert("message".to_string(), json!({ "content": content }));
+    if let Some(tag) = tag {
+        post.insert("applied_tags".to_string(), json!([tag.0.to_string()]));
+    }
+    // serenity 0.11 has no builder for forum posts, but they're made with the same
+    // request as any other thread
+    let post = ctx.http().create_private_thread(forum.0, &post).await?;
+    Ok(post.id)
+}
+
+// Discord won't take an embed field longer than this, or an embed longer than the total.
+pub(crate) const MAX_EMBED_FIELD_LEN: usize = 1024;
+pub(crate) const MAX_EMBED_LEN: usize = 6000;
+// Nor a field name longer than this, and those are the filenames.
+const MAX_EMBED_FIELD_NAME_LEN: usize = 256;
+// How much of a prompt goes into a filename.
+const MAX_FILENAME_LEN: usize = 100;
+
+// A prompt, made fit to name a file after. Path separators and control characters (like
+// newlines) go, spaces become dashes, and anything empty is just `image`.
+fn sanitize_filename(prompt: &str) -> String {
+    let words: String = prompt
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' => ' ',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect();
+    let name: String = words
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("-")
+        .chars()
+        .take(MAX_FILENAME_LEN)
+        .collect();
+    // no leading dots either, so nothing's hidden or turns into `..`
+    match name.trim_matches(|c| c == '-' || c == '.') {
+        "" => "image".to_string(),
+        name => name.to_string(),
+    }
+}
+
+// The files to attach, and which revised prompt made each one (keyed by filename), since
+// with several images OpenAI can rewrite the prompt differently for each of them. Images
+// that got the same rewrite share one entry, keyed by all of their filenames.
+struct Delivery {
+    attachments: Vec<serenity::AttachmentType<'static>>,
+    prompts: Vec<(String, String)>,
+}
+impl Delivery {
+    // Converting is slow for anything but PNG, so run this off the async threads.
+    fn new(images: Vec<Image>, format: Format) -> Self {
+        let mut filenames = Vec::new();
+        let mut files = Vec::new();
+        for (i, image) in images.iter().enumerate() {
+            let (bytes, format) = reencode(image.bytes.clone(), format);
+            // numbered, so the contact sheet still matches up
+            let name = sanitize_filename(image.revised_prompt.as_deref().unwrap_or_default());
+            filenames.push(format!("{}-{}.{}", name, i + 1, format.extension()));
+            files.push(bytes);
+        }
+        let mut prompts = revised_prompts(&images, &filenames);
+        let attachments = files
+            .into_iter()
+            .zip(filenames)
+            .map(|(bytes, filename)| serenity::AttachmentType::Bytes {
+                data: std::borrow::Cow::Owned(bytes),
+                filename,
+            })
+            .collect();
+        // share the embed out evenly, a full batch of long prompts won't fit otherwise
+        let budget = MAX_EMBED_LEN / prompts.len().max(1);
+        for (filename, prompt) in prompts.iter_mut() {
+            // several images with the same long prompt
+            if filename.chars().count() > MAX_EMBED_FIELD_NAME_LEN {
+                *filename = filename
+                    .chars()
+                    .take(MAX_EMBED_FIELD_NAME_LEN - 1)
+                    .collect::<String>()
+                    + "…";
+            }
+            let limit = MAX_EMBED_FIELD_LEN.min(budget - filename.len());
+            if prompt.chars().count() > limit {
+                *prompt = prompt.chars().take(limit - 1).collect::<String>() + "…";
+            }
+        }
+        Self {
+            attachments,
+            prompts,
+        }
+    }
+
+    // The prompts keep their image's filename, which is also its number on the sheet.
+    fn add_contact_sheet(&mut self, sheet: Vec<u8>, mode: ContactSheet) {
+        if mode == ContactSheet::Instead {
+            self.attachments.clear();
+        }
+        self.attachments.push(serenity::AttachmentType::Bytes {
+            data: std::borrow::Cow::Owned(sheet),
+            filename: CONTACT_SHEET_FILE.to_string(),
+        });
+    }
+}
+
+const CONTACT_SHEET_FILE: &str = "contact-sheet.png";
+
+// What kind of file each image gets sent as. OpenAI's PNGs are a few MB each.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum Format {
+    #[default]
+    #[name = "PNG, just as DALL-E made it"]
+    Png,
+    #[name = "JPEG, a fraction of the size, with a little loss"]
+    Jpeg,
+    #[name = "WebP, smaller than PNG with no loss"]
+    Webp,
+}
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+            Format::Webp => "webp",
+        }
+    }
+}
+
+// Good enough that nobody will notice, still a lot smaller than the PNG.
+const JPEG_QUALITY: u8 = 90;
+
+// `png` as a `format` file, or as it was if it won't convert. Returns what it ended up as.
+fn reencode(png: Vec<u8>, format: Format) -> (Vec<u8>, Format) {
+    if format == Format::Png {
+        return (png, Format::Png);
+    }
+    match encode(&png, format) {
+        Ok(bytes) => (bytes, format),
+        Err(err) => {
+            crate::throttled_log::log(format!(
+                "Failed to convert an image to {}, sending the PNG: {}",
+                format.extension(),
+                err
+            ));
+            (png, Format::Png)
+        }
+    }
+}
+
+fn encode(png: &[u8], format: Format) -> Result<Vec<u8>, Error> {
+    let image = image::load_from_memory_with_format(png, image::ImageFormat::Png)?;
+    let mut bytes = std::io::Cursor::new(Vec::new());
+    match format {
+        Format::Png => image.write_to(&mut bytes, image::ImageOutputFormat::Png)?,
+        // no alpha in a JPEG
+        Format::Jpeg => image
+            .to_rgb8()
+            .write_to(&mut bytes, image::ImageOutputFormat::Jpeg(JPEG_QUALITY))?,
+        Format::Webp => image
+            .to_rgba8()
+            .write_to(&mut bytes, image::ImageOutputFormat::WebP)?,
+    }
+    Ok(bytes.into_inner())
+}
+
+// Each different revised prompt, in the order they first come up, with the filenames of
+// the images it made (which go with `images` one for one). Images OpenAI didn't rewrite
+// the prompt for are left out.
+fn revised_prompts(images: &[Image], filenames: &[String]) -> Vec<(String, String)> {
+    let mut prompts: Vec<(Vec<String>, &str)> = Vec::new();
+    for (image, filename) in images.iter().zip(filenames) {
+        let prompt = match image.revised_prompt.as_deref() {
+            None => continue,
+            Some(prompt) => prompt,
+        };
+        match prompts.iter_mut().find(|(_, seen)| *seen == prompt) {
+            Some((filenames, _)) => filenames.push(filename.clone()),
+            None => prompts.push((vec![filename.clone()], prompt)),
+        }
+    }
+    prompts
+        .into_iter()
+        .map(|(filenames, prompt)| (filenames.join(", "), prompt.to_string()))
+        .collect()
+}
+
+// How much of our OpenAI quota is left, going by the headers on the last response.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct RateLimit {
+    remaining_requests: Option<u64>,
+    // how long until the quota refills, like `6m0s`
+    reset_requests: Option<String>,
+    remaining_images: Option<u64>,
+    reset_images: Option<String>,
+    seen_at: DateTime<Utc>,
+}
+impl RateLimit {
+    fn from_headers(headers: &reqwest::header::HeaderMap) -> Option<Self> {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().to_string())
+        };
+        let number = |name: &str| header(name).and_then(|value| value.parse().ok());
+        let rate_limit = RateLimit {
+            remaining_requests: number("x-ratelimit-remaining-requests"),
+            reset_requests: header("x-ratelimit-reset-requests"),
+            remaining_images: number("x-ratelimit-remaining-images"),
+            reset_images: header("x-ratelimit-reset-images"),
+            seen_at: Utc::now(),
+        };
+        if rate_limit.remaining_requests.is_none() && rate_limit.remaining_images.is_none() {
+            return None;
+        }
+        Some(rate_limit)
+    }
+
+    fn describe(&self) -> String {
+        let line =
+            |what: &str, remaining: Option<u64>, reset: &Option<String>| match (remaining, reset) {
+                (None, _) => format!("{}: unknown", what),
+                (Some(remaining), None) => format!("{}: {} left", what, remaining),
+                (Some(remaining), Some(reset)) => {
+                    format!("{}: {} left, resets in {}", what, remaining, reset)
+                }
+            };
+        format!(
+            "{}\n{}\nAs of <t:{}:R>",
+            line("Requests", self.remaining_requests, &self.reset_requests),
+            line("Images", self.remaining_images, &self.reset_images),
+            self.seen_at.timestamp()
+        )
+    }
+}
+
+#[poise::command(slash_command)]
+pub async fn ratelimit(ctx: Context<'_>) -> Result<(), Error> {
+    if !settings::ensure_admin(ctx).await? {
+        return Ok(());
+    }
+    let response = match &*ctx.data().rate_limit.lock().await {
+        None => "I haven't heard from OpenAI since I woke up, so no idea yet.".to_string(),
+        Some(rate_limit) => rate_limit.describe(),
+    };
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+    Ok(())
+}
+
+// What each kind of image costs, straight from `ImageRequest::cost`, so it can't drift
+// from what people actually get charged.
+#[poise::command(slash_command)]
+pub async fn prices(ctx: Context<'_>) -> Result<(), Error> {
+    let multiplier = ctx.data().settings.lock().await.cost_multiplier();
+    let response = price_table(multiplier);
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+    Ok(())
+}
+
+fn price_table(multiplier: f64) -> String {
+    let mut table = format!(
+        "```\n{:<10}{:<11}{:<10}{}\n",
+        "Model", "Size", "Quality", "Per image"
+    );
+    for (model, dimensions, quality) in offerings() {
+        let request = ImageRequest {
+            description: String::new(),
+            num: 1,
+            model,
+            dimensions,
+            style: Style::Vivid,
+            quality,
+        };
+        let cost = request.cost().scaled(multiplier).millicents();
+        table += &format!(
+            "{:<10}{:<11}{:<10}{}\n",
+            model.to_str(),
+            dimensions.to_size(),
+            quality.to_str(),
+            crate::info::format_millicents(cost.min(i64::MAX as u128) as i64)
+        );
+    }
+    let variation = ImageRequest::variation("", 1)
+        .cost()
+        .scaled(multiplier)
+        .millicents();
+    table += &format!(
+        "{:<31}{}\n",
+        "Variation (/vary)",
+        crate::info::format_millicents(variation.min(i64::MAX as u128) as i64)
+    );
+    table += "```\nStyle doesn't change the price. Rounded to the nearest cent.";
+    table
+}
+
+// Every model, size and quality that /gen will take.
+fn offerings() -> impl Iterator<Item = (Model, Dimensions, Quality)> {
+    Model::ALL.into_iter().flat_map(|model| {
+        Dimensions::ALL.into_iter().flat_map(move |dimensions| {
+            Quality::ALL
+                .into_iter()
+                .filter(move |&quality| model.check(dimensions, quality).is_ok())
+                .map(move |quality| (model, dimensions, quality))
+        })
+    })
+}
+
+// Generations running in each channel, so one busy channel can't hog OpenAI.
+#[derive(Debug, Default)]
+pub(crate) struct ChannelSlots {
+    in_flight: std::sync::Mutex<HashMap<u64, usize>>,
+}
+impl ChannelSlots {
+    // None if the channel is already at `max`. The slot frees up when it's dropped.
+    pub(crate) fn try_start(&self, channel: u64, max: usize) -> Option<ChannelSlot<'_>> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(channel).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(ChannelSlot {
+            slots: self,
+            channel,
+        })
+    }
+}
+
+pub(crate) struct ChannelSlot<'a> {
+    slots: &'a ChannelSlots,
+    channel: u64,
+}
+impl Drop for ChannelSlot<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.slots.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.channel) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.channel);
+            }
+        }
+    }
+}
+
+// Each user gets this many /gens per RATE_WINDOW, unless GEN_PER_MINUTE says otherwise.
+const DEFAULT_GEN_PER_MINUTE: usize = 5;
+const RATE_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
+
+fn gen_per_minute() -> usize {
+    std::env::var("GEN_PER_MINUTE")
+        .ok()
+        .and_then(|n| n.trim().parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_GEN_PER_MINUTE)
+}
+
+// When each user last used /gen, so nobody can fire off dozens at once even when they can
+// afford it.
+#[derive(Debug, Default)]
+pub(crate) struct UserRateLimiter {
+    recent: HashMap<u64, std::collections::VecDeque<std::time::Instant>>,
+}
+impl UserRateLimiter {
+    // Notes a generation by `user` at `now`, unless they've had `max` already in the
+    // RATE_WINDOW before it, in which case it says how long until they can go again.
+    pub(crate) fn try_record(
+        &mut self,
+        user: u64,
+        now: std::time::Instant,
+        max: usize,
+    ) -> Result<(), std::time::Duration> {
+        let recent = self.recent.entry(user).or_default();
+        while recent
+            .front()
+            .is_some_and(|&at| now.duration_since(at) >= RATE_WINDOW)
+        {
+            recent.pop_front();
+        }
+        if recent.len() >= max {
+            let oldest = recent[recent.len() - max];
+            return Err(RATE_WINDOW - now.duration_since(oldest));
+        }
+        recent.push_back(now);
+        Ok(())
+    }
+}
+
+// Where /gen's images come from. Picked once at startup from IMAGE_BACKEND.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) enum Backend {
+    #[default]
+    OpenAI,
+    // an Automatic1111-style server, at STABLE_DIFFUSION_URL
+    StableDiffusion(String),
+    // DRY_RUN, nothing gets generated at all
+    DryRun,
+}
+impl Backend {
+    // A local server costs nothing per image, so nobody gets billed for it.
+    pub(crate) fn is_free(&self) -> bool {
+        matches!(self, Backend::StableDiffusion(_))
+    }
+
+    pub(crate) fn from_env() -> Self {
+        parse_backend(
+            std::env::var("IMAGE_BACKEND").ok().as_deref(),
+            std::env::var("STABLE_DIFFUSION_URL").ok().as_deref(),
+        )
+    }
+}
+
+// OpenAI unless IMAGE_BACKEND is `stable-diffusion` and there's a URL to find it at.
+fn parse_backend(backend: Option<&str>, url: Option<&str>) -> Backend {
+    let url = url
+        .map(|url| url.trim().trim_end_matches('/'))
+        .filter(|url| !url.is_empty());
+    match (backend.map(str::trim), url) {
+        (Some("stable-diffusion"), Some(url)) => Backend::StableDiffusion(url.to_string()),
+        (Some("stable-diffusion"), None) => {
+            println!("IMAGE_BACKEND is stable-diffusion, but STABLE_DIFFUSION_URL isn't set. Using OpenAI.");
+            Backend::OpenAI
+        }
+        _ => Backend::OpenAI,
+    }
+}
+
+// Something that can make the images for a /gen.
+trait ImageGenerator {
+    // Says on `progress` how many images each call was for as it finishes, whether or not
+    // they came out.
+    async fn create_image(
+        &self,
+        request: ImageRequest,
+        progress: tokio::sync::mpsc::UnboundedSender<u8>,
+    ) -> Result<Vec<Result<Image, Error>>, Error>;
+
+    // What the service said about how much more it'll take, if it says.
+    fn rate_limit(&self) -> Option<RateLimit> {
+        None
+    }
+}
+
+// Whichever generator the backend calls for.
+enum Generator {
+    OpenAI(OpenAIImageGen),
+    StableDiffusion(StableDiffusionGen),
+    Placeholder(PlaceholderGen),
+}
+impl Generator {
+    // Someone's own key only matters to OpenAI.
+    fn new(backend: &Backend, own_key: Option<ApiKey>) -> Result<Self, String> {
+        Ok(match backend {
+            Backend::OpenAI => Generator::OpenAI(OpenAIImageGen::new(own_key)?),
+            Backend::StableDiffusion(url) => {
+                Generator::StableDiffusion(StableDiffusionGen::new(url.clone()))
+            }
+            Backend::DryRun => Generator::Placeholder(PlaceholderGen),
+        })
+    }
+}
+impl ImageGenerator for Generator {
+    async fn create_image(
+        &self,
+        request: ImageRequest,
+        progress: tokio::sync::mpsc::UnboundedSender<u8>,
+    ) -> Result<Vec<Result<Image, Error>>, Error> {
+        match self {
+            Generator::OpenAI(generator) => generator.create_image(request, progress).await,
+            Generator::StableDiffusion(generator) => {
+                generator.create_image(request, progress).await
+            }
+            Generator::Placeholder(generator) => generator.create_image(request, progress).await,
+        }
+    }
+
+    fn rate_limit(&self) -> Option<RateLimit> {
+        match self {
+            Generator::OpenAI(generator) => generator.rate_limit(),
+            Generator::StableDiffusion(generator) => generator.rate_limit(),
+            Generator::Placeholder(generator) => generator.rate_limit(),
+        }
+    }
+}
+
+// What came of a /gen, sorted by what to tell them about.
+struct Tally {
+    images: Vec<Image>,
+    failures: usize,
+    // turned down by the content filter
+    rejected: u8,
+    timed_out: u8,
+}
+
+async fn run_generation(
+    generator: &impl ImageGenerator,
+    request: ImageRequest,
+    progress: tokio::sync::mpsc::UnboundedSender<u8>,
+) -> Result<Tally, Error> {
+    let mut tally = Tally {
+        images: Vec::new(),
+        failures: 0,
+        rejected: 0,
+        timed_out: 0,
+    };
+    for image in generator.create_image(request, progress).await? {
+        match image {
+            Ok(image) => {
+                tally.images.push(image);
+            }
+            Err(err) if rejected_by_content_filter(&err) => {
+                tally.rejected += 1;
+            }
+            Err(err) if is_timeout(&err) => {
+                tally.timed_out += 1;
+            }
+            Err(err) => {
+                tally.failures += 1;
+                crate::throttled_log::log(format!("Failed to generate image: {}", err));
+            }
+        }
+    }
+    Ok(tally)
+}
+
+// Plain grey squares for DRY_RUN, so everything but the generating itself can be tried out.
+struct PlaceholderGen;
+impl PlaceholderGen {
+    fn images(&self, num: u8) -> Vec<Result<Image, Error>> {
+        let mut png = std::io::Cursor::new(Vec::new());
+        let image = image::RgbaImage::from_pixel(256, 256, image::Rgba([128, 128, 128, 255]))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .map(|()| Image {
+                revised_prompt: Some("A placeholder, this is a dry run.".to_string()),
+                bytes: png.into_inner(),
+            });
+        match image {
+            Ok(image) => (0..num).map(|_| Ok(image.clone())).collect(),
+            Err(err) => repeat_error(err.into(), num as usize),
+        }
+    }
+}
+impl ImageGenerator for PlaceholderGen {
+    async fn create_image(
+        &self,
+        request: ImageRequest,
+        progress: tokio::sync::mpsc::UnboundedSender<u8>,
+    ) -> Result<Vec<Result<Image, Error>>, Error> {
+        let _ = progress.send(request.num);
+        Ok(self.images(request.num))
+    }
+}
+
+// A local Stable Diffusion server with Automatic1111's API. Free to run, so it's only the
+// images that matter here, and /gen doesn't charge anyone for them.
+struct StableDiffusionGen {
+    url: String,
+    timeout: std::time::Duration,
+    retry_budget: u32,
+}
+impl StableDiffusionGen {
+    fn new(url: String) -> Self {
+        let timeout = timeout_from_env("STABLE_DIFFUSION_TIMEOUT_SECS");
+        let retry_budget = std::env::var("MAX_RETRIES_PER_REQUEST")
+            .ok()
+            .and_then(|r| r.trim().parse().ok())
+            .unwrap_or(DEFAULT_RETRY_BUDGET);
+        StableDiffusionGen {
+            url,
+            timeout,
+            retry_budget,
+        }
+    }
+}
+impl ImageGenerator for StableDiffusionGen {
+    async fn create_image(
+        &self,
+        request: ImageRequest,
+        progress: tokio::sync::mpsc::UnboundedSender<u8>,
+    ) -> Result<Vec<Result<Image, Error>>, Error> {
+        let client = reqwest::Client::builder().timeout(self.timeout).build()?;
+        let url = format!("{}/sdapi/v1/txt2img", self.url);
+        let images = report_when_done(
+            request_stable_diffusion(
+                client,
+                &url,
+                &request,
+                RETRY_BACKOFF,
+                RetryBudget::new(self.retry_budget),
+            ),
+            progress,
+            request.num,
+        )
+        .await;
+        // the whole batch is one call, so a failure is a failure of every image
+        Ok(images.unwrap_or_else(|err| repeat_error(err, request.num as usize)))
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StableDiffusionImages {
+    // base64 PNGs
+    images: Vec<String>,
+}
+
+async fn request_stable_diffusion(
+    client: reqwest::Client,
+    url: &str,
+    request: &ImageRequest,
+    backoff: std::time::Duration,
+    budget: RetryBudget,
+) -> Result<Vec<Result<Image, Error>>, Error> {
+    let (width, height) = request.dimensions.pixels();
+    let body = json!({
+        "prompt": request.description,
+        "batch_size": request.num,
+        "width": width,
+        "height": height,
+    });
+    let response = send_with_retries("Stable Diffusion", backoff, &budget, || {
+        client.post(url).json(&body).send()
+    })
+    .await?;
+    let status = response.status();
+    let response = response.text().await.map_err(from_reqwest)?;
+    if !status.is_success() {
+        return Err(format!("Stable Diffusion responded with {}: {}", status, response).into());
+    }
+    let json_response: StableDiffusionImages = serde_json::from_str(&response).map_err(|err| {
+        format!(
+            "Failed to parse Stable Diffusion's response as JSON: {:?}. Full response: {}",
+            err, response
+        )
+    })?;
+    let mut images: Vec<Result<Image, Error>> = json_response
+        .images
+        .iter()
+        .take(request.num as usize)
+        .map(|b64| Image::from_base64(b64, None))
+        .collect();
+    // it can come back short, and every image asked for needs an answer
+    let missing = request.num as usize - images.len();
+    if missing > 0 {
+        let err = format!(
+            "Stable Diffusion sent back {} of the {} images asked for",
+            images.len(),
+            request.num
+        );
+        images.extend(repeat_error(err.into(), missing));
+    }
+    Ok(images)
+}
+
+const OPENAI_IMAGE_GEN_URL: &str = "https://api.openai.com/v1/images/generations";
+const OPENAI_IMAGE_VARIATIONS_URL: &str = "https://api.openai.com/v1/images/variations";
+// A request that failed for a reason that might not last is retried this many times,
+// waiting RETRY_BACKOFF before the first retry and twice as long before each one after.
+const MAX_RETRIES: u32 = 3;
+const RETRY_BACKOFF: std::time::Duration = std::time::Duration::from_secs(1);
+// All the retries of one /gen, of any kind, come out of a budget this big unless
+// MAX_RETRIES_PER_REQUEST says otherwise, so a request that keeps going wrong in new ways
+// can't keep us busy forever.
+const DEFAULT_RETRY_BUDGET: u32 = 10;
+// How long one request gets, unless OPENAI_TIMEOUT_SECS (or for Stable Diffusion,
+// STABLE_DIFFUSION_TIMEOUT_SECS) says otherwise. HD images can take a while, but not this
+// long.
+const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+fn timeout_from_env(var: &str) -> std::time::Duration {
+    std::env::var(var)
+        .ok()
+        .and_then(|t| t.trim().parse().ok())
+        .filter(|&t| t > 0)
+        .map(std::time::Duration::from_secs)
+        .unwrap_or(DEFAULT_TIMEOUT)
+}
 
 #[derive(Debug, serde::Deserialize, Clone)]
 struct OpenAIImages {
@@ -117,16 +1506,151 @@ struct OpenAIImageData {
 }
 impl OpenAIImageData {}
 
+// What OpenAI sends back instead of images when it won't make them.
+#[derive(Debug, serde::Deserialize, Clone)]
+struct OpenAIErrorBody {
+    error: OpenAIError,
+}
+
+#[derive(Debug, serde::Deserialize, Clone, PartialEq)]
+struct OpenAIError {
+    // like `content_policy_violation`, not always there
+    code: Option<String>,
+    message: String,
+}
+
+// An error response from OpenAI, with its error object if we could make sense of it.
+#[derive(Debug, Clone)]
+struct ApiError {
+    status: reqwest::StatusCode,
+    error: Option<OpenAIError>,
+    body: String,
+}
+impl ApiError {
+    fn new(status: reqwest::StatusCode, body: String) -> Self {
+        let error = serde_json::from_str::<OpenAIErrorBody>(&body)
+            .ok()
+            .map(|body| body.error);
+        ApiError {
+            status,
+            error,
+            body,
+        }
+    }
+
+    fn is_content_policy(&self) -> bool {
+        self.error.as_ref().and_then(|error| error.code.as_deref())
+            == Some("content_policy_violation")
+    }
+}
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            Some(error) => write!(
+                f,
+                "OpenAI responded with {}: {}",
+                self.status, error.message
+            ),
+            None => write!(f, "OpenAI responded with {}: {}", self.status, self.body),
+        }
+    }
+}
+impl std::error::Error for ApiError {}
+
+// Whether OpenAI turned the prompt down, rather than failing to make the image.
+fn rejected_by_content_filter(err: &Error) -> bool {
+    err.downcast_ref::<ApiError>()
+        .is_some_and(ApiError::is_content_policy)
+}
+
+// The image service didn't answer before the client's timeout.
+#[derive(Debug, Clone)]
+struct TimedOut;
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The image service took too long to answer")
+    }
+}
+impl std::error::Error for TimedOut {}
+
+// Turns reqwest's timeouts into TimedOut, so they can be told apart from other failures.
+fn from_reqwest(err: reqwest::Error) -> Error {
+    if err.is_timeout() {
+        TimedOut.into()
+    } else {
+        err.into()
+    }
+}
+
+fn is_timeout(err: &Error) -> bool {
+    err.is::<TimedOut>()
+}
+
+// How many more retries one /gen gets, shared by all of its calls to OpenAI.
+#[derive(Debug, Clone)]
+struct RetryBudget {
+    remaining: Arc<std::sync::atomic::AtomicU32>,
+}
+impl RetryBudget {
+    fn new(retries: u32) -> Self {
+        RetryBudget {
+            remaining: Arc::new(retries.into()),
+        }
+    }
+
+    // Spends one retry, if there's one left.
+    fn take(&self) -> bool {
+        use std::sync::atomic::Ordering;
+        self.remaining
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+    }
+}
+
 struct OpenAIImageGen {
     key: String,
+    degenerate_retries: u32,
+    // how many of one /gen's images to ask for at a time, from IMAGE_CONCURRENCY. None
+    // asks for them all at once.
+    concurrency: Option<usize>,
+    // the rate limit headers from the most recent response
+    rate_limit: Arc<std::sync::Mutex<Option<RateLimit>>>,
+    // how long each request gets before we give up on it, from OPENAI_TIMEOUT_SECS
+    timeout: std::time::Duration,
+    // how many retries each /gen gets in all, from MAX_RETRIES_PER_REQUEST
+    retry_budget: u32,
 }
 
 impl OpenAIImageGen {
-    fn new() -> Result<Self, String> {
-        let key = std::env::var("OPENAI_API_KEY")
-            .or_else(|_| Err("missing OPENAI_API_KEY env variable".to_string()))?;
+    // Uses the user's own key if they brought one, otherwise ours.
+    fn new(own_key: Option<ApiKey>) -> Result<Self, String> {
+        let key = match own_key {
+            Some(key) => key.expose().to_string(),
+            None => std::env::var("OPENAI_API_KEY")
+                .map_err(|_| "missing OPENAI_API_KEY env variable".to_string())?,
+        };
+        let degenerate_retries = std::env::var("DEGENERATE_IMAGE_RETRIES")
+            .ok()
+            .and_then(|r| r.trim().parse().ok())
+            .unwrap_or(0);
+        let concurrency = std::env::var("IMAGE_CONCURRENCY")
+            .ok()
+            .and_then(|c| c.trim().parse().ok())
+            .filter(|&c| c > 0);
+        let timeout = timeout_from_env("OPENAI_TIMEOUT_SECS");
+        let retry_budget = std::env::var("MAX_RETRIES_PER_REQUEST")
+            .ok()
+            .and_then(|r| r.trim().parse().ok())
+            .unwrap_or(DEFAULT_RETRY_BUDGET);
 
-        Ok(Self { key })
+        Ok(Self {
+            key,
+            degenerate_retries,
+            concurrency,
+            rate_limit: Arc::default(),
+            timeout,
+            retry_budget,
+        })
     }
 }
 
@@ -134,20 +1658,81 @@ impl OpenAIImageGen {
 pub struct ImageRequest {
     description: String,
     num: u8,
+    model: Model,
     dimensions: Dimensions,
     style: Style,
     quality: Quality,
 }
 impl ImageRequest {
+    #[cfg(test)]
+    pub(crate) fn new(description: String, num: u8) -> Self {
+        ImageRequest {
+            description,
+            num,
+            model: Model::DallE3,
+            dimensions: Dimensions::Square,
+            style: Style::Vivid,
+            quality: Quality::Standard,
+        }
+    }
+
     pub fn cost(&self) -> Cost {
         // https://openai.com/pricing#:~:text=Other%20models-,Image%20models,-Build%20DALL%C2%B7E%20directly
-        let base_cents = match (self.dimensions, self.quality) {
-            (Dimensions::Square, Quality::Standard) => 4,
-            (Dimensions::Square, Quality::HD) => 8,
-            (_, Quality::Standard) => 8,
-            (_, Quality::HD) => 12,
+        // Sizes a model doesn't do are turned away by `Model::check` before anyone's
+        // charged, so those just go with the nearest size it does.
+        let base_millicents = match (self.model, self.dimensions, self.quality) {
+            (Model::DallE2, Dimensions::Small, _) => 1_600,
+            (Model::DallE2, Dimensions::Medium, _) => 1_800,
+            (Model::DallE2, _, _) => 2_000,
+            (Model::DallE3, Dimensions::Wide | Dimensions::Tall, Quality::Standard) => 8_000,
+            (Model::DallE3, Dimensions::Wide | Dimensions::Tall, Quality::HD) => 12_000,
+            (Model::DallE3, _, Quality::Standard) => 4_000,
+            (Model::DallE3, _, Quality::HD) => 8_000,
         };
-        return Cost::cents(base_cents * self.num as u64);
+        Cost::millicents_of(base_millicents * self.num as u128)
+    }
+
+    // How many images to ask for in each call to OpenAI. DALL-E 3 only makes one at a
+    // time, DALL-E 2 can do the whole lot at once.
+    fn images_per_call(&self) -> u8 {
+        match self.model {
+            Model::DallE2 => self.num,
+            Model::DallE3 => 1,
+        }
+    }
+
+    fn body(&self) -> serde_json::Value {
+        match self.model {
+            // no quality or style, DALL-E 2 only has the one
+            Model::DallE2 => json!({
+                "model": self.model.to_str(),
+                "n": self.images_per_call(),
+                "response_format": "b64_json",
+                "size": self.dimensions.to_size(),
+                "prompt": self.description,
+            }),
+            Model::DallE3 => json!({
+                "model": self.model.to_str(),
+                "n": self.images_per_call(),
+                "response_format": "b64_json",
+                "size": self.dimensions.to_size(),
+                "prompt": self.description,
+                "quality": self.quality.to_str(),
+                "style": self.style.to_str(),
+            }),
+        }
+    }
+
+    // What /vary bills for. Variations cost the same as a DALL-E 2 image at 1024x1024.
+    fn variation(filename: &str, num: u8) -> Self {
+        ImageRequest {
+            description: format!("variations of {}", filename),
+            num,
+            model: Model::DallE2,
+            dimensions: Dimensions::Square,
+            style: Style::Vivid,
+            quality: Quality::Standard,
+        }
     }
 
     pub fn num_images(&self) -> u8 {
@@ -155,6 +1740,40 @@ impl ImageRequest {
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, poise::ChoiceParameter)]
+pub enum Model {
+    #[name = "DALL-E 2, cheaper and smaller, and a lot less faithful to the prompt"]
+    DallE2,
+    #[default]
+    #[name = "DALL-E 3, the default"]
+    DallE3,
+}
+impl Model {
+    const ALL: [Model; 2] = [Model::DallE3, Model::DallE2];
+
+    fn to_str(self) -> &'static str {
+        match self {
+            Model::DallE2 => "dall-e-2",
+            Model::DallE3 => "dall-e-3",
+        }
+    }
+
+    // Whether the model can make an image that size and quality, and what to tell the
+    // user if it can't.
+    fn check(self, dimensions: Dimensions, quality: Quality) -> Result<(), &'static str> {
+        match (self, dimensions, quality) {
+            (Model::DallE2, Dimensions::Wide | Dimensions::Tall, _) => {
+                Err("DALL-E 2 only does square images: 256x256, 512x512 or 1024x1024.")
+            }
+            (Model::DallE2, _, Quality::HD) => Err("DALL-E 2 doesn't have an HD mode."),
+            (Model::DallE3, Dimensions::Small | Dimensions::Medium, _) => {
+                Err("DALL-E 3 doesn't go smaller than 1024x1024. Try DALL-E 2 for those.")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, poise::ChoiceParameter)]
 pub enum Dimensions {
     #[name = "A wide landscape image, 1792x1024"]
@@ -163,13 +1782,38 @@ pub enum Dimensions {
     Tall,
     #[name = "A square image, 1024x1024"]
     Square,
+    #[name = "A smaller square image, 512x512 (DALL-E 2 only)"]
+    Medium,
+    #[name = "A tiny square image, 256x256 (DALL-E 2 only)"]
+    Small,
 }
 impl Dimensions {
-    fn to_size(&self) -> &'static str {
+    const ALL: [Dimensions; 5] = [
+        Dimensions::Square,
+        Dimensions::Wide,
+        Dimensions::Tall,
+        Dimensions::Medium,
+        Dimensions::Small,
+    ];
+
+    fn to_size(self) -> &'static str {
         match self {
             Dimensions::Square => "1024x1024",
             Dimensions::Wide => "1792x1024",
             Dimensions::Tall => "1024x1792",
+            Dimensions::Medium => "512x512",
+            Dimensions::Small => "256x256",
+        }
+    }
+
+    // Width and height.
+    fn pixels(self) -> (u32, u32) {
+        match self {
+            Dimensions::Square => (1024, 1024),
+            Dimensions::Wide => (1792, 1024),
+            Dimensions::Tall => (1024, 1792),
+            Dimensions::Medium => (512, 512),
+            Dimensions::Small => (256, 256),
         }
     }
 }
@@ -182,7 +1826,7 @@ pub enum Style {
     Vivid,
 }
 impl Style {
-    fn to_str(&self) -> &'static str {
+    fn to_str(self) -> &'static str {
         match self {
             Style::Natural => "natural",
             Style::Vivid => "vivid",
@@ -198,7 +1842,9 @@ pub enum Quality {
     HD,
 }
 impl Quality {
-    fn to_str(&self) -> &'static str {
+    const ALL: [Quality; 2] = [Quality::Standard, Quality::HD];
+
+    fn to_str(self) -> &'static str {
         match self {
             Quality::Standard => "standard",
             Quality::HD => "hd",
@@ -206,92 +1852,1594 @@ impl Quality {
     }
 }
 
-impl OpenAIImageGen {
+impl ImageGenerator for OpenAIImageGen {
     async fn create_image(
         &self,
         request: ImageRequest,
+        progress: tokio::sync::mpsc::UnboundedSender<u8>,
     ) -> Result<Vec<Result<Image, Error>>, Error> {
-        let client = reqwest::Client::new();
+        let client = reqwest::Client::builder().timeout(self.timeout).build()?;
+        let per_call = request.images_per_call().max(1);
+        let calls = (request.num / per_call) as usize;
+        let concurrency = self.concurrency.unwrap_or(calls);
+        let budget = RetryBudget::new(self.retry_budget);
 
-        let mut tasks = vec![];
-        for _ in 0..request.num {
+        let responses = with_concurrency(calls, concurrency, || {
             let client = client.clone();
             let key = self.key.clone();
-            let request_clone = request.clone(); // Assuming ImageRequest is cloneable
+            let request = request.clone();
+            let retries = self.degenerate_retries;
+            let rate_limit = self.rate_limit.clone();
+            let budget = budget.clone();
 
             let task: tokio::task::JoinHandle<Result<Vec<Result<Image, Error>>, Error>> =
-                tokio::spawn(async move {
-                    let response = client
-                        .post(OPENAI_IMAGE_GEN_URL)
-                        .bearer_auth(&key)
-                        .json(&json!({
-                            "model": "dall-e-3",
-                            "n": 1,
-                            "response_format": "b64_json",
-                            "size": request_clone.dimensions.to_size(),
-                            "prompt": request_clone.description,
-                            "quality": request_clone.quality.to_str(),
-                            "style": request_clone.style.to_str(),
-                        }))
-                        .send()
-                        .await?
-                        .text()
-                        .await?;
-
-                    let json_response: OpenAIImages =
-                        serde_json::from_str(&response).map_err(|op| {
-                            format!(
-                                "Failed to parse OpenAI response as JSON: {:?}. Full response: {}",
-                                op, response
-                            )
-                            .to_string()
-                        })?;
-                    let images = match json_response.data {
-                        Some(images) => images,
-                        None => {
-                            return Err(format!("OpenAI returned no images: {}", response).into())
-                        }
-                    };
-                    let images: Vec<Result<Image, Error>> = images
-                        .into_iter()
-                        .map(|image| Image::from_open_ai(image))
-                        .collect();
-                    Ok(images)
-                });
-            tasks.push(task);
-        }
-
-        let responses = join_all(tasks)
-            .await
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()?;
+                tokio::spawn(regenerate_degenerate(
+                    retries,
+                    budget.clone(),
+                    per_call,
+                    move |num| {
+                        request_image(
+                            client.clone(),
+                            OPENAI_IMAGE_GEN_URL,
+                            key.clone(),
+                            ImageRequest {
+                                num,
+                                ..request.clone()
+                            },
+                            rate_limit.clone(),
+                            RETRY_BACKOFF,
+                            budget.clone(),
+                        )
+                    },
+                ));
+            report_when_done(task, progress.clone(), per_call)
+        })
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
 
         let mut images = Vec::new();
         for response in responses.into_iter() {
             match response {
                 Ok(mut r) => images.append(&mut r),
-                Err(err) => images.push(Err(err)),
+                // one for each image the call was for, so they're counted (and refunded)
+                // right
+                Err(err) => images.extend(repeat_error(err, per_call as usize)),
             }
         }
 
         Ok(images)
     }
+
+    fn rate_limit(&self) -> Option<RateLimit> {
+        self.rate_limit.lock().unwrap().take()
+    }
+}
+
+impl OpenAIImageGen {
+    // `num` variations of `png`, all from the one call, since DALL-E 2 does them together.
+    async fn create_variation(
+        &self,
+        png: Vec<u8>,
+        num: u8,
+    ) -> Result<Vec<Result<Image, Error>>, Error> {
+        let client = reqwest::Client::builder().timeout(self.timeout).build()?;
+        let result = request_variation(
+            client,
+            OPENAI_IMAGE_VARIATIONS_URL,
+            self.key.clone(),
+            png,
+            num,
+            self.rate_limit.clone(),
+            RETRY_BACKOFF,
+            RetryBudget::new(self.retry_budget),
+        )
+        .await;
+        Ok(result.unwrap_or_else(|err| repeat_error(err, num as usize)))
+    }
+}
+
+// Sends `images` on `progress` once `task` is done.
+async fn report_when_done<Fut: std::future::Future>(
+    task: Fut,
+    progress: tokio::sync::mpsc::UnboundedSender<u8>,
+    images: u8,
+) -> Fut::Output {
+    let output = task.await;
+    // nobody listening is fine
+    let _ = progress.send(images);
+    output
+}
+
+// Runs `start` `n` times, with no more than `concurrency` of them going at once, and
+// returns what they came to in order. Each one is only started once there's room for it.
+async fn with_concurrency<F, Fut>(n: usize, concurrency: usize, mut start: F) -> Vec<Fut::Output>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future,
+{
+    futures::stream::iter(0..n)
+        .map(|_| start())
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+// `n` copies of `err`. An error from OpenAI keeps its details, so a content policy
+// rejection is still one in every copy.
+fn repeat_error(err: Error, n: usize) -> Vec<Result<Image, Error>> {
+    let mut copies: Vec<Result<Image, Error>> = (1..n)
+        .map(|_| match err.downcast_ref::<ApiError>() {
+            Some(api_error) => Err(api_error.clone().into()),
+            None if is_timeout(&err) => Err(TimedOut.into()),
+            None => Err(err.to_string().into()),
+        })
+        .collect();
+    copies.insert(0, Err(err));
+    copies
+}
+
+async fn request_image(
+    client: reqwest::Client,
+    url: &str,
+    key: String,
+    request: ImageRequest,
+    rate_limit: Arc<std::sync::Mutex<Option<RateLimit>>>,
+    backoff: std::time::Duration,
+    budget: RetryBudget,
+) -> Result<Vec<Result<Image, Error>>, Error> {
+    let body = request.body();
+    let response = send_with_retries("OpenAI", backoff, &budget, || async {
+        let response = client
+            .post(url)
+            .bearer_auth(&key)
+            .json(&body)
+            .send()
+            .await?;
+        if let Some(latest) = RateLimit::from_headers(response.headers()) {
+            *rate_limit.lock().unwrap() = Some(latest);
+        }
+        Ok(response)
+    })
+    .await?;
+    images_from(response).await
+}
+
+// Same as request_image, but uploading `png` to the variations endpoint.
+#[allow(clippy::too_many_arguments)]
+async fn request_variation(
+    client: reqwest::Client,
+    url: &str,
+    key: String,
+    png: Vec<u8>,
+    num: u8,
+    rate_limit: Arc<std::sync::Mutex<Option<RateLimit>>>,
+    backoff: std::time::Duration,
+    budget: RetryBudget,
+) -> Result<Vec<Result<Image, Error>>, Error> {
+    let response = send_with_retries("OpenAI", backoff, &budget, || async {
+        // a form can't be sent twice, so each attempt gets its own
+        let image = reqwest::multipart::Part::bytes(png.clone())
+            .file_name("image.png")
+            .mime_str("image/png")?;
+        let form = reqwest::multipart::Form::new()
+            .part("image", image)
+            .text("model", Model::DallE2.to_str())
+            .text("n", num.to_string())
+            .text("response_format", "b64_json")
+            .text("size", Dimensions::Square.to_size());
+        let response = client
+            .post(url)
+            .bearer_auth(&key)
+            .multipart(form)
+            .send()
+            .await?;
+        if let Some(latest) = RateLimit::from_headers(response.headers()) {
+            *rate_limit.lock().unwrap() = Some(latest);
+        }
+        Ok(response)
+    })
+    .await?;
+    images_from(response).await
+}
+
+// The images in one of OpenAI's answers, or what went wrong.
+async fn images_from(response: reqwest::Response) -> Result<Vec<Result<Image, Error>>, Error> {
+    let status = response.status();
+    let response = response.text().await.map_err(from_reqwest)?;
+    if !status.is_success() {
+        return Err(ApiError::new(status, response).into());
+    }
+
+    let json_response: OpenAIImages = serde_json::from_str(&response).map_err(|op| {
+        format!(
+            "Failed to parse OpenAI response as JSON: {:?}. Full response: {}",
+            op, response
+        )
+    })?;
+    let images = match json_response.data {
+        Some(images) => images,
+        None => return Err(format!("OpenAI returned no images: {}", response).into()),
+    };
+    Ok(images.into_iter().map(Image::from_open_ai).collect())
+}
+
+// Sends the request until it gets an answer worth keeping. Dropped connections, rate
+// limiting and OpenAI's own errors are often gone a moment later, so those are retried
+// with exponential backoff. Anything else, like a prompt that was turned down, isn't
+// going to change and comes straight back. So do timeouts, since someone's already
+// waited the whole timeout for that one. Each retry comes out of `budget`, and once
+// that's gone the last failure is what we go with.
+async fn send_with_retries<F, Fut>(
+    service: &str,
+    backoff: std::time::Duration,
+    budget: &RetryBudget,
+    mut send: F,
+) -> Result<reqwest::Response, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<reqwest::Response, reqwest::Error>>,
+{
+    let mut attempt = 0;
+    loop {
+        let problem = match send().await {
+            Ok(response) if !is_transient(response.status()) => return Ok(response),
+            Ok(response) if attempt >= MAX_RETRIES || !budget.take() => return Ok(response),
+            Ok(response) => response.status().to_string(),
+            Err(err) if err.is_builder() || err.is_timeout() => return Err(from_reqwest(err)),
+            Err(err) if attempt >= MAX_RETRIES || !budget.take() => return Err(from_reqwest(err)),
+            Err(err) => err.to_string(),
+        };
+        let wait = backoff * 2u32.pow(attempt);
+        crate::throttled_log::log(format!(
+            "{} request failed ({}), retrying in {:?}",
+            service, problem, wait
+        ));
+        tokio::time::sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+fn is_transient(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Every so often a render comes back as a nearly empty image. The user already paid for a
+// real one, so if DEGENERATE_IMAGE_RETRIES is set, quietly ask again (up to that many times,
+// and only while there's `budget` left). `generate` makes however many images it's asked
+// for, `num` to begin with and then one for each that came out degenerate. The rest are
+// kept where they were.
+async fn regenerate_degenerate<F, Fut>(
+    retries: u32,
+    budget: RetryBudget,
+    num: u8,
+    mut generate: F,
+) -> Result<Vec<Result<Image, Error>>, Error>
+where
+    F: FnMut(u8) -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<Result<Image, Error>>, Error>>,
+{
+    let mut images = generate(num).await?;
+    for _ in 0..retries {
+        let degenerate: Vec<usize> = images
+            .iter()
+            .enumerate()
+            .filter(|(_, image)| matches!(image, Ok(image) if image.looks_degenerate()))
+            .map(|(i, _)| i)
+            .collect();
+        if degenerate.is_empty() || !budget.take() {
+            break;
+        }
+        match generate(degenerate.len() as u8).await {
+            Ok(retried) => {
+                for (i, image) in degenerate.into_iter().zip(retried) {
+                    // a poor image still beats none
+                    if image.is_ok() {
+                        images[i] = image;
+                    }
+                }
+            }
+            Err(err) => {
+                // stick with what we've got
+                crate::throttled_log::log(format!("Failed to regenerate image: {}", err));
+                break;
+            }
+        }
+    }
+    Ok(images)
 }
 
+#[derive(Clone)]
 struct Image {
     revised_prompt: Option<String>,
     bytes: Vec<u8>,
 }
 
+// What goes next to each image in the gallery.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct GalleryEntry {
+    prompt: String,
+    revised_prompt: Option<String>,
+    created: DateTime<Utc>,
+}
+
+// GALLERY_DIR, if every generated image should also be kept on disk.
+fn gallery_dir() -> Option<std::path::PathBuf> {
+    match std::env::var("GALLERY_DIR") {
+        Ok(dir) if !dir.trim().is_empty() => Some(dir.trim().into()),
+        _ => None,
+    }
+}
+
+// Writes `image` to `dir/{user_id}/{timestamp}.png`, with the prompts in a .json of the
+// same name. Images made in the same second get -1, -2 and so on tacked on. Returns
+// where the image went.
+fn save_to_gallery(
+    dir: &std::path::Path,
+    user_id: u64,
+    now: DateTime<Utc>,
+    prompt: &str,
+    image: &Image,
+) -> Result<std::path::PathBuf, Error> {
+    use std::io::Write;
+    let dir = dir.join(user_id.to_string());
+    std::fs::create_dir_all(&dir)?;
+    // no colons, Windows won't have them in a filename
+    let timestamp = now.format("%Y-%m-%dT%H-%M-%SZ").to_string();
+    let mut counter = 0;
+    let (path, mut file) = loop {
+        let name = match counter {
+            0 => timestamp.clone(),
+            n => format!("{}-{}", timestamp, n),
+        };
+        let path = dir.join(name).with_extension("png");
+        // create_new, so two images racing for a name can't both get it
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => break (path, file),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => counter += 1,
+            Err(err) => return Err(err.into()),
+        }
+    };
+    file.write_all(&image.bytes)?;
+    let entry = GalleryEntry {
+        prompt: prompt.to_string(),
+        revised_prompt: image.revised_prompt.clone(),
+        created: now,
+    };
+    std::fs::write(
+        path.with_extension("json"),
+        serde_json::to_string_pretty(&entry)?,
+    )?;
+    Ok(path)
+}
+
+// Real DALL-E renders are hundreds of KB at the very least.
+const MIN_PLAUSIBLE_IMAGE_BYTES: usize = 10_000;
+
 impl Image {
+    fn looks_degenerate(&self) -> bool {
+        self.bytes.len() < MIN_PLAUSIBLE_IMAGE_BYTES
+    }
+
     fn from_open_ai(response: OpenAIImageData) -> Result<Self, Error> {
-        let bytes = match base64::engine::general_purpose::STANDARD.decode(response.b64_json) {
+        Self::from_base64(&response.b64_json, response.revised_prompt)
+    }
+
+    fn from_base64(b64: &str, revised_prompt: Option<String>) -> Result<Self, Error> {
+        let bytes = match base64::engine::general_purpose::STANDARD.decode(b64) {
             Ok(bytes) => bytes,
-            Err(_) => return Err("failed to decode base64 image from OpenAI".into()),
+            Err(_) => return Err("failed to decode base64 image".into()),
         };
+        check_png(&bytes)?;
         Ok(Self {
-            revised_prompt: response.revised_prompt,
+            revised_prompt,
             bytes,
         })
     }
 }
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+// A cheap sanity check that we got a whole PNG back, rather than sending along something
+// Discord will show as a broken image. PNGs start with a signature and an IHDR chunk,
+// and end with an empty IEND chunk, so a truncated response will be missing the end.
+fn check_png(bytes: &[u8]) -> Result<(), Error> {
+    if !bytes.starts_with(PNG_SIGNATURE) || bytes.get(12..16) != Some(b"IHDR") {
+        return Err("OpenAI sent back something that isn't a PNG".into());
+    }
+    let end = bytes.len().saturating_sub(12);
+    if bytes.len() < PNG_SIGNATURE.len() + 25 + 12 || &bytes[end..end + 8] != b"\0\0\0\0IEND" {
+        return Err("OpenAI sent back a truncated PNG".into());
+    }
+    Ok(())
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_price_table_matches_cost() {
+        for multiplier in [1.0, 1.5] {
+            let table = price_table(multiplier);
+            for (model, dimensions, quality) in offerings() {
+                let cost = ImageRequest {
+                    model,
+                    dimensions,
+                    quality,
+                    ..ImageRequest::new("x".to_string(), 1)
+                }
+                .cost()
+                .scaled(multiplier)
+                .millicents();
+                let row = format!(
+                    "{:<10}{:<11}{:<10}{}\n",
+                    model.to_str(),
+                    dimensions.to_size(),
+                    quality.to_str(),
+                    crate::info::format_millicents(cost as i64)
+                );
+                assert!(table.contains(&row), "{:?} not in\n{}", row, table);
+            }
+        }
+        assert_eq!(offerings().count(), 9);
+        assert!(price_table(1.0).contains("dall-e-3  1792x1024  hd        $0.12\n"));
+        assert!(price_table(1.5).contains("dall-e-3  1024x1024  standard  $0.06\n"));
+        assert!(price_table(1.0).contains("dall-e-2  256x256    standard  $0.02\n"));
+        assert!(!price_table(1.0).contains("dall-e-2  1792x1024"));
+    }
+
+    #[test]
+    fn test_cost_per_model() {
+        let request = |model, dimensions, quality| ImageRequest {
+            model,
+            dimensions,
+            quality,
+            ..ImageRequest::new("x".to_string(), 2)
+        };
+        let millicents = |request: ImageRequest| request.cost().millicents();
+        assert_eq!(
+            millicents(request(
+                Model::DallE3,
+                Dimensions::Square,
+                Quality::Standard
+            )),
+            8_000
+        );
+        assert_eq!(
+            millicents(request(Model::DallE3, Dimensions::Tall, Quality::HD)),
+            24_000
+        );
+        assert_eq!(
+            millicents(request(
+                Model::DallE2,
+                Dimensions::Square,
+                Quality::Standard
+            )),
+            4_000
+        );
+        assert_eq!(
+            millicents(request(
+                Model::DallE2,
+                Dimensions::Medium,
+                Quality::Standard
+            )),
+            3_600
+        );
+        assert_eq!(
+            millicents(request(Model::DallE2, Dimensions::Small, Quality::Standard)),
+            3_200
+        );
+        assert!(Model::DallE2
+            .check(Dimensions::Wide, Quality::Standard)
+            .is_err());
+        assert!(Model::DallE2
+            .check(Dimensions::Square, Quality::HD)
+            .is_err());
+        assert!(Model::DallE3
+            .check(Dimensions::Small, Quality::Standard)
+            .is_err());
+        assert!(Model::DallE3.check(Dimensions::Tall, Quality::HD).is_ok());
+    }
+
+    #[test]
+    fn test_request_body_per_model() {
+        let dall_e_3 = ImageRequest {
+            style: Style::Natural,
+            quality: Quality::HD,
+            ..ImageRequest::new("a cat".to_string(), 3)
+        };
+        assert_eq!(dall_e_3.images_per_call(), 1);
+        assert_eq!(
+            dall_e_3.body(),
+            json!({
+                "model": "dall-e-3",
+                "n": 1,
+                "response_format": "b64_json",
+                "size": "1024x1024",
+                "prompt": "a cat",
+                "quality": "hd",
+                "style": "natural",
+            })
+        );
+        let dall_e_2 = ImageRequest {
+            model: Model::DallE2,
+            dimensions: Dimensions::Medium,
+            ..ImageRequest::new("a cat".to_string(), 3)
+        };
+        assert_eq!(dall_e_2.images_per_call(), 3);
+        assert_eq!(
+            dall_e_2.body(),
+            json!({
+                "model": "dall-e-2",
+                "n": 3,
+                "response_format": "b64_json",
+                "size": "512x512",
+                "prompt": "a cat",
+            })
+        );
+    }
+
+    #[test]
+    fn test_a_failed_batch_counts_for_each_image() {
+        let rejected = ApiError::new(
+            reqwest::StatusCode::BAD_REQUEST,
+            r#"{"error": {"code": "content_policy_violation", "message": "no"}}"#.to_string(),
+        );
+        let errors = repeat_error(rejected.into(), 3);
+        assert_eq!(errors.len(), 3);
+        assert!(errors
+            .iter()
+            .all(|e| matches!(e, Err(err) if rejected_by_content_filter(err))));
+        let errors = repeat_error("timed out".into(), 2);
+        assert!(errors
+            .iter()
+            .all(|e| matches!(e, Err(err) if err.to_string() == "timed out")));
+    }
+
+    #[tokio::test]
+    async fn test_with_concurrency_bounds_how_many_are_in_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let started = AtomicUsize::new(0);
+        let results = with_concurrency(5, 2, || {
+            let i = started.fetch_add(1, Ordering::SeqCst);
+            let in_flight = in_flight.clone();
+            let most = most.clone();
+            tokio::spawn(async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                // the first one is slowest, so the rest have to wait on it
+                tokio::time::sleep(std::time::Duration::from_millis(if i == 0 {
+                    40
+                } else {
+                    10
+                }))
+                .await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                i
+            })
+        })
+        .await;
+        let results: Vec<usize> = results.into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(results, vec![0, 1, 2, 3, 4]);
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        assert_eq!(started.load(Ordering::SeqCst), 5);
+    }
+
+    type Generated = Result<Vec<Result<Image, Error>>, Error>;
+
+    // Answers with whatever it's told to, and remembers what it was asked for.
+    struct MockGen {
+        results: std::sync::Mutex<Option<Generated>>,
+        asked_for: std::sync::Mutex<Vec<u8>>,
+    }
+    impl ImageGenerator for MockGen {
+        async fn create_image(
+            &self,
+            request: ImageRequest,
+            progress: tokio::sync::mpsc::UnboundedSender<u8>,
+        ) -> Generated {
+            self.asked_for.lock().unwrap().push(request.num);
+            let _ = progress.send(request.num);
+            self.results.lock().unwrap().take().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_generation() {
+        let image = Image {
+            revised_prompt: None,
+            bytes: vec![1, 2, 3],
+        };
+        let results = vec![
+            Ok(image.clone()),
+            Err(ApiError::new(
+                reqwest::StatusCode::BAD_REQUEST,
+                r#"{"error": {"code": "content_policy_violation", "message": "no"}}"#.to_string(),
+            )
+            .into()),
+            Err(TimedOut.into()),
+            Err("it broke".into()),
+            Ok(image),
+        ];
+        let generator = MockGen {
+            results: std::sync::Mutex::new(Some(Ok(results))),
+            asked_for: std::sync::Mutex::default(),
+        };
+        let (progress, mut updates) = tokio::sync::mpsc::unbounded_channel();
+        let request = ImageRequest::new("a cat in a hat".to_string(), 5);
+        let tally = run_generation(&generator, request, progress).await.unwrap();
+        assert_eq!(*generator.asked_for.lock().unwrap(), vec![5]);
+        assert_eq!(updates.recv().await, Some(5));
+        assert_eq!(tally.images.len(), 2);
+        assert_eq!((tally.failures, tally.rejected, tally.timed_out), (1, 1, 1));
+        assert_eq!(generator.rate_limit(), None);
+
+        // and when the generator gives up on the whole thing, so does the command
+        let generator = MockGen {
+            results: std::sync::Mutex::new(Some(Err("no route to host".into()))),
+            asked_for: std::sync::Mutex::default(),
+        };
+        let (progress, _updates) = tokio::sync::mpsc::unbounded_channel();
+        let request = ImageRequest::new("a cat in a hat".to_string(), 1);
+        assert!(run_generation(&generator, request, progress).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stable_diffusion() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let images = json!({"images": [TINY_PNG, "not base64!"], "info": "{}"});
+        let server = tokio::spawn(fake_openai(listener, vec![(200, images.to_string())]));
+        let mut generator = StableDiffusionGen::new(url);
+        generator.retry_budget = 0;
+        let (progress, mut updates) = tokio::sync::mpsc::unbounded_channel();
+        let images = generator
+            .create_image(ImageRequest::new("a cat in a hat".to_string(), 2), progress)
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert!(images[0].is_ok());
+        assert!(images[1].is_err());
+        assert_eq!(updates.recv().await, Some(2));
+
+        // a short batch still has an answer for every image
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let images = json!({"images": [TINY_PNG], "info": "{}"});
+        let server = tokio::spawn(fake_openai(listener, vec![(200, images.to_string())]));
+        let mut generator = StableDiffusionGen::new(url);
+        generator.retry_budget = 0;
+        let (progress, _updates) = tokio::sync::mpsc::unbounded_channel();
+        let images = generator
+            .create_image(ImageRequest::new("a cat in a hat".to_string(), 3), progress)
+            .await
+            .unwrap();
+        server.await.unwrap();
+        assert_eq!(images.len(), 3);
+        assert!(images[0].is_ok());
+        assert!(images[1..].iter().all(|image| image.is_err()));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        // a directory of its own, since settings and the like go next to data.json
+        let dir = std::env::temp_dir().join(format!("hypnos-dry-run-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.json");
+        let mut user = serenity::User::default();
+        user.id = serenity::UserId(7);
+        let before = crate::data::Account::named("someone".to_string(), 100_000);
+        let mut store = crate::store::JsonStore::empty(&path);
+        crate::store::Store::put(&mut store, &[(7, before.clone())].into()).unwrap();
+        let data = crate::data::Data::configured(
+            &path,
+            Box::new(crate::store::JsonStore::open(&path).unwrap()),
+            true,
+        );
+        assert_eq!(data.image_backend, Backend::DryRun);
+
+        let request = ImageRequest::new("a cat in a hat".to_string(), 2);
+        let billing = crate::data::bill_request(&data, Payer::for_user(&user, None), 7, &request)
+            .await
+            .unwrap();
+        assert!(matches!(billing, Billing::Credit));
+        let generator = Generator::new(&data.image_backend, None).unwrap();
+        let (progress, _updates) = tokio::sync::mpsc::unbounded_channel();
+        let tally = run_generation(&generator, request, progress).await.unwrap();
+        assert_eq!(tally.images.len(), 2);
+        for image in tally.images.iter() {
+            check_png(&image.bytes).unwrap();
+        }
+        // it's charged as far as the bot can tell, but nothing on disk changed
+        let charged = crate::data::get_account(&data, &user, None).await.unwrap();
+        assert!(charged.credit < before.credit);
+        let on_disk = crate::store::JsonStore::open(&path).unwrap();
+        assert_eq!(crate::store::Store::get(&on_disk, 7).unwrap(), Some(before));
+
+        // and neither does anything else
+        data.update_preferences(|preferences| preferences.set_flavor(7, Some(Flavor::Terse)))
+            .await
+            .unwrap();
+        assert_eq!(data.preferences.lock().await.flavor(7), Some(Flavor::Terse));
+        data.update_settings(|_| {}).await.unwrap();
+        let files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec![std::ffi::OsString::from("data.json")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_declining_uses_up_nothing() {
+        let data = crate::data::Data::default();
+        let mut user = serenity::User::default();
+        user.id = serenity::UserId(7);
+        let payer = Payer::for_user(&user, None);
+        let max_per_channel = data.settings.lock().await.max_per_channel() as usize;
+        for _ in 0..=gen_per_minute() {
+            let declined = async {
+                // nothing's held while they make up their mind
+                assert!(data.channel_slots.try_start(1, 1).is_some());
+                Ok(false)
+            };
+            let held = try_admit(&data, payer, 7, 1, declined).await.unwrap();
+            assert_eq!(held.err(), Some(Held::Declined));
+        }
+        // so they've still got every generation, and the channel every slot
+        let mut slots = Vec::new();
+        for _ in 0..max_per_channel {
+            let admitted = try_admit(&data, payer, 7, 1, async { Ok(true) }).await;
+            slots.push(admitted.unwrap().unwrap());
+        }
+        let held = try_admit(&data, payer, 7, 1, async { Ok(true) }).await;
+        assert_eq!(held.unwrap().err(), Some(Held::ChannelBusy));
+    }
+
+    #[tokio::test]
+    async fn test_automation_skips_the_cooldown() {
+        let path =
+            std::env::temp_dir().join(format!("hypnos-automation-{}.json", std::process::id()));
+        // a dry run, so none of it is saved
+        let data = crate::data::Data::configured(
+            &path,
+            Box::new(crate::store::JsonStore::empty(&path)),
+            true,
+        );
+        let mut user = serenity::User::default();
+        user.id = serenity::UserId(7);
+        let person = Payer::for_user(&user, None);
+        let go = || async { Ok(true) };
+        for _ in 0..gen_per_minute() {
+            assert!(try_admit(&data, person, 7, 1, go()).await.unwrap().is_ok());
+        }
+        let held = try_admit(&data, person, 7, 1, go()).await.unwrap();
+        assert!(matches!(held, Err(Held::RateLimited(_))));
+        // the same user running automation isn't held to it
+        for _ in 0..=gen_per_minute() {
+            assert!(try_admit(&data, Payer::Automation, 7, 1, go())
+                .await
+                .unwrap()
+                .is_ok());
+        }
+
+        // and it's the automation account that pays
+        let request = ImageRequest::new("a cat in a hat".to_string(), 1);
+        let billing = crate::data::bill_request(&data, Payer::Automation, 7, &request)
+            .await
+            .unwrap();
+        assert_eq!(billing, Billing::Credit);
+        let (automation, theirs) = data
+            .with_accounts(|store| {
+                Ok((
+                    store.get(crate::data::AUTOMATION_ACCOUNT_ID)?,
+                    store.get(7)?,
+                ))
+            })
+            .await
+            .unwrap();
+        assert_eq!(automation.unwrap().images, 1);
+        assert_eq!(theirs, None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_parse_backend() {
+        assert_eq!(parse_backend(None, None), Backend::OpenAI);
+        assert_eq!(
+            parse_backend(Some("stable-diffusion"), Some(" http://localhost:7860/ ")),
+            Backend::StableDiffusion("http://localhost:7860".to_string())
+        );
+        assert_eq!(
+            parse_backend(Some("stable-diffusion"), Some("")),
+            Backend::OpenAI
+        );
+        assert_eq!(
+            parse_backend(Some("openai"), Some("http://localhost:7860")),
+            Backend::OpenAI
+        );
+        assert!(Backend::StableDiffusion("http://localhost:7860".to_string()).is_free());
+        assert!(!Backend::OpenAI.is_free());
+        assert!(!Backend::DryRun.is_free());
+    }
+
+    #[tokio::test]
+    async fn test_progress_for_each_call() {
+        let (progress, mut updates) = tokio::sync::mpsc::unbounded_channel();
+        let outputs = with_concurrency(3, 2, || {
+            report_when_done(async { "done" }, progress.clone(), 2)
+        })
+        .await;
+        drop(progress);
+        assert_eq!(outputs, vec!["done"; 3]);
+        let mut reported = Vec::new();
+        while let Some(images) = updates.recv().await {
+            reported.push(images);
+        }
+        assert_eq!(reported, vec![2, 2, 2]);
+    }
+
+    #[test]
+    fn test_destination() {
+        use poise::serenity_prelude::{ChannelFlags, ChannelType};
+        let tag = |id: u64, moderated: bool| -> serenity::ForumTag {
+            serde_json::from_value(json!({
+                "id": id.to_string(),
+                "name": format!("tag {}", id),
+                "moderated": moderated,
+                "emoji_id": null,
+                "emoji_name": null,
+            }))
+            .unwrap()
+        };
+        let tags = [tag(1, true), tag(2, false)];
+        let forum = serenity::ChannelId(9);
+        for kind in [
+            ChannelType::Text,
+            ChannelType::PublicThread,
+            ChannelType::News,
+        ] {
+            assert_eq!(
+                Destination::for_parent(forum, kind, ChannelFlags::REQUIRE_TAG, &tags),
+                Destination::Channel
+            );
+        }
+        assert_eq!(
+            Destination::for_parent(forum, ChannelType::Forum, ChannelFlags::empty(), &tags),
+            Destination::ForumPost {
+                forum,
+                tag: None,
+                missing_tag: false,
+            }
+        );
+        // one that needs a tag gets one anybody can apply
+        assert_eq!(
+            Destination::for_parent(forum, ChannelType::Forum, ChannelFlags::REQUIRE_TAG, &tags),
+            Destination::ForumPost {
+                forum,
+                tag: Some(serenity::ForumTagId(2)),
+                missing_tag: false,
+            }
+        );
+        // and never a moderated one, even when that's all there is
+        assert_eq!(
+            Destination::for_parent(
+                forum,
+                ChannelType::Forum,
+                ChannelFlags::REQUIRE_TAG,
+                &tags[..1]
+            ),
+            Destination::ForumPost {
+                forum,
+                tag: None,
+                missing_tag: true,
+            }
+        );
+        assert!(is_thread(ChannelType::PublicThread));
+        assert!(!is_thread(ChannelType::Forum));
+
+        assert_eq!(post_title("a cat\nin a  hat"), "a cat in a hat");
+        let title = post_title(&"long ".repeat(50));
+        assert_eq!(title.chars().count(), MAX_POST_TITLE_LEN);
+        assert!(title.ends_with('…'));
+    }
+
+    #[test]
+    fn test_refusals() {
+        assert_eq!(refusal(&Billing::Credit, Flavor::Terse), None);
+        for billing in [Billing::Refused, Billing::OverSelfLimit] {
+            let flavorful = refusal(&billing, Flavor::Flavorful).unwrap();
+            let terse = refusal(&billing, Flavor::Terse).unwrap();
+            assert!(terse.len() < flavorful.len());
+        }
+    }
+
+    #[test]
+    fn test_needs_confirmation() {
+        assert!(!needs_confirmation(Cost::cents(0)));
+        assert!(!needs_confirmation(Cost::cents(CONFIRM_OVER_CENTS)));
+        assert!(needs_confirmation(Cost::millicents_of(
+            Cost::cents(CONFIRM_OVER_CENTS).millicents() + 1
+        )));
+        // four standard squares is fine, four HD wide ones isn't
+        assert!(!needs_confirmation(
+            ImageRequest::new("a cat".to_string(), 4).cost()
+        ));
+        let hd = ImageRequest {
+            dimensions: Dimensions::Wide,
+            quality: Quality::HD,
+            ..ImageRequest::new("a cat".to_string(), 5)
+        };
+        assert!(needs_confirmation(hd.cost()));
+    }
+
+    #[test]
+    fn test_validate_request() {
+        assert_eq!(validate_request(0, 10), Err(BadImageCount::Zero));
+        assert_eq!(validate_request(1, 10), Ok(()));
+        assert_eq!(validate_request(10, 10), Ok(()));
+        assert_eq!(validate_request(11, 10), Err(BadImageCount::TooMany(10)));
+        assert_eq!(validate_request(1, 1), Ok(()));
+        assert!(BadImageCount::TooMany(4)
+            .message(Flavor::Terse)
+            .contains("At most 4"));
+    }
+
+    // a 1x1 PNG
+    const TINY_PNG: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=";
+
+    // Answers each request to `listener` with the next of `responses`, and hangs up.
+    async fn fake_openai(listener: tokio::net::TcpListener, responses: Vec<(u16, String)>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        for (status, body) in responses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // the client sends a content-length, so read until we have that much body
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("content-length:")?
+                                .trim()
+                                .parse()
+                                .ok()
+                        })
+                        .unwrap_or(0);
+                    if n == 0 || body.len() >= length {
+                        break;
+                    }
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 {} Whatever\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+
+    async fn request_from(url: &str) -> Result<Vec<Result<Image, Error>>, Error> {
+        request_image(
+            reqwest::Client::new(),
+            url,
+            "key".to_string(),
+            ImageRequest::new("a cat in a hat".to_string(), 1),
+            Arc::default(),
+            std::time::Duration::from_millis(1),
+            RetryBudget::new(DEFAULT_RETRY_BUDGET),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_transient_failures_are_retried() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/v1/images/generations",
+            listener.local_addr().unwrap()
+        );
+        let image = json!({"data": [{"b64_json": TINY_PNG}]}).to_string();
+        let unavailable = (503, r#"{"error": {"message": "try again"}}"#.to_string());
+        let server = tokio::spawn(fake_openai(
+            listener,
+            vec![unavailable.clone(), unavailable, (200, image)],
+        ));
+        let images = request_from(&url).await.unwrap();
+        server.await.unwrap();
+        assert_eq!(images.len(), 1);
+        assert!(images[0].is_ok());
+    }
+
+    #[test]
+    fn test_save_to_gallery() {
+        let dir = std::env::temp_dir().join(format!("hypnos-gallery-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let image = Image {
+            revised_prompt: Some("a cat wearing a tall striped hat".to_string()),
+            bytes: vec![1, 2, 3],
+        };
+        let now = DateTime::parse_from_rfc3339("2024-05-06T07:08:09Z")
+            .unwrap()
+            .into();
+        let first = save_to_gallery(&dir, 42, now, "a cat in a hat", &image).unwrap();
+        assert_eq!(first, dir.join("42").join("2024-05-06T07-08-09Z.png"));
+        assert_eq!(std::fs::read(&first).unwrap(), vec![1, 2, 3]);
+        let entry: GalleryEntry =
+            serde_json::from_str(&std::fs::read_to_string(first.with_extension("json")).unwrap())
+                .unwrap();
+        assert_eq!(entry.prompt, "a cat in a hat");
+        assert_eq!(
+            entry.revised_prompt.as_deref(),
+            Some("a cat wearing a tall striped hat")
+        );
+        assert_eq!(entry.created, now);
+
+        // the same second again doesn't overwrite it
+        let second = save_to_gallery(&dir, 42, now, "a cat in a hat", &image).unwrap();
+        assert_eq!(second, dir.join("42").join("2024-05-06T07-08-09Z-1.png"));
+        assert!(second.with_extension("json").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_responses_time_out() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/v1/images/generations",
+            listener.local_addr().unwrap()
+        );
+        // takes the connection and never answers. Returns whether anyone tried again.
+        let server = tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            let again = std::time::Duration::from_millis(500);
+            tokio::time::timeout(again, listener.accept()).await.is_ok()
+        });
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let started = std::time::Instant::now();
+        let result = request_image(
+            client,
+            &url,
+            "key".to_string(),
+            ImageRequest::new("a cat in a hat".to_string(), 1),
+            Arc::default(),
+            std::time::Duration::from_millis(1),
+            RetryBudget::new(DEFAULT_RETRY_BUDGET),
+        )
+        .await;
+        let err = match result {
+            Ok(_) => panic!("a hung request should time out"),
+            Err(err) => err,
+        };
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert!(is_timeout(&err), "{}", err);
+        assert!(!rejected_by_content_filter(&err));
+        assert!(!server.await.unwrap(), "timeouts shouldn't be retried");
+        // and every image in the batch is reported as timed out
+        let errors = repeat_error(err, 2);
+        assert!(errors
+            .iter()
+            .all(|e| matches!(e, Err(err) if is_timeout(err))));
+    }
+
+    #[tokio::test]
+    async fn test_retries_stop_when_the_budget_runs_out() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/v1/images/generations",
+            listener.local_addr().unwrap()
+        );
+        let unavailable = (503, r#"{"error": {"message": "try again"}}"#.to_string());
+        // one retry left, so the server only ever sees two requests
+        let server = tokio::spawn(fake_openai(
+            listener,
+            vec![unavailable.clone(), unavailable],
+        ));
+        let budget = RetryBudget::new(1);
+        let result = request_image(
+            reqwest::Client::new(),
+            &url,
+            "key".to_string(),
+            ImageRequest::new("a cat in a hat".to_string(), 1),
+            Arc::default(),
+            std::time::Duration::from_millis(1),
+            budget.clone(),
+        )
+        .await;
+        server.await.unwrap();
+        let err = match result {
+            Ok(_) => panic!("the last failure should come back"),
+            Err(err) => err,
+        };
+        assert!(err.to_string().contains("503"), "{}", err);
+        assert!(!budget.take());
+
+        // and nothing degenerate gets regenerated on an empty budget either
+        let calls = std::sync::atomic::AtomicU32::new(0);
+        let images = regenerate_degenerate(3, budget, 1, |_| {
+            calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async {
+                Ok(vec![Ok(Image {
+                    revised_prompt: None,
+                    bytes: vec![0; 100],
+                })])
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(images[0].as_ref().unwrap().looks_degenerate());
+    }
+
+    #[tokio::test]
+    async fn test_variations() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/v1/images/variations",
+            listener.local_addr().unwrap()
+        );
+        let images = json!({"data": [{"b64_json": TINY_PNG}, {"b64_json": TINY_PNG}]});
+        let server = tokio::spawn(fake_openai(listener, vec![(200, images.to_string())]));
+        let png = base64::engine::general_purpose::STANDARD
+            .decode(TINY_PNG)
+            .unwrap();
+        assert_eq!(check_upload(&png), Ok(()));
+        let images = request_variation(
+            reqwest::Client::new(),
+            &url,
+            "key".to_string(),
+            png,
+            2,
+            Arc::default(),
+            std::time::Duration::from_millis(1),
+            RetryBudget::new(DEFAULT_RETRY_BUDGET),
+        )
+        .await
+        .unwrap();
+        server.await.unwrap();
+        assert_eq!(images.len(), 2);
+        assert!(images.iter().all(|image| image.is_ok()));
+    }
+
+    #[test]
+    fn test_check_upload() {
+        assert!(check_upload(b"GIF89a").unwrap_err().contains("PNG"));
+        let mut wide = base64::engine::general_purpose::STANDARD
+            .decode(TINY_PNG)
+            .unwrap();
+        wide[16..20].copy_from_slice(&2u32.to_be_bytes());
+        assert!(check_upload(&wide).unwrap_err().contains("square"));
+        assert_eq!(
+            ImageRequest::variation("cat.png", 2).cost().millicents(),
+            4_000
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests_are_not_retried() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/v1/images/generations",
+            listener.local_addr().unwrap()
+        );
+        let rejected = r#"{"error": {"message": "content policy"}}"#.to_string();
+        let server = tokio::spawn(fake_openai(listener, vec![(400, rejected)]));
+        let err = match request_from(&url).await {
+            Ok(_) => panic!("a 400 should be an error"),
+            Err(err) => err,
+        };
+        server.await.unwrap();
+        assert!(err.to_string().contains("400"), "{}", err);
+        assert!(err.to_string().contains("content policy"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_content_policy_rejections() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/v1/images/generations",
+            listener.local_addr().unwrap()
+        );
+        let rejected = json!({"error": {
+            "code": "content_policy_violation",
+            "message": "Your request was rejected as a result of our safety system.",
+            "param": null,
+            "type": "invalid_request_error",
+        }})
+        .to_string();
+        let server = tokio::spawn(fake_openai(listener, vec![(400, rejected)]));
+        let err = match request_from(&url).await {
+            Ok(_) => panic!("a rejected prompt should be an error"),
+            Err(err) => err,
+        };
+        server.await.unwrap();
+        assert!(rejected_by_content_filter(&err));
+        assert_eq!(
+            err.to_string(),
+            "OpenAI responded with 400 Bad Request: Your request was rejected as a result of our safety system."
+        );
+
+        // other errors aren't the content filter, even when OpenAI explains them
+        let other = ApiError::new(
+            reqwest::StatusCode::BAD_REQUEST,
+            r#"{"error": {"code": "invalid_size", "message": "nope"}}"#.to_string(),
+        );
+        assert!(!other.is_content_policy());
+        let garbled = ApiError::new(reqwest::StatusCode::BAD_GATEWAY, "<html>".to_string());
+        assert_eq!(garbled.error, None);
+        assert_eq!(
+            garbled.to_string(),
+            "OpenAI responded with 502 Bad Gateway: <html>"
+        );
+        assert!(!rejected_by_content_filter(&"no images".into()));
+    }
+
+    #[test]
+    fn test_is_transient() {
+        assert!(is_transient(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_transient(reqwest::StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_transient(reqwest::StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!is_transient(reqwest::StatusCode::BAD_REQUEST));
+        assert!(!is_transient(reqwest::StatusCode::UNAUTHORIZED));
+        assert!(!is_transient(reqwest::StatusCode::OK));
+    }
+
+    #[tokio::test]
+    async fn test_degenerate_images_are_regenerated() {
+        let calls = std::sync::atomic::AtomicU32::new(0);
+        let generate = |_| {
+            let call = calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                // the first render is suspiciously tiny, the next is fine
+                let size = if call == 0 {
+                    100
+                } else {
+                    MIN_PLAUSIBLE_IMAGE_BYTES
+                };
+                Ok(vec![Ok(Image {
+                    revised_prompt: None,
+                    bytes: vec![0; size],
+                })])
+            }
+        };
+        let budget = || RetryBudget::new(DEFAULT_RETRY_BUDGET);
+        let images = regenerate_degenerate(3, budget(), 1, generate)
+            .await
+            .unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert!(!images[0].as_ref().unwrap().looks_degenerate());
+
+        calls.store(0, std::sync::atomic::Ordering::SeqCst);
+        let images = regenerate_degenerate(0, budget(), 1, generate)
+            .await
+            .unwrap();
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(images[0].as_ref().unwrap().looks_degenerate());
+    }
+
+    #[tokio::test]
+    async fn test_only_degenerate_images_are_regenerated() {
+        let image = |byte: u8, size: usize| -> Result<Image, Error> {
+            Ok(Image {
+                revised_prompt: None,
+                bytes: vec![byte; size],
+            })
+        };
+        let asked_for = std::sync::Mutex::new(Vec::new());
+        let images = regenerate_degenerate(3, RetryBudget::new(DEFAULT_RETRY_BUDGET), 4, |num| {
+            asked_for.lock().unwrap().push(num);
+            let images = match num {
+                // the second and fourth come out tiny
+                4 => vec![
+                    image(1, MIN_PLAUSIBLE_IMAGE_BYTES),
+                    image(2, 100),
+                    image(3, MIN_PLAUSIBLE_IMAGE_BYTES),
+                    image(4, 100),
+                ],
+                _ => (0..num)
+                    .map(|i| image(10 + i, MIN_PLAUSIBLE_IMAGE_BYTES))
+                    .collect(),
+            };
+            async { Ok(images) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(*asked_for.lock().unwrap(), vec![4, 2]);
+        let firsts: Vec<u8> = images
+            .iter()
+            .map(|image| image.as_ref().unwrap().bytes[0])
+            .collect();
+        assert_eq!(firsts, vec![1, 10, 3, 11]);
+    }
+
+    #[test]
+    fn test_prompts_follow_their_attachments() {
+        let image = |prompt: Option<&str>, byte: u8| Image {
+            revised_prompt: prompt.map(str::to_string),
+            bytes: vec![byte],
+        };
+        let delivery = Delivery::new(
+            vec![
+                image(Some("a cat in a hat"), 1),
+                image(None, 2),
+                image(Some(&"long ".repeat(300)), 3),
+            ],
+            Format::Png,
+        );
+        let attachments: Vec<(String, Vec<u8>)> = delivery
+            .attachments
+            .into_iter()
+            .map(|attachment| match attachment {
+                serenity::AttachmentType::Bytes { data, filename } => (filename, data.into_owned()),
+                _ => panic!("expected bytes"),
+            })
+            .collect();
+        let long = format!("{}-3.png", sanitize_filename(&"long ".repeat(300)));
+        assert_eq!(
+            attachments,
+            vec![
+                ("a-cat-in-a-hat-1.png".to_string(), vec![1]),
+                ("image-2.png".to_string(), vec![2]),
+                (long.clone(), vec![3]),
+            ]
+        );
+        assert_eq!(delivery.prompts.len(), 2);
+        assert_eq!(
+            delivery.prompts[0],
+            (
+                "a-cat-in-a-hat-1.png".to_string(),
+                "a cat in a hat".to_string()
+            )
+        );
+        assert_eq!(delivery.prompts[1].0, long);
+        assert_eq!(delivery.prompts[1].1.chars().count(), MAX_EMBED_FIELD_LEN);
+
+        let filenames = |delivery: &Delivery| -> Vec<String> {
+            delivery
+                .attachments
+                .iter()
+                .map(|attachment| match attachment {
+                    serenity::AttachmentType::Bytes { filename, .. } => filename.clone(),
+                    _ => panic!("expected bytes"),
+                })
+                .collect()
+        };
+        let mut delivery =
+            Delivery::new(vec![image(Some("a cat"), 1), image(None, 2)], Format::Png);
+        delivery.add_contact_sheet(vec![9], ContactSheet::Also);
+        assert_eq!(
+            filenames(&delivery),
+            vec!["a-cat-1.png", "image-2.png", CONTACT_SHEET_FILE]
+        );
+        let mut delivery =
+            Delivery::new(vec![image(Some("a cat"), 1), image(None, 2)], Format::Png);
+        delivery.add_contact_sheet(vec![9], ContactSheet::Instead);
+        assert_eq!(filenames(&delivery), vec![CONTACT_SHEET_FILE]);
+        assert_eq!(delivery.prompts[0].0, "a-cat-1.png");
+
+        // ten long prompts still fit in one embed
+        let delivery = Delivery::new(
+            (0..10)
+                .map(|i| image(Some(&format!("{} {}", i, "long ".repeat(300))), 0))
+                .collect(),
+            Format::Png,
+        );
+        assert_eq!(delivery.prompts.len(), 10);
+        let total: usize = delivery
+            .prompts
+            .iter()
+            .map(|(filename, prompt)| filename.len() + prompt.chars().count())
+            .sum();
+        assert!(total <= MAX_EMBED_LEN, "{}", total);
+
+        // and so does a batch that all got the same one
+        let delivery = Delivery::new(
+            (0..10)
+                .map(|_| image(Some(&"long ".repeat(300)), 0))
+                .collect(),
+            Format::Png,
+        );
+        assert_eq!(delivery.prompts.len(), 1);
+        let name = &delivery.prompts[0].0;
+        assert_eq!(name.chars().count(), MAX_EMBED_FIELD_NAME_LEN);
+    }
+
+    #[test]
+    fn test_sanitize_filename() {
+        assert_eq!(
+            sanitize_filename("a cat/dog\\hybrid\non a\tmat"),
+            "a-cat-dog-hybrid-on-a-mat"
+        );
+        assert_eq!(sanitize_filename("../../etc/passwd"), "etc-passwd");
+        let long = sanitize_filename(&"a very long prompt ".repeat(50));
+        assert_eq!(long.chars().count(), MAX_FILENAME_LEN);
+        assert!(!long.ends_with('-'));
+        assert_eq!(sanitize_filename(""), "image");
+        assert_eq!(sanitize_filename(" \n/\t "), "image");
+        assert_eq!(sanitize_filename(".."), "image");
+    }
+
+    #[test]
+    fn test_revised_prompts() {
+        let image = |prompt: Option<&str>| Image {
+            revised_prompt: prompt.map(str::to_string),
+            bytes: Vec::new(),
+        };
+        let images = [
+            image(Some("a tabby cat in a top hat")),
+            image(None),
+            image(Some("a ginger cat in a bowler")),
+            image(Some("a tabby cat in a top hat")),
+        ];
+        let filenames: Vec<String> = (1..=4).map(|i| format!("image-{}.png", i)).collect();
+        assert_eq!(
+            revised_prompts(&images, &filenames),
+            vec![
+                (
+                    "image-1.png, image-4.png".to_string(),
+                    "a tabby cat in a top hat".to_string()
+                ),
+                (
+                    "image-3.png".to_string(),
+                    "a ginger cat in a bowler".to_string()
+                ),
+            ]
+        );
+        // DALL-E 2 doesn't rewrite anything
+        assert!(revised_prompts(&[image(None), image(None)], &filenames).is_empty());
+    }
+
+    #[test]
+    fn test_convert_to_jpeg() {
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbaImage::from_pixel(64, 32, image::Rgba([200, 40, 40, 255]))
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let png = png.into_inner();
+        let (jpeg, format) = reencode(png.clone(), Format::Jpeg);
+        assert_eq!(format, Format::Jpeg);
+        let decoded = image::load_from_memory_with_format(&jpeg, image::ImageFormat::Jpeg)
+            .unwrap()
+            .to_rgb8();
+        assert_eq!(decoded.dimensions(), (64, 32));
+        // lossy, but not that lossy
+        let [r, g, b] = decoded.get_pixel(32, 16).0;
+        assert!(r > 180 && g < 60 && b < 60, "{:?}", (r, g, b));
+
+        let (webp, format) = reencode(png.clone(), Format::Webp);
+        assert_eq!(format, Format::Webp);
+        let decoded = image::load_from_memory_with_format(&webp, image::ImageFormat::WebP)
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(*decoded.get_pixel(0, 0), image::Rgba([200, 40, 40, 255]));
+
+        // something that won't convert goes as it came
+        assert_eq!(
+            reencode(vec![1, 2, 3], Format::Jpeg),
+            (vec![1, 2, 3], Format::Png)
+        );
+        let delivery = Delivery::new(
+            vec![Image {
+                revised_prompt: None,
+                bytes: png,
+            }],
+            Format::Webp,
+        );
+        match &delivery.attachments[0] {
+            serenity::AttachmentType::Bytes { filename, .. } => {
+                assert_eq!(filename, "image-1.webp")
+            }
+            _ => panic!("expected bytes"),
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        assert_eq!(RateLimit::from_headers(&headers), None);
+        headers.insert("x-ratelimit-remaining-requests", "42".parse().unwrap());
+        headers.insert("x-ratelimit-reset-requests", "6m0s".parse().unwrap());
+        headers.insert("x-ratelimit-remaining-images", "nonsense".parse().unwrap());
+        let rate_limit = RateLimit::from_headers(&headers).unwrap();
+        assert_eq!(rate_limit.remaining_requests, Some(42));
+        assert_eq!(rate_limit.reset_requests.as_deref(), Some("6m0s"));
+        assert_eq!(rate_limit.remaining_images, None);
+        assert!(rate_limit
+            .describe()
+            .starts_with("Requests: 42 left, resets in 6m0s\nImages: unknown\n"));
+    }
+
+    #[test]
+    fn test_user_rate_limiter() {
+        let start = std::time::Instant::now();
+        let at = |secs: u64| start + std::time::Duration::from_secs(secs);
+        let mut limiter = UserRateLimiter::default();
+        for secs in 0..3 {
+            assert_eq!(limiter.try_record(1, at(secs), 3), Ok(()));
+        }
+        // the fourth in a minute trips it, until the first one's a minute old
+        assert_eq!(
+            limiter.try_record(1, at(10), 3),
+            Err(std::time::Duration::from_secs(50))
+        );
+        assert!(limiter.try_record(1, at(59), 3).is_err());
+        // someone else isn't affected
+        assert_eq!(limiter.try_record(2, at(10), 3), Ok(()));
+        assert_eq!(limiter.try_record(1, at(60), 3), Ok(()));
+        assert!(limiter.try_record(1, at(60), 3).is_err());
+        // turned away requests don't count against them
+        assert_eq!(limiter.try_record(1, at(62), 3), Ok(()));
+    }
+
+    #[test]
+    fn test_channel_slots() {
+        let slots = ChannelSlots::default();
+        let first = slots.try_start(1, 1).unwrap();
+        assert!(slots.try_start(1, 1).is_none());
+        // other channels aren't affected
+        let other = slots.try_start(2, 1).unwrap();
+        drop(first);
+        let again = slots.try_start(1, 1).unwrap();
+        drop(again);
+        drop(other);
+        assert!(slots.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_check_png() {
+        let png = base64::engine::general_purpose::STANDARD
+            .decode(TINY_PNG)
+            .unwrap();
+        assert!(check_png(&png).is_ok());
+        assert!(check_png(&png[..png.len() - 4]).is_err());
+        assert!(check_png(b"GIF89a definitely not a png").is_err());
+        assert!(check_png(b"").is_err());
+
+        let image = Image::from_open_ai(OpenAIImageData {
+            revised_prompt: None,
+            b64_json: TINY_PNG.to_string(),
+        });
+        assert!(image.is_ok());
+        let not_png = Image::from_open_ai(OpenAIImageData {
+            revised_prompt: None,
+            b64_json: base64::engine::general_purpose::STANDARD.encode("<html>oops</html>"),
+        });
+        assert!(not_png.is_err());
+    }
+
+    #[test]
+    fn test_prompt_from_reference() {
+        assert_eq!(
+            prompt_from_reference(Some("a cat"), Some("a dog")),
+            Ok("a cat".to_string())
+        );
+        assert_eq!(
+            prompt_from_reference(None, Some("  a dog in a hat ")),
+            Ok("a dog in a hat".to_string())
+        );
+        assert_eq!(
+            prompt_from_reference(Some(" "), Some("a dog")),
+            Ok("a dog".to_string())
+        );
+        assert!(prompt_from_reference(None, Some("")).is_err());
+        assert!(prompt_from_reference(None, None).is_err());
+    }
+}
@@ -1,65 +1,538 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use chrono::{DateTime, Datelike, Utc};
 use poise::serenity_prelude as serenity;
 use tokio::sync::Mutex;
 
-use crate::dalle::ImageRequest;
+use crate::costs::CostLog;
+use crate::dalle::{Backend, ChannelSlots, ImageRequest, RateLimit, UserRateLimiter};
+use crate::dice::LastRoll;
+use crate::history::{Pins, RollHistory};
+use crate::keys::{ApiKey, KeyCipher, UserKeys, USER_KEYS_FILE};
+use crate::preferences::{Preferences, PREFERENCES_FILE};
+use crate::settings::{Settings, SETTINGS_FILE};
+use crate::store::{DryRunStore, JsonStore, SqliteStore, Store};
+use crate::table::{self, Tables};
+
+const DATA_FILE: &str = "data.json";
+// Goes next to data.json, unless TRANSACTION_LOG says where.
+const TRANSACTION_LOG_FILE: &str = "transactions.jsonl";
+// What new accounts start with unless DEFAULT_CREDIT_CENTS says otherwise. Erry body gets
+// 20 bucks.
+const DEFAULT_CREDIT_CENTS: i64 = 2000;
+pub(crate) const DEFAULT_CREDIT: i64 = DEFAULT_CREDIT_CENTS * 1000;
+// How often to look for grants that have run out, with CREDIT_EXPIRY_DAYS set.
+const EXPIRY_SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
 
 // User data, which is stored and accessible in all command invocations
 pub struct Data {
-    accounts: Mutex<CostMap>,
+    // only ever used through `with_accounts`, since every store goes to disk
+    accounts: Arc<Mutex<Box<dyn Store>>>,
+    path: PathBuf,
+    // what new accounts start with, in millicents
+    default_credit: i64,
+    // None unless credit refreshes every month
+    monthly_credit: Option<MonthlyCredit>,
+    // how long granted credit lasts before what's left of it is taken back, None for ever
+    credit_expiry: Option<chrono::Duration>,
+    // where every change to an account's credit gets appended, None to not keep track
+    transaction_log: Option<PathBuf>,
+    pub(crate) tables: Tables,
+    pub(crate) settings: Mutex<Settings>,
+    // the settings' banner, for `settings::add_banner`, which can't wait on their lock
+    banner: std::sync::RwLock<Option<String>>,
+    pub(crate) preferences: Mutex<Preferences>,
+    // OpenAI keys people brought themselves, and what they're encrypted with. No cipher
+    // means USER_KEY_SECRET isn't set, and everyone uses the shared key.
+    user_keys: Mutex<UserKeys>,
+    key_cipher: Option<KeyCipher>,
+    pub(crate) history: Mutex<RollHistory>,
+    // everyone's last /roll in full, by user id. Left out of snapshots, there's no saving
+    // a RollResult.
+    pub(crate) last_rolls: Mutex<HashMap<u64, LastRoll>>,
+    pub(crate) pins: Mutex<Pins>,
+    pub(crate) channel_slots: ChannelSlots,
+    pub(crate) gen_rate_limits: Mutex<UserRateLimiter>,
+    // where /gen's images come from, OpenAI or a local Stable Diffusion
+    pub(crate) image_backend: Backend,
+    pub(crate) rate_limit: Mutex<Option<RateLimit>>,
+    pub(crate) costs: Mutex<CostLog>,
 }
 impl Data {
     pub async fn read_or_create() -> Result<Self, Error> {
-        let data = std::fs::read_to_string("data.json").unwrap_or_else(|_| "{}".to_string());
-        let cost_map = serde_json::from_str(&data).unwrap_or_default();
-        Ok(Self {
-            accounts: Mutex::new(cost_map),
+        let dry_run = dry_run();
+        match std::env::var("HYPNOS_DB") {
+            Ok(db) if !db.trim().is_empty() => {
+                let store = match dry_run {
+                    true => SqliteStore::open_read_only(db.trim(), DATA_FILE)?,
+                    false => SqliteStore::open(db.trim(), DATA_FILE)?,
+                };
+                Ok(Self::configured(DATA_FILE, Box::new(store), dry_run))
+            }
+            _ => Self::read_or_create_at(DATA_FILE, dry_run).await,
+        }
+    }
+
+    async fn read_or_create_at(path: impl AsRef<Path>, dry_run: bool) -> Result<Self, Error> {
+        // a dry run doesn't even upgrade an old file
+        let store = match dry_run {
+            true => JsonStore::read(&path)?,
+            false => JsonStore::open(&path)?,
+        };
+        Ok(Self::configured(path, Box::new(store), dry_run))
+    }
+
+    // `path` is where data.json would be, settings and the like go next to it. In a dry
+    // run nothing goes to OpenAI and nothing at all is saved.
+    pub(crate) fn configured(path: impl AsRef<Path>, store: Box<dyn Store>, dry_run: bool) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let (store, image_backend): (Box<dyn Store>, _) = if dry_run {
+            println!("DRY_RUN is on: images are placeholders, and nothing is saved");
+            (Box::new(DryRunStore::new(store)), Backend::DryRun)
+        } else {
+            (store, Backend::from_env())
+        };
+        let settings = Settings::read(path.with_file_name(SETTINGS_FILE));
+        let preferences = Preferences::read(path.with_file_name(PREFERENCES_FILE));
+        let user_keys = UserKeys::read(path.with_file_name(USER_KEYS_FILE));
+        let transaction_log = match std::env::var("TRANSACTION_LOG") {
+            Ok(log) if !log.trim().is_empty() => PathBuf::from(log.trim()),
+            _ => path.with_file_name(TRANSACTION_LOG_FILE),
+        };
+        Self {
+            accounts: Arc::new(Mutex::new(store)),
+            path,
+            default_credit: parse_default_credit(
+                std::env::var("DEFAULT_CREDIT_CENTS").ok().as_deref(),
+            ),
+            monthly_credit: parse_monthly_credit(
+                std::env::var("MONTHLY_CREDIT_CENTS").ok().as_deref(),
+                std::env::var("CREDIT_ROLLOVER_CENTS").ok().as_deref(),
+            ),
+            credit_expiry: parse_credit_expiry(std::env::var("CREDIT_EXPIRY_DAYS").ok().as_deref()),
+            // what the transactions would be on isn't being saved either
+            transaction_log: (!dry_run).then_some(transaction_log),
+            tables: table::load_tables(table::TABLES_FILE),
+            banner: std::sync::RwLock::new(settings.banner()),
+            settings: Mutex::new(settings),
+            preferences: Mutex::new(preferences),
+            user_keys: Mutex::new(user_keys),
+            key_cipher: KeyCipher::from_env(),
+            history: Mutex::new(RollHistory::default()),
+            last_rolls: Mutex::new(HashMap::new()),
+            pins: Mutex::new(Pins::default()),
+            channel_slots: ChannelSlots::default(),
+            gen_rate_limits: Mutex::new(UserRateLimiter::default()),
+            image_backend,
+            rate_limit: Mutex::new(None),
+            costs: Mutex::new(CostLog::default()),
+        }
+    }
+
+    pub(crate) async fn update_settings(&self, f: impl FnOnce(&mut Settings)) -> Result<(), Error> {
+        let mut settings = self.settings.lock().await;
+        f(&mut settings);
+        *self.banner.write().unwrap_or_else(|err| err.into_inner()) = settings.banner();
+        if self.dry_run() {
+            return Ok(());
+        }
+        settings
+            .write(self.path.with_file_name(SETTINGS_FILE))
+            .await
+    }
+
+    // DRY_RUN, where changes only last until a restart and nothing leaves the bot.
+    pub(crate) fn dry_run(&self) -> bool {
+        self.image_backend == Backend::DryRun
+    }
+
+    // The maintenance banner, if maintenance mode is on.
+    pub(crate) fn banner(&self) -> Option<String> {
+        self.banner
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    pub(crate) async fn update_preferences(
+        &self,
+        f: impl FnOnce(&mut Preferences),
+    ) -> Result<(), Error> {
+        let mut preferences = self.preferences.lock().await;
+        f(&mut preferences);
+        if self.dry_run() {
+            return Ok(());
+        }
+        preferences
+            .write(self.path.with_file_name(PREFERENCES_FILE))
+            .await
+    }
+
+    // Whether USER_KEY_SECRET is set, so there's something to keep people's own keys with.
+    pub(crate) fn takes_user_keys(&self) -> bool {
+        self.key_cipher.is_some()
+    }
+
+    // Saves (or with `None`, forgets) the user's own OpenAI key. Only the encrypted key is
+    // ever written down, and it's only used once it has been.
+    pub(crate) async fn set_user_key(
+        &self,
+        user_id: u64,
+        key: Option<&ApiKey>,
+    ) -> Result<(), Error> {
+        let sealed = match (key, &self.key_cipher) {
+            (None, _) => None,
+            (Some(key), Some(cipher)) => Some(cipher.seal(user_id, key)?),
+            (Some(_), None) => return Err("USER_KEY_SECRET isn't set".into()),
+        };
+        let mut user_keys = self.user_keys.lock().await;
+        let mut updated = user_keys.clone();
+        updated.set(user_id, sealed);
+        if !self.dry_run() {
+            updated
+                .write(self.path.with_file_name(USER_KEYS_FILE))
+                .await?;
+        }
+        *user_keys = updated;
+        Ok(())
+    }
+
+    // The user's own OpenAI key, if they've brought one and we can still open it.
+    pub(crate) async fn user_key(&self, user_id: u64) -> Option<ApiKey> {
+        let cipher = self.key_cipher.as_ref()?;
+        let user_keys = self.user_keys.lock().await;
+        cipher.open(user_id, user_keys.get(user_id)?)
+    }
+
+    // Runs `f` with the store on a blocking thread, so its disk I/O doesn't hold up
+    // everything else. Nobody else gets at the store until it's done.
+    pub(crate) async fn with_accounts<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut dyn Store) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let mut store = self.accounts.clone().lock_owned().await;
+        tokio::task::spawn_blocking(move || f(&mut **store)).await?
+    }
+
+    // Everything we've built up while running, so that reloading the config doesn't
+    // lose it. Config itself (settings, tables) is left out, that's what gets reloaded.
+    pub async fn snapshot(&self) -> Result<Snapshot, Error> {
+        Ok(Snapshot {
+            accounts: self.with_accounts(|store| store.all()).await?,
+            history: self.history.lock().await.clone(),
+            pins: self.pins.lock().await.clone(),
+            costs: self.costs.lock().await.clone(),
+            rate_limit: self.rate_limit.lock().await.clone(),
         })
     }
+
+    // Puts back what `snapshot` took. Accounts are saved as they're restored.
+    pub async fn restore(&self, snapshot: Snapshot) -> Result<(), Error> {
+        let accounts = snapshot.accounts;
+        self.with_accounts(move |store| store.put(&accounts))
+            .await?;
+        *self.history.lock().await = snapshot.history;
+        *self.pins.lock().await = snapshot.pins;
+        *self.costs.lock().await = snapshot.costs;
+        *self.rate_limit.lock().await = snapshot.rate_limit;
+        Ok(())
+    }
 }
 impl Default for Data {
     fn default() -> Self {
         Self {
-            accounts: Mutex::new(BTreeMap::new()),
+            accounts: Arc::new(Mutex::new(Box::new(JsonStore::empty(DATA_FILE)))),
+            path: PathBuf::from(DATA_FILE),
+            default_credit: DEFAULT_CREDIT,
+            monthly_credit: None,
+            credit_expiry: None,
+            transaction_log: None,
+            tables: Tables::new(),
+            settings: Mutex::new(Settings::default()),
+            banner: std::sync::RwLock::new(None),
+            preferences: Mutex::new(Preferences::default()),
+            user_keys: Mutex::new(UserKeys::default()),
+            key_cipher: None,
+            history: Mutex::new(RollHistory::default()),
+            last_rolls: Mutex::new(HashMap::new()),
+            pins: Mutex::new(Pins::default()),
+            channel_slots: ChannelSlots::default(),
+            gen_rate_limits: Mutex::new(UserRateLimiter::default()),
+            image_backend: Backend::default(),
+            rate_limit: Mutex::new(None),
+            costs: Mutex::new(CostLog::default()),
         }
     }
 }
 
-type CostMap = BTreeMap<u64, Account>;
+pub(crate) type CostMap = BTreeMap<u64, Account>;
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+// The runtime state from `Data::snapshot`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    accounts: CostMap,
+    history: RollHistory,
+    pins: Pins,
+    costs: CostLog,
+    rate_limit: Option<RateLimit>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Account {
     pub user: String,
     pub images: u64,
     // in millicents
     pub credit: i64,
+    // what the user was charged, after COST_MULTIPLIER
     pub total_cost: i64,
+    // what OpenAI charged us, for reconciling against the bill. Only tracked from when
+    // COST_MULTIPLIER was added, older accounts start at zero.
+    #[serde(default)]
+    pub raw_cost: i64,
+    // when the monthly credit was last topped up. Accounts from before monthly credit
+    // (and new ones) have the epoch, so they get topped up the first time they're used.
+    #[serde(default)]
+    pub last_reset: DateTime<Utc>,
+    // the grants with something left, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub grants: Vec<Grant>,
+    #[serde(default, skip_serializing_if = "SelfLimit::is_default")]
+    pub self_limit: SelfLimit,
+}
+
+// A cap someone's put on their own spending each month with /set_limit, and how much of it
+// they've used. Spending is tracked even without a cap, so one set mid-month counts what
+// came before it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SelfLimit {
+    // in millicents, None for no cap
+    #[serde(default)]
+    pub monthly: Option<i64>,
+    // what they've been charged in `month`, in millicents
+    #[serde(default)]
+    pub spent: i64,
+    // some time in the month `spent` is for
+    #[serde(default)]
+    pub month: DateTime<Utc>,
+}
+impl SelfLimit {
+    fn is_default(&self) -> bool {
+        *self == SelfLimit::default()
+    }
+
+    // What they've spent in the month of `now`.
+    pub(crate) fn spent_in(&self, now: DateTime<Utc>) -> i64 {
+        let month = |date: DateTime<Utc>| (date.year(), date.month());
+        if month(self.month) == month(now) {
+            self.spent
+        } else {
+            0
+        }
+    }
+
+    // Whether they can spend another `millicents` at `now` without going over.
+    fn allows(&self, millicents: i64, now: DateTime<Utc>) -> bool {
+        self.monthly
+            .is_none_or(|monthly| self.spent_in(now).saturating_add(millicents) <= monthly)
+    }
+
+    fn record(&mut self, millicents: i64, now: DateTime<Utc>) {
+        self.spent = self.spent_in(now).saturating_add(millicents);
+        self.month = now;
+    }
+
+    // Takes a refund off what's been spent. Only this month's spending can come down, a
+    // debit from last month has already stopped counting.
+    fn refund(&mut self, millicents: i64, now: DateTime<Utc>) {
+        if self.spent_in(now) > 0 {
+            self.spent = (self.spent - millicents).max(0);
+        }
+    }
+}
+
+// Credit given with /grant and the like, and how much of it hasn't been spent yet. Only
+// this can expire. What an account starts with and monthly credit never do.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Grant {
+    pub credit_granted_at: DateTime<Utc>,
+    // in millicents
+    pub unspent: i64,
+}
+
+// MONTHLY_CREDIT_CENTS and CREDIT_ROLLOVER_CENTS, in millicents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MonthlyCredit {
+    // what everyone's credit goes back to on the 1st
+    pub(crate) allowance: i64,
+    // how much unspent credit carries over on top of the allowance
+    pub(crate) rollover_cap: i64,
+}
+
+// Monthly credit is off unless MONTHLY_CREDIT_CENTS is a number of cents, zero or more.
+// Rollover is off unless CREDIT_ROLLOVER_CENTS says otherwise.
+fn parse_monthly_credit(
+    allowance: Option<&str>,
+    rollover_cap: Option<&str>,
+) -> Option<MonthlyCredit> {
+    let cents = |value: Option<&str>| {
+        value
+            .and_then(|cents| cents.trim().parse::<i64>().ok())
+            .filter(|&cents| cents >= 0)
+            .and_then(|cents| cents.checked_mul(1000))
+    };
+    Some(MonthlyCredit {
+        allowance: cents(allowance)?,
+        rollover_cap: cents(rollover_cap).unwrap_or(0),
+    })
 }
 impl Account {
     pub fn overdrafted(&self) -> bool {
         self.credit < 0
     }
 
-    fn account_for_request(&mut self, request: &ImageRequest) {
-        let cost = request.cost();
-        self.credit -= cost.millicents as i64;
-        self.total_cost += cost.millicents as i64;
+    pub fn credit_dollars(&self) -> f64 {
+        millicents_to_dollars(self.credit)
+    }
+
+    pub fn total_cost_dollars(&self) -> f64 {
+        millicents_to_dollars(self.total_cost)
+    }
+
+    // Puts the credit back to the allowance if it hasn't been yet this month, keeping up
+    // to the rollover cap of what was left. Overdrafts don't carry over. Unspent grants
+    // aren't part of the leftover, they're kept whole until they expire. Returns whether
+    // it reset.
+    pub(crate) fn maybe_reset(&mut self, now: DateTime<Utc>, monthly: MonthlyCredit) -> bool {
+        let month = |date: DateTime<Utc>| (date.year(), date.month());
+        if month(self.last_reset) == month(now) {
+            return false;
+        }
+        // settled, the grants are never more than the credit
+        let granted: i64 = self.grants.iter().map(|grant| grant.unspent).sum();
+        self.credit = (self.credit - granted).clamp(0, monthly.rollover_cap) + granted;
+        self.credit = self.credit.saturating_add(monthly.allowance);
+        self.last_reset = now;
+        true
+    }
+
+    // Adds `credit` millicents, granted at `now`. Taking credit away eats into the grants
+    // like spending does.
+    pub(crate) fn grant(&mut self, credit: i64, now: DateTime<Utc>) {
+        self.credit = self.credit.saturating_add(credit);
+        if credit > 0 {
+            self.grants.push(Grant {
+                credit_granted_at: now,
+                unspent: credit,
+            });
+        }
+        self.settle_grants();
+    }
+
+    // Oldest credit gets spent first. Credit that didn't come from a grant is the oldest
+    // there is (the account started with it, or had it before grants were tracked), so
+    // that goes before any grant, then the grants in the order they were given.
+    fn settle_grants(&mut self) {
+        let granted: i64 = self.grants.iter().map(|grant| grant.unspent).sum();
+        let mut spent = granted - self.credit.max(0);
+        while spent > 0 {
+            let oldest = &mut self.grants[0];
+            let taken = oldest.unspent.min(spent);
+            oldest.unspent -= taken;
+            spent -= taken;
+            if oldest.unspent == 0 {
+                self.grants.remove(0);
+            }
+        }
+    }
+
+    // Takes back whatever's left of the grants from before `cutoff`. Returns how much that
+    // was, in millicents.
+    pub(crate) fn expire_grants(&mut self, cutoff: DateTime<Utc>) -> i64 {
+        let mut expired = 0;
+        self.grants.retain(|grant| {
+            if grant.credit_granted_at >= cutoff {
+                return true;
+            }
+            expired += grant.unspent;
+            false
+        });
+        self.credit -= expired;
+        expired
+    }
+
+    // Whether the request fits in what's left. Landing exactly on zero is fine.
+    fn can_afford(&self, request: &ImageRequest, multiplier: f64) -> bool {
+        let charged = request.cost().scaled(multiplier);
+        self.credit as i128 - charged.millicents as i128 >= 0
+    }
+
+    fn account_for_request(&mut self, request: &ImageRequest, multiplier: f64) {
+        let raw = request.cost();
+        let charged = raw.scaled(multiplier);
+        self.credit -= charged.millicents as i64;
+        self.total_cost += charged.millicents as i64;
+        self.raw_cost += raw.millicents as i64;
         self.images += request.num_images() as u64;
+        self.settle_grants();
+    }
+
+    // Undoes `account_for_request`, for images that never got made. Returns what the user
+    // gets back, in millicents.
+    fn refund_request(&mut self, request: &ImageRequest, multiplier: f64) -> i64 {
+        let raw = request.cost();
+        let charged = raw.scaled(multiplier);
+        self.credit += charged.millicents as i64;
+        self.total_cost -= charged.millicents as i64;
+        self.raw_cost -= raw.millicents as i64;
+        self.images = self.images.saturating_sub(request.num_images() as u64);
+        charged.millicents as i64
     }
 }
 impl Account {
-    fn default_for_user(user: &serenity::User) -> Self {
+    fn default_for_user(user: &serenity::User, credit: i64) -> Self {
+        Account::named(format!("{}#{}", user.name, user.discriminator), credit)
+    }
+
+    // A brand new account, starting with `credit` millicents.
+    pub(crate) fn named(user: String, credit: i64) -> Self {
         Account {
             images: 0,
-            // erry body gets 20 bucks
-            credit: 20 * 100 * 1000,
+            credit,
             total_cost: 0,
-            user: format!("{}#{}", user.name, user.discriminator),
+            raw_cost: 0,
+            last_reset: DateTime::default(),
+            grants: Vec::new(),
+            self_limit: SelfLimit::default(),
+            user,
         }
     }
 }
 
+// DEFAULT_CREDIT_CENTS, in millicents. Anything that isn't a number of cents, zero or more,
+// gets the usual amount.
+fn parse_default_credit(cents: Option<&str>) -> i64 {
+    cents
+        .and_then(|cents| cents.trim().parse::<i64>().ok())
+        .filter(|&cents| cents >= 0)
+        .and_then(|cents| cents.checked_mul(1000))
+        .unwrap_or(DEFAULT_CREDIT)
+}
+
+// CREDIT_EXPIRY_DAYS. Granted credit lasts for ever unless it's a number of days, one or
+// more.
+fn parse_credit_expiry(days: Option<&str>) -> Option<chrono::Duration> {
+    days.and_then(|days| days.trim().parse::<i64>().ok())
+        .filter(|&days| days > 0)
+        .and_then(chrono::Duration::try_days)
+}
+
+// A cent is 1000 millicents, so a dollar is 100,000.
+fn millicents_to_dollars(millicents: i64) -> f64 {
+    millicents as f64 / 100_000.0
+}
+
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
 pub type Context<'a> = poise::Context<'a, Data, Error>;
 
@@ -68,40 +541,523 @@ pub type Context<'a> = poise::Context<'a, Data, Error>;
 pub enum RequestPermitted {
     Yes,
     No,
+    // they've still got credit, but they asked not to spend this much this month
+    OverSelfLimit,
+}
+
+// Requests from AUTOMATION_USER_IDS are billed here rather than to whoever ran them.
+pub(crate) const AUTOMATION_ACCOUNT_ID: u64 = 0;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Payer<'a> {
+    User(&'a serenity::User),
+    // With GUILD_CREDIT_POOL set, everyone in a server spends from one shared account.
+    // Guild ids never clash with user ids, so these live right alongside the users'. The
+    // user is whoever's spending from it.
+    Guild(serenity::GuildId, &'a serenity::User),
+    // A trusted bot or scheduler. It isn't held to the per-user limits, but we still
+    // keep track of what it spends.
+    Automation,
+}
+impl<'a> Payer<'a> {
+    // Whose credit `user` is spending. DMs don't have a server, so there it's always
+    // their own.
+    pub(crate) fn for_user(user: &'a serenity::User, guild_id: Option<serenity::GuildId>) -> Self {
+        Self::scoped(user, guild_id, guild_pool())
+    }
+
+    fn scoped(user: &'a serenity::User, guild_id: Option<serenity::GuildId>, pooled: bool) -> Self {
+        match guild_id {
+            Some(guild_id) if pooled => Payer::Guild(guild_id, user),
+            _ => Payer::User(user),
+        }
+    }
+
+    fn account_id(self) -> u64 {
+        match self {
+            Payer::User(user) => user.id.0,
+            Payer::Guild(guild_id, _) => guild_id.0,
+            Payer::Automation => AUTOMATION_ACCOUNT_ID,
+        }
+    }
+
+    // What the payer starts with, if they don't have an account yet.
+    fn new_account(self, default_credit: i64) -> Account {
+        match self {
+            Payer::User(user) => Account::default_for_user(user, default_credit),
+            Payer::Guild(guild_id, _) => {
+                Account::named(format!("guild {}", guild_id.0), default_credit)
+            }
+            Payer::Automation => Account::named("automation".to_string(), default_credit),
+        }
+    }
+
+    // Whose account counts against the spender's own /set_limit, when that isn't the
+    // payer's. Limits are per person, so in a pool it's their own account, if they have
+    // one. Someone who's only ever spent from a pool doesn't until they set a limit.
+    fn limit_id(self) -> Option<u64> {
+        match self {
+            Payer::Guild(_, user) => Some(user.id.0),
+            _ => None,
+        }
+    }
+}
+
+// The account to bill, and the one `limit_id` says counts against a /set_limit if that
+// account exists.
+fn load_accounts(
+    store: &dyn Store,
+    id: u64,
+    new_account: Account,
+    limit_id: Option<u64>,
+) -> Result<(Account, Option<(u64, Account)>), Error> {
+    let account = store.get(id)?.unwrap_or(new_account);
+    let limit_account = match limit_id {
+        Some(limit_id) => store.get(limit_id)?.map(|own| (limit_id, own)),
+        None => None,
+    };
+    Ok((account, limit_account))
+}
+
+// DRY_RUN=true, for trying the bot out somewhere without spending or saving anything.
+fn dry_run() -> bool {
+    std::env::var("DRY_RUN")
+        .map(|dry_run| matches!(dry_run.trim(), "1" | "true"))
+        .unwrap_or(false)
+}
+
+fn guild_pool() -> bool {
+    std::env::var("GUILD_CREDIT_POOL")
+        .map(|pool| matches!(pool.trim(), "1" | "true"))
+        .unwrap_or(false)
 }
 
 pub(crate) async fn debit_for_request(
     data: &Data,
-    user: &serenity::User,
+    payer: Payer<'_>,
     request: &ImageRequest,
 ) -> Result<RequestPermitted, Error> {
-    let user_id = user.id.0;
+    let multiplier = data.settings.lock().await.cost_multiplier();
+    let (id, new_account, limit_id) = (
+        payer.account_id(),
+        payer.new_account(data.default_credit),
+        payer.limit_id(),
+    );
+    let automation = matches!(payer, Payer::Automation);
+    let own_credit = matches!(payer, Payer::User(_));
+    let (monthly_credit, log) = (data.monthly_credit, data.transaction_log.clone());
+    let request = request.clone();
+    data.with_accounts(move |store| {
+        let (mut account, limit_account) = load_accounts(store, id, new_account, limit_id)?;
+        let now = Utc::now();
+        if let Some(monthly) = monthly_credit {
+            account.maybe_reset(now, monthly);
+        }
+        if !automation {
+            if !account.can_afford(&request, multiplier) {
+                return Ok(RequestPermitted::No);
+            }
+            let charged = request.cost().scaled(multiplier).millicents as i64;
+            let self_limit = match &limit_account {
+                Some((_, own)) => &own.self_limit,
+                None => &account.self_limit,
+            };
+            if !self_limit.allows(charged, now) {
+                return Ok(RequestPermitted::OverSelfLimit);
+            }
+        }
+        let before = account.credit;
+        account.account_for_request(&request, multiplier);
+        let mut accounts = CostMap::new();
+        match limit_account {
+            Some((limit_id, mut own)) => {
+                own.self_limit.record(before - account.credit, now);
+                accounts.insert(limit_id, own);
+            }
+            None if own_credit => account.self_limit.record(before - account.credit, now),
+            None => {}
+        }
+        accounts.insert(id, account.clone());
+        store.put(&accounts)?;
+        record_transaction(
+            log.as_deref(),
+            id,
+            TransactionAction::Debit,
+            before - account.credit,
+            &account,
+        );
+        Ok(RequestPermitted::Yes)
+    })
+    .await
+}
+
+// How a generation gets paid for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[must_use]
+pub(crate) enum Billing {
+    // with the user's own OpenAI key, so our credit doesn't come into it
+    OwnKey(ApiKey),
+    // with the shared key, and it's been debited from the payer's credit
+    Credit,
+    // they're out of credit
+    Refused,
+    // they'd go past the monthly limit they set themselves
+    OverSelfLimit,
+}
+
+// Works out who pays for `request`, and debits the payer if it's us. Someone who's
+// brought their own key always uses it, even when a server pools its credit. Automation
+// has no key of its own.
+pub(crate) async fn bill_request(
+    data: &Data,
+    payer: Payer<'_>,
+    user_id: u64,
+    request: &ImageRequest,
+) -> Result<Billing, Error> {
+    if !matches!(payer, Payer::Automation) {
+        if let Some(key) = data.user_key(user_id).await {
+            return Ok(Billing::OwnKey(key));
+        }
+    }
+    Ok(match debit_for_request(data, payer, request).await? {
+        RequestPermitted::Yes => Billing::Credit,
+        RequestPermitted::No => Billing::Refused,
+        RequestPermitted::OverSelfLimit => Billing::OverSelfLimit,
+    })
+}
+
+// Gives back what `request` cost, when OpenAI wouldn't make its images. Returns how much
+// that was, in millicents.
+pub(crate) async fn refund_for_request(
+    data: &Data,
+    payer: Payer<'_>,
+    request: &ImageRequest,
+) -> Result<i64, Error> {
+    let multiplier = data.settings.lock().await.cost_multiplier();
+    let (id, new_account, limit_id) = (
+        payer.account_id(),
+        payer.new_account(data.default_credit),
+        payer.limit_id(),
+    );
+    let log = data.transaction_log.clone();
+    let request = request.clone();
+    data.with_accounts(move |store| {
+        let (mut account, limit_account) = load_accounts(store, id, new_account, limit_id)?;
+        let refunded = account.refund_request(&request, multiplier);
+        let now = Utc::now();
+        let mut accounts = CostMap::new();
+        match limit_account {
+            Some((limit_id, mut own)) => {
+                own.self_limit.refund(refunded, now);
+                accounts.insert(limit_id, own);
+            }
+            None => account.self_limit.refund(refunded, now),
+        }
+        accounts.insert(id, account.clone());
+        store.put(&accounts)?;
+        record_transaction(
+            log.as_deref(),
+            id,
+            TransactionAction::Refund,
+            -refunded,
+            &account,
+        );
+        Ok(refunded)
+    })
+    .await
+}
+
+// One line of the transaction log.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Transaction {
+    pub(crate) timestamp: DateTime<Utc>,
+    // the account's id, so a guild's for a shared pool
+    pub(crate) user_id: u64,
+    pub(crate) action: TransactionAction,
+    // negative when credit was added
+    pub(crate) cost_millicents: i64,
+    // the account's credit afterwards
+    pub(crate) balance_millicents: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TransactionAction {
+    Debit,
+    Grant,
+    // a debit given back, because the images never got made
+    Refund,
+    // granted credit that went unspent for too long
+    Expire,
+}
+
+// Appends `entry` to the log at `path` as a line of JSON, creating the file if need be.
+pub(crate) fn log_transaction(path: &Path, entry: &Transaction) -> Result<(), Error> {
+    use std::io::Write;
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())?;
+    Ok(())
+}
+
+// The account's already been saved by now, so a log we can't write to shouldn't undo
+// that. It's noted in the console instead. Writes to the disk, so it's for inside
+// `Data::with_accounts`.
+fn record_transaction(
+    log: Option<&Path>,
+    user_id: u64,
+    action: TransactionAction,
+    cost_millicents: i64,
+    account: &Account,
+) {
+    let path = match log {
+        None => return,
+        Some(path) => path,
+    };
+    let entry = Transaction {
+        timestamp: Utc::now(),
+        user_id,
+        action,
+        cost_millicents,
+        balance_millicents: account.credit,
+    };
+    if let Err(err) = log_transaction(path, &entry) {
+        crate::throttled_log::log(format!("Failed to write to {}: {}", path.display(), err));
+    }
+}
+
+// Adds `credit` (in millicents) to each of `users`, and saves once at the end. Returns how
+// many accounts got it.
+pub(crate) async fn grant_credit(
+    data: &Data,
+    users: &[serenity::User],
+    credit: i64,
+) -> Result<usize, Error> {
+    let (users, default_credit) = (users.to_vec(), data.default_credit);
+    let log = data.transaction_log.clone();
+    data.with_accounts(move |store| {
+        let mut accounts = CostMap::new();
+        for user in users.iter() {
+            if let Some(account) = store.get(user.id.0)? {
+                accounts.insert(user.id.0, account);
+            }
+        }
+        let granted = grant_to_each(&mut accounts, &users, credit, default_credit);
+        store.put(&accounts)?;
+        for (id, account) in accounts.iter() {
+            record_transaction(
+                log.as_deref(),
+                *id,
+                TransactionAction::Grant,
+                -credit,
+                account,
+            );
+        }
+        Ok(granted)
+    })
+    .await
+}
+
+// Adds `credit` (in millicents) to one user's account, opening it if they don't have one
+// yet. Returns the account as it stands afterwards.
+pub(crate) async fn add_credit(
+    data: &Data,
+    user: &serenity::User,
+    credit: i64,
+) -> Result<Account, Error> {
+    let (id, new_account) = (
+        user.id.0,
+        Account::default_for_user(user, data.default_credit),
+    );
+    let log = data.transaction_log.clone();
+    data.with_accounts(move |store| {
+        let mut account = store.get(id)?.unwrap_or(new_account);
+        account.grant(credit, Utc::now());
+        store.put(&CostMap::from([(id, account.clone())]))?;
+        record_transaction(
+            log.as_deref(),
+            id,
+            TransactionAction::Grant,
+            -credit,
+            &account,
+        );
+        Ok(account)
+    })
+    .await
+}
+
+// Sets (or with `None`, lifts) the monthly cap on one user's own spending. Returns the
+// account as it stands afterwards.
+pub(crate) async fn set_self_limit(
+    data: &Data,
+    user: &serenity::User,
+    monthly: Option<i64>,
+) -> Result<Account, Error> {
+    let (id, new_account) = (
+        user.id.0,
+        Account::default_for_user(user, data.default_credit),
+    );
+    data.with_accounts(move |store| {
+        let mut account = store.get(id)?.unwrap_or(new_account);
+        account.self_limit.monthly = monthly;
+        store.put(&CostMap::from([(id, account.clone())]))?;
+        Ok(account)
+    })
+    .await
+}
 
-    let mut accounts = data.accounts.lock().await;
+fn grant_to_each(
+    accounts: &mut CostMap,
+    users: &[serenity::User],
+    credit: i64,
+    default_credit: i64,
+) -> usize {
+    for user in users {
+        let account = accounts
+            .entry(user.id.0)
+            .or_insert_with(|| Account::default_for_user(user, default_credit));
+        account.grant(credit, Utc::now());
+    }
+    users.len()
+}
+
+// Takes back what's left of every grant older than CREDIT_EXPIRY_DAYS, as of `now`.
+// Returns how much was taken, in millicents.
+pub(crate) async fn expire_credit(data: &Data, now: DateTime<Utc>) -> Result<i64, Error> {
+    let expiry = match data.credit_expiry {
+        None => return Ok(0),
+        Some(expiry) => expiry,
+    };
+    let log = data.transaction_log.clone();
+    data.with_accounts(move |store| {
+        let mut expired = CostMap::new();
+        let mut amounts = BTreeMap::new();
+        for (id, mut account) in store.all()? {
+            let amount = account.expire_grants(now - expiry);
+            if amount > 0 {
+                amounts.insert(id, amount);
+                expired.insert(id, account);
+            }
+        }
+        store.put(&expired)?;
+        for (id, account) in expired.iter() {
+            let amount = amounts[id];
+            record_transaction(
+                log.as_deref(),
+                *id,
+                TransactionAction::Expire,
+                amount,
+                account,
+            );
+        }
+        Ok(amounts.values().sum())
+    })
+    .await
+}
 
-    let account = accounts
-        .entry(user_id)
-        .or_insert(Account::default_for_user(user));
-    if account.overdrafted() {
-        return Ok(RequestPermitted::No);
+// Runs `expire_credit` every so often, for as long as the bot's up. Does nothing without
+// CREDIT_EXPIRY_DAYS.
+pub async fn sweep_expired_credit(data: &Data) {
+    if data.credit_expiry.is_none() {
+        return;
     }
-    account.account_for_request(request);
-    // serialize the cost map to a data.json
-    let serialized = serde_json::to_string(&*accounts)?;
-    // write that to a file using tokio file io
-    tokio::fs::write("data.json", serialized).await?;
+    let mut interval = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match expire_credit(data, Utc::now()).await {
+            Ok(0) => {}
+            Ok(expired) => println!(
+                "Expired {} of unspent credit",
+                crate::info::format_millicents(expired)
+            ),
+            Err(err) => crate::throttled_log::log(format!("Failed to expire credit: {}", err)),
+        }
+    }
+}
+
+// What to rank accounts by for the leaderboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ranking {
+    Images,
+    TotalCost,
+}
+
+// The `n` accounts with the most images or spending, highest first. Ties go to the lower
+// id, and the automation account isn't in the running.
+pub(crate) async fn top_accounts(
+    data: &Data,
+    n: usize,
+    by: Ranking,
+) -> Result<Vec<(u64, Account)>, Error> {
+    let accounts = data.with_accounts(|store| store.all()).await?;
+    Ok(rank_accounts(accounts, n, by))
+}
 
-    Ok(RequestPermitted::Yes)
+fn rank_accounts(accounts: CostMap, n: usize, by: Ranking) -> Vec<(u64, Account)> {
+    let mut accounts: Vec<(u64, Account)> = accounts
+        .into_iter()
+        .filter(|(id, _)| *id != AUTOMATION_ACCOUNT_ID)
+        .collect();
+    // stable, and the map was in id order
+    accounts.sort_by_key(|(_, account)| {
+        std::cmp::Reverse(match by {
+            Ranking::Images => account.images as i64,
+            Ranking::TotalCost => account.total_cost,
+        })
+    });
+    accounts.truncate(n);
+    accounts
 }
 
-pub(crate) async fn get_account(data: &Data, user: &serenity::User) -> Result<Account, Error> {
-    let user_id = user.id.0;
-    let cost_map = data.accounts.lock().await;
+// Both sides of the books, in millicents. `prepaid` is credit users haven't spent yet,
+// `overdrawn` is how far the overdrafted accounts are in the hole (as a positive number).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Liability {
+    pub(crate) prepaid: i64,
+    pub(crate) overdrawn: i64,
+}
+
+pub(crate) async fn liability(data: &Data) -> Result<Liability, Error> {
+    Ok(sum_liability(
+        &data.with_accounts(|store| store.all()).await?,
+    ))
+}
+
+fn sum_liability(accounts: &CostMap) -> Liability {
+    let mut liability = Liability {
+        prepaid: 0,
+        overdrawn: 0,
+    };
+    for account in accounts.values() {
+        if account.credit > 0 {
+            liability.prepaid = liability.prepaid.saturating_add(account.credit);
+        } else {
+            liability.overdrawn = liability.overdrawn.saturating_sub(account.credit);
+        }
+    }
+    liability
+}
 
-    match cost_map.get(&user_id) {
-        None => Ok(Account::default_for_user(user)),
-        Some(account) => Ok(account.clone()),
+// The account `user` spends from in `guild_id`, which is the server's if they're pooled.
+pub(crate) async fn get_account(
+    data: &Data,
+    user: &serenity::User,
+    guild_id: Option<serenity::GuildId>,
+) -> Result<Account, Error> {
+    let payer = Payer::for_user(user, guild_id);
+    let (id, new_account) = (payer.account_id(), payer.new_account(data.default_credit));
+    let mut account = data
+        .with_accounts(move |store| Ok(store.get(id)?.unwrap_or(new_account)))
+        .await?;
+    // what they'd have if they generated something now, without saving the reset yet
+    if let Some(monthly) = data.monthly_credit {
+        account.maybe_reset(Utc::now(), monthly);
     }
+    Ok(account)
 }
 
 #[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
@@ -114,4 +1070,866 @@ impl Cost {
             millicents: (cents as u128) * 1000,
         }
     }
+
+    pub(crate) fn millicents_of(millicents: u128) -> Self {
+        Cost { millicents }
+    }
+
+    pub(crate) fn millicents(self) -> u128 {
+        self.millicents
+    }
+
+    pub(crate) fn scaled(self, multiplier: f64) -> Self {
+        Cost {
+            millicents: (self.millicents as f64 * multiplier).round() as u128,
+        }
+    }
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unversioned_file_is_upgraded() {
+        let path = std::env::temp_dir().join(format!("hypnos-v0-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"1234":{"user":"someone#0001","images":3,"credit":1880000,"total_cost":120000}}"#,
+        )
+        .unwrap();
+
+        let data = Data::read_or_create_at(&path, false).await.unwrap();
+        let account = data.accounts.lock().await.get(1234).unwrap().unwrap();
+        assert_eq!(account.images, 3);
+        assert_eq!(account.credit, 1_880_000);
+
+        let rewritten: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(rewritten["schema_version"], 1);
+        assert_eq!(rewritten["accounts"]["1234"]["total_cost"], 120_000);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn temp_data(name: &str) -> Data {
+        let path =
+            std::env::temp_dir().join(format!("hypnos-{}-{}.json", name, std::process::id()));
+        Data {
+            accounts: Arc::new(Mutex::new(Box::new(JsonStore::empty(&path)))),
+            path,
+            ..Default::default()
+        }
+    }
+
+    fn request(num: u8) -> ImageRequest {
+        ImageRequest::new("a cat".to_string(), num)
+    }
+
+    #[tokio::test]
+    async fn test_self_limit() {
+        let data = temp_data("self-limit");
+        let mut user = serenity::User::default();
+        user.id = serenity::UserId(5);
+        // 10 cents a month is two and a half images
+        set_self_limit(&data, &user, Some(10_000)).await.unwrap();
+        for _ in 0..2 {
+            let permitted = debit_for_request(&data, Payer::User(&user), &request(1))
+                .await
+                .unwrap();
+            assert_eq!(permitted, RequestPermitted::Yes);
+        }
+        let permitted = debit_for_request(&data, Payer::User(&user), &request(1))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::OverSelfLimit);
+        let account = get_account(&data, &user, None).await.unwrap();
+        assert!(account.credit > 0);
+        assert_eq!(account.self_limit.spent_in(Utc::now()), 8_000);
+
+        // come next month, it's a clean slate
+        let last_month = Utc::now() - chrono::Duration::days(40);
+        data.accounts
+            .lock()
+            .await
+            .put(&CostMap::from([(
+                user.id.0,
+                Account {
+                    self_limit: SelfLimit {
+                        month: last_month,
+                        ..account.self_limit.clone()
+                    },
+                    ..account
+                },
+            )]))
+            .unwrap();
+        let permitted = debit_for_request(&data, Payer::User(&user), &request(2))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::Yes);
+
+        // and lifting it lets them spend what they've got
+        set_self_limit(&data, &user, None).await.unwrap();
+        let permitted = debit_for_request(&data, Payer::User(&user), &request(2))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::Yes);
+        std::fs::remove_file(&data.path).unwrap();
+    }
+
+    #[test]
+    fn test_self_limit_counts_by_month() {
+        let at = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+        let mut limit = SelfLimit {
+            monthly: Some(5_000),
+            ..SelfLimit::default()
+        };
+        limit.record(4_000, at("2024-03-31T23:00:00Z"));
+        assert!(!limit.allows(2_000, at("2024-03-31T23:30:00Z")));
+        assert!(limit.allows(1_000, at("2024-03-31T23:30:00Z")));
+        assert!(limit.allows(5_000, at("2024-04-01T00:00:00Z")));
+        // refunding last month's debit doesn't come off this month's
+        let mut refunded = limit.clone();
+        refunded.refund(4_000, at("2024-04-01T00:00:00Z"));
+        assert_eq!(refunded, limit);
+        limit.record(2_000, at("2024-04-01T00:00:00Z"));
+        assert_eq!(limit.spent_in(at("2024-04-15T00:00:00Z")), 2_000);
+        limit.refund(500, at("2024-04-15T00:00:00Z"));
+        assert_eq!(limit.spent_in(at("2024-04-15T00:00:00Z")), 1_500);
+        assert!(SelfLimit::default().allows(i64::MAX, at("2024-04-01T00:00:00Z")));
+    }
+
+    #[tokio::test]
+    async fn test_automation_bypasses_user_limits() {
+        let data = temp_data("automation");
+        let user = serenity::User::default();
+        data.accounts
+            .lock()
+            .await
+            .put(&CostMap::from([(
+                user.id.0,
+                Account {
+                    credit: -1,
+                    ..Account::default_for_user(&user, DEFAULT_CREDIT)
+                },
+            )]))
+            .unwrap();
+
+        let permitted = debit_for_request(&data, Payer::User(&user), &request(1))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::No);
+
+        let permitted = debit_for_request(&data, Payer::Automation, &request(1))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::Yes);
+        let accounts = data.accounts.lock().await.all().unwrap();
+        assert_eq!(accounts[&AUTOMATION_ACCOUNT_ID].images, 1);
+        assert_eq!(accounts[&user.id.0].credit, -1);
+        std::fs::remove_file(&data.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_requests_must_fit_in_the_credit() {
+        let data = temp_data("fits");
+        let user = serenity::User::default();
+        // one standard square image is 4 cents
+        data.accounts
+            .lock()
+            .await
+            .put(&CostMap::from([(
+                user.id.0,
+                Account {
+                    credit: 10_000,
+                    ..Account::default_for_user(&user, DEFAULT_CREDIT)
+                },
+            )]))
+            .unwrap();
+        let credit = |data: &Data| {
+            let store = data.accounts.try_lock().unwrap();
+            store.get(user.id.0).unwrap().unwrap().credit
+        };
+
+        let permitted = debit_for_request(&data, Payer::User(&user), &request(1))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::Yes);
+        assert_eq!(credit(&data), 6_000);
+
+        // 8 cents would leave it at -2 cents
+        let permitted = debit_for_request(&data, Payer::User(&user), &request(2))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::No);
+        assert_eq!(credit(&data), 6_000);
+
+        data.accounts
+            .lock()
+            .await
+            .put(&CostMap::from([(
+                user.id.0,
+                Account {
+                    credit: 8_000,
+                    ..Account::default_for_user(&user, DEFAULT_CREDIT)
+                },
+            )]))
+            .unwrap();
+        let permitted = debit_for_request(&data, Payer::User(&user), &request(2))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::Yes);
+        assert_eq!(credit(&data), 0);
+        std::fs::remove_file(&data.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_add_credit() {
+        let data = temp_data("add-credit");
+        let mut user = serenity::User::default();
+        user.id = serenity::UserId(42);
+        // new accounts start with the usual 20 bucks
+        let account = add_credit(&data, &user, 500_000).await.unwrap();
+        assert_eq!(account.credit, 2_500_000);
+        let account = add_credit(&data, &user, 1_000).await.unwrap();
+        assert_eq!(account.credit, 2_501_000);
+
+        let reopened = JsonStore::open(&data.path).unwrap();
+        assert_eq!(reopened.get(42).unwrap(), Some(account));
+        std::fs::remove_file(&data.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_round_trip() {
+        let data = temp_data("snapshot");
+        let mut user = serenity::User::default();
+        user.id = serenity::UserId(42);
+        add_credit(&data, &user, 500_000).await.unwrap();
+        let permitted = debit_for_request(&data, Payer::Automation, &request(2))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::Yes);
+        let roll = crate::dice::DiceRollRequest::parse("2d6")
+            .unwrap()
+            .roll(&mut rand::thread_rng());
+        data.history
+            .lock()
+            .await
+            .record(42, crate::history::RollRecord::new("2d6", &roll));
+        data.costs.lock().await.record(crate::costs::CostRecord {
+            timestamp: chrono::Utc::now(),
+            user: "someone".to_string(),
+            prompt: "a cat".to_string(),
+            images: 2,
+            cost_millicents: 8_000,
+        });
+        let snapshot = data.snapshot().await.unwrap();
+        assert_eq!(snapshot.accounts.len(), 2);
+
+        // it has to survive being written out somewhere in between
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let restored = temp_data("restored");
+        restored
+            .restore(serde_json::from_str(&json).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(restored.snapshot().await.unwrap(), snapshot);
+        std::fs::remove_file(&data.path).unwrap();
+        std::fs::remove_file(&restored.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_guild_pool() {
+        let data = temp_data("guild-pool");
+        let users: Vec<serenity::User> = (1..=2)
+            .map(|id| {
+                let mut user = serenity::User::default();
+                user.id = serenity::UserId(id);
+                user
+            })
+            .collect();
+        let guild = Some(serenity::GuildId(1000));
+        for user in users.iter() {
+            let payer = Payer::scoped(user, guild, true);
+            let permitted = debit_for_request(&data, payer, &request(1)).await.unwrap();
+            assert_eq!(permitted, RequestPermitted::Yes);
+        }
+        let accounts = data.accounts.lock().await.all().unwrap();
+        // both of them spent from the one pool, and neither has an account of their own
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[&1000].images, 2);
+        assert_eq!(accounts[&1000].credit, 2_000_000 - 8_000);
+        assert_eq!(accounts[&1000].user, "guild 1000");
+
+        // the pool runs dry for everyone at once
+        data.accounts
+            .lock()
+            .await
+            .put(&CostMap::from([(
+                1000,
+                Account {
+                    credit: 3_999,
+                    ..accounts[&1000].clone()
+                },
+            )]))
+            .unwrap();
+        let permitted =
+            debit_for_request(&data, Payer::scoped(&users[1], guild, true), &request(1))
+                .await
+                .unwrap();
+        assert_eq!(permitted, RequestPermitted::No);
+        std::fs::remove_file(&data.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_self_limit_in_a_guild_pool() {
+        let data = temp_data("self-limit-pool");
+        let users: Vec<serenity::User> = (1..=2)
+            .map(|id| {
+                let mut user = serenity::User::default();
+                user.id = serenity::UserId(id);
+                user
+            })
+            .collect();
+        let guild = Some(serenity::GuildId(1000));
+        // one image's worth a month, for the first of them only
+        set_self_limit(&data, &users[0], Some(4_000)).await.unwrap();
+        let one = request(1);
+        let debit = |user| debit_for_request(&data, Payer::scoped(user, guild, true), &one);
+        assert_eq!(debit(&users[0]).await.unwrap(), RequestPermitted::Yes);
+        assert_eq!(
+            debit(&users[0]).await.unwrap(),
+            RequestPermitted::OverSelfLimit
+        );
+        // the pool's still got plenty for everyone else
+        assert_eq!(debit(&users[1]).await.unwrap(), RequestPermitted::Yes);
+        assert_eq!(debit(&users[1]).await.unwrap(), RequestPermitted::Yes);
+
+        let accounts = data.accounts.lock().await.all().unwrap();
+        assert_eq!(accounts[&1000].images, 3);
+        // it's their own account that keeps count, its credit isn't touched
+        assert_eq!(accounts[&1].self_limit.spent_in(Utc::now()), 4_000);
+        assert_eq!(accounts[&1].credit, DEFAULT_CREDIT);
+        assert_eq!(accounts[&1000].self_limit, SelfLimit::default());
+        assert!(!accounts.contains_key(&2));
+
+        // and a refund from the pool frees it up again
+        refund_for_request(&data, Payer::scoped(&users[0], guild, true), &request(1))
+            .await
+            .unwrap();
+        assert_eq!(debit(&users[0]).await.unwrap(), RequestPermitted::Yes);
+        std::fs::remove_file(&data.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_guild_pool_falls_back_to_the_user_in_dms() {
+        let data = temp_data("guild-pool-dm");
+        let mut user = serenity::User::default();
+        user.id = serenity::UserId(7);
+        assert!(matches!(Payer::scoped(&user, None, true), Payer::User(_)));
+        // and with pooling off, servers are per user too
+        assert!(matches!(
+            Payer::scoped(&user, Some(serenity::GuildId(1000)), false),
+            Payer::User(_)
+        ));
+
+        let permitted = debit_for_request(&data, Payer::scoped(&user, None, true), &request(1))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::Yes);
+        let accounts = data.accounts.lock().await.all().unwrap();
+        assert_eq!(accounts.keys().collect::<Vec<_>>(), vec![&7]);
+        assert_eq!(accounts[&7].images, 1);
+        std::fs::remove_file(&data.path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_top_accounts() {
+        let data = temp_data("leaderboard");
+        let mut accounts = CostMap::new();
+        for (id, images, total_cost) in [
+            (1, 4, 16_000),
+            (2, 10, 30_000),
+            (3, 2, 64_000),
+            (4, 10, 40_000),
+            (5, 0, 0),
+            (AUTOMATION_ACCOUNT_ID, 500, 2_000_000),
+        ] {
+            accounts.insert(
+                id,
+                Account {
+                    images,
+                    total_cost,
+                    ..Account::named(id.to_string(), DEFAULT_CREDIT)
+                },
+            );
+        }
+        data.accounts.lock().await.put(&accounts).unwrap();
+
+        let ids = |top: Vec<(u64, Account)>| top.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        let top = top_accounts(&data, 3, Ranking::Images).await.unwrap();
+        assert_eq!(ids(top), vec![2, 4, 1]);
+        let top = top_accounts(&data, 2, Ranking::TotalCost).await.unwrap();
+        assert_eq!(ids(top), vec![3, 4]);
+        let top = top_accounts(&data, 10, Ranking::Images).await.unwrap();
+        assert_eq!(ids(top), vec![2, 4, 1, 3, 5]);
+        assert!(top_accounts(&data, 0, Ranking::Images)
+            .await
+            .unwrap()
+            .is_empty());
+        std::fs::remove_file(&data.path).unwrap();
+    }
+
+    #[test]
+    fn test_transaction_log() {
+        let path =
+            std::env::temp_dir().join(format!("hypnos-transactions-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let entries = [
+            Transaction {
+                timestamp: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+                user_id: u64::MAX,
+                action: TransactionAction::Debit,
+                cost_millicents: 16_000,
+                balance_millicents: 1_984_000,
+            },
+            Transaction {
+                timestamp: DateTime::from_timestamp(1_700_000_060, 0).unwrap(),
+                user_id: 42,
+                action: TransactionAction::Grant,
+                cost_millicents: -500_000,
+                balance_millicents: -1,
+            },
+        ];
+        // the file doesn't exist until the first entry
+        for entry in entries.iter() {
+            log_transaction(&path, entry).unwrap();
+        }
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""action":"debit""#), "{}", lines[0]);
+        let read: Vec<Transaction> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(read, entries);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_debits_are_logged() {
+        let log =
+            std::env::temp_dir().join(format!("hypnos-debit-log-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        let data = Data {
+            transaction_log: Some(log.clone()),
+            ..temp_data("debit-log")
+        };
+        let user = serenity::User::default();
+        let permitted = debit_for_request(&data, Payer::User(&user), &request(2))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::Yes);
+        let entry: Transaction =
+            serde_json::from_str(std::fs::read_to_string(&log).unwrap().trim()).unwrap();
+        assert_eq!(entry.action, TransactionAction::Debit);
+        assert_eq!(entry.user_id, user.id.0);
+        assert_eq!(entry.cost_millicents, 8_000);
+        assert_eq!(entry.balance_millicents, DEFAULT_CREDIT - 8_000);
+        std::fs::remove_file(&data.path).unwrap();
+        std::fs::remove_file(&log).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refunds_undo_the_debit() {
+        let log =
+            std::env::temp_dir().join(format!("hypnos-refund-log-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&log);
+        let data = Data {
+            transaction_log: Some(log.clone()),
+            ..temp_data("refund")
+        };
+        let user = serenity::User::default();
+        let permitted = debit_for_request(&data, Payer::User(&user), &request(3))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::Yes);
+        let refunded = refund_for_request(&data, Payer::User(&user), &request(2))
+            .await
+            .unwrap();
+        assert_eq!(refunded, 8_000);
+        let account = data.accounts.lock().await.get(user.id.0).unwrap().unwrap();
+        assert_eq!(account.credit, DEFAULT_CREDIT - 4_000);
+        assert_eq!(account.total_cost, 4_000);
+        assert_eq!(account.images, 1);
+
+        let entries: Vec<Transaction> = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].action, TransactionAction::Refund);
+        assert_eq!(entries[1].cost_millicents, -8_000);
+        assert_eq!(entries[1].balance_millicents, DEFAULT_CREDIT - 4_000);
+        std::fs::remove_file(&data.path).unwrap();
+        std::fs::remove_file(&log).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_own_keys_skip_the_credit() {
+        let data = Data {
+            key_cipher: Some(KeyCipher::new(&[3; 32])),
+            ..temp_data("own-key")
+        };
+        let keys = data.path.with_file_name(USER_KEYS_FILE);
+        let mut user = serenity::User::default();
+        user.id = serenity::UserId(42);
+        let key = ApiKey::for_test("sk-test-key-for-user-42");
+
+        // nobody's brought a key yet, so it's the shared one and their credit
+        let billing = bill_request(&data, Payer::User(&user), user.id.0, &request(1))
+            .await
+            .unwrap();
+        assert_eq!(billing, Billing::Credit);
+        let credit = |data: &Data| {
+            let store = data.accounts.try_lock().unwrap();
+            store.get(42).unwrap().unwrap().credit
+        };
+        assert_eq!(credit(&data), DEFAULT_CREDIT - 4_000);
+
+        data.set_user_key(42, Some(&key)).await.unwrap();
+        assert!(!std::fs::read_to_string(&keys).unwrap().contains("sk-test"));
+        let billing = bill_request(&data, Payer::User(&user), user.id.0, &request(5))
+            .await
+            .unwrap();
+        assert_eq!(billing, Billing::OwnKey(key.clone()));
+        assert_eq!(credit(&data), DEFAULT_CREDIT - 4_000);
+
+        // automation never gets someone's key
+        let billing = bill_request(&data, Payer::Automation, user.id.0, &request(1))
+            .await
+            .unwrap();
+        assert_eq!(billing, Billing::Credit);
+
+        data.set_user_key(42, None).await.unwrap();
+        assert_eq!(data.user_key(42).await, None);
+        // without a secret there's nothing to keep keys with
+        assert!(temp_data("no-secret")
+            .set_user_key(42, Some(&key))
+            .await
+            .is_err());
+        std::fs::remove_file(&data.path).unwrap();
+        std::fs::remove_file(&keys).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unsaved_keys_are_not_used() {
+        let dir = std::env::temp_dir().join(format!("hypnos-no-such-dir-{}", std::process::id()));
+        let data = Data {
+            key_cipher: Some(KeyCipher::new(&[3; 32])),
+            path: dir.join(DATA_FILE),
+            ..temp_data("unsaved-key")
+        };
+        let key = ApiKey::for_test("sk-test-key-for-user-42");
+        assert!(data.set_user_key(42, Some(&key)).await.is_err());
+        assert_eq!(data.user_key(42).await, None);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_grant_to_each() {
+        let users: Vec<serenity::User> = (1..=3)
+            .map(|id| {
+                let mut user = serenity::User::default();
+                user.id = serenity::UserId(id);
+                user
+            })
+            .collect();
+        let mut accounts = CostMap::new();
+        accounts.insert(
+            2,
+            Account {
+                credit: -500,
+                ..Account::named("broke".to_string(), DEFAULT_CREDIT)
+            },
+        );
+        assert_eq!(
+            grant_to_each(&mut accounts, &users, 1_000_000, DEFAULT_CREDIT),
+            3
+        );
+        assert_eq!(accounts.len(), 3);
+        // new accounts start with the usual credit, and get the grant on top
+        assert_eq!(accounts[&1].credit, 3_000_000);
+        assert_eq!(accounts[&2].credit, 999_500);
+        assert_eq!(accounts[&2].user, "broke");
+        assert_eq!(
+            grant_to_each(&mut accounts, &[], 1_000_000, DEFAULT_CREDIT),
+            0
+        );
+    }
+
+    #[test]
+    fn test_grants_are_spent_oldest_first() {
+        let mut account = Account::named("someone".to_string(), 1_000);
+        account.grant(5_000, date("2024-01-01T00:00:00Z"));
+        account.grant(3_000, date("2024-02-01T00:00:00Z"));
+        assert_eq!(account.credit, 9_000);
+        let unspent = |account: &Account| -> Vec<i64> {
+            account.grants.iter().map(|grant| grant.unspent).collect()
+        };
+        // what the account started with goes first
+        account.account_for_request(&request(1), 0.25);
+        assert_eq!(account.credit, 8_000);
+        assert_eq!(unspent(&account), vec![5_000, 3_000]);
+        // then the oldest grant, and it's gone once it's used up
+        account.account_for_request(&request(1), 1.0);
+        assert_eq!(unspent(&account), vec![1_000, 3_000]);
+        account.account_for_request(&request(1), 0.5);
+        assert_eq!(unspent(&account), vec![2_000]);
+        assert_eq!(
+            account.grants[0].credit_granted_at,
+            date("2024-02-01T00:00:00Z")
+        );
+        // an overdraft leaves nothing granted
+        account.account_for_request(&request(1), 1.0);
+        assert_eq!(account.credit, -2_000);
+        assert!(account.grants.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_old_unspent_grants_expire() {
+        let data = Data {
+            credit_expiry: parse_credit_expiry(Some("90")),
+            ..temp_data("expiry")
+        };
+        let mut old = Account::named("old".to_string(), 1_000);
+        old.grant(5_000, date("2024-01-01T00:00:00Z"));
+        old.grant(3_000, date("2024-04-01T00:00:00Z"));
+        old.account_for_request(&request(1), 0.5);
+        let untouched = Account::named("untouched".to_string(), 1_000);
+        data.accounts
+            .lock()
+            .await
+            .put(&CostMap::from([(1, old), (2, untouched.clone())]))
+            .unwrap();
+
+        // the January grant is past 90 days, April's isn't
+        let expired = expire_credit(&data, date("2024-04-15T00:00:00Z"))
+            .await
+            .unwrap();
+        assert_eq!(expired, 4_000);
+        let store = data.accounts.lock().await;
+        let old = store.get(1).unwrap().unwrap();
+        assert_eq!(old.credit, 3_000);
+        assert_eq!(old.grants.len(), 1);
+        assert_eq!(store.get(2).unwrap(), Some(untouched));
+        drop(store);
+        assert_eq!(
+            expire_credit(&data, date("2024-04-15T00:00:00Z"))
+                .await
+                .unwrap(),
+            0
+        );
+
+        assert_eq!(parse_credit_expiry(Some("0")), None);
+        assert_eq!(parse_credit_expiry(Some("soon")), None);
+        assert_eq!(parse_credit_expiry(None), None);
+        assert_eq!(temp_data("no-expiry").credit_expiry, None);
+        std::fs::remove_file(&data.path).unwrap();
+    }
+
+    #[test]
+    fn test_liability() {
+        let mut accounts = CostMap::new();
+        for (id, credit) in [(1, 2_000_000), (2, -123_456), (3, 0), (4, 500), (5, -1)] {
+            accounts.insert(
+                id,
+                Account {
+                    credit,
+                    ..Account::named(id.to_string(), DEFAULT_CREDIT)
+                },
+            );
+        }
+        assert_eq!(
+            sum_liability(&accounts),
+            Liability {
+                prepaid: 2_000_500,
+                overdrawn: 123_457,
+            }
+        );
+        assert_eq!(
+            sum_liability(&CostMap::new()),
+            Liability {
+                prepaid: 0,
+                overdrawn: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_cost_multiplier() {
+        let mut account = Account::named("someone".to_string(), DEFAULT_CREDIT);
+        let credit = account.credit;
+        // 2 standard square images are 8 cents
+        account.account_for_request(&request(2), 1.5);
+        assert_eq!(account.credit, credit - 12_000);
+        assert_eq!(account.total_cost, 12_000);
+        assert_eq!(account.raw_cost, 8_000);
+
+        account.account_for_request(&request(2), 1.0);
+        assert_eq!(account.total_cost, 20_000);
+        assert_eq!(account.raw_cost, 16_000);
+    }
+
+    #[test]
+    fn test_dollars() {
+        let account = Account {
+            credit: -123_456,
+            total_cost: 2_000_000,
+            ..Account::named("someone".to_string(), DEFAULT_CREDIT)
+        };
+        assert_eq!(account.total_cost_dollars(), 20.0);
+        assert_eq!(account.credit_dollars(), -1.23456);
+        assert_eq!(
+            Account::named("new".to_string(), DEFAULT_CREDIT).credit_dollars(),
+            20.0
+        );
+        assert_eq!(millicents_to_dollars(1_000), 0.01);
+    }
+
+    #[test]
+    fn test_default_credit() {
+        assert_eq!(parse_default_credit(None), 2_000_000);
+        assert_eq!(parse_default_credit(Some("lots")), 2_000_000);
+        assert_eq!(parse_default_credit(Some("-500")), 2_000_000);
+        assert_eq!(parse_default_credit(Some(" 500 ")), 500_000);
+        assert_eq!(parse_default_credit(Some("0")), 0);
+    }
+
+    #[tokio::test]
+    async fn test_new_accounts_get_the_default_credit() {
+        let data = Data {
+            default_credit: parse_default_credit(Some("150")),
+            ..temp_data("default-credit")
+        };
+        let user = serenity::User::default();
+        assert_eq!(
+            get_account(&data, &user, None).await.unwrap().credit,
+            150_000
+        );
+        let permitted = debit_for_request(&data, Payer::User(&user), &request(1))
+            .await
+            .unwrap();
+        assert_eq!(permitted, RequestPermitted::Yes);
+        assert_eq!(
+            get_account(&data, &user, None).await.unwrap().credit,
+            146_000
+        );
+        std::fs::remove_file(&data.path).unwrap();
+    }
+
+    fn date(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().into()
+    }
+
+    const MONTHLY: MonthlyCredit = MonthlyCredit {
+        allowance: 1_000_000,
+        rollover_cap: 250_000,
+    };
+
+    #[test]
+    fn test_monthly_reset_across_a_month_boundary() {
+        let mut account = Account {
+            credit: 400_000,
+            last_reset: date("2024-01-01T00:05:00Z"),
+            ..Account::named("someone".to_string(), DEFAULT_CREDIT)
+        };
+        let now = date("2024-02-01T00:00:00Z");
+        assert!(account.maybe_reset(now, MONTHLY));
+        // the allowance, plus as much of the leftover as rolls over
+        assert_eq!(account.credit, 1_250_000);
+        assert_eq!(account.last_reset, now);
+        // and only once
+        assert!(!account.maybe_reset(date("2024-02-20T00:00:00Z"), MONTHLY));
+        assert_eq!(account.credit, 1_250_000);
+
+        // overdrafts are forgiven, and the same month in another year still counts
+        account.credit = -300_000;
+        assert!(account.maybe_reset(date("2025-02-03T00:00:00Z"), MONTHLY));
+        assert_eq!(account.credit, 1_000_000);
+    }
+
+    #[test]
+    fn test_monthly_reset_keeps_grants() {
+        // granted to someone who hadn't been around yet this month
+        let mut account = Account {
+            credit: 400_000,
+            last_reset: date("2024-01-01T00:05:00Z"),
+            ..Account::named("someone".to_string(), DEFAULT_CREDIT)
+        };
+        account.grant(500_000, date("2024-02-10T00:00:00Z"));
+        assert!(account.maybe_reset(date("2024-02-11T00:00:00Z"), MONTHLY));
+        // the allowance, the most that rolls over, and all of the grant
+        assert_eq!(account.credit, 1_750_000);
+        assert_eq!(account.grants[0].unspent, 500_000);
+
+        // half spent, what's left of it still carries over come next month
+        account.credit = 250_000;
+        account.settle_grants();
+        assert!(account.maybe_reset(date("2024-03-01T00:00:00Z"), MONTHLY));
+        assert_eq!(account.credit, 1_250_000);
+        assert_eq!(account.grants[0].unspent, 250_000);
+    }
+
+    #[test]
+    fn test_no_monthly_reset_within_the_month() {
+        let mut account = Account {
+            credit: 12_000,
+            last_reset: date("2024-03-01T09:00:00Z"),
+            ..Account::named("someone".to_string(), DEFAULT_CREDIT)
+        };
+        assert!(!account.maybe_reset(date("2024-03-31T23:59:59Z"), MONTHLY));
+        assert_eq!(account.credit, 12_000);
+        assert_eq!(account.last_reset, date("2024-03-01T09:00:00Z"));
+    }
+
+    #[test]
+    fn test_accounts_from_before_monthly_credit() {
+        let mut account: Account = serde_json::from_str(
+            r#"{"user":"someone#0001","images":3,"credit":5000,"total_cost":120000}"#,
+        )
+        .unwrap();
+        assert_eq!(account.last_reset, DateTime::<Utc>::default());
+        assert!(account.maybe_reset(date("2024-03-10T00:00:00Z"), MONTHLY));
+        assert_eq!(account.credit, 1_005_000);
+
+        assert_eq!(parse_monthly_credit(None, Some("100")), None);
+        assert_eq!(parse_monthly_credit(Some("-5"), None), None);
+        assert_eq!(
+            parse_monthly_credit(Some("1000"), Some("bogus")),
+            Some(MonthlyCredit {
+                allowance: 1_000_000,
+                rollover_cap: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_cost_serializes_as_millicents() {
+        let v = serde_json::to_value(Cost::cents(4)).unwrap();
+        assert_eq!(v["millicents"], 4000);
+    }
+
+    #[tokio::test]
+    async fn test_store_is_used_off_the_async_thread() {
+        let data = Data::default();
+        let here = std::thread::current().id();
+        let there = data
+            .with_accounts(|_| Ok(std::thread::current().id()))
+            .await
+            .unwrap();
+        assert_ne!(here, there);
+    }
 }
@@ -1,37 +1,710 @@
+use poise::serenity_prelude as serenity;
 use rand::Rng;
+use std::collections::BTreeMap;
 use std::fmt::Write;
 
+use crate::dalle::{MAX_EMBED_FIELD_LEN, MAX_EMBED_LEN};
 use crate::data::{Context, Error};
+use crate::dice_common::{say_chunked, too_many_to_show, Overflow, ParseError, MAX_MESSAGE_LEN};
+use crate::history::{RollHistory, RollRecord};
+use crate::preferences::Flavor;
+use crate::settings::{flavor_for, with_persona};
 
 #[poise::command(slash_command, prefix_command)]
 pub async fn roll(
     ctx: Context<'_>,
-    #[description = "The dice you want to roll, like: `d4` or `3d6 1d10` or even just `6 8 10`"]
+    #[description = "The dice you want to roll, like: `3d6 1d10` or `2d6 + 1d4 - 1`. Add `# why` to label it"]
     dice: String,
+    #[description = "Show exactly what's being rolled before rolling it"] show_pool: Option<bool>,
+    #[description = "Just trying it out, don't keep it in your history"] practice: Option<bool>,
+    #[description = "Don't roll, just tell me the min, max and average"] stats: Option<bool>,
+    #[description = "Only you get to see the result"] private: Option<bool>,
 ) -> Result<(), Error> {
-    let response = get_response(&dice);
-    ctx.say(response).await?;
+    let practice = practice.unwrap_or(false);
+    let (ephemeral, private_note) = visibility(
+        private.unwrap_or(false),
+        matches!(ctx, poise::Context::Application(_)),
+    );
+    let (dice, comment) = split_comment(&dice);
+    let request = match split_repeats(dice)
+        .and_then(|(times, dice)| Ok((times, dice, DiceRollRequest::parse(dice)?)))
+    {
+        Err(err) => {
+            let flavor = flavor_for(ctx.data(), ctx.author().id.0).await;
+            let response =
+                with_persona(ctx.data(), ctx.author().id.0, "roll", err.message(flavor)).await;
+            ctx.send(|m| m.content(response).ephemeral(ephemeral))
+                .await?;
+            return Ok(());
+        }
+        Ok(request) => request,
+    };
+    let (times, dice, request) = request;
+    if stats.unwrap_or(false) {
+        let response = request.stats().describe(dice);
+        let response = with_persona(ctx.data(), ctx.author().id.0, "roll", response).await;
+        say_chunked(ctx, &response, ephemeral).await?;
+        return Ok(());
+    }
+    if show_pool.unwrap_or(false) {
+        let pool = format!("Rolling {}...", request.describe());
+        let pool = with_persona(ctx.data(), ctx.author().id.0, "roll", pool).await;
+        ctx.send(|m| m.content(pool).ephemeral(ephemeral)).await?;
+    }
+    let rolls: Vec<RollResult> = (0..times)
+        .map(|_| request.roll(&mut rand::thread_rng()))
+        .collect();
+    {
+        let mut history = ctx.data().history.lock().await;
+        for roll in rolls.iter() {
+            record_roll(&mut history, ctx.author().id.0, dice, roll, practice);
+        }
+    }
+    if let (Some(roll), false) = (rolls.last(), practice) {
+        ctx.data().last_rolls.lock().await.insert(
+            ctx.author().id.0,
+            LastRoll {
+                dice: dice.trim().to_string(),
+                request,
+                roll: roll.clone(),
+            },
+        );
+    }
+    let reveal_delay = reveal_delay(ctx).await;
+    if let [roll] = rolls.as_slice() {
+        let title = format!("Rolling {}", dice.trim());
+        if let Some(fields) = embed_fields(&title, comment, roll) {
+            // the persona prefix and banner (if any) go above the embed
+            let content = with_persona(
+                ctx.data(),
+                ctx.author().id.0,
+                "roll",
+                private_note.unwrap_or(""),
+            )
+            .await;
+            let fill = |m: &mut poise::CreateReply<'_>| {
+                m.ephemeral(ephemeral);
+                // an edit has to clear out the last step of the reveal
+                m.content(content.trim());
+                m.embed(|e| {
+                    e.title(title);
+                    if let Some(comment) = comment {
+                        e.description(format!("**{}**", comment));
+                    }
+                    for (name, value) in fields {
+                        e.field(name, value, false);
+                    }
+                    if let Some(colour) = embed_colour(roll) {
+                        e.colour(colour);
+                    }
+                    if practice {
+                        e.footer(|f| f.text("practice roll, not recorded"));
+                    }
+                    e
+                });
+            };
+            match reveal_delay {
+                Some(delay) => {
+                    let reply = reveal(ctx, reveal_steps(dice, roll), delay, ephemeral).await?;
+                    reply
+                        .edit(ctx, |m| {
+                            fill(m);
+                            m
+                        })
+                        .await?;
+                }
+                None => {
+                    ctx.send(|m| {
+                        fill(m);
+                        m
+                    })
+                    .await?;
+                }
+            }
+            return Ok(());
+        }
+    }
+    let overflow = ctx.data().settings.lock().await.roll_overflow();
+    let flavor = flavor_for(ctx.data(), ctx.author().id.0).await;
+    let reply = get_reply(dice, comment, &rolls, overflow, flavor);
+    let mut response = reply.content;
+    if practice {
+        response += "\n\n(practice roll, not recorded)";
+    }
+    if let Some(note) = private_note {
+        response += "\n\n";
+        response += note;
+    }
+    let response = with_persona(ctx.data(), ctx.author().id.0, "roll", response).await;
+    match (rolls.as_slice(), reveal_delay) {
+        // a result that needs more than one message just gets posted
+        ([roll], Some(delay)) if response.chars().count() <= MAX_MESSAGE_LEN => {
+            let steps = reveal_steps(dice, roll);
+            reveal(ctx, steps, delay, ephemeral)
+                .await?
+                .edit(ctx, |m| m.content(response))
+                .await?;
+        }
+        _ => say_chunked(ctx, &response, ephemeral).await?,
+    }
+    if let Some((filename, contents)) = reply.attachment {
+        ctx.send(|m| {
+            m.attachment(serenity::AttachmentType::Bytes {
+                data: std::borrow::Cow::Owned(contents.into_bytes()),
+                filename,
+            })
+            .ephemeral(ephemeral)
+        })
+        .await?;
+    }
     Ok(())
 }
 
-fn get_response(dice: &String) -> String {
-    let roll = DiceRollRequest::parse(&dice);
-    let roll = match roll {
-        Err(err) => {
-            return err;
+// Whether to send a roll as ephemeral, and what to tell the roller if they asked for
+// privacy and can't have it. Only slash commands can be ephemeral, `!roll` has to answer
+// in the open.
+fn visibility(private: bool, slash_command: bool) -> (bool, Option<&'static str>) {
+    match (private, slash_command) {
+        (false, _) => (false, None),
+        (true, true) => (true, None),
+        (true, false) => (
+            false,
+            Some("(only /roll can keep a roll private, so everyone can see this one)"),
+        ),
+    }
+}
+
+// How long to pause between each step of building up to a result, if at all. Terse users
+// just want the result.
+async fn reveal_delay(ctx: Context<'_>) -> Option<std::time::Duration> {
+    let delay = ctx.data().settings.lock().await.reveal_delay()?;
+    match crate::settings::flavor_for(ctx.data(), ctx.author().id.0).await {
+        Flavor::Terse => None,
+        Flavor::Flavorful => Some(delay),
+    }
+}
+
+// What the message says on the way to the result: the roll getting started, then any
+// glitches. Whatever the result is replaces the last one.
+fn reveal_steps(dice: &str, roll: &RollResult) -> Vec<String> {
+    let rolling = format!("Rolling {}...", dice.trim());
+    let mut steps = vec![rolling.clone()];
+    // arithmetic and Fate rolls don't glitch, they just add up
+    if roll.expression.is_some() || roll.fate_total().is_some() {
+        return steps;
+    }
+    if roll.is_botch() {
+        steps.push(format!("{} oh no.", rolling));
+        return steps;
+    }
+    match roll.rolled_die.iter().filter(|r| r.is_glitch()).count() {
+        0 => {}
+        1 => steps.push(format!("{} a glitch!", rolling)),
+        n => steps.push(format!("{} {} glitches!", rolling, n)),
+    }
+    steps
+}
+
+// Posts the first step, then edits in the rest, pausing before each one and before
+// handing back the message for the result to go in.
+async fn reveal<'a>(
+    ctx: Context<'a>,
+    steps: Vec<String>,
+    delay: std::time::Duration,
+    ephemeral: bool,
+) -> Result<poise::ReplyHandle<'a>, Error> {
+    let mut reply = None;
+    for step in steps {
+        let step = with_persona(ctx.data(), ctx.author().id.0, "roll", step).await;
+        match &reply {
+            None => reply = Some(ctx.send(|m| m.content(step).ephemeral(ephemeral)).await?),
+            Some(reply) => reply.edit(ctx, |m| m.content(step)).await?,
+        }
+        tokio::time::sleep(delay).await;
+    }
+    reply.ok_or_else(|| "nothing to reveal".into())
+}
+
+// Practice rolls are for trying out an expression, so they don't count for anything.
+fn record_roll(
+    history: &mut RollHistory,
+    user: u64,
+    dice: &str,
+    roll: &RollResult,
+    practice: bool,
+) {
+    if !practice {
+        history.record(user, RollRecord::new(dice, roll));
+    }
+}
+
+// Someone's most recent /roll, all of it, so /reroll_glitches has something to work with.
+// Like the history this only lives in memory.
+pub(crate) struct LastRoll {
+    dice: String,
+    request: DiceRollRequest,
+    roll: RollResult,
+}
+
+// Rolls just the dice that glitched in your last roll again, keeping the rest as they were.
+#[poise::command(slash_command, prefix_command)]
+pub async fn reroll_glitches(ctx: Context<'_>) -> Result<(), Error> {
+    let user = ctx.author().id.0;
+    let rerolled = {
+        let mut last_rolls = ctx.data().last_rolls.lock().await;
+        match last_rolls.get_mut(&user) {
+            None => Err("You haven't rolled anything since I last woke up.".to_string()),
+            Some(last) => match last
+                .request
+                .reroll_glitches(&last.roll, &mut rand::thread_rng())
+            {
+                None => Err(format!(
+                    "Nothing glitched in your last roll ({}).",
+                    last.dice
+                )),
+                Some(roll) => {
+                    // so it can be rerolled again if it glitches again
+                    last.roll = roll.clone();
+                    Ok((last.dice.clone(), roll))
+                }
+            },
         }
-        Ok(roll) => roll,
     };
-    let mut roll = roll.roll();
-    let resp = format!(
-        "Rolling {}\n\nResult: {}",
+    let response = match rerolled {
+        Err(response) => response,
+        Ok((dice, roll)) => {
+            ctx.data()
+                .history
+                .lock()
+                .await
+                .record(user, RollRecord::new(&dice, &roll));
+            full_response(&format!("{}, rerolling glitches", dice), None, &roll)
+        }
+    };
+    let response = with_persona(ctx.data(), user, "roll", response).await;
+    say_chunked(ctx, &response, false).await?;
+    Ok(())
+}
+
+#[poise::command(slash_command, prefix_command)]
+pub async fn contest(
+    ctx: Context<'_>,
+    #[description = "Two pools separated by a |, like: `3d6 | 2d8 1d10`"]
+    #[rest]
+    pools: String,
+) -> Result<(), Error> {
+    let flavor = flavor_for(ctx.data(), ctx.author().id.0).await;
+    let response = get_contest_response(&pools, flavor);
+    say_chunked(
+        ctx,
+        &with_persona(ctx.data(), ctx.author().id.0, "contest", response).await,
+        false,
+    )
+    .await?;
+    Ok(())
+}
+
+fn get_contest_response(pools: &str, flavor: Flavor) -> String {
+    let (first, second) = match pools.split_once('|') {
+        Some((first, second)) if !second.contains('|') => (first.trim(), second.trim()),
+        _ => {
+            return "Give me exactly two pools separated by a |, like: `3d6 | 2d8 1d10`".to_string()
+        }
+    };
+    let (first_request, second_request) = match (
+        DiceRollRequest::parse(first),
+        DiceRollRequest::parse(second),
+    ) {
+        (Err(err), _) | (_, Err(err)) => return err.message(flavor),
+        (Ok(first), Ok(second)) => (first, second),
+    };
+    let mut rng = rand::thread_rng();
+    let first_roll = first_request.roll(&mut rng);
+    let second_roll = second_request.roll(&mut rng);
+    let outcome = match decide_contest(
+        first_roll.get_highest_total(),
+        second_roll.get_highest_total(),
+    ) {
+        ContestOutcome::FirstWins => format!("**{} wins!**", first),
+        ContestOutcome::SecondWins => format!("**{} wins!**", second),
+        ContestOutcome::Tie => "**It's a tie!**".to_string(),
+        ContestOutcome::BothBotched => "**Both sides botched!** Nobody wins this one.".to_string(),
+    };
+    format!(
+        "{}: {}\n\n{}: {}\n\n{}",
+        first,
+        first_roll.to_discord_markdown().trim(),
+        second,
+        second_roll.to_discord_markdown().trim(),
+        outcome
+    )
+}
+
+// Rolls against a difficulty, which is either a number or a pool the bot rolls, like a
+// trap's 2d8.
+#[poise::command(slash_command, prefix_command)]
+pub async fn check(
+    ctx: Context<'_>,
+    #[description = "The dice you're rolling, like `3d8 1d10`"] dice: String,
+    #[description = "What you have to beat, a number like `11` or dice like `2d8`"]
+    #[rest]
+    difficulty: String,
+) -> Result<(), Error> {
+    let flavor = flavor_for(ctx.data(), ctx.author().id.0).await;
+    let response = get_check_response(&dice, &difficulty, flavor, &mut rand::thread_rng());
+    say_chunked(
+        ctx,
+        &with_persona(ctx.data(), ctx.author().id.0, "check", response).await,
+        false,
+    )
+    .await?;
+    Ok(())
+}
+
+fn get_check_response(dice: &str, difficulty: &str, flavor: Flavor, rng: &mut impl Rng) -> String {
+    let (dice, difficulty) = (dice.trim(), difficulty.trim());
+    let request = match DiceRollRequest::parse(dice) {
+        Err(err) => return err.message(flavor),
+        Ok(request) => request,
+    };
+    // a bare number is a fixed difficulty, anything else is dice for the bot to roll
+    let (difficulty_total, difficulty_line) = match difficulty.parse::<u64>() {
+        Ok(number) => (Some(number), format!("Difficulty: {}", number)),
+        Err(_) => match DiceRollRequest::parse(difficulty) {
+            Err(err) => return err.message(flavor),
+            Ok(difficulty_request) => {
+                let roll = difficulty_request.roll(rng);
+                let total = match roll.get_highest_total() {
+                    CortexResult::Botch => None,
+                    CortexResult::Result { total, .. } => Some(total),
+                };
+                (
+                    total,
+                    format!(
+                        "Difficulty {}: {}",
+                        difficulty,
+                        roll.to_discord_markdown().trim()
+                    ),
+                )
+            }
+        },
+    };
+    let roll = request.roll(rng);
+    let outcome = match decide_check(roll.get_highest_total(), difficulty_total) {
+        CheckOutcome::Success { margin } => format!("**Success, by {}!**", margin),
+        CheckOutcome::Failure { margin } => format!("**Failed, by {}.**", margin),
+        CheckOutcome::Botched => "**Botched!** That's a failure no matter what.".to_string(),
+        CheckOutcome::DifficultyBotched => {
+            "**The difficulty botched!** That's a success, no matter what you rolled.".to_string()
+        }
+    };
+    format!(
+        "{}: {}\n\n{}\n\n{}",
         dice,
+        roll.to_discord_markdown().trim(),
+        difficulty_line,
+        outcome
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CheckOutcome {
+    Success { margin: u64 },
+    // a tie is a failure by zero
+    Failure { margin: u64 },
+    Botched,
+    // the difficulty pool botched, so the roller gets it for free
+    DifficultyBotched,
+}
+
+// You have to beat the difficulty, so ties go against you. `difficulty` is None when the
+// difficulty pool botched. Your own botch fails even then.
+fn decide_check(roll: CortexResult, difficulty: Option<u64>) -> CheckOutcome {
+    match (roll, difficulty) {
+        (CortexResult::Botch, _) => CheckOutcome::Botched,
+        (_, None) => CheckOutcome::DifficultyBotched,
+        (CortexResult::Result { total, .. }, Some(difficulty)) if total > difficulty => {
+            CheckOutcome::Success {
+                margin: total - difficulty,
+            }
+        }
+        (CortexResult::Result { total, .. }, Some(difficulty)) => CheckOutcome::Failure {
+            margin: difficulty - total,
+        },
+    }
+}
+
+// Just the dice and what they add up to, for when you want a number rather than Cortex.
+#[poise::command(slash_command, prefix_command)]
+pub async fn fair(
+    ctx: Context<'_>,
+    #[description = "The dice to roll and add up, like `3d6` or `1d20 + 5`"]
+    #[rest]
+    dice: String,
+) -> Result<(), Error> {
+    let flavor = flavor_for(ctx.data(), ctx.author().id.0).await;
+    let response = get_fair_response(&dice, flavor, &mut rand::thread_rng());
+    say_chunked(
+        ctx,
+        &with_persona(ctx.data(), ctx.author().id.0, "fair", response).await,
+        false,
+    )
+    .await?;
+    Ok(())
+}
+
+fn get_fair_response(dice: &str, flavor: Flavor, rng: &mut impl Rng) -> String {
+    // nothing glitches, a 1 is just a 1
+    let roll = match DiceRollRequest::parse_glitching_on(dice, 0) {
+        Err(err) => return err.message(flavor),
+        Ok(request) => request.roll(rng),
+    };
+    let sum = roll
+        .fate_total()
+        .or_else(|| roll.arithmetic_total())
+        .unwrap_or(roll.sum() as i64);
+    format!(
+        "Rolling {}\n\n{}\n\nSum: {}",
+        dice.trim(),
+        roll.dice_markdown().trim(),
+        sum
+    )
+}
+
+// Rolls once for a group success and hands the same total and effect to everyone.
+#[poise::command(slash_command, prefix_command)]
+pub async fn share(
+    ctx: Context<'_>,
+    #[description = "The dice to roll for the group, like `3d8 1d10`"] dice: String,
+    #[description = "Who shares the result, separated by commas"]
+    #[rest]
+    recipients: String,
+) -> Result<(), Error> {
+    let flavor = flavor_for(ctx.data(), ctx.author().id.0).await;
+    let response = get_share_response(&dice, &recipients, flavor);
+    say_chunked(
+        ctx,
+        &with_persona(ctx.data(), ctx.author().id.0, "share", response).await,
+        false,
+    )
+    .await?;
+    Ok(())
+}
+
+fn get_share_response(dice: &str, recipients: &str, flavor: Flavor) -> String {
+    let names = parse_recipients(recipients);
+    if names.is_empty() {
+        return "Who's sharing this one? Give me some names separated by commas".to_string();
+    }
+    let roll = match DiceRollRequest::parse(dice) {
+        Err(err) => return err.message(flavor),
+        Ok(request) => request.roll(&mut rand::thread_rng()),
+    };
+    format!(
+        "Rolling {} for the group\n\n{}\n\n{}",
+        dice,
+        roll.to_discord_markdown().trim(),
+        format_share(roll.get_highest_total(), &names)
+    )
+}
+
+fn parse_recipients(recipients: &str) -> Vec<String> {
+    recipients
+        .split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn format_share(outcome: CortexResult, names: &[String]) -> String {
+    let (total, effect) = match outcome {
+        CortexResult::Botch => return "**BOTCH!** Nobody gets anything from this one.".to_string(),
+        CortexResult::Result { total, effect } => (total.to_string(), effect.to_string()),
+    };
+    let width = names
+        .iter()
+        .map(|name| name.chars().count())
+        .chain(std::iter::once("Recipient".len()))
+        .max()
+        .unwrap_or(0);
+    let mut s = String::from("```\n");
+    s += &format!("{:<width$} | Total | Effect\n", "Recipient", width = width);
+    for name in names {
+        s += &format!(
+            "{:<width$} | {:>5} | {}\n",
+            name,
+            total,
+            effect,
+            width = width
+        );
+    }
+    s += "```";
+    s
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ContestOutcome {
+    FirstWins,
+    SecondWins,
+    Tie,
+    BothBotched,
+}
+
+// The higher total wins. If the totals are tied, the bigger effect die breaks the tie.
+fn decide_contest(first: CortexResult, second: CortexResult) -> ContestOutcome {
+    match (first, second) {
+        (CortexResult::Botch, CortexResult::Botch) => ContestOutcome::BothBotched,
+        (CortexResult::Botch, _) => ContestOutcome::SecondWins,
+        (_, CortexResult::Botch) => ContestOutcome::FirstWins,
+        (
+            CortexResult::Result {
+                total: first_total,
+                effect: first_effect,
+            },
+            CortexResult::Result {
+                total: second_total,
+                effect: second_effect,
+            },
+        ) => match (first_total, first_effect).cmp(&(second_total, second_effect)) {
+            std::cmp::Ordering::Greater => ContestOutcome::FirstWins,
+            std::cmp::Ordering::Less => ContestOutcome::SecondWins,
+            std::cmp::Ordering::Equal => ContestOutcome::Tie,
+        },
+    }
+}
+
+// `2d6 # stealth check` is 2d6, labelled "stealth check".
+fn split_comment(dice: &str) -> (&str, Option<&str>) {
+    match dice.split_once('#') {
+        None => (dice, None),
+        Some((dice, comment)) => {
+            let comment = comment.trim();
+            (dice.trim_end(), Some(comment).filter(|c| !c.is_empty()))
+        }
+    }
+}
+
+// Most you can ask for in one go with a prefix like `6x 4d6`.
+const MAX_REPEATS: u64 = 20;
+
+// `6x 4d6` is 4d6, rolled six times. Without the prefix it's just the once.
+fn split_repeats(dice: &str) -> Result<(u64, &str), ParseError> {
+    let dice = dice.trim_start();
+    let (prefix, rest) = dice.split_once(char::is_whitespace).unwrap_or((dice, ""));
+    let times = match prefix.strip_suffix('x').and_then(parse_number) {
+        None => return Ok((1, dice)),
+        Some(times) => times,
+    };
+    if !(1..=MAX_REPEATS).contains(&times) {
+        return Err(ParseError::Repeats(times));
+    }
+    Ok((times, rest.trim_start()))
+}
+
+// What to send for a roll, when it might be too big for a message.
+struct RollReply {
+    content: String,
+    // the filename and text of a file to send after the content
+    attachment: Option<(String, String)>,
+}
+
+fn get_reply(
+    dice: &str,
+    comment: Option<&str>,
+    rolls: &[RollResult],
+    overflow: Overflow,
+    flavor: Flavor,
+) -> RollReply {
+    let summary = RollReply {
+        content: get_response(dice, comment, rolls, flavor),
+        attachment: None,
+    };
+    let roll = match rolls {
+        [roll] => roll,
+        _ => return summary,
+    };
+    let full = full_response(dice, comment, roll);
+    if full.chars().count() <= MAX_MESSAGE_LEN {
+        return summary;
+    }
+    match overflow {
+        Overflow::Summary => summary,
+        Overflow::Attachment => RollReply {
+            content: format!(
+                "{}{}\n\n{}",
+                label(comment),
+                too_many_to_show(dice.trim(), flavor, true),
+                roll.short_summary()
+            ),
+            attachment: Some(("roll.txt".to_string(), full)),
+        },
+        Overflow::Truncate => match truncated_response(dice, comment, roll) {
+            None => summary,
+            Some(content) => RollReply {
+                content,
+                attachment: None,
+            },
+        },
+    }
+}
+
+fn label(comment: Option<&str>) -> String {
+    match comment {
+        None => String::new(),
+        Some(comment) => format!("**{}**\n", comment),
+    }
+}
+
+// Every die, however long that gets.
+fn full_response(dice: &str, comment: Option<&str>, roll: &RollResult) -> String {
+    format!(
+        "{}Rolling {}\n\nResult: {}",
+        label(comment),
+        dice.trim(),
         roll.to_discord_markdown().trim()
-    );
-    if resp.len() > 1950 {
+    )
+}
+
+// As many dice as fit in one message, then the summary. None if not even that fits.
+fn truncated_response(dice: &str, comment: Option<&str>, roll: &RollResult) -> Option<String> {
+    let head = format!("{}Rolling {}\n\nResult: ", label(comment), dice.trim());
+    let tail = format!(" …\n\n{}", roll.short_summary());
+    let mut budget = MAX_MESSAGE_LEN.checked_sub(head.chars().count() + tail.chars().count())?;
+    let mut shown = String::new();
+    for die in roll.dice_markdown().split_inclusive(") ") {
+        let len = die.chars().count();
+        if len > budget {
+            break;
+        }
+        budget -= len;
+        shown += die;
+    }
+    Some(format!("{}{}{}", head, shown.trim_end(), tail))
+}
+
+fn get_response(dice: &str, comment: Option<&str>, rolls: &[RollResult], flavor: Flavor) -> String {
+    let label = label(comment);
+    let roll = match rolls {
+        [roll] => roll,
+        rolls => {
+            let mut resp = format!("{}Rolling {} {} times\n", label, dice.trim(), rolls.len());
+            for (i, roll) in rolls.iter().enumerate() {
+                resp += &format!(
+                    "\n{}. {}",
+                    i + 1,
+                    roll.short_summary().trim().replace('\n', ", ")
+                );
+            }
+            return resp;
+        }
+    };
+    let resp = full_response(dice, comment, roll);
+    if resp.chars().count() > MAX_MESSAGE_LEN {
         format!(
-            "Roll {}?? hoo.. that's a lot. I don't wanna flood the chat here, so, uh, I'll give you the quick summary:\n\n{}",
-            dice,
+            "{}{}\n\n{}",
+            label,
+            too_many_to_show(dice.trim(), flavor, false),
             roll.short_summary()
         )
     } else {
@@ -39,118 +712,1080 @@ fn get_response(dice: &String) -> String {
     }
 }
 
+const MAX_EMBED_TITLE_LEN: usize = 256;
+const MAX_EMBED_FIELDS: usize = 25;
+
+// The roll's fields, with the long ones split up so that Discord will take them. None if
+// the roll is too big for an embed at all, and should go out as plain text instead.
+fn embed_fields(
+    title: &str,
+    comment: Option<&str>,
+    roll: &RollResult,
+) -> Option<Vec<(String, String)>> {
+    if title.chars().count() > MAX_EMBED_TITLE_LEN {
+        return None;
+    }
+    let mut fields = Vec::new();
+    for (name, value) in roll.fields() {
+        for (i, piece) in split_field(value.trim(), MAX_EMBED_FIELD_LEN)
+            .into_iter()
+            .enumerate()
+        {
+            let name = match i {
+                0 => name.to_string(),
+                _ => format!("{} (cont.)", name),
+            };
+            fields.push((name, piece));
+        }
+    }
+    let len = title.chars().count()
+        + comment.map_or(0, |comment| comment.chars().count() + 4)
+        + fields
+            .iter()
+            .map(|(name, value)| name.chars().count() + value.chars().count())
+            .sum::<usize>();
+    if fields.len() > MAX_EMBED_FIELDS || len > MAX_EMBED_LEN {
+        return None;
+    }
+    Some(fields)
+}
+
+// Splits between dice, so that no die gets cut in half.
+fn split_field(value: &str, max: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    for die in value.split_inclusive(") ") {
+        if !piece.is_empty() && piece.chars().count() + die.chars().count() > max {
+            pieces.push(piece.trim_end().to_string());
+            piece = String::new();
+        }
+        piece += die;
+    }
+    if !piece.is_empty() {
+        pieces.push(piece.trim_end().to_string());
+    }
+    pieces
+}
+
+// Red for a botch, gold when something exploded.
+fn embed_colour(roll: &RollResult) -> Option<serenity::Colour> {
+    if roll.is_botch() {
+        Some(serenity::Colour::RED)
+    } else if roll
+        .rolled_die
+        .iter()
+        .any(|r| matches!(r, Roll::Exploded { .. }))
+    {
+        Some(serenity::Colour::GOLD)
+    } else {
+        None
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
-struct Die {
+pub struct Die {
     sides: u64,
 }
 
+// Even a d2 that keeps coming up 2 has to stop somewhere.
+const MAX_EXPLOSIONS: u32 = 100;
+
 impl Die {
-    fn roll(self) -> Roll {
-        let num = rand::thread_rng().gen_range(1..=self.sides);
-        if num == 1 {
-            Roll::Glitch(self)
+    // A Fate die, with faces of -1, 0 and +1. It has no sides in the usual sense, so it
+    // gets zero, which no real die can have.
+    const FUDGE: Die = Die { sides: 0 };
+
+    pub fn sides(self) -> u64 {
+        self.sides
+    }
+
+    fn is_fudge(self) -> bool {
+        self == Die::FUDGE
+    }
+
+    fn roll(self, glitch_on: u64, rng: &mut impl Rng) -> Roll {
+        if self.is_fudge() {
+            return Roll::Fudge(rng.gen_range(-1..=1));
+        }
+        let num = rng.gen_range(1..=self.sides);
+        if num <= glitch_on {
+            Roll::Glitch(num, self)
         } else {
             Roll::Value(num, self)
         }
     }
+
+    fn throw(self, explodes: bool, glitch_on: u64, rng: &mut impl Rng) -> Roll {
+        if explodes {
+            self.roll_exploding(glitch_on, rng)
+        } else {
+            self.roll(glitch_on, rng)
+        }
+    }
+
+    fn roll_exploding(self, glitch_on: u64, rng: &mut impl Rng) -> Roll {
+        self.explode_from(&mut || rng.gen_range(1..=self.sides), glitch_on)
+    }
+
+    // Every max roll gets rolled again and added on. `face` picks the number that comes up
+    // on each throw, so tests can force particular rolls.
+    fn explode_from(self, face: &mut impl FnMut() -> u64, glitch_on: u64) -> Roll {
+        let first = face();
+        if first <= glitch_on {
+            return Roll::Glitch(first, self);
+        }
+        let mut last = first;
+        let mut explosions = 0;
+        while last == self.sides && explosions < MAX_EXPLOSIONS {
+            explosions += 1;
+            last = face();
+        }
+        if explosions == 0 {
+            Roll::Value(first, self)
+        } else {
+            Roll::Exploded {
+                explosions,
+                last,
+                die: self,
+            }
+        }
+    }
 }
 impl std::fmt::Display for Die {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_fudge() {
+            return f.write_str("dF");
+        }
         f.write_char('d')?;
         f.write_fmt(format_args!("{}", self.sides))
     }
 }
 
 #[derive(Debug, Clone, Copy)]
-enum Roll {
-    Glitch(Die),
-    Value(u64, Die),
+enum Roll {
+    // Came up GLITCH_ON or less, usually just a 1.
+    Glitch(u64, Die),
+    Value(u64, Die),
+    // Thrown out by a keep/drop rule like 4d6kh3. Doesn't count for anything.
+    Dropped(u64, Die),
+    // Rolled its max `explosions` times in a row, then `last`.
+    Exploded {
+        explosions: u32,
+        last: u64,
+        die: Die,
+    },
+    // -1, 0 or +1 on a Fate die. These only count towards the Fate total, never Cortex.
+    Fudge(i8),
+}
+impl Roll {
+    fn is_glitch(self) -> bool {
+        matches!(self, Roll::Glitch(..))
+    }
+
+    fn die(self) -> Die {
+        match self {
+            Roll::Glitch(_, die) | Roll::Value(_, die) | Roll::Dropped(_, die) => die,
+            Roll::Exploded { die, .. } => die,
+            Roll::Fudge(_) => Die::FUDGE,
+        }
+    }
+
+    fn is_dropped(self) -> bool {
+        matches!(self, Roll::Dropped(..))
+    }
+
+    // The value and die of a roll that counts towards a total, i.e. not a glitch or dropped.
+    fn counted(self) -> Option<(u64, Die)> {
+        match self {
+            Roll::Glitch(..) | Roll::Dropped(..) | Roll::Fudge(_) => None,
+            Roll::Value(value, die) => Some((value, die)),
+            Roll::Exploded {
+                explosions,
+                last,
+                die,
+            } => Some((
+                die.sides
+                    .saturating_mul(explosions as u64)
+                    .saturating_add(last),
+                die,
+            )),
+        }
+    }
+
+    fn face(self) -> u64 {
+        match self {
+            Roll::Glitch(value, _) | Roll::Dropped(value, _) => value,
+            // so that - sorts before 0 before +
+            Roll::Fudge(value) => (value + 1) as u64,
+            roll => roll.counted().map_or(0, |(value, _)| value),
+        }
+    }
+
+    fn into_dropped(self) -> Roll {
+        match self {
+            Roll::Glitch(value, die) => Roll::Dropped(value, die),
+            Roll::Dropped(..) => self,
+            roll => match roll.counted() {
+                Some((value, die)) => Roll::Dropped(value, die),
+                None => roll,
+            },
+        }
+    }
+}
+
+// Which dice of a single term survive, e.g. the highest 3 of the 4d6 in `4d6kh3`.
+// Drops are turned into keeps when parsing, so `4d6dl1` is the same as `4d6kh3`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Keep {
+    dice: std::ops::Range<usize>,
+    highest: bool,
+    count: usize,
+}
+impl Keep {
+    fn apply(&self, rolls: &mut [Roll]) {
+        let mut order: Vec<usize> = self.dice.clone().collect();
+        order.sort_by_key(|&i| rolls[i].face());
+        if self.highest {
+            order.reverse();
+        }
+        for &i in order.iter().skip(self.count) {
+            rolls[i] = rolls[i].into_dropped();
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "keeping the {} {}",
+            if self.highest { "highest" } else { "lowest" },
+            self.count
+        )
+    }
+}
+
+// A normalized description of a pool, like `2d6 1d10`, with identical dice grouped
+// together in the order they first show up.
+fn describe_pool(dice: impl Iterator<Item = Die>) -> String {
+    let mut groups: Vec<(u64, Die)> = Vec::new();
+    for die in dice {
+        match groups.iter_mut().find(|(_, d)| *d == die) {
+            Some((count, _)) => *count += 1,
+            None => groups.push((1, die)),
+        }
+    }
+    groups
+        .iter()
+        .map(|(count, die)| format!("{}{}", count, die))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Add,
+    Subtract,
+}
+impl Op {
+    fn symbol(self) -> char {
+        match self {
+            Op::Add => '+',
+            Op::Subtract => '-',
+        }
+    }
+}
+
+// A roll like `2d6 + 1d4 - 1`, which adds up each group of dice on its own instead of
+// pooling them. Operators go left to right, so `a - b + c` is `(a - b) + c`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    // the dice from one term, by where they are in the request
+    Dice(std::ops::Range<usize>),
+    Constant(i64),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+impl Expr {
+    fn replace_dice(&mut self, from: &std::ops::Range<usize>, to: &std::ops::Range<usize>) {
+        match self {
+            Expr::Dice(dice) if dice == from => *dice = to.clone(),
+            Expr::Dice(_) | Expr::Constant(_) => {}
+            Expr::Binary(lhs, _, rhs) => {
+                lhs.replace_dice(from, to);
+                rhs.replace_dice(from, to);
+            }
+        }
+    }
+
+    fn evaluate(&self, rolls: &[Roll]) -> i64 {
+        match self {
+            Expr::Dice(dice) => rolls[dice.clone()]
+                .iter()
+                .map(|roll| match roll {
+                    Roll::Glitch(value, _) => *value as i64,
+                    roll => roll.counted().map_or(0, |(value, _)| value as i64),
+                })
+                .fold(0, i64::saturating_add),
+            Expr::Constant(value) => *value,
+            Expr::Binary(lhs, op, rhs) => {
+                let (lhs, rhs) = (lhs.evaluate(rolls), rhs.evaluate(rolls));
+                match op {
+                    Op::Add => lhs.saturating_add(rhs),
+                    Op::Subtract => lhs.saturating_sub(rhs),
+                }
+            }
+        }
+    }
+
+    fn describe(&self, dice: &[Die]) -> String {
+        match self {
+            Expr::Dice(range) => describe_pool(dice[range.clone()].iter().copied()),
+            Expr::Constant(value) => value.to_string(),
+            Expr::Binary(lhs, op, rhs) => {
+                format!(
+                    "{} {} {}",
+                    lhs.describe(dice),
+                    op.symbol(),
+                    rhs.describe(dice)
+                )
+            }
+        }
+    }
+
+    // Like `2d6 (7) + 1d4 (3) - 1`, with what each group of dice came to.
+    fn breakdown(&self, rolls: &[Roll]) -> String {
+        match self {
+            Expr::Dice(range) => format!(
+                "{} ({})",
+                describe_pool(rolls[range.clone()].iter().map(|roll| roll.die())),
+                self.evaluate(rolls)
+            ),
+            Expr::Constant(value) => value.to_string(),
+            Expr::Binary(lhs, op, rhs) => format!(
+                "{} {} {}",
+                lhs.breakdown(rolls),
+                op.symbol(),
+                rhs.breakdown(rolls)
+            ),
+        }
+    }
+
+    // The lowest, highest and average totals, counting every die at face value. In i128,
+    // since a single die can have more sides than an i64 goes up to.
+    fn spread(&self, dice: &[Die]) -> (i128, i128, f64) {
+        match self {
+            Expr::Dice(range) => {
+                dice[range.clone()]
+                    .iter()
+                    .fold((0, 0, 0.0), |(min, max, mean), die| {
+                        let sides = die.sides as i128;
+                        (
+                            min + 1,
+                            max.saturating_add(sides),
+                            mean + (1 + sides) as f64 / 2.0,
+                        )
+                    })
+            }
+            Expr::Constant(value) => (*value as i128, *value as i128, *value as f64),
+            Expr::Binary(lhs, op, rhs) => {
+                let (lmin, lmax, lmean) = lhs.spread(dice);
+                let (rmin, rmax, rmean) = rhs.spread(dice);
+                match op {
+                    Op::Add => (
+                        lmin.saturating_add(rmin),
+                        lmax.saturating_add(rmax),
+                        lmean + rmean,
+                    ),
+                    Op::Subtract => (
+                        lmin.saturating_sub(rmax),
+                        lmax.saturating_sub(rmin),
+                        lmean - rmean,
+                    ),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Term(&'a str),
+    Op(Op),
+}
+
+// A roll is arithmetic once a + or - does more than tack a modifier onto a single term,
+// e.g. `2d6 + 1d4`, `2d6 +3` or `1d8+1d6`. `2d6+3` on its own is still a pool with a
+// modifier.
+fn is_arithmetic(s: &str) -> bool {
+    s.split_whitespace().any(|term| {
+        let ops: Vec<usize> = term.match_indices(['+', '-']).map(|(i, _)| i).collect();
+        match ops.as_slice() {
+            [] => false,
+            [0, ..] => true,
+            [op] => term[op + 1..].contains('d'),
+            _ => true,
+        }
+    })
+}
+
+// Whitespace separates terms, and in an arithmetic roll so does every + and -.
+fn tokenize(s: &str, arithmetic: bool) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    for term in s.split_whitespace() {
+        if !arithmetic {
+            tokens.push(Token::Term(term));
+            continue;
+        }
+        let mut start = 0;
+        for (i, op) in term.match_indices(['+', '-']) {
+            if i > start {
+                tokens.push(Token::Term(&term[start..i]));
+            }
+            tokens.push(Token::Op(match op {
+                "+" => Op::Add,
+                _ => Op::Subtract,
+            }));
+            start = i + 1;
+        }
+        if start < term.len() {
+            tokens.push(Token::Term(&term[start..]));
+        }
+    }
+    tokens
+}
+
+// Adds the next operand onto the expression so far, with the operator before it.
+fn push_operand(
+    expression: &mut Option<Expr>,
+    pending: &mut Option<Op>,
+    operand: Expr,
+    term: &str,
+) -> Result<(), ParseError> {
+    *expression = match (expression.take(), pending.take()) {
+        (None, _) => Some(operand),
+        (Some(lhs), Some(op)) => Some(Expr::Binary(Box::new(lhs), op, Box::new(operand))),
+        (Some(_), None) => return Err(ParseError::MissingOperator(term.to_string())),
+    };
+    Ok(())
+}
+
+const MAX_DICE: u64 = 1_000_000;
+
+#[derive(Debug)]
+pub struct DiceRollRequest {
+    dice: Vec<Die>,
+    // the flat +N/-N from every term, added up
+    modifier: i64,
+    keeps: Vec<Keep>,
+    // the dice from terms like 3d6! that explode on their max
+    exploding: Vec<std::ops::Range<usize>>,
+    // the dice from terms like 4d8r1, which get thrown once more if they come up 1
+    rerolling_ones: Vec<std::ops::Range<usize>>,
+    // with `tn4`, count the dice that come up 4 or more instead of adding them up
+    target: Option<u64>,
+    // dice that come up this or lower glitch
+    glitch_on: u64,
+    // how to add up the dice, if there were + or - between the terms
+    expression: Option<Expr>,
+}
+
+impl DiceRollRequest {
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        DiceRollRequest::parse_glitching_on(s, crate::dice_common::glitch_on())
+    }
+
+    fn parse_glitching_on(s: &str, glitch_on: u64) -> Result<Self, ParseError> {
+        let mut dice = Vec::new();
+        let mut modifier: i64 = 0;
+        let mut keeps: Vec<Keep> = Vec::new();
+        let mut exploding = Vec::new();
+        let mut rerolling_ones = Vec::new();
+        let mut target = None;
+        if s.trim().is_empty() {
+            return Err(ParseError::Empty);
+        }
+        let arithmetic = is_arithmetic(s);
+        let mut expression = None;
+        // the operator waiting for its right hand side
+        let mut pending = None;
+        // the dice from the term we just parsed, for adv/dis to apply to
+        let mut last_term: Option<std::ops::Range<usize>> = None;
+        for token in tokenize(s, arithmetic) {
+            let s = match token {
+                Token::Op(op) => {
+                    if expression.is_none() || pending.is_some() {
+                        return Err(ParseError::MissingOperand(op.symbol().to_string()));
+                    }
+                    pending = Some(op);
+                    last_term = None;
+                    continue;
+                }
+                Token::Term(s) => s,
+            };
+            if let Some(number) = s.strip_prefix("tn") {
+                target = match (target, parse_number(number)) {
+                    (None, Some(number)) if number > 0 => Some(number),
+                    _ => return Err(ParseError::BadTarget(s.to_string())),
+                };
+                continue;
+            }
+            if s == "adv" || s == "dis" {
+                let standalone = last_term.is_none();
+                let term = match last_term.take() {
+                    // on its own, it's a d20
+                    None => {
+                        dice.push(Die { sides: 20 });
+                        dice.len() - 1..dice.len()
+                    }
+                    Some(term) if term.len() == 1 && !keeps.iter().any(|k| k.dice == term) => term,
+                    Some(_) => return Err(ParseError::Advantage(s.to_string())),
+                };
+                // roll the die twice, keep the better (or worse) one
+                dice.push(dice[term.start]);
+                let doubled = term.start..dice.len();
+                for dice in exploding.iter_mut().chain(rerolling_ones.iter_mut()) {
+                    if *dice == term {
+                        *dice = doubled.clone();
+                    }
+                }
+                keeps.push(Keep {
+                    dice: doubled.clone(),
+                    highest: s == "adv",
+                    count: 1,
+                });
+                if arithmetic {
+                    if standalone {
+                        push_operand(
+                            &mut expression,
+                            &mut pending,
+                            Expr::Dice(doubled.clone()),
+                            s,
+                        )?;
+                    } else if let Some(expression) = expression.as_mut() {
+                        expression.replace_dice(&term, &doubled);
+                    }
+                }
+                last_term = Some(doubled);
+                continue;
+            }
+            // in arithmetic, a bare number is just a number rather than a die
+            if arithmetic {
+                if let Some(number) = parse_number(s) {
+                    let number = number.min(i64::MAX as u64) as i64;
+                    push_operand(&mut expression, &mut pending, Expr::Constant(number), s)?;
+                    continue;
+                }
+            }
+            let (term, term_modifier) = DiceRollRequest::split_modifier(s)
+                .ok_or_else(|| ParseError::BadModifier(s.to_string()))?;
+            modifier = modifier.saturating_add(term_modifier);
+            let (term, keep) = DiceRollRequest::split_keep(term)
+                .ok_or_else(|| ParseError::BadKeep(s.to_string()))?;
+            let (term, rerolls_ones) = match term.strip_suffix("r1") {
+                Some(term) => (term, true),
+                None => (term, false),
+            };
+            let (term, explodes) = match term.strip_suffix('!') {
+                Some(term) => (term, true),
+                None => (term, false),
+            };
+            let (count, die) = DiceRollRequest::get_die_count(term, s)?;
+            if count.saturating_add(dice.len() as u64) > MAX_DICE {
+                return Err(ParseError::TooManyDice);
+            }
+            if die.is_fudge() && (keep.is_some() || explodes || rerolls_ones) {
+                return Err(ParseError::Fudge(s.to_string()));
+            }
+            let start = dice.len();
+            for _ in 0..count {
+                dice.push(die);
+            }
+            last_term = Some(start..dice.len());
+            if arithmetic {
+                push_operand(
+                    &mut expression,
+                    &mut pending,
+                    Expr::Dice(start..dice.len()),
+                    s,
+                )?;
+            }
+            if explodes {
+                exploding.push(start..dice.len());
+            }
+            if rerolls_ones {
+                rerolling_ones.push(start..dice.len());
+            }
+            if let Some(keep) = keep {
+                // asking to keep more dice than there are just keeps all of them
+                let kept = match keep {
+                    KeepDirective::KeepHighest(n) | KeepDirective::KeepLowest(n) => n.min(count),
+                    KeepDirective::DropHighest(n) | KeepDirective::DropLowest(n) => {
+                        count.saturating_sub(n)
+                    }
+                };
+                keeps.push(Keep {
+                    dice: start..dice.len(),
+                    highest: matches!(
+                        keep,
+                        KeepDirective::KeepHighest(_) | KeepDirective::DropLowest(_)
+                    ),
+                    count: kept as usize,
+                });
+            }
+        }
+        // a trailing + or -, like `2d6 +`
+        if let Some(op) = pending {
+            return Err(ParseError::MissingOperand(op.symbol().to_string()));
+        }
+        if arithmetic {
+            if dice.is_empty() {
+                return Err(ParseError::Empty);
+            }
+            if target.is_some() {
+                return Err(ParseError::Arithmetic("a target number".to_string()));
+            }
+            if dice.iter().any(|die| die.is_fudge()) {
+                return Err(ParseError::Arithmetic("Fate dice".to_string()));
+            }
+        }
+        // Fate is its own thing, it doesn't mix with the other dice or rules
+        if dice.iter().any(|die| die.is_fudge()) {
+            if let Some(die) = dice.iter().find(|die| !die.is_fudge()) {
+                return Err(ParseError::Fudge(die.to_string()));
+            }
+            if target.is_some() {
+                return Err(ParseError::Fudge("a target number".to_string()));
+            }
+            if !keeps.is_empty() {
+                return Err(ParseError::Fudge("adv or dis".to_string()));
+            }
+        }
+        // a die that can only glitch isn't much use to anyone
+        if let Some(die) = dice
+            .iter()
+            .filter(|die| !die.is_fudge())
+            .find(|die| die.sides <= glitch_on)
+        {
+            return Err(ParseError::GlitchOn {
+                threshold: glitch_on,
+                sides: die.sides,
+            });
+        }
+        Ok(DiceRollRequest {
+            dice,
+            modifier,
+            keeps,
+            exploding,
+            rerolling_ones,
+            target,
+            glitch_on,
+            expression,
+        })
+    }
+
+    // Splits `4d6kh3` into `4d6` and its directive. The directive has to come after the d,
+    // so that the d in `dh`/`dl` isn't mistaken for the one in `XdY`.
+    fn split_keep(s: &str) -> Option<(&str, Option<KeepDirective>)> {
+        for (pattern, directive) in [
+            ("kh", KeepDirective::KeepHighest as fn(u64) -> KeepDirective),
+            ("kl", KeepDirective::KeepLowest),
+            ("dh", KeepDirective::DropHighest),
+            ("dl", KeepDirective::DropLowest),
+        ] {
+            if let Some(idx) = s.rfind(pattern) {
+                if s[..idx].contains('d') {
+                    let count = parse_number(&s[idx + pattern.len()..])?;
+                    // keeping none of them would leave nothing to roll
+                    if count == 0 && pattern.starts_with('k') {
+                        return None;
+                    }
+                    return Some((&s[..idx], Some(directive(count))));
+                }
+            }
+        }
+        Some((s, None))
+    }
+
+    // Splits `2d6+3` into `2d6` and 3. Terms without a modifier get 0.
+    fn split_modifier(s: &str) -> Option<(&str, i64)> {
+        match s.rfind(['+', '-']) {
+            Some(idx) if idx > 0 => Some((&s[..idx], s[idx..].parse().ok()?)),
+            _ => Some((s, 0)),
+        }
+    }
+
+    // `XdY`, `dY` or just `Y`. Anything else (`dd6`, `3d`, `d`, `+6`, ...) is InvalidDie,
+    // with the whole term `s` came from.
+    fn get_die_count(s: &str, term: &str) -> Result<(u64, Die), ParseError> {
+        let invalid = || ParseError::InvalidDie(term.to_string());
+        let (count, sides) = match s.split_once('d') {
+            None => ("1", s),
+            Some(("", sides)) => ("1", sides),
+            Some(split) => split,
+        };
+        let count = parse_number(count).ok_or_else(invalid)?;
+        // d% is the usual way of writing a d100
+        let die = match sides {
+            "%" => Die { sides: 100 },
+            "F" => Die::FUDGE,
+            sides => match parse_number(sides).ok_or_else(invalid)? {
+                sides if sides < 2 => return Err(ParseError::TooFewSides(sides)),
+                sides => Die { sides },
+            },
+        };
+        Ok((count, die))
+    }
+
+    fn describe(&self) -> String {
+        let mut s = match &self.expression {
+            Some(expression) => expression.describe(&self.dice),
+            None => describe_pool(self.dice.iter().copied()) + &format_modifier(self.modifier),
+        };
+        for dice in self.exploding.iter() {
+            s += &format!(
+                ", exploding {}",
+                describe_pool(self.dice[dice.clone()].iter().copied())
+            );
+        }
+        for dice in self.rerolling_ones.iter() {
+            s += &format!(
+                ", rerolling ones on {}",
+                describe_pool(self.dice[dice.clone()].iter().copied())
+            );
+        }
+        if let Some(target) = self.target {
+            s += &format!(", counting dice that come up {} or more", target);
+        }
+        for keep in self.keeps.iter() {
+            s += &format!(
+                ", {} of {}",
+                keep.describe(),
+                describe_pool(self.dice[keep.dice.clone()].iter().copied())
+            );
+        }
+        s
+    }
+
+    pub fn dice_count(&self) -> usize {
+        self.dice.len()
+    }
+
+    // Worked out from the dice rather than by rolling them. Every die is counted at face
+    // value, so keeps, explosions and rerolls are left out.
+    fn stats(&self) -> PoolStats {
+        let approximate = !self.keeps.is_empty()
+            || !self.exploding.is_empty()
+            || !self.rerolling_ones.is_empty()
+            || self.target.is_some();
+        if let Some(expression) = &self.expression {
+            let (min, max, mean) = expression.spread(&self.dice);
+            return PoolStats {
+                dice: self.dice.len(),
+                min,
+                max,
+                mean,
+                approximate,
+            };
+        }
+        // only Fate totals go below zero
+        let floor = match self.dice.iter().any(|die| die.is_fudge()) {
+            true => i128::MIN,
+            false => 0,
+        };
+        let with_modifier = |total: i128| total.saturating_add(self.modifier as i128).max(floor);
+        let range = |die: &Die| match die.is_fudge() {
+            true => (-1, 1),
+            false => (1, die.sides as i128),
+        };
+        let total = |side: fn((i128, i128)) -> i128| {
+            self.dice
+                .iter()
+                .fold(0i128, |total, die| total.saturating_add(side(range(die))))
+        };
+        PoolStats {
+            dice: self.dice.len(),
+            min: with_modifier(total(|(min, _)| min)),
+            max: with_modifier(total(|(_, max)| max)),
+            mean: (self
+                .dice
+                .iter()
+                .map(|die| {
+                    let (min, max) = range(die);
+                    (min + max) as f64 / 2.0
+                })
+                .sum::<f64>()
+                + self.modifier as f64)
+                .max(floor as f64),
+            approximate,
+        }
+    }
+
+    // Takes the rng so that tests can seed it. Commands just use `rand::thread_rng()`.
+    pub fn roll(&self, rng: &mut impl Rng) -> RollResult {
+        let mut rolls = Vec::new();
+        let mut rerolled = BTreeMap::new();
+        for (i, die) in self.dice.iter().enumerate() {
+            let explodes = self.exploding.iter().any(|dice| dice.contains(&i));
+            let mut roll = die.throw(explodes, self.glitch_on, rng);
+            // only the once, we keep the second throw even if it's another 1
+            if roll.is_glitch() && self.rerolling_ones.iter().any(|dice| dice.contains(&i)) {
+                rerolled.insert(i, roll.face());
+                roll = die.throw(explodes, self.glitch_on, rng);
+            }
+            rolls.push(roll);
+        }
+        for keep in self.keeps.iter() {
+            keep.apply(&mut rolls);
+        }
+        RollResult {
+            rolled_die: rolls,
+            modifier: self.modifier,
+            rerolled,
+            target: self.target.map(|number| Target {
+                number,
+                reachable: self.can_reach(number),
+            }),
+            expression: self.expression.clone(),
+        }
+    }
+
+    // Whether any of the dice could come up `target` or more. One that explodes always can,
+    // given enough explosions.
+    fn can_reach(&self, target: u64) -> bool {
+        self.dice.iter().enumerate().any(|(i, die)| {
+            die.sides >= target || self.exploding.iter().any(|dice| dice.contains(&i))
+        })
+    }
+
+    // `roll` (which came from this request) with every glitch thrown again and the other
+    // dice left alone. Keeps aren't applied again, a die that was kept stays kept. None if
+    // nothing glitched.
+    fn reroll_glitches(&self, roll: &RollResult, rng: &mut impl Rng) -> Option<RollResult> {
+        if !roll.rolled_die.iter().any(|die| die.is_glitch()) {
+            return None;
+        }
+        let mut rerolled = roll.clone();
+        for (i, die) in rerolled.rolled_die.iter_mut().enumerate() {
+            if !die.is_glitch() {
+                continue;
+            }
+            // the glitch we're rolling over is the one to show crossed out
+            rerolled.rerolled.insert(i, die.face());
+            let explodes = self.exploding.iter().any(|dice| dice.contains(&i));
+            *die = die.die().throw(explodes, self.glitch_on, rng);
+        }
+        Some(rerolled)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PoolStats {
+    dice: usize,
+    min: i128,
+    max: i128,
+    mean: f64,
+    // whether the pool has rules that the numbers don't take into account
+    approximate: bool,
+}
+impl PoolStats {
+    fn describe(&self, dice: &str) -> String {
+        let mut s = format!(
+            "Stats for {} ({} dice)\nMin: {}\nMax: {}\nAverage: {:.1}",
+            dice.trim(),
+            self.dice,
+            self.min,
+            self.max,
+            self.mean
+        );
+        if self.approximate {
+            s +=
+                "\n\n(that's just adding up the dice, keeps, explosions and rerolls will shift it)";
+        }
+        s
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum KeepDirective {
+    KeepHighest(u64),
+    KeepLowest(u64),
+    DropHighest(u64),
+    DropLowest(u64),
 }
-impl Roll {
-    fn is_glitch(self) -> bool {
-        match self {
-            Roll::Glitch(_) => true,
-            _ => false,
-        }
+
+// Plain ASCII digits only. `str::parse` would also take a leading `+`.
+fn parse_number(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
     }
+    s.parse().ok()
 }
 
-struct DiceRollRequest {
-    dice: Vec<Die>,
+// Like ` +3` or ` -1`, or nothing at all for no modifier.
+fn format_modifier(modifier: i64) -> String {
+    match modifier {
+        0 => String::new(),
+        m if m > 0 => format!(" +{}", m),
+        m => format!(" {}", m),
+    }
 }
 
-impl DiceRollRequest {
-    fn parse(s: &str) -> Result<Self, String> {
-        let mut dice = Vec::new();
-        for s in s.split_whitespace() {
-            if s.trim().is_empty() {
-                continue;
-            }
-            let (count, die) = DiceRollRequest::get_die_count(s)
-                .ok_or_else(|| format!("Expected {} to be like XdY, e.g. 3d6 or 1d8", s))?;
-            if count > 1_000_000 {
-                return Err(format!(
-                    "Hey buddy, I'm just a demigod, that's too many dice!",
-                ));
-            }
-            for _ in 0..count {
-                dice.push(die);
-            }
-        }
-        Ok(DiceRollRequest { dice })
+#[derive(Clone)]
+pub struct RollResult {
+    rolled_die: Vec<Roll>,
+    modifier: i64,
+    // what the dice that glitched and were rerolled came up the first time, by their index
+    // into rolled_die. Only the reroll counts, the glitch is just for show.
+    rerolled: BTreeMap<usize, u64>,
+    target: Option<Target>,
+    expression: Option<Expr>,
+}
+
+// A target number from the request, and whether the dice could ever reach it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Target {
+    number: u64,
+    reachable: bool,
+}
+
+impl RollResult {
+    fn with_modifier(&self, total: u64) -> u64 {
+        total.saturating_add_signed(self.modifier)
     }
 
-    fn get_die_count(s: &str) -> Option<(u64, Die)> {
-        if let Ok(sides) = s.trim().parse() {
-            return Some((1, Die { sides }));
+    // The plain sum of every die (and the modifier), glitches included, for systems that
+    // just add things up. An arithmetic roll is its total, or zero if that's negative.
+    pub fn sum(&self) -> u64 {
+        if let Some(total) = self.arithmetic_total() {
+            return total.max(0) as u64;
         }
-        let idx = s.find('d')?;
-        let (count, sides) = (&s[..idx], &s[idx + 1..]);
-        let count: u64 = if count.trim().is_empty() {
-            1
-        } else {
-            count.trim().parse().ok()?
-        };
-        let sides = sides.trim().parse().ok()?;
-        Some((count, Die { sides }))
+        self.with_modifier(
+            self.rolled_die
+                .iter()
+                .map(|roll| match roll {
+                    Roll::Glitch(value, _) => *value,
+                    roll => roll.counted().map_or(0, |(value, _)| value),
+                })
+                .sum(),
+        )
     }
 
-    fn roll(self) -> RollResult {
-        let mut rolls = Vec::new();
-        for die in self.dice {
-            rolls.push(die.roll());
+    // The best total by Cortex rules, or zero for a botch. Arithmetic rolls just add up.
+    pub fn total(&self) -> u64 {
+        if self.expression.is_some() {
+            return self.sum();
+        }
+        match self.get_highest_total() {
+            CortexResult::Botch => 0,
+            CortexResult::Result { total, .. } => total,
         }
-        RollResult { rolled_die: rolls }
     }
-}
 
-struct RollResult {
-    rolled_die: Vec<Roll>,
-}
-impl RollResult {
-    fn is_botch(&self) -> bool {
-        self.rolled_die.iter().all(|r| r.is_glitch())
+    // The total of a roll like `2d6 + 1d4 - 1`, if it had operators. Can be negative.
+    pub fn arithmetic_total(&self) -> Option<i64> {
+        self.expression
+            .as_ref()
+            .map(|expression| expression.evaluate(&self.rolled_die))
+    }
+
+    // The total on the Fate ladder, if these are Fate dice.
+    pub fn fate_total(&self) -> Option<i64> {
+        let mut fudge = self.rolled_die.iter().filter_map(|roll| match roll {
+            Roll::Fudge(value) => Some(*value as i64),
+            _ => None,
+        });
+        let first = fudge.next()?;
+        Some(fudge.sum::<i64>() + first + self.modifier)
+    }
+
+    // How many dice met the target, plus the modifier. A die that explodes counts once, for
+    // its whole total.
+    pub fn successes(&self, target: u64) -> u64 {
+        let successes = self
+            .rolled_die
+            .iter()
+            .filter(|roll| !roll.is_dropped() && roll.face() >= target)
+            .count() as u64;
+        successes.saturating_add_signed(self.modifier)
+    }
+
+    pub fn is_botch(&self) -> bool {
+        let mut kept = self
+            .rolled_die
+            .iter()
+            .filter(|r| !r.is_dropped())
+            .peekable();
+        // with every die dropped there's nothing to have botched
+        kept.peek().is_some() && kept.all(|r| r.is_glitch())
+    }
+
+    pub fn to_discord_markdown(&self) -> String {
+        self.fields().map(|(_, value)| value).join("\n\n")
+    }
+
+    // The parts of a result, by name, for an embed or to go one after another in a message.
+    fn fields(&self) -> [(&'static str, String); 2] {
+        [
+            ("Dice", self.dice_markdown()),
+            ("Result", self.short_summary()),
+        ]
     }
 
-    fn to_discord_markdown(&mut self) -> String {
+    pub fn dice_markdown(&self) -> String {
         let mut s = String::new();
-        for roll in self.rolled_die.iter() {
+        for (i, roll) in self.rolled_die.iter().enumerate() {
+            if let Some(first) = self.rerolled.get(&i) {
+                s.push_str(&format!("~~{}~~ -> ", first));
+            }
             match roll {
-                Roll::Glitch(die) => {
-                    s.push_str(&format!("**1** (d{}) ", die.sides));
+                Roll::Glitch(value, die) => {
+                    s.push_str(&format!("**{}** (d{}) ", value, die.sides));
                 }
                 Roll::Value(value, die) => {
                     s.push_str(&format!("{} (d{}) ", value, die.sides));
                 }
+                Roll::Dropped(value, die) => {
+                    s.push_str(&format!("~~{}~~ (d{}) ", value, die.sides));
+                }
+                Roll::Exploded {
+                    explosions,
+                    last,
+                    die,
+                } => {
+                    // like 6+6+3
+                    let mut chain = vec![die.sides.to_string(); *explosions as usize];
+                    chain.push(last.to_string());
+                    s.push_str(&format!("**{}** (d{}) ", chain.join("+"), die.sides));
+                }
+                Roll::Fudge(value) => {
+                    let face = match value {
+                        1 => "+",
+                        -1 => "-",
+                        _ => "0",
+                    };
+                    s.push_str(&format!("{} (dF) ", face));
+                }
             }
         }
-        s += "\n\n";
-        s += &self.short_summary();
+        if self.modifier != 0 {
+            s.push_str(format_modifier(self.modifier).trim());
+        }
         s
     }
 
-    fn short_summary(&mut self) -> String {
+    pub fn short_summary(&self) -> String {
         let mut s = String::new();
+        // added up in the order given, no effect dice
+        if let Some(expression) = &self.expression {
+            s += &expression.breakdown(&self.rolled_die);
+            s += &format!("\nTotal: {}", expression.evaluate(&self.rolled_die));
+            return s;
+        }
+        // Fate has no glitches or effect dice, just where you land on the ladder
+        if let Some(total) = self.fate_total() {
+            if self.modifier != 0 {
+                s += &format!("Modifier:{}\n", format_modifier(self.modifier));
+            }
+            s += &format!("Total: {:+} ({})", total, fate_ladder(total));
+            return s;
+        }
         if self.is_botch() {
             s += "**BOTCH!**";
             return s;
@@ -159,6 +1794,20 @@ impl RollResult {
         if glitch_count > 0 {
             s += &format!("{} Glitches!\n", glitch_count);
         }
+        if self.modifier != 0 {
+            s += &format!("Modifier:{}\n", format_modifier(self.modifier));
+        }
+        if let Some(Target { number, reachable }) = self.target {
+            s += &format!("Successes: {} (tn{})", self.successes(number), number);
+            // don't leave them wondering why nothing ever succeeds
+            if !reachable {
+                s += &format!(
+                    "\nHeads up: tn{} is more than any of these dice have sides",
+                    number
+                );
+            }
+            return s;
+        }
         let highest_effect = self.get_highest_effect();
         let highest_total = self.get_highest_total();
         match (highest_effect, highest_total) {
@@ -190,14 +1839,11 @@ impl RollResult {
         s
     }
 
-    fn get_highest_effect(&self) -> CortexResult {
+    pub fn get_highest_effect(&self) -> CortexResult {
         let non_glitches = self
             .rolled_die
             .iter()
-            .filter_map(|roll| match roll {
-                Roll::Glitch(_) => None,
-                Roll::Value(value, die) => Some((*value, *die)),
-            })
+            .filter_map(|roll| roll.counted())
             .enumerate()
             .collect::<Vec<_>>();
         let effect = non_glitches
@@ -211,7 +1857,7 @@ impl RollResult {
             // too few rolls to have an effect die, fall back to a d4
             return CortexResult::Result {
                 effect: Die { sides: 4 },
-                total: non_glitches.into_iter().map(|(_, (v, _))| v).sum(),
+                total: self.with_modifier(non_glitches.into_iter().map(|(_, (v, _))| v).sum()),
             };
         }
         let mut remaining_vals: Vec<_> = non_glitches
@@ -221,50 +1867,1186 @@ impl RollResult {
             .collect();
         remaining_vals.sort();
         let val = remaining_vals.iter().rev().take(2).sum();
-        CortexResult::Result { total: val, effect }
+        CortexResult::Result {
+            total: self.with_modifier(val),
+            effect,
+        }
+    }
+
+    // Whether the dice can be split into a total of at least `total` and an effect die with
+    // at least `effect` sides: up to two dice for the total and one of the rest for the
+    // effect, or a d4 effect when there isn't one to spare.
+    pub(crate) fn reaches(&self, total: u64, effect: u64) -> bool {
+        let counted: Vec<(u64, Die)> = self.rolled_die.iter().filter_map(|r| r.counted()).collect();
+        if counted.is_empty() {
+            return false;
+        }
+        let best_two_without = |skip: Option<usize>| {
+            let mut values: Vec<u64> = counted
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| Some(*i) != skip)
+                .map(|(_, (value, _))| *value)
+                .collect();
+            values.sort_unstable();
+            self.with_modifier(values.iter().rev().take(2).sum())
+        };
+        if effect <= 4 && best_two_without(None) >= total {
+            return true;
+        }
+        counted
+            .iter()
+            .enumerate()
+            .any(|(i, (_, die))| die.sides >= effect && best_two_without(Some(i)) >= total)
     }
 
-    fn get_highest_total(&mut self) -> CortexResult {
-        self.rolled_die.sort_by_key(|roll| match roll {
-            Roll::Glitch(_) => (0, 0),
-            Roll::Value(v, d) => (*v, -(d.sides as i128)),
+    pub fn get_highest_total(&self) -> CortexResult {
+        let mut rolled_die = self.rolled_die.clone();
+        rolled_die.sort_by_key(|roll| match roll.counted() {
+            None => (0, 0),
+            Some((v, d)) => (v, -(d.sides as i128)),
         });
-        let total = self
-            .rolled_die
+        let total = rolled_die
             .iter()
             .rev()
             .take(2)
-            .filter_map(|roll| {
-                if let Roll::Value(v, _) = roll {
-                    Some(*v)
-                } else {
-                    None
-                }
-            })
+            .filter_map(|roll| roll.counted().map(|(v, _)| v))
             .sum();
         if total == 0 {
             return CortexResult::Botch;
         }
-        let effect = self
-            .rolled_die
+        let effect = rolled_die
             .iter()
             .rev()
             .skip(2)
-            .filter_map(|d| {
-                if let Roll::Value(_, d) = d {
-                    Some(*d)
-                } else {
-                    None
-                }
-            })
+            .filter_map(|roll| roll.counted().map(|(_, d)| d))
             .max()
             .unwrap_or(Die { sides: 4 });
-        CortexResult::Result { total, effect }
+        CortexResult::Result {
+            total: self.with_modifier(total),
+            effect,
+        }
+    }
+}
+
+fn fate_ladder(total: i64) -> &'static str {
+    match total {
+        i64::MIN..=-2 => "Terrible",
+        -1 => "Poor",
+        0 => "Mediocre",
+        1 => "Average",
+        2 => "Fair",
+        3 => "Good",
+        4 => "Great",
+        5 => "Superb",
+        6 => "Fantastic",
+        7 => "Epic",
+        _ => "Legendary",
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum CortexResult {
+pub enum CortexResult {
     Botch,
     Result { total: u64, effect: Die },
 }
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(total: u64, sides: u64) -> CortexResult {
+        CortexResult::Result {
+            total,
+            effect: Die { sides },
+        }
+    }
+
+    #[test]
+    fn test_contest() {
+        assert_eq!(
+            decide_contest(result(9, 4), result(7, 12)),
+            ContestOutcome::FirstWins
+        );
+        assert_eq!(
+            decide_contest(result(7, 6), result(7, 8)),
+            ContestOutcome::SecondWins
+        );
+        assert_eq!(
+            decide_contest(result(7, 6), result(7, 6)),
+            ContestOutcome::Tie
+        );
+        assert_eq!(
+            decide_contest(CortexResult::Botch, CortexResult::Botch),
+            ContestOutcome::BothBotched
+        );
+        assert_eq!(
+            decide_contest(CortexResult::Botch, result(2, 4)),
+            ContestOutcome::SecondWins
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        // the player beats the difficulty
+        assert_eq!(
+            decide_check(result(9, 4), Some(7)),
+            CheckOutcome::Success { margin: 2 }
+        );
+        // the difficulty wins, ties included
+        assert_eq!(
+            decide_check(result(5, 8), Some(8)),
+            CheckOutcome::Failure { margin: 3 }
+        );
+        assert_eq!(
+            decide_check(result(8, 8), Some(8)),
+            CheckOutcome::Failure { margin: 0 }
+        );
+        // a botched difficulty pool hands over the win
+        assert_eq!(
+            decide_check(result(2, 4), None),
+            CheckOutcome::DifficultyBotched
+        );
+        assert_eq!(
+            decide_check(CortexResult::Botch, None),
+            CheckOutcome::Botched
+        );
+        assert_eq!(
+            decide_check(CortexResult::Botch, Some(3)),
+            CheckOutcome::Botched
+        );
+    }
+
+    #[test]
+    fn test_check_response() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let response = get_check_response("3d8", "2d8", Flavor::Flavorful, &mut rng);
+        assert!(response.starts_with("3d8: "), "{}", response);
+        assert!(response.contains("\n\nDifficulty 2d8: "), "{}", response);
+        let response = get_check_response("3d8", " 11 ", Flavor::Flavorful, &mut rng);
+        assert!(response.contains("\n\nDifficulty: 11\n\n"), "{}", response);
+        assert_eq!(
+            get_check_response("3d8", "2x8", Flavor::Flavorful, &mut rng),
+            DiceRollRequest::parse("2x8").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_contest_needs_two_pools() {
+        assert!(
+            get_contest_response("3d6", Flavor::Flavorful).starts_with("Give me exactly two pools")
+        );
+        assert!(get_contest_response("3d6 | 2d8 | 1d4", Flavor::Flavorful)
+            .starts_with("Give me exactly two pools"));
+        assert!(get_contest_response("3d6 | 2d8", Flavor::Flavorful).contains("2d8: "));
+    }
+
+    #[test]
+    fn test_fair_rolls() {
+        use rand::SeedableRng;
+        for seed in 0..200 {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let response = get_fair_response("3d6", Flavor::Flavorful, &mut rng);
+            assert!(!response.contains("BOTCH"), "{}", response);
+            assert!(!response.contains("**"), "{}", response);
+
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let roll = DiceRollRequest::parse_glitching_on("3d6", 0)
+                .unwrap()
+                .roll(&mut rng);
+            assert!(!roll.is_botch());
+            let faces: u64 = roll.rolled_die.iter().map(|r| r.face()).sum();
+            assert_eq!(roll.sum(), faces);
+            assert!(response.ends_with(&format!("Sum: {}", faces)));
+        }
+        assert!(
+            get_fair_response("2d6 + 1d4 - 1", Flavor::Flavorful, &mut rand::thread_rng())
+                .contains("Sum: ")
+        );
+        assert!(
+            get_fair_response("3x6", Flavor::Flavorful, &mut rand::thread_rng()).contains("XdY")
+        );
+    }
+
+    #[test]
+    fn test_share_table() {
+        let names = parse_recipients("Ana, Bo ,, Celestine");
+        assert_eq!(names, vec!["Ana", "Bo", "Celestine"]);
+        assert_eq!(
+            format_share(result(9, 8), &names),
+            "```\n\
+             Recipient | Total | Effect\n\
+             Ana       |     9 | d8\n\
+             Bo        |     9 | d8\n\
+             Celestine |     9 | d8\n\
+             ```"
+        );
+        assert!(format_share(CortexResult::Botch, &names).starts_with("**BOTCH!**"));
+        assert!(get_share_response("3d6", " , ", Flavor::Flavorful).starts_with("Who's sharing"));
+    }
+
+    #[test]
+    fn test_parse_common_rpg_dice() {
+        let request = DiceRollRequest::parse("1d20").unwrap();
+        assert_eq!(request.dice, vec![Die { sides: 20 }]);
+        let request = DiceRollRequest::parse("d% 2d100").unwrap();
+        assert_eq!(request.dice, vec![Die { sides: 100 }; 3]);
+        assert_eq!(
+            DiceRollRequest::parse("d0").unwrap_err(),
+            ParseError::TooFewSides(0)
+        );
+        assert_eq!(
+            DiceRollRequest::parse("2d1").unwrap_err(),
+            ParseError::TooFewSides(1)
+        );
+        assert!(DiceRollRequest::parse("0").is_err());
+    }
+
+    #[test]
+    fn test_modifiers() {
+        let request = DiceRollRequest::parse("2d6+5").unwrap();
+        assert_eq!(request.dice, vec![Die { sides: 6 }; 2]);
+        assert_eq!(request.modifier, 5);
+        assert_eq!(request.describe(), "2d6 +5");
+        let request = DiceRollRequest::parse("1d8-2").unwrap();
+        assert_eq!(request.dice, vec![Die { sides: 8 }]);
+        assert_eq!(request.modifier, -2);
+        assert_eq!(DiceRollRequest::parse("2d6+1 d8-3").unwrap().modifier, -2);
+        assert_eq!(
+            DiceRollRequest::parse("2d6+x").unwrap_err(),
+            ParseError::BadModifier("2d6+x".to_string())
+        );
+
+        let roll = RollResult {
+            rolled_die: vec![
+                Roll::Value(4, Die { sides: 6 }),
+                Roll::Value(3, Die { sides: 6 }),
+            ],
+            modifier: 5,
+            rerolled: BTreeMap::new(),
+            target: None,
+            expression: None,
+        };
+        assert_eq!(roll.sum(), 12);
+        assert_eq!(roll.total(), 12);
+        assert!(roll.dice_markdown().ends_with("+5"));
+        assert!(roll.short_summary().contains("Total: 12"));
+        assert!(roll.to_discord_markdown().contains("Modifier: +5"));
+
+        // a modifier can't take a total below zero
+        let roll = RollResult {
+            rolled_die: vec![Roll::Value(2, Die { sides: 8 })],
+            modifier: -3,
+            rerolled: BTreeMap::new(),
+            target: None,
+            expression: None,
+        };
+        assert_eq!(roll.sum(), 0);
+    }
+
+    #[test]
+    fn test_keep_highest() {
+        let request = DiceRollRequest::parse("4d6kh3").unwrap();
+        assert_eq!(request.dice, vec![Die { sides: 6 }; 4]);
+        assert_eq!(
+            request.keeps,
+            vec![Keep {
+                dice: 0..4,
+                highest: true,
+                count: 3
+            }]
+        );
+        assert_eq!(request.describe(), "4d6, keeping the highest 3 of 4d6");
+
+        let d6 = Die { sides: 6 };
+        let mut rolls = vec![
+            Roll::Value(5, d6),
+            Roll::Glitch(1, d6),
+            Roll::Value(3, d6),
+            Roll::Value(6, d6),
+        ];
+        request.keeps[0].apply(&mut rolls);
+        let roll = RollResult {
+            rolled_die: rolls,
+            modifier: 0,
+            rerolled: BTreeMap::new(),
+            target: None,
+            expression: None,
+        };
+        assert!(roll.rolled_die[1].is_dropped());
+        assert_eq!(roll.sum(), 14);
+        assert!(roll.dice_markdown().contains("~~1~~ (d6)"));
+        // the dropped 1 isn't a glitch anymore
+        assert!(!roll.short_summary().contains("Glitches"));
+    }
+
+    #[test]
+    fn test_drop_lowest() {
+        let request = DiceRollRequest::parse("4d6dl1 2d20kl1").unwrap();
+        assert_eq!(
+            request.keeps[0],
+            DiceRollRequest::parse("4d6kh3").unwrap().keeps[0]
+        );
+        assert_eq!(
+            request.keeps[1],
+            Keep {
+                dice: 4..6,
+                highest: false,
+                count: 1
+            }
+        );
+        let roll = request.roll(&mut rand::thread_rng());
+        assert_eq!(roll.rolled_die.iter().filter(|r| r.is_dropped()).count(), 2);
+
+        // keeping more than there are keeps everything
+        let request = DiceRollRequest::parse("2d6kh5").unwrap();
+        assert_eq!(request.keeps[0].count, 2);
+        assert!(!request
+            .roll(&mut rand::thread_rng())
+            .rolled_die
+            .iter()
+            .any(|r| r.is_dropped()));
+
+        assert_eq!(
+            DiceRollRequest::parse("4d6khx").unwrap_err(),
+            ParseError::BadKeep("4d6khx".to_string())
+        );
+        for keep_none in ["4d6kh0", "4d6kl0"] {
+            assert_eq!(
+                DiceRollRequest::parse(keep_none).unwrap_err(),
+                ParseError::BadKeep(keep_none.to_string())
+            );
+        }
+
+        // dropping every die isn't a botch, since none of them count
+        let roll = DiceRollRequest::parse("2d6dl2")
+            .unwrap()
+            .roll(&mut rand::thread_rng());
+        assert!(roll.rolled_die.iter().all(|r| r.is_dropped()));
+        assert!(!roll.is_botch());
+    }
+
+    fn faces(faces: &[u64]) -> impl FnMut() -> u64 + '_ {
+        let mut faces = faces.iter();
+        move || *faces.next().unwrap()
+    }
+
+    #[test]
+    fn test_advantage() {
+        let request = DiceRollRequest::parse("d20 adv").unwrap();
+        assert_eq!(request.dice, vec![Die { sides: 20 }; 2]);
+        assert_eq!(
+            request.keeps,
+            vec![Keep {
+                dice: 0..2,
+                highest: true,
+                count: 1
+            }]
+        );
+        let roll = request.roll(&mut rand::thread_rng());
+        assert_eq!(roll.rolled_die.iter().filter(|r| r.is_dropped()).count(), 1);
+        assert!(roll.dice_markdown().contains("~~"));
+
+        let request = DiceRollRequest::parse("d20 dis").unwrap();
+        assert_eq!(request.dice.len(), 2);
+        assert!(!request.keeps[0].highest);
+        // on its own it means a d20
+        assert_eq!(
+            DiceRollRequest::parse("adv").unwrap().keeps,
+            DiceRollRequest::parse("d20 adv").unwrap().keeps
+        );
+        // and it only applies to the die right before it
+        let request = DiceRollRequest::parse("d6 d20 adv 2d4").unwrap();
+        assert_eq!(request.dice.len(), 5);
+        assert_eq!(request.keeps[0].dice, 1..3);
+
+        for dice in ["2d20 adv", "d20kh1 adv", "d20 adv dis"] {
+            assert!(
+                matches!(DiceRollRequest::parse(dice), Err(ParseError::Advantage(_))),
+                "{}",
+                dice
+            );
+        }
+    }
+
+    #[test]
+    fn test_roll_comment() {
+        let (dice, comment) = split_comment("2d6 # sneak");
+        assert_eq!(comment, Some("sneak"));
+        let request = DiceRollRequest::parse(dice).unwrap();
+        assert_eq!(request.dice, vec![Die { sides: 6 }; 2]);
+        let response = get_response(
+            dice,
+            comment,
+            &[request.roll(&mut rand::thread_rng())],
+            Flavor::Flavorful,
+        );
+        assert!(
+            response.starts_with("**sneak**\nRolling 2d6\n"),
+            "{}",
+            response
+        );
+
+        assert_eq!(split_comment("2d6"), ("2d6", None));
+        assert_eq!(split_comment("2d6 #"), ("2d6", None));
+    }
+
+    #[test]
+    fn test_repeated_rolls() {
+        let (times, dice) = split_repeats("6x 4d6").unwrap();
+        assert_eq!((times, dice), (6, "4d6"));
+        let request = DiceRollRequest::parse(dice).unwrap();
+        let rolls: Vec<RollResult> = (0..times)
+            .map(|_| request.roll(&mut rand::thread_rng()))
+            .collect();
+        let response = get_response(dice, None, &rolls, Flavor::Flavorful);
+        assert!(
+            response.starts_with("Rolling 4d6 6 times\n"),
+            "{}",
+            response
+        );
+        assert_eq!(response.lines().filter(|l| l.contains(". ")).count(), 6);
+        assert!(response.contains("\n6. "));
+
+        assert_eq!(split_repeats("4d6"), Ok((1, "4d6")));
+        assert_eq!(split_repeats("20x 1d8 2d6"), Ok((20, "1d8 2d6")));
+    }
+
+    #[test]
+    fn test_repeat_cap() {
+        assert_eq!(split_repeats("21x 4d6"), Err(ParseError::Repeats(21)));
+        assert_eq!(split_repeats("0x 4d6"), Err(ParseError::Repeats(0)));
+        assert!(ParseError::Repeats(21)
+            .to_string()
+            .contains("1 to 20 times"));
+    }
+
+    #[test]
+    fn test_embed_fields_split_between_dice() {
+        use rand::SeedableRng;
+        let roll = DiceRollRequest::parse("300d6")
+            .unwrap()
+            .roll(&mut rand::rngs::StdRng::seed_from_u64(3));
+        let fields = embed_fields("Rolling 300d6", None, &roll).unwrap();
+        let dice: Vec<&(String, String)> = fields
+            .iter()
+            .filter(|(name, _)| name.starts_with("Dice"))
+            .collect();
+        assert!(dice.len() > 1);
+        assert_eq!(dice[1].0, "Dice (cont.)");
+        for (_, value) in dice.iter() {
+            assert!(value.chars().count() <= MAX_EMBED_FIELD_LEN);
+            assert!(value.ends_with(')'), "{}", value);
+        }
+        let rejoined: Vec<&str> = dice.iter().map(|(_, value)| value.as_str()).collect();
+        assert_eq!(rejoined.join(" "), roll.dice_markdown().trim());
+        assert_eq!(fields.last().unwrap().0, "Result");
+
+        // too much for one embed
+        let roll = DiceRollRequest::parse("3000d6")
+            .unwrap()
+            .roll(&mut rand::thread_rng());
+        assert_eq!(embed_fields("Rolling 3000d6", None, &roll), None);
+
+        let small = DiceRollRequest::parse("2d6")
+            .unwrap()
+            .roll(&mut rand::thread_rng());
+        let fields = embed_fields("Rolling 2d6", Some("sneak"), &small).unwrap();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0].1, small.dice_markdown().trim());
+    }
+
+    #[test]
+    fn test_massive_rolls_fit_in_messages() {
+        let request = DiceRollRequest::parse("5000d6").unwrap();
+        let roll = request.roll(&mut rand::thread_rng());
+        let response = get_response("5000d6", None, &[roll], Flavor::Flavorful);
+        assert!(response.chars().count() <= MAX_MESSAGE_LEN);
+        assert!(response.contains("quick summary"));
+
+        // even the summary is too long with a comment like this one
+        let comment = "why ".repeat(1000);
+        let roll = request.roll(&mut rand::thread_rng());
+        let response = get_response("5000d6", Some(&comment), &[roll], Flavor::Flavorful);
+        assert!(response.chars().count() > MAX_MESSAGE_LEN);
+        let chunks = crate::dice_common::chunk_message(&response, MAX_MESSAGE_LEN);
+        assert!(chunks.len() > 1);
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.chars().count() <= MAX_MESSAGE_LEN));
+        assert_eq!(chunks.concat(), response);
+    }
+
+    #[test]
+    fn test_terse_overflow_and_errors() {
+        let request = DiceRollRequest::parse("5000d6").unwrap();
+        let rolls = [request.roll(&mut rand::thread_rng())];
+        let response = get_response("5000d6", None, &rolls, Flavor::Terse);
+        assert!(response.starts_with("Rolled 5000d6, too many dice to show:"));
+        assert!(!response.contains("hoo"));
+        let reply = get_reply("5000d6", None, &rolls, Overflow::Attachment, Flavor::Terse);
+        assert!(reply
+            .content
+            .starts_with("Rolled 5000d6, every die is in the file:"));
+
+        let response = get_check_response("3d1", "12", Flavor::Terse, &mut rand::thread_rng());
+        assert_eq!(response, "A d1 is too small, dice need at least two sides");
+        let response = get_contest_response("3d6 | 999999999d6", Flavor::Terse);
+        assert_eq!(response, "Too many dice.");
+        assert!(
+            !get_fair_response("3dF+1d6", Flavor::Terse, &mut rand::thread_rng()).contains("chum")
+        );
+    }
+
+    #[test]
+    fn test_overflow_strategies() {
+        let request = DiceRollRequest::parse("1000d6").unwrap();
+        let roll = request.roll(&mut rand::thread_rng());
+        let rolls = [roll];
+
+        let summary = get_reply("1000d6", None, &rolls, Overflow::Summary, Flavor::Flavorful);
+        assert_eq!(
+            summary.content,
+            get_response("1000d6", None, &rolls, Flavor::Flavorful)
+        );
+        assert!(summary.attachment.is_none());
+
+        let reply = get_reply(
+            "1000d6",
+            Some("fireball"),
+            &rolls,
+            Overflow::Attachment,
+            Flavor::Flavorful,
+        );
+        assert!(reply.content.chars().count() <= MAX_MESSAGE_LEN);
+        assert!(reply.content.contains(&rolls[0].short_summary()));
+        let (filename, contents) = reply.attachment.unwrap();
+        assert!(filename.ends_with(".txt"));
+        assert_eq!(contents.matches("(d6)").count(), 1000);
+        assert!(contents.contains(&rolls[0].dice_markdown()));
+        assert!(contents.starts_with("**fireball**\nRolling 1000d6"));
+
+        let reply = get_reply(
+            "1000d6",
+            None,
+            &rolls,
+            Overflow::Truncate,
+            Flavor::Flavorful,
+        );
+        assert!(reply.attachment.is_none());
+        assert!(reply.content.chars().count() <= MAX_MESSAGE_LEN);
+        assert!(reply.content.contains(" …\n\n"));
+        let shown = reply.content.matches("(d6)").count();
+        assert!(shown > 100 && shown < 1000, "{}", shown);
+
+        // small rolls go out in full whatever the strategy
+        let roll = DiceRollRequest::parse("3d6")
+            .unwrap()
+            .roll(&mut rand::thread_rng());
+        let reply = get_reply(
+            "3d6",
+            None,
+            &[roll],
+            Overflow::Attachment,
+            Flavor::Flavorful,
+        );
+        assert!(reply.attachment.is_none());
+        assert!(reply.content.contains("Result: "));
+    }
+
+    #[test]
+    fn test_private_rolls() {
+        assert_eq!(visibility(false, true), (false, None));
+        assert_eq!(visibility(true, true), (true, None));
+        let (ephemeral, note) = visibility(true, false);
+        assert!(!ephemeral);
+        assert!(note.unwrap().contains("everyone can see"));
+    }
+
+    #[test]
+    fn test_practice_rolls_are_not_recorded() {
+        let mut history = RollHistory::default();
+        let roll = DiceRollRequest::parse("2d6")
+            .unwrap()
+            .roll(&mut rand::thread_rng());
+        record_roll(&mut history, 1, "2d6", &roll, true);
+        assert!(history.for_user(1).is_empty());
+        record_roll(&mut history, 1, "2d6", &roll, false);
+        assert_eq!(history.for_user(1).len(), 1);
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = DiceRollRequest::parse("2d6").unwrap().stats();
+        assert_eq!(stats.mean, 7.0);
+        assert_eq!((stats.dice, stats.min, stats.max), (2, 2, 12));
+        assert_eq!(
+            stats.describe("2d6"),
+            "Stats for 2d6 (2 dice)\nMin: 2\nMax: 12\nAverage: 7.0"
+        );
+
+        let stats = DiceRollRequest::parse("1d8 1d4").unwrap().stats();
+        assert_eq!(stats.max, 12);
+        assert_eq!(stats.mean, 7.0);
+        assert!(!stats.approximate);
+
+        let stats = DiceRollRequest::parse("1d20+5").unwrap().stats();
+        assert_eq!((stats.min, stats.max, stats.mean), (6, 25, 15.5));
+
+        // more sides than an i64 has room for
+        let stats = DiceRollRequest::parse("d9223372036854775807")
+            .unwrap()
+            .stats();
+        assert_eq!(stats.max, i64::MAX as i128);
+        assert_eq!(stats.mean, (i64::MAX as f64 + 1.0) / 2.0);
+        let stats = DiceRollRequest::parse("d18446744073709551615 2d18446744073709551615")
+            .unwrap()
+            .stats();
+        assert_eq!(stats.max, 3 * u64::MAX as i128);
+        assert!(stats.mean > u64::MAX as f64);
+        let stats = DiceRollRequest::parse("d18446744073709551615 + d9223372036854775807")
+            .unwrap()
+            .stats();
+        assert_eq!(stats.max, u64::MAX as i128 + i64::MAX as i128);
+        assert!(stats.mean > u64::MAX as f64 / 2.0);
+        assert!(
+            DiceRollRequest::parse("4d6kh3")
+                .unwrap()
+                .stats()
+                .approximate
+        );
+    }
+
+    #[test]
+    fn test_exploding() {
+        let request = DiceRollRequest::parse("3d6! 1d8").unwrap();
+        assert_eq!(request.exploding, vec![0..3]);
+        assert_eq!(request.describe(), "3d6 1d8, exploding 3d6");
+
+        let d6 = Die { sides: 6 };
+        // no explosion
+        assert!(matches!(
+            d6.explode_from(&mut faces(&[4]), 1),
+            Roll::Value(4, _)
+        ));
+        assert!(matches!(
+            d6.explode_from(&mut faces(&[1]), 1),
+            Roll::Glitch(1, _)
+        ));
+
+        // one explosion
+        let roll = d6.explode_from(&mut faces(&[6, 3]), 1);
+        assert_eq!(roll.counted(), Some((9, d6)));
+        let roll = RollResult {
+            rolled_die: vec![
+                d6.explode_from(&mut faces(&[6, 6, 3]), 1),
+                Roll::Value(2, d6),
+            ],
+            modifier: 0,
+            rerolled: BTreeMap::new(),
+            target: None,
+            expression: None,
+        };
+        assert_eq!(roll.sum(), 17);
+        assert!(roll.dice_markdown().starts_with("**6+6+3** (d6)"));
+    }
+
+    #[test]
+    fn test_fate_dice() {
+        let request = DiceRollRequest::parse("4dF").unwrap();
+        assert_eq!(request.dice, vec![Die::FUDGE; 4]);
+        assert_eq!(request.describe(), "4dF");
+        assert_eq!(DiceRollRequest::parse("dF+2").unwrap().modifier, 2);
+
+        let roll = RollResult {
+            rolled_die: vec![
+                Roll::Fudge(1),
+                Roll::Fudge(-1),
+                Roll::Fudge(0),
+                Roll::Fudge(1),
+            ],
+            modifier: 2,
+            rerolled: BTreeMap::new(),
+            target: None,
+            expression: None,
+        };
+        assert_eq!(roll.fate_total(), Some(3));
+        assert!(!roll.is_botch());
+        assert!(roll
+            .dice_markdown()
+            .starts_with("+ (dF) - (dF) 0 (dF) + (dF) "));
+        assert!(roll.short_summary().ends_with("Total: +3 (Good)"));
+
+        for dice in ["4dF!", "4dFkh2", "4dF 1d6", "4dF tn2", "dF adv", "4dFr1"] {
+            assert!(
+                matches!(DiceRollRequest::parse(dice), Err(ParseError::Fudge(_))),
+                "{}",
+                dice
+            );
+        }
+    }
+
+    #[test]
+    fn test_fate_dice_range() {
+        let request = DiceRollRequest::parse("4dF").unwrap();
+        let stats = request.stats();
+        assert_eq!((stats.min, stats.max, stats.mean), (-4, 4, 0.0));
+        let mut seen = std::collections::BTreeSet::new();
+        for _ in 0..2000 {
+            let total = request.roll(&mut rand::thread_rng()).fate_total().unwrap();
+            assert!((-4..=4).contains(&total), "{}", total);
+            seen.insert(total);
+        }
+        // a few thousand rolls should turn up every total at least once
+        assert_eq!(seen.len(), 9);
+    }
+
+    #[test]
+    fn test_target_number() {
+        use rand::SeedableRng;
+        let request = DiceRollRequest::parse("6d6 tn5").unwrap();
+        assert_eq!(request.target, Some(5));
+        assert_eq!(request.dice.len(), 6);
+        assert_eq!(
+            request.describe(),
+            "6d6, counting dice that come up 5 or more"
+        );
+        let roll = request.roll(&mut rand::rngs::StdRng::seed_from_u64(42));
+        let faces: Vec<u64> = roll.rolled_die.iter().map(|r| r.face()).collect();
+        assert_eq!(faces, vec![4, 4, 3, 1, 3, 5]);
+        assert_eq!(roll.successes(5), 1);
+        assert!(roll.short_summary().ends_with("Successes: 1 (tn5)"));
+
+        // nothing on a d6 gets to 7
+        let roll = DiceRollRequest::parse("10d6 tn7")
+            .unwrap()
+            .roll(&mut rand::rngs::StdRng::seed_from_u64(1));
+        assert_eq!(roll.successes(7), 0);
+        assert!(roll.short_summary().contains("Successes: 0 (tn7)"));
+        assert!(roll
+            .short_summary()
+            .contains("more than any of these dice have sides"));
+
+        // unless they explode, and then it's just unlikely
+        let request = DiceRollRequest::parse("10d6! tn7").unwrap();
+        for seed in 0..20 {
+            let roll = request.roll(&mut rand::rngs::StdRng::seed_from_u64(seed));
+            assert!(!roll.short_summary().contains("Heads up"));
+        }
+        let request = DiceRollRequest::parse("2d4! 1d6 tn7").unwrap();
+        assert!(request.can_reach(7));
+        assert!(!DiceRollRequest::parse("2d4 1d6 tn7").unwrap().can_reach(7));
+
+        for dice in ["6d6 tnx", "6d6 tn0", "6d6 tn4 tn5", "6d6 tn"] {
+            assert_eq!(
+                DiceRollRequest::parse(dice).unwrap_err(),
+                ParseError::BadTarget(dice.rsplit(' ').next().unwrap().to_string()),
+                "{}",
+                dice
+            );
+        }
+    }
+
+    #[test]
+    fn test_reroll_ones() {
+        let request = DiceRollRequest::parse("4d8r1 1d6").unwrap();
+        assert_eq!(request.dice.len(), 5);
+        assert_eq!(request.rerolling_ones, vec![0..4]);
+        assert_eq!(request.describe(), "4d8 1d6, rerolling ones on 4d8");
+        assert_eq!(
+            DiceRollRequest::parse("3d6!r1kh2+1")
+                .unwrap()
+                .rerolling_ones,
+            vec![0..3]
+        );
+        assert!(DiceRollRequest::parse("4d8r2").is_err());
+
+        // any 1 left in a rerolling pool has to be the second throw
+        use rand::SeedableRng;
+        let roll = DiceRollRequest::parse("200d4r1")
+            .unwrap()
+            .roll(&mut rand::rngs::StdRng::seed_from_u64(7));
+        assert!(!roll.rerolled.is_empty());
+        for (i, die) in roll.rolled_die.iter().enumerate() {
+            if die.is_glitch() {
+                assert!(roll.rerolled.contains_key(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn test_reroll_glitches() {
+        use rand::SeedableRng;
+        let request = DiceRollRequest::parse("1d8 1d6 1d10 1d4").unwrap();
+        let (d8, d6, d10, d4) = (
+            Die { sides: 8 },
+            Die { sides: 6 },
+            Die { sides: 10 },
+            Die { sides: 4 },
+        );
+        let roll = RollResult {
+            rolled_die: vec![
+                Roll::Value(5, d8),
+                Roll::Glitch(1, d6),
+                Roll::Value(3, d10),
+                Roll::Glitch(1, d4),
+            ],
+            modifier: 0,
+            rerolled: BTreeMap::new(),
+            target: None,
+            expression: None,
+        };
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        let rerolled = request.reroll_glitches(&roll, &mut rng).unwrap();
+        assert_eq!(rerolled.rerolled, BTreeMap::from([(1, 1), (3, 1)]));
+        for (i, (before, after)) in roll.rolled_die.iter().zip(&rerolled.rolled_die).enumerate() {
+            // same die in the same place either way
+            assert_eq!(before.die(), after.die());
+            if !before.is_glitch() {
+                assert_eq!(format!("{:?}", before), format!("{:?}", after), "die {}", i);
+            }
+        }
+        assert!(rerolled.dice_markdown().starts_with("5 (d8) ~~1~~ -> "));
+
+        // nothing to do without a glitch
+        let clean = RollResult {
+            rolled_die: vec![Roll::Value(5, d8), Roll::Value(2, d6)],
+            ..roll
+        };
+        assert!(DiceRollRequest::parse("1d8 1d6")
+            .unwrap()
+            .reroll_glitches(&clean, &mut rng)
+            .is_none());
+    }
+
+    #[test]
+    fn test_reroll_out_of_a_glitch() {
+        let d8 = Die { sides: 8 };
+        let roll = RollResult {
+            rolled_die: vec![Roll::Value(5, d8), Roll::Value(3, d8)],
+            modifier: 0,
+            rerolled: BTreeMap::from([(0, 1)]),
+            target: None,
+            expression: None,
+        };
+        assert!(!roll.is_botch());
+        assert_eq!(roll.sum(), 8);
+        assert!(roll.dice_markdown().starts_with("~~1~~ -> 5 (d8) 3 (d8)"));
+        assert!(!roll.short_summary().contains("Glitches"));
+    }
+
+    #[test]
+    fn test_reroll_into_another_glitch() {
+        let d8 = Die { sides: 8 };
+        let roll = RollResult {
+            rolled_die: vec![Roll::Glitch(1, d8), Roll::Value(6, d8)],
+            modifier: 0,
+            rerolled: BTreeMap::from([(0, 1)]),
+            target: None,
+            expression: None,
+        };
+        assert!(!roll.is_botch());
+        assert!(roll.dice_markdown().starts_with("~~1~~ -> **1** (d8)"));
+        // the first 1 doesn't count, so it's still just the one glitch
+        assert!(roll.short_summary().contains("1 Glitches!"));
+
+        let roll = RollResult {
+            rolled_die: vec![Roll::Glitch(1, d8)],
+            modifier: 0,
+            rerolled: BTreeMap::from([(0, 1)]),
+            target: None,
+            expression: None,
+        };
+        assert!(roll.is_botch());
+    }
+
+    #[test]
+    fn test_glitch_on() {
+        use rand::SeedableRng;
+        let d6 = Die { sides: 6 };
+        assert!(matches!(
+            d6.explode_from(&mut faces(&[2]), 2),
+            Roll::Glitch(2, _)
+        ));
+        assert!(matches!(
+            d6.explode_from(&mut faces(&[2]), 1),
+            Roll::Value(2, _)
+        ));
+
+        let request = DiceRollRequest::parse_glitching_on("20d6", 2).unwrap();
+        let roll = request.roll(&mut rand::rngs::StdRng::seed_from_u64(42));
+        let twos: Vec<&Roll> = roll.rolled_die.iter().filter(|r| r.face() == 2).collect();
+        assert!(!twos.is_empty());
+        assert!(twos.iter().all(|r| r.is_glitch()));
+        for roll in roll.rolled_die.iter() {
+            assert_eq!(roll.is_glitch(), roll.face() <= 2);
+        }
+        assert!(roll.dice_markdown().contains("**2** (d6)"));
+
+        assert_eq!(
+            DiceRollRequest::parse_glitching_on("3d8 1d2", 2).unwrap_err(),
+            ParseError::GlitchOn {
+                threshold: 2,
+                sides: 2
+            }
+        );
+        assert!(DiceRollRequest::parse_glitching_on("4dF", 2).is_ok());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let request = DiceRollRequest::parse("2d6 + 1d4 - 1").unwrap();
+        assert_eq!(request.dice.len(), 3);
+        assert_eq!(request.modifier, 0);
+        assert_eq!(
+            request.expression,
+            Some(Expr::Binary(
+                Box::new(Expr::Binary(
+                    Box::new(Expr::Dice(0..2)),
+                    Op::Add,
+                    Box::new(Expr::Dice(2..3))
+                )),
+                Op::Subtract,
+                Box::new(Expr::Constant(1))
+            ))
+        );
+        assert_eq!(request.describe(), "2d6 + 1d4 - 1");
+        let stats = request.stats();
+        assert_eq!((stats.min, stats.max, stats.mean), (2, 15, 8.5));
+
+        let d6 = Die { sides: 6 };
+        let roll = RollResult {
+            rolled_die: vec![
+                Roll::Value(5, d6),
+                Roll::Glitch(1, d6),
+                Roll::Value(3, Die { sides: 4 }),
+            ],
+            modifier: 0,
+            rerolled: BTreeMap::new(),
+            target: None,
+            expression: request.expression.clone(),
+        };
+        assert_eq!(roll.arithmetic_total(), Some(8));
+        assert_eq!(roll.total(), 8);
+        assert_eq!(roll.short_summary(), "2d6 (6) + 1d4 (3) - 1\nTotal: 8");
+
+        // the same, however it's spaced
+        for dice in ["2d6+1d4-1", "2d6 +1d4 -1", "2d6+ 1d4 - 1"] {
+            let parsed = DiceRollRequest::parse(dice).unwrap();
+            assert_eq!(parsed.expression, request.expression, "{}", dice);
+        }
+        // and it can go below zero
+        let roll = DiceRollRequest::parse("1d4 - 10")
+            .unwrap()
+            .roll(&mut rand::thread_rng());
+        assert!(roll.arithmetic_total().unwrap() < 0);
+        assert_eq!(roll.sum(), 0);
+
+        // a modifier stuck on a single term is still a pool
+        let request = DiceRollRequest::parse("2d6+5 1d8").unwrap();
+        assert_eq!(request.expression, None);
+        assert_eq!(request.modifier, 5);
+        assert_eq!(DiceRollRequest::parse("6 8").unwrap().dice.len(), 2);
+
+        let request = DiceRollRequest::parse("d20 adv + 5").unwrap();
+        assert_eq!(
+            request.describe(),
+            "2d20 + 5, keeping the highest 1 of 2d20"
+        );
+        let roll = request.roll(&mut rand::thread_rng());
+        let kept = roll.rolled_die.iter().find(|r| !r.is_dropped()).unwrap();
+        assert_eq!(roll.arithmetic_total(), Some(kept.face() as i64 + 5));
+    }
+
+    #[test]
+    fn test_mismatched_operators() {
+        for (dice, err) in [
+            ("2d6 + + 1d4", ParseError::MissingOperand("+".to_string())),
+            ("2d6 + 1d4 -", ParseError::MissingOperand("-".to_string())),
+            ("- 2d6", ParseError::MissingOperand("-".to_string())),
+            ("+2d6", ParseError::MissingOperand("+".to_string())),
+            ("2d6 +- 1", ParseError::MissingOperand("-".to_string())),
+            (
+                "2d6 1d4 + 1",
+                ParseError::MissingOperator("1d4".to_string()),
+            ),
+            ("2d6 + 1 3", ParseError::MissingOperator("3".to_string())),
+            ("3 + 2", ParseError::Empty),
+            (
+                "10d6 + 1 tn4",
+                ParseError::Arithmetic("a target number".to_string()),
+            ),
+            ("4dF - 1", ParseError::Arithmetic("Fate dice".to_string())),
+        ] {
+            assert_eq!(DiceRollRequest::parse(dice).unwrap_err(), err, "{}", dice);
+        }
+    }
+
+    #[test]
+    fn test_explosion_cap() {
+        let d2 = Die { sides: 2 };
+        let mut always_max = || 2;
+        match d2.explode_from(&mut always_max, 1) {
+            Roll::Exploded {
+                explosions, last, ..
+            } => {
+                assert_eq!(explosions, MAX_EXPLOSIONS);
+                assert_eq!(last, 2);
+            }
+            roll => panic!("expected an explosion, got {:?}", roll),
+        }
+    }
+
+    #[test]
+    fn test_malformed_dice_are_rejected() {
+        for dice in [
+            "",
+            " ",
+            "d",
+            "3d",
+            "dd6",
+            "3dd6",
+            "d6d",
+            "+6",
+            "-6",
+            "3d+6",
+            "d6+",
+            "d6kh",
+            "d6!!",
+            "ｄ6",
+            "3d6🎲",
+            "🎲",
+            "3d６",
+            "2d6+🎲",
+            "d6kh-1",
+            "99999999999999999999d6",
+            "1000000d6 1d6",
+        ] {
+            let err = DiceRollRequest::parse(dice).unwrap_err();
+            assert!(!err.to_string().is_empty(), "{:?}", dice);
+        }
+    }
+
+    #[test]
+    fn test_parser_never_panics() {
+        let pieces = [
+            "d", "D", "3", "0", "%", "+", "-", "!", "k", "h", "l", "ß", "é", "🎲", "ｄ", " ", "6",
+            "\u{0}", "kh", "dl",
+        ];
+        let mut rng = rand::thread_rng();
+        for _ in 0..20_000 {
+            let len = rng.gen_range(0..8);
+            let dice: String = (0..len)
+                .map(|_| pieces[rng.gen_range(0..pieces.len())])
+                .collect();
+            if let Ok(request) = DiceRollRequest::parse(&dice) {
+                if request.dice_count() < 100 {
+                    request.roll(&mut rng);
+                }
+            }
+        }
+        for _ in 0..5_000 {
+            let bytes: Vec<u8> = (0..rng.gen_range(0..12)).map(|_| rng.gen()).collect();
+            let _ = DiceRollRequest::parse(&String::from_utf8_lossy(&bytes));
+        }
+    }
+
+    #[test]
+    fn test_seeded_rolls_are_deterministic() {
+        use rand::SeedableRng;
+        let request = DiceRollRequest::parse("3d6 1d8 2d10! 4d6kh3+1").unwrap();
+        let first = request.roll(&mut rand::rngs::StdRng::seed_from_u64(42));
+        let second = request.roll(&mut rand::rngs::StdRng::seed_from_u64(42));
+        assert_eq!(first.to_discord_markdown(), second.to_discord_markdown());
+        assert_eq!(first.sum(), second.sum());
+        assert_eq!(first.get_highest_total(), second.get_highest_total());
+        assert_eq!(first.get_highest_effect(), second.get_highest_effect());
+    }
+
+    #[test]
+    fn test_pool_description_matches_roll() {
+        let request = DiceRollRequest::parse("d6 10 3d6 1d4").unwrap();
+        let description = request.describe();
+        assert_eq!(description, "4d6 1d10 1d4");
+        let roll = request.roll(&mut rand::thread_rng());
+        assert_eq!(
+            describe_pool(roll.rolled_die.iter().map(|r| r.die())),
+            description
+        );
+    }
+
+    #[test]
+    fn test_reaches() {
+        let roll = |rolled_die: Vec<Roll>| RollResult {
+            rolled_die,
+            modifier: 0,
+            rerolled: BTreeMap::new(),
+            target: None,
+            expression: None,
+        };
+        let (d6, d8, d12) = (Die { sides: 6 }, Die { sides: 8 }, Die { sides: 12 });
+        let three = roll(vec![
+            Roll::Value(5, d6),
+            Roll::Value(6, d8),
+            Roll::Value(2, d12),
+        ]);
+        // 5 + 6 with the d12 for effect
+        assert!(three.reaches(11, 12));
+        assert!(!three.reaches(12, 4));
+        // the d12 counts as a d8 effect or better, but 12 needs it in the total too
+        assert!(three.reaches(11, 8));
+        assert!(!three.reaches(12, 8));
+        // with two dice, it's both for the total or one for the effect
+        let two = roll(vec![Roll::Value(5, d6), Roll::Value(6, d8)]);
+        assert!(two.reaches(11, 4));
+        assert!(two.reaches(5, 8));
+        assert!(!two.reaches(6, 8));
+        assert!(!roll(vec![Roll::Glitch(1, d6), Roll::Glitch(1, d8)]).reaches(0, 4));
+    }
+
+    #[test]
+    fn test_reveal_steps() {
+        use rand::SeedableRng;
+
+        let d6 = Die { sides: 6 };
+        let roll = |rolled_die: Vec<Roll>| RollResult {
+            rolled_die,
+            modifier: 0,
+            rerolled: BTreeMap::new(),
+            target: None,
+            expression: None,
+        };
+        assert_eq!(
+            reveal_steps(" 2d6 ", &roll(vec![Roll::Value(4, d6), Roll::Value(3, d6)])),
+            vec!["Rolling 2d6..."]
+        );
+        assert_eq!(
+            reveal_steps(
+                "3d6",
+                &roll(vec![
+                    Roll::Glitch(1, d6),
+                    Roll::Value(5, d6),
+                    Roll::Glitch(1, d6)
+                ])
+            ),
+            vec!["Rolling 3d6...", "Rolling 3d6... 2 glitches!"]
+        );
+        assert_eq!(
+            reveal_steps("2d6", &roll(vec![Roll::Glitch(1, d6), Roll::Value(5, d6)])),
+            vec!["Rolling 2d6...", "Rolling 2d6... a glitch!"]
+        );
+        assert_eq!(
+            reveal_steps("2d6", &roll(vec![Roll::Glitch(1, d6), Roll::Glitch(1, d6)])),
+            vec!["Rolling 2d6...", "Rolling 2d6... oh no."]
+        );
+        // arithmetic rolls don't glitch
+        let request = DiceRollRequest::parse("1d6 + 1d6").unwrap();
+        let roll = request.roll(&mut rand::rngs::StdRng::seed_from_u64(1));
+        assert_eq!(
+            reveal_steps("1d6 + 1d6", &roll),
+            vec!["Rolling 1d6 + 1d6..."]
+        );
+    }
+}
@@ -0,0 +1,296 @@
+// Bits shared by the plain dice roller and the sparkle roller.
+
+use crate::data::{Context, Error};
+use crate::preferences::Flavor;
+
+// Discord won't send a message longer than this, in characters.
+pub(crate) const MAX_MESSAGE_LEN: usize = 2000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    TooManyDice,
+    // the whole term, when it isn't dice at all
+    InvalidDie(String),
+    // a die size that shimmer doesn't have, like a d7
+    UnknownSides(i32),
+    TooFewSides(u64),
+    BadModifier(String),
+    BadKeep(String),
+    // adv or dis without a single die right before it
+    Advantage(String),
+    // a `Nx` prefix asking for zero rolls, or too many
+    Repeats(u64),
+    // a target number like `tn4` that isn't a number, or a second one
+    BadTarget(String),
+    // Fate dice with anything but a modifier, like `4dF!` or `4dF 1d6`
+    Fudge(String),
+    // a die too small for GLITCH_ON, which would make it glitch every time
+    GlitchOn { threshold: u64, sides: u64 },
+    // a + or - with nothing to add or take away on one side of it
+    MissingOperand(String),
+    // two terms side by side in a roll that's otherwise added up, like `2d6 1d4 + 1`
+    MissingOperator(String),
+    // something that has no total to add to or take away from, like Fate dice
+    Arithmetic(String),
+}
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "Give me some dice to roll, like `3d6`"),
+            ParseError::TooManyDice => {
+                write!(f, "Hey buddy, I'm just a demigod, that's too many dice!")
+            }
+            ParseError::InvalidDie(term) => {
+                write!(f, "Expected {} to be like XdY, e.g. 3d6 or 1d8", term)
+            }
+            ParseError::UnknownSides(sides) => write!(
+                f,
+                "There's no such thing as a d{}, chum. Try a d4, d6, d8, d10 or d12",
+                sides
+            ),
+            ParseError::TooFewSides(sides) => write!(
+                f,
+                "A d{} isn't much of a die, chum. Give me at least two sides",
+                sides
+            ),
+            ParseError::BadModifier(term) => {
+                write!(f, "Expected {} to end in a modifier like +2 or -1", term)
+            }
+            ParseError::BadKeep(term) => write!(
+                f,
+                "Expected {} to keep or drop some number of dice, like 4d6kh3",
+                term
+            ),
+            ParseError::Advantage(term) => write!(
+                f,
+                "{} goes right after a single die, like `d20 {}`",
+                term, term
+            ),
+            ParseError::BadTarget(term) => write!(
+                f,
+                "Expected {} to be a single target number, like `10d6 tn4`",
+                term
+            ),
+            ParseError::Fudge(term) => write!(
+                f,
+                "Fate dice don't mix with {}, chum. Just the dF and a modifier, like `4dF+2`",
+                term
+            ),
+            ParseError::GlitchOn { threshold, sides } => write!(
+                f,
+                "Everything {} or under glitches here, so a d{} would glitch every time. Use dice with more than {} sides",
+                threshold, sides, threshold
+            ),
+            ParseError::MissingOperand(op) => write!(
+                f,
+                "Expected dice or a number on both sides of that {}, like `2d6 + 1d4 - 1`",
+                op
+            ),
+            ParseError::MissingOperator(term) => write!(
+                f,
+                "Expected a + or - before {}, since the rest of that gets added up",
+                term
+            ),
+            ParseError::Arithmetic(what) => write!(
+                f,
+                "I can't add and take away with {}, chum. Try it without the + and -",
+                what
+            ),
+            ParseError::Repeats(times) => write!(
+                f,
+                "I'll roll that anywhere from 1 to 20 times, chum, but not {}",
+                times
+            ),
+        }
+    }
+}
+impl std::error::Error for ParseError {}
+impl ParseError {
+    // What to tell someone who'd rather the demigod kept it short. Only the chatty ones
+    // are any different.
+    pub(crate) fn message(&self, flavor: Flavor) -> String {
+        if flavor == Flavor::Flavorful {
+            return self.to_string();
+        }
+        match self {
+            ParseError::TooManyDice => "Too many dice.".to_string(),
+            ParseError::UnknownSides(sides) => {
+                format!("There's no d{}. Use a d4, d6, d8, d10 or d12", sides)
+            }
+            ParseError::TooFewSides(sides) => {
+                format!("A d{} is too small, dice need at least two sides", sides)
+            }
+            ParseError::Fudge(term) => format!(
+                "Fate dice don't mix with {}. Use just the dF and a modifier, like `4dF+2`",
+                term
+            ),
+            ParseError::Arithmetic(what) => {
+                format!(
+                    "Can't add and take away with {}. Leave out the + and -",
+                    what
+                )
+            }
+            ParseError::Repeats(times) => {
+                format!("A roll can be repeated 1 to 20 times, not {}", times)
+            }
+            err => err.to_string(),
+        }
+    }
+}
+
+// What goes above the quick summary of a roll with too many dice to show. `in_file` is
+// whether every die is attached instead.
+pub(crate) fn too_many_to_show(dice: &str, flavor: Flavor, in_file: bool) -> String {
+    match (flavor, in_file) {
+        (Flavor::Flavorful, true) => format!(
+            "Roll {}?? hoo.. that's a lot. Here's the quick summary, every last die is in the file:",
+            dice
+        ),
+        (Flavor::Flavorful, false) => format!(
+            "Roll {}?? hoo.. that's a lot. I don't wanna flood the chat here, so, uh, I'll give you the quick summary:",
+            dice
+        ),
+        (Flavor::Terse, true) => format!("Rolled {}, every die is in the file:", dice),
+        (Flavor::Terse, false) => format!("Rolled {}, too many dice to show:", dice),
+    }
+}
+
+// What to do with a single roll that's too long to show in one message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Overflow {
+    // just the totals
+    #[default]
+    Summary,
+    // the totals, with every die in a .txt file
+    Attachment,
+    // as many dice as fit, then the totals
+    Truncate,
+}
+impl Overflow {
+    pub(crate) const NAMES: &'static [&'static str] = &["summary", "attachment", "truncate"];
+
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "summary" => Some(Overflow::Summary),
+            "attachment" => Some(Overflow::Attachment),
+            "truncate" => Some(Overflow::Truncate),
+            _ => None,
+        }
+    }
+}
+
+// Dice glitch when they come up this or lower. Some hacks glitch on 1 or 2.
+pub(crate) fn glitch_on() -> u64 {
+    std::env::var("GLITCH_ON")
+        .ok()
+        .and_then(|threshold| threshold.trim().parse().ok())
+        .filter(|&threshold| threshold >= 1)
+        .unwrap_or(1)
+}
+
+// Sends `content` as however many messages it takes to stay under Discord's limit, with
+// room left on each for the maintenance banner.
+pub(crate) async fn say_chunked(
+    ctx: Context<'_>,
+    content: &str,
+    ephemeral: bool,
+) -> Result<(), Error> {
+    let banner = ctx
+        .data()
+        .banner()
+        .map_or(0, |banner| banner.chars().count() + 2);
+    let max = MAX_MESSAGE_LEN
+        .saturating_sub(banner)
+        .max(MAX_MESSAGE_LEN / 2);
+    for chunk in chunk_message(content, max) {
+        ctx.send(|m| m.content(chunk).ephemeral(ephemeral)).await?;
+    }
+    Ok(())
+}
+
+// Breaks between lines where it can, then between words, and only cuts a word in half
+// when a single word is over the limit.
+pub(crate) fn chunk_message(content: &str, max: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    for line in content.split_inclusive('\n') {
+        if line.chars().count() <= max {
+            pieces.push(line.to_string());
+            continue;
+        }
+        for word in line.split_inclusive(' ') {
+            let chars: Vec<char> = word.chars().collect();
+            pieces.extend(chars.chunks(max).map(|c| c.iter().collect::<String>()));
+        }
+    }
+    let mut chunks = Vec::new();
+    let mut chunk = String::new();
+    let mut len = 0;
+    for piece in pieces {
+        let piece_len = piece.chars().count();
+        if len + piece_len > max {
+            chunks.push(std::mem::take(&mut chunk));
+            len = 0;
+        }
+        chunk += &piece;
+        len += piece_len;
+    }
+    chunks.push(chunk);
+    // Discord won't send an empty message either
+    chunks.retain(|chunk| !chunk.trim().is_empty());
+    chunks
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks_stay_under_the_limit() {
+        assert_eq!(chunk_message("short", 10), vec!["short".to_string()]);
+        assert_eq!(
+            chunk_message("one\ntwo\nthree", 9),
+            vec!["one\ntwo\n".to_string(), "three".to_string()]
+        );
+        let chunks = chunk_message("aaaa bbbbbbbbbbbb cc", 5);
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 5));
+        assert_eq!(chunks.concat(), "aaaa bbbbbbbbbbbb cc");
+        assert!(chunk_message("\n\n", 1).is_empty());
+    }
+
+    #[test]
+    fn test_terse_messages() {
+        let chatty = [
+            ParseError::TooManyDice,
+            ParseError::UnknownSides(7),
+            ParseError::TooFewSides(1),
+            ParseError::Fudge("1d6".to_string()),
+            ParseError::Arithmetic("Fate dice".to_string()),
+            ParseError::Repeats(50),
+        ];
+        for err in chatty.iter() {
+            assert_eq!(err.message(Flavor::Flavorful), err.to_string());
+            let terse = err.message(Flavor::Terse);
+            assert_ne!(terse, err.to_string());
+            assert!(
+                !terse.contains("chum") && !terse.contains("demigod"),
+                "{}",
+                terse
+            );
+        }
+        let plain = ParseError::BadModifier("3d6+x".to_string());
+        assert_eq!(plain.message(Flavor::Terse), plain.to_string());
+
+        for in_file in [true, false] {
+            assert!(too_many_to_show("5000d6", Flavor::Flavorful, in_file).contains("hoo"));
+            let terse = too_many_to_show("5000d6", Flavor::Terse, in_file);
+            assert!(
+                terse.contains("5000d6") && !terse.contains("hoo"),
+                "{}",
+                terse
+            );
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use chrono::{DateTime, Utc};
+use poise::serenity_prelude as serenity;
+
+use crate::data::{Context, Error};
+use crate::dice::{CortexResult, DiceRollRequest, RollResult};
+
+// History only lives in memory. It's for looking back over a session, not an archive.
+const MAX_ROLLS_PER_USER: usize = 500;
+// Long enough for `initiative` or `bob's stealth`, short enough to type.
+const MAX_LABEL_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct RollRecord {
+    pub(crate) timestamp: DateTime<Utc>,
+    pub(crate) expression: String,
+    // can be negative for Fate dice or arithmetic like `1d4 - 3`
+    pub(crate) total: i64,
+    // None for a botch
+    pub(crate) effect: Option<String>,
+    pub(crate) botch: bool,
+}
+impl RollRecord {
+    pub(crate) fn new(expression: &str, roll: &RollResult) -> Self {
+        let fixed_total = roll.fate_total().or_else(|| roll.arithmetic_total());
+        let (total, effect) = match (fixed_total, roll.get_highest_total()) {
+            (Some(total), _) => (total, None),
+            (None, CortexResult::Botch) => (0, None),
+            (None, CortexResult::Result { total, effect }) => {
+                (total as i64, Some(effect.to_string()))
+            }
+        };
+        Self {
+            timestamp: Utc::now(),
+            expression: expression.to_string(),
+            total,
+            effect,
+            botch: roll.is_botch(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct RollHistory {
+    rolls: HashMap<u64, VecDeque<RollRecord>>,
+}
+impl RollHistory {
+    pub(crate) fn record(&mut self, user: u64, record: RollRecord) {
+        let rolls = self.rolls.entry(user).or_default();
+        if rolls.len() >= MAX_ROLLS_PER_USER {
+            rolls.pop_front();
+        }
+        rolls.push_back(record);
+    }
+
+    // Oldest first.
+    pub(crate) fn for_user(&self, user: u64) -> Vec<RollRecord> {
+        self.rolls
+            .get(&user)
+            .map(|rolls| rolls.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+// Rolls kept under a label, per user and per channel, so `initiative` in one game doesn't
+// clobber it in another. Like the history, these only live in memory.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Pins {
+    // user id, then channel id, then label
+    pins: HashMap<u64, HashMap<u64, BTreeMap<String, RollRecord>>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PinOutcome {
+    Pinned,
+    // what used to be pinned there
+    Replaced(RollRecord),
+    // nothing changed, since overwriting wasn't confirmed
+    AlreadyPinned(RollRecord),
+}
+
+impl Pins {
+    // `label` should already be through `normalize_label`.
+    pub(crate) fn pin(
+        &mut self,
+        user: u64,
+        channel: u64,
+        label: &str,
+        record: RollRecord,
+        overwrite: bool,
+    ) -> PinOutcome {
+        let labels = self
+            .pins
+            .entry(user)
+            .or_default()
+            .entry(channel)
+            .or_default();
+        match labels.get(label) {
+            Some(existing) if !overwrite => PinOutcome::AlreadyPinned(existing.clone()),
+            _ => match labels.insert(label.to_string(), record) {
+                None => PinOutcome::Pinned,
+                Some(old) => PinOutcome::Replaced(old),
+            },
+        }
+    }
+
+    pub(crate) fn recall(&self, user: u64, channel: u64, label: &str) -> Option<&RollRecord> {
+        self.pins.get(&user)?.get(&channel)?.get(label)
+    }
+}
+
+// Labels don't care about case or stray spaces.
+fn normalize_label(label: &str) -> Result<String, String> {
+    let label = label.trim().to_lowercase();
+    if label.is_empty() {
+        return Err("Give it a label, like `initiative`.".to_string());
+    }
+    if label.chars().count() > MAX_LABEL_LEN {
+        return Err(format!(
+            "That label's a mouthful, chum. Keep it to {} characters.",
+            MAX_LABEL_LEN
+        ));
+    }
+    Ok(label)
+}
+
+fn describe_pin(label: &str, record: &RollRecord) -> String {
+    let result = match (&record.effect, record.botch) {
+        (_, true) => "a botch".to_string(),
+        (Some(effect), false) => format!("{}, effect {}", record.total, effect),
+        (None, false) => record.total.to_string(),
+    };
+    format!(
+        "**{}**: {} came to {} <t:{}:R>",
+        label,
+        record.expression,
+        result,
+        record.timestamp.timestamp()
+    )
+}
+
+// Rolls and keeps the result under a label, to look up later with /recall.
+#[poise::command(slash_command)]
+pub async fn pin_roll(
+    ctx: Context<'_>,
+    #[description = "What to call it, like `initiative`"] label: String,
+    #[description = "The dice to roll, like `3d8 1d10`"] dice: String,
+    #[description = "Replace whatever's already pinned under this label"] overwrite: Option<bool>,
+) -> Result<(), Error> {
+    let label = match normalize_label(&label) {
+        Err(err) => {
+            ctx.send(|m| m.content(err).ephemeral(true)).await?;
+            return Ok(());
+        }
+        Ok(label) => label,
+    };
+    let dice = dice.trim();
+    let roll = match DiceRollRequest::parse(dice) {
+        Err(err) => {
+            let flavor = crate::settings::flavor_for(ctx.data(), ctx.author().id.0).await;
+            ctx.send(|m| m.content(err.message(flavor)).ephemeral(true))
+                .await?;
+            return Ok(());
+        }
+        Ok(request) => request.roll(&mut rand::thread_rng()),
+    };
+    let (user, channel) = (ctx.author().id.0, ctx.channel_id().0);
+    let record = RollRecord::new(dice, &roll);
+    let outcome = ctx.data().pins.lock().await.pin(
+        user,
+        channel,
+        &label,
+        record.clone(),
+        overwrite.unwrap_or(false),
+    );
+    let pinned = match outcome {
+        PinOutcome::AlreadyPinned(existing) => {
+            let content = format!(
+                "You've already pinned {} in this channel. Pin again with `overwrite: True` to replace it.",
+                describe_pin(&label, &existing)
+            );
+            ctx.send(|m| m.content(content).ephemeral(true)).await?;
+            return Ok(());
+        }
+        PinOutcome::Pinned => format!("Pinned as **{}**.", label),
+        PinOutcome::Replaced(old) => format!(
+            "Pinned as **{}**, replacing {}.",
+            label,
+            describe_pin(&label, &old)
+        ),
+    };
+    ctx.data().history.lock().await.record(user, record);
+    let response = format!(
+        "Rolling {}\n\n{}\n\n{}",
+        dice,
+        roll.to_discord_markdown().trim(),
+        pinned
+    );
+    crate::dice_common::say_chunked(ctx, &response, false).await?;
+    Ok(())
+}
+
+// Looks up something you pinned with /pin_roll in this channel.
+#[poise::command(slash_command, prefix_command)]
+pub async fn recall(
+    ctx: Context<'_>,
+    #[description = "The label you pinned it under"]
+    #[rest]
+    label: String,
+) -> Result<(), Error> {
+    let label = normalize_label(&label).unwrap_or_else(|_| label.trim().to_string());
+    let record = ctx
+        .data()
+        .pins
+        .lock()
+        .await
+        .recall(ctx.author().id.0, ctx.channel_id().0, &label)
+        .cloned();
+    let response = match record {
+        None => format!(
+            "You haven't pinned anything as **{}** in this channel, chum. Try /pin_roll.",
+            label
+        ),
+        Some(record) => describe_pin(&label, &record),
+    };
+    ctx.say(response).await?;
+    Ok(())
+}
+
+// DMs you your own roll history, nobody else's.
+#[poise::command(slash_command)]
+pub async fn export_rolls(ctx: Context<'_>) -> Result<(), Error> {
+    let records = ctx.data().history.lock().await.for_user(ctx.author().id.0);
+    if records.is_empty() {
+        ctx.send(|m| {
+            m.content("You haven't rolled anything since I last woke up, chum.")
+                .ephemeral(true)
+        })
+        .await?;
+        return Ok(());
+    }
+    let csv = to_csv(&records);
+    ctx.author()
+        .direct_message(ctx, |m| {
+            m.content(format!("Here are your last {} rolls.", records.len()))
+                .add_file(serenity::AttachmentType::Bytes {
+                    data: std::borrow::Cow::Owned(csv.into_bytes()),
+                    filename: "rolls.csv".to_string(),
+                })
+        })
+        .await?;
+    ctx.send(|m| m.content("Sent you a DM!").ephemeral(true))
+        .await?;
+    Ok(())
+}
+
+fn to_csv(records: &[RollRecord]) -> String {
+    let mut csv = String::from("timestamp,expression,total,effect,botch\n");
+    for record in records {
+        csv += &format!(
+            "{},{},{},{},{}\n",
+            record.timestamp.to_rfc3339(),
+            csv_field(&record.expression),
+            record.total,
+            csv_field(record.effect.as_deref().unwrap_or("")),
+            record.botch
+        );
+    }
+    csv
+}
+
+// Quotes a field if it needs it, doubling any quotes inside. Anything a spreadsheet would
+// take for a formula gets a ' in front, so opening the export can't run one.
+fn csv_field(field: &str) -> String {
+    let field = match field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        true => format!("'{}", field),
+        false => field.to_string(),
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(expression: &str) -> RollRecord {
+        RollRecord {
+            timestamp: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            expression: expression.to_string(),
+            total: 9,
+            effect: Some("d8".to_string()),
+            botch: false,
+        }
+    }
+
+    #[test]
+    fn test_csv_escaping() {
+        let csv = to_csv(&[
+            record("3d8 1d10"),
+            record("2d6, then \"some\" more"),
+            RollRecord {
+                total: 0,
+                effect: None,
+                botch: true,
+                ..record("d4")
+            },
+        ]);
+        assert_eq!(
+            csv,
+            "timestamp,expression,total,effect,botch\n\
+             2023-11-14T22:13:20+00:00,3d8 1d10,9,d8,false\n\
+             2023-11-14T22:13:20+00:00,\"2d6, then \"\"some\"\" more\",9,d8,false\n\
+             2023-11-14T22:13:20+00:00,d4,0,,true\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_formulas_are_defused() {
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("+1d6"), "'+1d6");
+        assert_eq!(csv_field("-2"), "'-2");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("2d6-1"), "2d6-1");
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn test_pin_and_recall() {
+        let mut pins = Pins::default();
+        assert_eq!(
+            pins.pin(1, 10, "initiative", record("3d8"), false),
+            PinOutcome::Pinned
+        );
+        assert_eq!(pins.recall(1, 10, "initiative"), Some(&record("3d8")));
+        // other channels and other people have their own
+        assert_eq!(pins.recall(1, 11, "initiative"), None);
+        assert_eq!(pins.recall(2, 10, "initiative"), None);
+        assert_eq!(pins.recall(1, 10, "stealth"), None);
+        assert_eq!(normalize_label("  Initiative ").unwrap(), "initiative");
+        assert!(normalize_label(" ").is_err());
+        assert!(normalize_label(&"x".repeat(MAX_LABEL_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_overwriting_a_pin_needs_confirmation() {
+        let mut pins = Pins::default();
+        let _ = pins.pin(1, 10, "initiative", record("3d8"), false);
+        assert_eq!(
+            pins.pin(1, 10, "initiative", record("2d6"), false),
+            PinOutcome::AlreadyPinned(record("3d8"))
+        );
+        assert_eq!(pins.recall(1, 10, "initiative"), Some(&record("3d8")));
+        assert_eq!(
+            pins.pin(1, 10, "initiative", record("2d6"), true),
+            PinOutcome::Replaced(record("3d8"))
+        );
+        assert_eq!(pins.recall(1, 10, "initiative"), Some(&record("2d6")));
+        // nothing to confirm the first time
+        assert_eq!(
+            pins.pin(1, 10, "stealth", record("d4"), true),
+            PinOutcome::Pinned
+        );
+    }
+
+    #[test]
+    fn test_describe_pin() {
+        assert_eq!(
+            describe_pin("initiative", &record("3d8")),
+            "**initiative**: 3d8 came to 9, effect d8 <t:1700000000:R>"
+        );
+        let botch = RollRecord {
+            total: 0,
+            effect: None,
+            botch: true,
+            ..record("d4")
+        };
+        assert_eq!(
+            describe_pin("x", &botch),
+            "**x**: d4 came to a botch <t:1700000000:R>"
+        );
+    }
+
+    #[test]
+    fn test_history_is_per_user_and_bounded() {
+        let mut history = RollHistory::default();
+        for i in 0..MAX_ROLLS_PER_USER + 5 {
+            history.record(1, record(&format!("{}d6", i + 1)));
+        }
+        history.record(2, record("d4"));
+        let rolls = history.for_user(1);
+        assert_eq!(rolls.len(), MAX_ROLLS_PER_USER);
+        assert_eq!(rolls[0].expression, "6d6");
+        assert_eq!(history.for_user(2), vec![record("d4")]);
+        assert!(history.for_user(3).is_empty());
+    }
+}
@@ -1,22 +1,362 @@
-use crate::data::{self, Context, Error};
+use futures::StreamExt;
+use poise::serenity_prelude as serenity;
+
+use crate::data::{self, Context, Error, Ranking};
+use crate::settings;
 
 #[poise::command(slash_command)]
 pub async fn info(ctx: Context<'_>) -> Result<(), Error> {
-    let account = data::get_account(ctx.data(), ctx.author()).await?;
+    let account = data::get_account(ctx.data(), ctx.author(), ctx.guild_id()).await?;
 
-    // need to format these numbers from millicents to just dollars and cents!
-    // dividing by a million isn't right lol
-    ctx.send(|m| {
-    let m = if account.overdrafted() {
-      m.content(format!("You should take rictic out to lunch! Or just ping him and venmo him like 20 bucks. He'll update your limits. Your credits stand at ${}, you've used ${} worth of credits all time, and generated {} images.", (account.credit as f64)  / 100_000.0, (account.total_cost as f64) / 10_000.0, account.images))
+    let content = if account.overdrafted() {
+        format!("You should take rictic out to lunch! Or just ping him and venmo him like 20 bucks. He'll update your limits. Your credits stand at ${:.2}, you've used ${:.2} worth of credits all time, and generated {} images.", account.credit_dollars(), account.total_cost_dollars(), account.images)
     } else {
-      m.content(format!(
-        "You've got ${} worth of rictic image generation credits left until you should take him out to lunch sometime. You've used ${} worth of credits all time, and generated {} images.",
-        (account.credit as f64) / 100_000.0,
-        (account.total_cost as f64) /  100_000.0, account.images
-      ))
+        format!(
+            "You've got ${:.2} worth of rictic image generation credits left until you should take him out to lunch sometime. You've used ${:.2} worth of credits all time, and generated {} images.",
+            account.credit_dollars(),
+            account.total_cost_dollars(), account.images
+        )
+    };
+    let ephemeral = should_be_ephemeral(ctx.guild_id());
+    ctx.send(|m| m.content(content).ephemeral(ephemeral))
+        .await?;
+    Ok(())
+}
+
+// Lets someone cap their own spending each month, however much credit they've got left.
+#[poise::command(slash_command)]
+pub async fn set_limit(
+    ctx: Context<'_>,
+    #[description = "The most you want to spend in a month, in dollars. Leave it off for no limit"]
+    dollars: Option<f64>,
+) -> Result<(), Error> {
+    let monthly = match dollars {
+        Some(dollars) if !dollars.is_finite() || dollars < 0.0 => {
+            ctx.send(|m| {
+                m.content("The limit has to be a dollar amount, zero or more")
+                    .ephemeral(true)
+            })
+            .await?;
+            return Ok(());
+        }
+        Some(dollars) => Some((dollars * 100_000.0).round() as i64),
+        None => None,
+    };
+    let account = data::set_self_limit(ctx.data(), ctx.author(), monthly).await?;
+    let spent = account.self_limit.spent_in(chrono::Utc::now());
+    let response = match monthly {
+        None => "No limit, then. Spend away!".to_string(),
+        Some(monthly) => format!(
+            "You'll be stopped at {} a month. So far this month you've spent {}.",
+            format_millicents(monthly),
+            format_millicents(spent)
+        ),
     };
-    m.ephemeral(true)
-  }).await?;
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
     Ok(())
 }
+
+#[poise::command(slash_command)]
+pub async fn topup_needed(
+    ctx: Context<'_>,
+    #[description = "The balance you want to end up with, in dollars. Leave it off to just clear any overdraft"]
+    target: Option<f64>,
+) -> Result<(), Error> {
+    let account = data::get_account(ctx.data(), ctx.author(), ctx.guild_id()).await?;
+    let content = match target {
+        Some(target) if !target.is_finite() || target < 0.0 => {
+            "The target has to be a dollar amount, zero or more".to_string()
+        }
+        _ => {
+            let target = (target.unwrap_or(0.0) * 100_000.0).round() as i64;
+            match topup_cents(account.credit, target) {
+                0 => "You're all set, no top-up needed!".to_string(),
+                cents => format!(
+                    "Venmo rictic {} and he'll get you there.",
+                    format_cents(cents)
+                ),
+            }
+        }
+    };
+    let ephemeral = should_be_ephemeral(ctx.guild_id());
+    ctx.send(|m| m.content(content).ephemeral(ephemeral))
+        .await?;
+    Ok(())
+}
+
+// Hands out the same credit to everyone with a role, e.g. before a game night.
+#[poise::command(slash_command, guild_only)]
+pub async fn grant_role(
+    ctx: Context<'_>,
+    #[description = "Everyone with this role gets the credit"] role: serenity::Role,
+    #[description = "How many dollars each of them gets"] dollars: f64,
+) -> Result<(), Error> {
+    if !settings::ensure_admin(ctx).await? {
+        return Ok(());
+    }
+    let guild_id = match ctx.guild_id() {
+        None => return Ok(()),
+        Some(guild_id) => guild_id,
+    };
+    if !dollars.is_finite() || dollars <= 0.0 {
+        ctx.send(|m| {
+            m.content("The grant has to be a dollar amount more than zero")
+                .ephemeral(true)
+        })
+        .await?;
+        return Ok(());
+    }
+    let mut users = Vec::new();
+    let mut members = guild_id.members_iter(ctx).boxed();
+    while let Some(member) = members.next().await {
+        let member = member?;
+        if member.roles.contains(&role.id) {
+            users.push(member.user);
+        }
+    }
+    let credit = (dollars * 100_000.0).round() as i64;
+    let granted = data::grant_credit(ctx.data(), &users, credit).await?;
+    let response = format!(
+        "Gave {} to {} accounts with the {} role.",
+        format_cents((credit + 999) / 1000),
+        granted,
+        role.name
+    );
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+    Ok(())
+}
+
+// Tops up one person, e.g. after they've paid rictic back.
+#[poise::command(slash_command)]
+pub async fn grant(
+    ctx: Context<'_>,
+    #[description = "Who gets the credit"] user: serenity::User,
+    #[description = "How many dollars they get"] dollars: f64,
+) -> Result<(), Error> {
+    if !settings::ensure_admin(ctx).await? {
+        return Ok(());
+    }
+    if !dollars.is_finite() || dollars <= 0.0 {
+        ctx.send(|m| {
+            m.content("The grant has to be a dollar amount more than zero")
+                .ephemeral(true)
+        })
+        .await?;
+        return Ok(());
+    }
+    let credit = (dollars * 100_000.0).round() as i64;
+    let account = data::add_credit(ctx.data(), &user, credit).await?;
+    let balance = if account.overdrafted() {
+        format!("still {} in the hole", format_millicents(-account.credit))
+    } else {
+        format!("now at {}", format_millicents(account.credit))
+    };
+    let response = format!(
+        "Gave {} to {}, they're {}.",
+        format_cents((credit + 999) / 1000),
+        user.name,
+        balance
+    );
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+    Ok(())
+}
+
+// Who's generated the most, and who's spent the most.
+#[poise::command(slash_command)]
+pub async fn leaderboard(
+    ctx: Context<'_>,
+    #[description = "How many places to show, 10 by default and 15 at most"] count: Option<u8>,
+    #[description = "Only you get to see it"] private: Option<bool>,
+) -> Result<(), Error> {
+    // any more and a long list of names won't fit in an embed field
+    let n = count.unwrap_or(10).clamp(1, 15) as usize;
+    let by_images = data::top_accounts(ctx.data(), n, Ranking::Images).await?;
+    let by_cost = data::top_accounts(ctx.data(), n, Ranking::TotalCost).await?;
+    let images = standings(&by_images, |account| {
+        format!(
+            "{} image{}",
+            account.images,
+            if account.images == 1 { "" } else { "s" }
+        )
+    });
+    let cost = standings(&by_cost, |account| format_millicents(account.total_cost));
+    ctx.send(|m| {
+        // nothing but room for the maintenance banner, if there is one
+        m.content("")
+            .ephemeral(private.unwrap_or(false))
+            .embed(|e| {
+                e.title("Leaderboard")
+                    .field("Most images", images, true)
+                    .field("Most spent", cost, true)
+            })
+    })
+    .await?;
+    Ok(())
+}
+
+// How you stack up against someone else. Nothing the leaderboard doesn't already show.
+#[poise::command(slash_command)]
+pub async fn compare(
+    ctx: Context<'_>,
+    #[description = "Who to measure yourself against"] user: serenity::User,
+) -> Result<(), Error> {
+    // their own accounts, like on the leaderboard, not a server's shared pool
+    let mine = data::get_account(ctx.data(), ctx.author(), None).await?;
+    let theirs = data::get_account(ctx.data(), &user, None).await?;
+    let response = comparison(&mine, &theirs);
+    ctx.send(|m| m.content(response)).await?;
+    Ok(())
+}
+
+fn comparison(mine: &data::Account, theirs: &data::Account) -> String {
+    let images = |account: &data::Account| {
+        format!(
+            "{} image{}",
+            account.images,
+            if account.images == 1 { "" } else { "s" }
+        )
+    };
+    let verdict = match mine.total_cost.cmp(&theirs.total_cost) {
+        std::cmp::Ordering::Equal => "Dead even. Suspicious.".to_string(),
+        std::cmp::Ordering::Greater => format!(
+            "You're ahead by {}. Big spender!",
+            format_millicents(mine.total_cost - theirs.total_cost)
+        ),
+        std::cmp::Ordering::Less => format!(
+            "{} is ahead by {}. Better get generating.",
+            theirs.user,
+            format_millicents(theirs.total_cost - mine.total_cost)
+        ),
+    };
+    format!(
+        "**You**: {}, {} spent\n**{}**: {}, {} spent\n{}",
+        images(mine),
+        format_millicents(mine.total_cost),
+        theirs.user,
+        images(theirs),
+        format_millicents(theirs.total_cost),
+        verdict
+    )
+}
+
+// One line per place, with medals for the podium.
+fn standings(top: &[(u64, data::Account)], score: impl Fn(&data::Account) -> String) -> String {
+    if top.is_empty() {
+        return "Nobody yet!".to_string();
+    }
+    top.iter()
+        .enumerate()
+        .map(|(i, (_, account))| {
+            let place = match i {
+                0 => "🥇".to_string(),
+                1 => "🥈".to_string(),
+                2 => "🥉".to_string(),
+                _ => format!("{}.", i + 1),
+            };
+            format!("{} {}: {}", place, account.user, score(account))
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// What the operator owes in prepaid credit, and is owed in overdrafts.
+#[poise::command(slash_command)]
+pub async fn liability(ctx: Context<'_>) -> Result<(), Error> {
+    if !settings::ensure_admin(ctx).await? {
+        return Ok(());
+    }
+    let liability = data::liability(ctx.data()).await?;
+    let response = format!(
+        "Users have {} of credit left to spend, and overdrafted accounts owe {}.",
+        format_millicents(liability.prepaid),
+        format_millicents(liability.overdrawn)
+    );
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+    Ok(())
+}
+
+// How many cents it takes to get from `credit` to `target` (both in millicents), rounded
+// up so that paying it actually gets you there.
+fn topup_cents(credit: i64, target: i64) -> i64 {
+    let shortfall = target.saturating_sub(credit).max(0);
+    (shortfall + 999) / 1000
+}
+
+// Rounded to the nearest cent. Only for amounts that are zero or more.
+pub(crate) fn format_millicents(millicents: i64) -> String {
+    format_cents((millicents.max(0) + 500) / 1000)
+}
+
+fn format_cents(cents: i64) -> String {
+    format!("${}.{:02}", cents / 100, cents % 100)
+}
+
+// Nobody else can see a DM, so only hide account details when we're in a server.
+fn should_be_ephemeral(guild_id: Option<serenity::GuildId>) -> bool {
+    guild_id.is_some()
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ephemeral_only_in_guilds() {
+        assert!(should_be_ephemeral(Some(serenity::GuildId(1234))));
+        assert!(!should_be_ephemeral(None));
+    }
+
+    #[test]
+    fn test_topup_for_overdraft() {
+        // $1.23456 in the hole, so it takes $1.24 to get back to zero
+        let cents = topup_cents(-123_456, 0);
+        assert_eq!(cents, 124);
+        assert_eq!(format_cents(cents), "$1.24");
+        // and $21.24 to get back up to the usual 20 bucks
+        assert_eq!(format_cents(topup_cents(-123_456, 2_000_000)), "$21.24");
+        assert_eq!(topup_cents(500_000, 0), 0);
+        assert_eq!(format_cents(topup_cents(500_000, 500_005)), "$0.01");
+    }
+
+    #[test]
+    fn test_standings() {
+        let top: Vec<(u64, data::Account)> = ["a", "b", "c", "d"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                (
+                    i as u64,
+                    data::Account::named(name.to_string(), data::DEFAULT_CREDIT),
+                )
+            })
+            .collect();
+        let lines = standings(&top, |account| account.user.to_uppercase());
+        assert_eq!(lines, "🥇 a: A\n🥈 b: B\n🥉 c: C\n4. d: D");
+        assert_eq!(standings(&[], |_| String::new()), "Nobody yet!");
+    }
+
+    #[test]
+    fn test_comparison() {
+        let account = |name: &str, images: u64, total_cost: i64| data::Account {
+            images,
+            total_cost,
+            ..data::Account::named(name.to_string(), data::DEFAULT_CREDIT)
+        };
+        let me = account("me", 12, 480_000);
+        let them = account("them", 1, 40_000);
+        assert_eq!(
+            comparison(&me, &them),
+            "**You**: 12 images, $4.80 spent\n**them**: 1 image, $0.40 spent\nYou're ahead by $4.40. Big spender!"
+        );
+        assert!(comparison(&them, &me).ends_with("me is ahead by $4.40. Better get generating."));
+        assert!(comparison(&me, &me).ends_with("Dead even. Suspicious."));
+    }
+
+    #[test]
+    fn test_format_millicents() {
+        assert_eq!(format_millicents(2_000_500), "$20.01");
+        assert_eq!(format_millicents(123_457), "$1.23");
+        assert_eq!(format_millicents(0), "$0.00");
+    }
+}
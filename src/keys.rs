@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use base64::Engine;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+
+use crate::data::{Context, Error};
+
+pub(crate) const USER_KEYS_FILE: &str = "user_keys.json";
+
+// Someone's own OpenAI key. Debug doesn't show it, so it can't end up in a log by accident.
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct ApiKey(String);
+impl ApiKey {
+    #[cfg(test)]
+    pub(crate) fn for_test(key: &str) -> Self {
+        ApiKey(key.to_string())
+    }
+
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+}
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ApiKey(..)")
+    }
+}
+
+// One user's key, encrypted. The nonce is fresh for every key we seal.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct SealedKey {
+    nonce: String,
+    ciphertext: String,
+}
+
+// The keys people brought with /setkey. Like preferences.json these live in a sidecar
+// next to data.json, but only ever encrypted.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct UserKeys {
+    #[serde(default)]
+    keys: BTreeMap<u64, SealedKey>,
+}
+impl UserKeys {
+    pub(crate) fn get(&self, user_id: u64) -> Option<&SealedKey> {
+        self.keys.get(&user_id)
+    }
+
+    // `None` forgets their key.
+    pub(crate) fn set(&mut self, user_id: u64, key: Option<SealedKey>) {
+        match key {
+            None => self.keys.remove(&user_id),
+            Some(key) => self.keys.insert(user_id, key),
+        };
+    }
+
+    pub(crate) fn read(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Err(_) => UserKeys::default(),
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                println!("Failed to read {}: {}", path.display(), err);
+                UserKeys::default()
+            }),
+        }
+    }
+
+    pub(crate) async fn write(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        tokio::fs::write(path, serde_json::to_string(self)?).await?;
+        Ok(())
+    }
+}
+
+// Encrypts keys with USER_KEY_SECRET. Each key is tied to its user, so one copied into
+// someone else's entry won't open.
+pub(crate) struct KeyCipher {
+    key: LessSafeKey,
+}
+impl KeyCipher {
+    pub(crate) fn new(secret: &[u8; 32]) -> Self {
+        let key = UnboundKey::new(&AES_256_GCM, secret).expect("AES-256 takes a 32 byte key");
+        KeyCipher {
+            key: LessSafeKey::new(key),
+        }
+    }
+
+    // None unless USER_KEY_SECRET is 32 bytes of base64, in which case nobody can bring
+    // their own key.
+    pub(crate) fn from_env() -> Option<Self> {
+        let secret = std::env::var("USER_KEY_SECRET").ok()?;
+        let secret = base64::engine::general_purpose::STANDARD
+            .decode(secret.trim())
+            .ok()?;
+        Some(KeyCipher::new(&secret.try_into().ok()?))
+    }
+
+    pub(crate) fn seal(&self, user_id: u64, key: &ApiKey) -> Result<SealedKey, Error> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        let mut ciphertext = key.expose().as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(user_id.to_be_bytes()),
+                &mut ciphertext,
+            )
+            .map_err(|_| "couldn't encrypt the key")?;
+        let base64 = base64::engine::general_purpose::STANDARD;
+        Ok(SealedKey {
+            nonce: base64.encode(nonce),
+            ciphertext: base64.encode(ciphertext),
+        })
+    }
+
+    // None if it's been tampered with, sealed with another secret or for another user.
+    pub(crate) fn open(&self, user_id: u64, sealed: &SealedKey) -> Option<ApiKey> {
+        let base64 = base64::engine::general_purpose::STANDARD;
+        let nonce: [u8; NONCE_LEN] = base64.decode(&sealed.nonce).ok()?.try_into().ok()?;
+        let mut ciphertext = base64.decode(&sealed.ciphertext).ok()?;
+        let plaintext = self
+            .key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(user_id.to_be_bytes()),
+                &mut ciphertext,
+            )
+            .ok()?;
+        String::from_utf8(plaintext.to_vec()).ok().map(ApiKey)
+    }
+}
+
+// What's wrong with something that was supposed to be an OpenAI key, if anything. Careful
+// not to repeat it back.
+fn parse_key(key: &str) -> Result<ApiKey, &'static str> {
+    let key = key.trim();
+    if !key.starts_with("sk-") || key.len() < 20 || key.chars().any(char::is_whitespace) {
+        return Err("That doesn't look like an OpenAI API key. They start with `sk-`.");
+    }
+    Ok(ApiKey(key.to_string()))
+}
+
+// Lets someone bill their generations to their own OpenAI account instead of the shared
+// credit. DMs only, so nobody else sees the key go by.
+#[poise::command(slash_command, dm_only)]
+pub async fn setkey(
+    ctx: Context<'_>,
+    #[description = "Your OpenAI API key. Leave it out to go back to the shared credit"]
+    key: Option<String>,
+) -> Result<(), Error> {
+    let user_id = ctx.author().id.0;
+    let response = match key.as_deref().map(parse_key) {
+        Some(Ok(_)) if !ctx.data().takes_user_keys() => {
+            "This bot isn't set up to take people's own keys, sorry!"
+        }
+        None => match ctx.data().set_user_key(user_id, None).await {
+            Ok(()) => "Forgot your key. Your images come out of the shared credit again.",
+            Err(_) => "Couldn't forget your key, sorry! Try again in a bit.",
+        },
+        Some(Err(problem)) => problem,
+        Some(Ok(key)) => match ctx.data().set_user_key(user_id, Some(&key)).await {
+            Ok(()) => "Saved. Your images will be billed to your own OpenAI account from now on.",
+            Err(err) => {
+                crate::throttled_log::log(format!("Failed to save a user's key: {}", err));
+                "Couldn't save your key, sorry! Try again in a bit."
+            }
+        },
+    };
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+    Ok(())
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_only_open_for_their_user() {
+        let cipher = KeyCipher::new(&[7; 32]);
+        let key = parse_key(" sk-abcdefghijklmnopqrstuvwxyz ").unwrap();
+        let sealed = cipher.seal(1, &key).unwrap();
+        assert!(!sealed.ciphertext.contains("abcdefgh"));
+        assert_eq!(cipher.open(1, &sealed), Some(key.clone()));
+        assert_eq!(cipher.open(2, &sealed), None);
+        assert_eq!(KeyCipher::new(&[8; 32]).open(1, &sealed), None);
+        // a fresh nonce every time
+        assert_ne!(cipher.seal(1, &key).unwrap(), sealed);
+        assert_eq!(format!("{:?}", key), "ApiKey(..)");
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert!(parse_key("sk-abcdefghijklmnopqrstuvwxyz").is_ok());
+        assert!(parse_key("hunter2").is_err());
+        assert!(parse_key("sk-abc def ghi jkl mno pqr").is_err());
+        let problem = parse_key("pk-abcdefghijklmnopqrstuvwxyz").unwrap_err();
+        assert!(!problem.contains("abcdefgh"));
+    }
+}
@@ -0,0 +1,78 @@
+mod contact_sheet;
+mod costs;
+mod dalle;
+mod data;
+mod dice;
+mod dice_common;
+mod history;
+mod info;
+mod keys;
+mod odds;
+mod preferences;
+mod settings;
+mod sparkle;
+mod store;
+mod table;
+mod throttled_log;
+mod typing;
+mod webhook;
+
+pub use data::{sweep_expired_credit, Data, Error};
+// for the integration tests
+pub use dice::{CortexResult, DiceRollRequest};
+pub use webhook::{deliver, payload, GenerationSummary};
+
+// Everything the bot registers, with `!` versions of the commands that have them when
+// `prefix_commands` is on.
+pub fn framework_options(prefix_commands: bool) -> poise::FrameworkOptions<Data, Error> {
+    poise::FrameworkOptions {
+        commands: vec![
+            dice::roll(),
+            dice::contest(),
+            dice::check(),
+            dice::share(),
+            dice::fair(),
+            dice::reroll_glitches(),
+            history::export_rolls(),
+            history::pin_roll(),
+            history::recall(),
+            poise::Command {
+                prefix_action: prefix_commands
+                    .then(|| dalle::gen_prefix().prefix_action)
+                    .flatten(),
+                ..dalle::gen()
+            },
+            sparkle::shimmer(),
+            info::info(),
+            info::topup_needed(),
+            info::set_limit(),
+            info::leaderboard(),
+            info::compare(),
+            table::roll_table(),
+            odds::variance(),
+            odds::odds(),
+            odds::optimize(),
+            preferences::flavor(),
+            settings::maintenance(),
+            settings::lint_config(),
+            settings::setconfig(),
+            dalle::vary(),
+            dalle::ratelimit(),
+            dalle::prices(),
+            keys::setkey(),
+            info::grant(),
+            info::grant_role(),
+            info::liability(),
+            costs::expensive(),
+        ],
+        reply_callback: Some(settings::add_banner),
+        prefix_options: poise::PrefixFrameworkOptions {
+            prefix: prefix_commands.then(|| "!".to_string()),
+            // automation is a bot, `settings::command_check` keeps out the rest
+            ignore_bots: false,
+            ..Default::default()
+        },
+        command_check: Some(settings::command_check),
+        ..Default::default()
+    }
+}
@@ -1,19 +1,35 @@
-mod dalle;
-mod data;
-mod dice;
-mod info;
-mod sparkle;
+use hypnos::{sweep_expired_credit, Data};
 use poise::serenity_prelude as serenity;
 
+// PREFIX_COMMANDS=true turns on `!` commands, like replying with `!gen`. They need the
+// privileged Message Content intent, which the bot can't even connect with unless it's
+// been turned on for the app, so they're off by default.
+fn prefix_commands() -> bool {
+    std::env::var("PREFIX_COMMANDS")
+        .map(|prefix| matches!(prefix.trim(), "1" | "true"))
+        .unwrap_or(false)
+}
+
 #[tokio::main]
 async fn main() {
+    // before connecting, so a data.json that won't read stops the bot instead of it
+    // starting everyone over
+    let data = match Data::read_or_create().await {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("Failed to load accounts: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let prefix_commands = prefix_commands();
+    let mut intents = serenity::GatewayIntents::non_privileged();
+    if prefix_commands {
+        intents |= serenity::GatewayIntents::MESSAGE_CONTENT;
+    }
     let framework = poise::Framework::builder()
-        .options(poise::FrameworkOptions {
-            commands: vec![dice::roll(), dalle::gen(), sparkle::shimmer(), info::info()],
-            ..Default::default()
-        })
+        .options(hypnos::framework_options(prefix_commands))
         .token(std::env::var("DISCORD_TOKEN").expect("missing DISCORD_TOKEN env variable"))
-        .intents(serenity::GatewayIntents::non_privileged())
+        .intents(intents)
         .setup(|ctx, _ready, framework| {
             Box::pin(async move {
                 println!("Registering commands...");
@@ -30,9 +46,12 @@ async fn main() {
                         println!(" - {}", command.name);
                     }
                 }
-                Ok(data::Data::read_or_create().await?)
+                Ok(data)
             })
         });
+    let framework = framework.build().await.unwrap();
+    let sweeper = framework.clone();
+    tokio::spawn(async move { sweep_expired_credit(sweeper.user_data().await).await });
     println!("Starting bot...");
-    framework.run().await.unwrap();
+    framework.start().await.unwrap();
 }
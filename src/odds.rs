@@ -0,0 +1,625 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use rand::Rng;
+
+use crate::data::{Context, Error};
+use crate::dice::DiceRollRequest;
+use crate::preferences::Flavor;
+use crate::settings::flavor_for;
+use crate::sparkle;
+
+const DEFAULT_TRIALS: u32 = 10_000;
+const MAX_TRIALS: u32 = 100_000;
+// Keeps a giant pool from tying us up, no matter how many trials were asked for.
+const MAX_DIE_ROLLS: usize = 10_000_000;
+// How long the longest bar in a chart is, in characters.
+const CHART_WIDTH: u64 = 20;
+// Past this many rows neighbouring values share a bar, so the chart fits in a message.
+const MAX_CHART_ROWS: u64 = 20;
+// What's left over at the end of a bar, in eighths of a character.
+const PARTIAL_BLOCKS: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+#[poise::command(slash_command, prefix_command)]
+pub async fn variance(
+    ctx: Context<'_>,
+    #[description = "The dice to look at, like `3d6 1d10`"] dice: String,
+    #[description = "How many times to roll them (more is slower but more accurate)"]
+    trials: Option<u32>,
+) -> Result<(), Error> {
+    let flavor = flavor_for(ctx.data(), ctx.author().id.0).await;
+    let typing = crate::typing::start(ctx);
+    let response =
+        tokio::task::spawn_blocking(move || get_variance_response(&dice, trials, flavor)).await?;
+    let _ = typing.send(());
+    ctx.say(response).await?;
+    Ok(())
+}
+
+fn get_variance_response(dice: &str, trials: Option<u32>, flavor: Flavor) -> String {
+    let request = match DiceRollRequest::parse(dice) {
+        Err(err) => return err.message(flavor),
+        Ok(request) => request,
+    };
+    if request.dice_count() == 0 {
+        return "Gotta give me some dice to roll!".to_string();
+    }
+    let trials = cap_trials(trials, request.dice_count());
+    let simulation = simulate(&request, trials);
+    let (totals, bucket_width) = bucket(&simulation.totals);
+    match (simulation.total.spread(), simulation.sum.spread()) {
+        (Some(total), Some(sum)) => format!(
+            "Rolled {} {} times\n\nTotal: {}\nSum of every die: {}\n{}",
+            dice,
+            trials,
+            total,
+            sum,
+            bar_chart(&totals, bucket_width).unwrap_or_default()
+        ),
+        _ => "Couldn't roll that enough times to say anything, sorry!".to_string(),
+    }
+}
+
+// Odds for the sparkle dice. Discord won't run the parent of a slash command with
+// subcommands, so this only answers `!odds` on its own.
+#[poise::command(slash_command, prefix_command, subcommands("shimmer_odds"))]
+pub async fn odds(ctx: Context<'_>) -> Result<(), Error> {
+    ctx.say("Try `/odds shimmer d4 d8` to see how often a pool shimmers.")
+        .await?;
+    Ok(())
+}
+
+// How likely a sparkle pool is to shimmer at all, and how many of its dice do on average.
+#[poise::command(slash_command, prefix_command, rename = "shimmer")]
+pub async fn shimmer_odds(
+    ctx: Context<'_>,
+    #[description = "The sparkle dice to look at, like `d4 d8`"] dice: String,
+    #[description = "How many times to roll them (more is slower but more accurate)"]
+    trials: Option<u32>,
+) -> Result<(), Error> {
+    let flavor = flavor_for(ctx.data(), ctx.author().id.0).await;
+    let typing = crate::typing::start(ctx);
+    let response = tokio::task::spawn_blocking(move || {
+        get_shimmer_response(&dice, trials, flavor, &mut rand::thread_rng())
+    })
+    .await?;
+    let _ = typing.send(());
+    ctx.say(response).await?;
+    Ok(())
+}
+
+fn get_shimmer_response(
+    dice: &str,
+    trials: Option<u32>,
+    flavor: Flavor,
+    rng: &mut impl Rng,
+) -> String {
+    let request = match sparkle::DiceRollRequest::parse(dice) {
+        Err(err) => return err.message(flavor),
+        Ok(request) => request,
+    };
+    if request.dice_count() == 0 {
+        return "Gotta give me some dice to roll!".to_string();
+    }
+    let trials = cap_trials(trials, request.dice_count());
+    let odds = ShimmerOdds::simulate(&request, trials, rng);
+    let (shimmers, bucket_width) = bucket(&odds.shimmers);
+    format!(
+        "Rolled {} {} times\n\nChance of at least one shimmer: {:.1}%\nDice that shimmer, on average: {:.2}\n{}",
+        dice.trim(),
+        trials,
+        odds.at_least_one * 100.0,
+        odds.expected,
+        bar_chart(&shimmers, bucket_width).unwrap_or_default()
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ShimmerOdds {
+    // the fraction of rolls with a shimmer in them
+    at_least_one: f64,
+    // the average number of dice that shimmered
+    expected: f64,
+    // how many rolls had each number of shimmers
+    shimmers: BTreeMap<u64, u32>,
+}
+impl ShimmerOdds {
+    fn simulate(request: &sparkle::DiceRollRequest, trials: u32, rng: &mut impl Rng) -> Self {
+        let mut any = 0;
+        let mut total = 0;
+        let mut counts = Vec::new();
+        for _ in 0..trials {
+            let shimmers = request.roll(rng).shimmers();
+            if shimmers > 0 {
+                any += 1;
+            }
+            total += shimmers;
+            counts.push(shimmers as u64);
+        }
+        ShimmerOdds {
+            at_least_one: any as f64 / trials as f64,
+            expected: total as f64 / trials as f64,
+            shimmers: histogram(counts.into_iter()),
+        }
+    }
+}
+
+// The dice /optimize builds pools from, and what each costs out of the budget. A step up
+// in size costs a step more.
+const POOL_DICE: [u64; 5] = [4, 6, 8, 10, 12];
+const MAX_BUDGET: u64 = 15;
+const MAX_POOL_DICE: usize = 6;
+const OPTIMIZE_TRIALS: u32 = 2_000;
+// A pool this close to the best is as good as, given how noisy the simulation is.
+const GOOD_ENOUGH: f64 = 0.01;
+
+fn die_cost(sides: u64) -> u64 {
+    sides / 2 - 1
+}
+
+// Suggests the smallest pool that's as likely as any to hit a total and effect die, to
+// help decide what's worth spending on.
+#[poise::command(slash_command, prefix_command)]
+pub async fn optimize(
+    ctx: Context<'_>,
+    #[description = "The total to hit"] total: u64,
+    #[description = "The smallest effect die that'll do, like 8 for a d8. A d4 by default"]
+    effect: Option<u64>,
+    #[description = "How many steps of dice to spend: a d4 is 1, a d6 is 2, up to 5 for a d12"]
+    budget: u64,
+) -> Result<(), Error> {
+    let typing = crate::typing::start(ctx);
+    let response = tokio::task::spawn_blocking(move || {
+        get_optimize_response(total, effect.unwrap_or(4), budget, &mut rand::thread_rng())
+    })
+    .await?;
+    let _ = typing.send(());
+    ctx.say(response).await?;
+    Ok(())
+}
+
+fn get_optimize_response(total: u64, effect: u64, budget: u64, rng: &mut impl Rng) -> String {
+    if !POOL_DICE.contains(&effect) {
+        return "The effect die has to be a d4, d6, d8, d10 or d12.".to_string();
+    }
+    if !(1..=MAX_BUDGET).contains(&budget) {
+        return format!("The budget has to be from 1 to {} steps.", MAX_BUDGET);
+    }
+    let Some(best) = optimize_pool(total, effect, budget, rng) else {
+        return "Couldn't find anything to try on that budget, sorry!".to_string();
+    };
+    if best.rate == 0.0 {
+        return format!(
+            "Nothing on a budget of {} gets a total of {} with a d{} effect. Time to spend more!",
+            budget, total, effect
+        );
+    }
+    format!(
+        "To get a total of {} with a d{} effect on a budget of {}, roll {} (costs {}).\n\nIt gets there about {:.1}% of the time, out of {} pools rolled {} times each.",
+        total,
+        effect,
+        budget,
+        best.pool,
+        best.cost,
+        best.rate * 100.0,
+        best.considered,
+        best.trials
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Suggestion {
+    // like `2d8 1d6`
+    pool: String,
+    cost: u64,
+    rate: f64,
+    // how many pools were tried, and how many times each
+    considered: usize,
+    trials: u32,
+}
+
+fn optimize_pool(total: u64, effect: u64, budget: u64, rng: &mut impl Rng) -> Option<Suggestion> {
+    let pools = affordable_pools(budget.min(MAX_BUDGET));
+    let trials = OPTIMIZE_TRIALS
+        .min((MAX_DIE_ROLLS / (pools.len() * MAX_POOL_DICE).max(1)) as u32)
+        .max(1);
+    let scored: Vec<(Vec<u64>, f64)> = pools
+        .into_iter()
+        .filter_map(|pool| {
+            let request = DiceRollRequest::parse(&describe_pool(&pool)).ok()?;
+            let rate = success_rate(&request, total, effect, trials, rng);
+            Some((pool, rate))
+        })
+        .collect();
+    let best = scored.iter().map(|(_, rate)| *rate).fold(0.0, f64::max);
+    let considered = scored.len();
+    // fewest dice, then cheapest, then most likely
+    let (pool, rate) = scored
+        .into_iter()
+        .filter(|(_, rate)| *rate >= best - GOOD_ENOUGH)
+        .min_by(|(a, a_rate), (b, b_rate)| {
+            (a.len(), a.iter().map(|&d| die_cost(d)).sum::<u64>())
+                .cmp(&(b.len(), b.iter().map(|&d| die_cost(d)).sum::<u64>()))
+                .then(b_rate.total_cmp(a_rate))
+        })?;
+    Some(Suggestion {
+        pool: describe_pool(&pool),
+        cost: pool.iter().map(|&d| die_cost(d)).sum(),
+        rate,
+        considered,
+        trials,
+    })
+}
+
+fn success_rate(
+    request: &DiceRollRequest,
+    total: u64,
+    effect: u64,
+    trials: u32,
+    rng: &mut impl Rng,
+) -> f64 {
+    let hits = (0..trials)
+        .filter(|_| request.roll(rng).reaches(total, effect))
+        .count();
+    hits as f64 / trials as f64
+}
+
+// Every pool of POOL_DICE, largest die first, that fits in the budget and MAX_POOL_DICE.
+fn affordable_pools(budget: u64) -> Vec<Vec<u64>> {
+    fn extend(pool: &mut Vec<u64>, budget: u64, pools: &mut Vec<Vec<u64>>) {
+        if !pool.is_empty() {
+            pools.push(pool.clone());
+        }
+        if pool.len() == MAX_POOL_DICE {
+            return;
+        }
+        // no bigger than the last die, so each pool only turns up once
+        let largest = pool.last().copied().unwrap_or(u64::MAX);
+        for &sides in POOL_DICE.iter().rev() {
+            if sides <= largest && die_cost(sides) <= budget {
+                pool.push(sides);
+                extend(pool, budget - die_cost(sides), pools);
+                pool.pop();
+            }
+        }
+    }
+    let mut pools = Vec::new();
+    extend(&mut Vec::new(), budget, &mut pools);
+    pools
+}
+
+// Like `2d8 1d6`, for a pool sorted largest first.
+fn describe_pool(pool: &[u64]) -> String {
+    let mut groups: Vec<(usize, u64)> = Vec::new();
+    for &sides in pool {
+        match groups.last_mut() {
+            Some((count, last)) if *last == sides => *count += 1,
+            _ => groups.push((1, sides)),
+        }
+    }
+    groups
+        .iter()
+        .map(|(count, sides)| format!("{}d{}", count, sides))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub(crate) fn cap_trials(trials: Option<u32>, dice_count: usize) -> u32 {
+    let budget = (MAX_DIE_ROLLS / dice_count.max(1)).min(MAX_TRIALS as usize) as u32;
+    trials.unwrap_or(DEFAULT_TRIALS).clamp(1, budget.max(1))
+}
+
+// What /variance needs to know about a lot of rolls. It's added up as each roll is made,
+// so none of them have to be kept around.
+pub(crate) struct Simulation {
+    total: RunningSpread,
+    sum: RunningSpread,
+    // how often each total came up
+    totals: BTreeMap<u64, u32>,
+}
+
+pub(crate) fn simulate(request: &DiceRollRequest, trials: u32) -> Simulation {
+    let mut rng = rand::thread_rng();
+    let mut simulation = Simulation {
+        total: RunningSpread::default(),
+        sum: RunningSpread::default(),
+        totals: BTreeMap::new(),
+    };
+    for _ in 0..trials {
+        let roll = request.roll(&mut rng);
+        simulation.total.add(roll.total());
+        simulation.sum.add(roll.sum());
+        *simulation.totals.entry(roll.total()).or_insert(0) += 1;
+    }
+    simulation
+}
+
+// How many times each value came up.
+pub(crate) fn histogram(values: impl Iterator<Item = u64>) -> BTreeMap<u64, u32> {
+    let mut histogram = BTreeMap::new();
+    for value in values {
+        *histogram.entry(value).or_insert(0) += 1;
+    }
+    histogram
+}
+
+// Merges neighbouring values so there are at most MAX_CHART_ROWS of them. Returns the
+// buckets, keyed by where each one starts, and how many values go in each.
+fn bucket(histogram: &BTreeMap<u64, u32>) -> (BTreeMap<u64, u32>, u64) {
+    let (min, max) = match (histogram.keys().next(), histogram.keys().next_back()) {
+        (Some(&min), Some(&max)) => (min, max),
+        _ => return (BTreeMap::new(), 1),
+    };
+    let width = (max - min + 1).div_ceil(MAX_CHART_ROWS);
+    let mut buckets = BTreeMap::new();
+    // every bucket gets a row, even one nothing landed in
+    for start in (min..=max).step_by(width as usize) {
+        buckets.insert(start, 0);
+    }
+    for (&value, &count) in histogram.iter() {
+        *buckets
+            .entry(min + (value - min) / width * width)
+            .or_insert(0) += count;
+    }
+    (buckets, width)
+}
+
+// A bar for each bucket of `histogram`, with the biggest CHART_WIDTH long and the rest in
+// proportion, followed by its share of the whole. Buckets `bucket_width` wide are labelled
+// with their range. In a code block so the bars line up. None if there's nothing to chart.
+pub(crate) fn bar_chart(histogram: &BTreeMap<u64, u32>, bucket_width: u64) -> Option<String> {
+    let most = *histogram.values().max()? as u64;
+    if most == 0 {
+        return None;
+    }
+    let all: u64 = histogram.values().map(|&count| count as u64).sum();
+    let labels: Vec<String> = histogram
+        .keys()
+        .map(|&start| match bucket_width {
+            0 | 1 => start.to_string(),
+            width => format!("{}-{}", start, start + width - 1),
+        })
+        .collect();
+    let label_width = labels.iter().map(|label| label.len()).max().unwrap_or(0);
+    let mut chart = "```\n".to_string();
+    for (label, &count) in labels.iter().zip(histogram.values()) {
+        let count = count as u64;
+        let mut eighths = (count * CHART_WIDTH * 8 + most / 2) / most;
+        // anything that happened at all gets a sliver
+        if count > 0 {
+            eighths = eighths.max(1);
+        }
+        let mut bar = "█".repeat((eighths / 8) as usize);
+        match eighths % 8 {
+            0 => {}
+            part => bar.push(PARTIAL_BLOCKS[part as usize]),
+        }
+        let _ = writeln!(
+            chart,
+            "{:>label_width$} {:<bar_width$} {:>5.1}%",
+            label,
+            bar,
+            count as f64 * 100.0 / all as f64,
+            bar_width = CHART_WIDTH as usize,
+        );
+    }
+    chart += "```";
+    Some(chart)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Spread {
+    mean: f64,
+    std_dev: f64,
+    min: u64,
+    max: u64,
+}
+impl std::fmt::Display for Spread {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "average {:.2}, standard deviation {:.2}, range {}-{}",
+            self.mean, self.std_dev, self.min, self.max
+        )
+    }
+}
+
+// A Spread worked out one sample at a time (Welford's method), so the samples themselves
+// can be thrown away.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RunningSpread {
+    count: u64,
+    mean: f64,
+    // the sum of squared differences from the mean so far
+    m2: f64,
+    min: u64,
+    max: u64,
+}
+impl RunningSpread {
+    pub(crate) fn add(&mut self, sample: u64) {
+        if self.count == 0 {
+            (self.min, self.max) = (sample, sample);
+        } else {
+            self.min = self.min.min(sample);
+            self.max = self.max.max(sample);
+        }
+        self.count += 1;
+        let delta = sample as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (sample as f64 - self.mean);
+    }
+
+    // None until there's been a sample.
+    pub(crate) fn spread(&self) -> Option<Spread> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(Spread {
+            mean: self.mean,
+            std_dev: (self.m2 / self.count as f64).sqrt(),
+            min: self.min,
+            max: self.max,
+        })
+    }
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_variance_of_2d6() {
+        let request = DiceRollRequest::parse("2d6").unwrap();
+        let spread = simulate(&request, 20_000).sum.spread().unwrap();
+        // 2d6 has a variance of 35/6
+        assert!(
+            (spread.std_dev.powi(2) - 35.0 / 6.0).abs() < 0.3,
+            "{:?}",
+            spread
+        );
+        assert!((spread.mean - 7.0).abs() < 0.1, "{:?}", spread);
+        assert_eq!((spread.min, spread.max), (2, 12));
+    }
+
+    #[test]
+    fn test_running_spread() {
+        let mut running = RunningSpread::default();
+        assert!(running.spread().is_none());
+        for sample in [2, 4, 4, 4, 5, 5, 7, 9] {
+            running.add(sample);
+        }
+        let spread = running.spread().unwrap();
+        assert!((spread.mean - 5.0).abs() < 1e-9, "{:?}", spread);
+        assert!((spread.std_dev - 2.0).abs() < 1e-9, "{:?}", spread);
+        assert_eq!((spread.min, spread.max), (2, 9));
+    }
+
+    #[test]
+    fn test_shimmer_odds_of_a_d4() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+        let request = sparkle::DiceRollRequest::parse("d4").unwrap();
+        let odds = ShimmerOdds::simulate(&request, MAX_TRIALS, &mut rng);
+        // a 4 on the d4 (1 in 4), then 4 or more on the d6 it becomes (1 in 2)
+        assert!((odds.at_least_one - 0.125).abs() < 0.01, "{:?}", odds);
+        assert_eq!(odds.at_least_one, odds.expected);
+
+        // twice the dice, twice the shimmers on average
+        let request = sparkle::DiceRollRequest::parse("2d4").unwrap();
+        let odds = ShimmerOdds::simulate(&request, MAX_TRIALS, &mut rng);
+        assert!((odds.expected - 0.25).abs() < 0.01, "{:?}", odds);
+        assert!(
+            (odds.at_least_one - (1.0 - 0.875 * 0.875)).abs() < 0.01,
+            "{:?}",
+            odds
+        );
+
+        let response = get_shimmer_response("d4", Some(u32::MAX), Flavor::Flavorful, &mut rng);
+        assert!(
+            response.starts_with("Rolled d4 100000 times"),
+            "{}",
+            response
+        );
+        assert!(
+            get_shimmer_response("d7", None, Flavor::Flavorful, &mut rng).contains("no such thing")
+        );
+
+        // a big pool shimmers in lots of different amounts, but the chart still fits
+        let response = get_shimmer_response("5000d4", None, Flavor::Flavorful, &mut rng);
+        assert!(
+            response.chars().count() <= crate::dice_common::MAX_MESSAGE_LEN,
+            "{}",
+            response
+        );
+    }
+
+    #[test]
+    fn test_affordable_pools() {
+        assert_eq!(affordable_pools(0), Vec::<Vec<u64>>::new());
+        assert_eq!(affordable_pools(1), vec![vec![4]]);
+        assert_eq!(affordable_pools(2), vec![vec![6], vec![4], vec![4, 4]]);
+        let pools = affordable_pools(MAX_BUDGET);
+        assert!(pools.iter().all(|pool| pool.len() <= MAX_POOL_DICE));
+        assert!(pools
+            .iter()
+            .all(|pool| pool.iter().map(|&d| die_cost(d)).sum::<u64>() <= MAX_BUDGET));
+        assert_eq!(describe_pool(&[12, 8, 8, 4]), "1d12 2d8 1d4");
+    }
+
+    #[test]
+    fn test_optimized_pool_beats_a_worse_one() {
+        use rand::SeedableRng;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+        let best = optimize_pool(12, 6, 8, &mut rng).unwrap();
+        assert!(best.cost <= 8, "{:?}", best);
+        let worse = DiceRollRequest::parse("2d4").unwrap();
+        let suggested = DiceRollRequest::parse(&best.pool).unwrap();
+        let worse_rate = success_rate(&worse, 12, 6, MAX_TRIALS, &mut rng);
+        let suggested_rate = success_rate(&suggested, 12, 6, MAX_TRIALS, &mut rng);
+        assert!(suggested_rate > worse_rate + 0.1, "{:?}", best);
+        assert!((suggested_rate - best.rate).abs() < 0.05, "{:?}", best);
+
+        let response = get_optimize_response(12, 6, 8, &mut rng);
+        assert!(
+            response.starts_with("To get a total of 12 with a d6 effect"),
+            "{}",
+            response
+        );
+        assert!(get_optimize_response(12, 7, 8, &mut rng).contains("has to be a d4"));
+        assert!(get_optimize_response(12, 6, 99, &mut rng).contains("from 1 to 15"));
+        assert!(get_optimize_response(40, 12, 2, &mut rng).starts_with("Nothing on a budget"));
+    }
+
+    #[test]
+    fn test_bar_chart() {
+        let chart = bar_chart(&BTreeMap::from([(2, 1), (3, 2), (4, 4), (5, 0)]), 1).unwrap();
+        let lines: Vec<&str> = chart.lines().collect();
+        assert_eq!(lines.first(), Some(&"```"));
+        assert_eq!(lines.last(), Some(&"```"));
+        let bars: Vec<usize> = lines[1..lines.len() - 1]
+            .iter()
+            .map(|line| line.chars().filter(|&c| c == '█').count())
+            .collect();
+        assert_eq!(bars, vec![5, 10, 20, 0]);
+        assert_eq!(lines[3], format!("4 {}  57.1%", "█".repeat(20)));
+        // the percentages line up
+        assert!(lines[1..5]
+            .iter()
+            .all(|line| line.chars().count() == lines[3].chars().count()));
+
+        // a bar that doesn't come out even ends part way through a character
+        let chart = bar_chart(&BTreeMap::from([(1, 3), (2, 40)]), 1).unwrap();
+        assert!(chart.contains("1 █▌"), "{}", chart);
+
+        assert_eq!(bar_chart(&BTreeMap::new(), 1), None);
+        assert_eq!(bar_chart(&BTreeMap::from([(7, 0)]), 1), None);
+        let single = bar_chart(&BTreeMap::from([(7, 12)]), 1).unwrap();
+        assert!(single.contains(&format!("7 {} 100.0%", "█".repeat(20))));
+    }
+
+    #[test]
+    fn test_bucket() {
+        let (buckets, width) = bucket(&histogram(0..100));
+        assert_eq!(width, 5);
+        assert_eq!(buckets.len(), MAX_CHART_ROWS as usize);
+        assert!(buckets.values().all(|&count| count == 5));
+        let chart = bar_chart(&buckets, width).unwrap();
+        assert!(chart.contains("\n95-99 "), "{}", chart);
+        // few enough values are left alone
+        let small = histogram([2, 4, 4].into_iter());
+        assert_eq!(
+            bucket(&small),
+            (BTreeMap::from([(2, 1), (3, 0), (4, 2)]), 1)
+        );
+        assert_eq!(bucket(&BTreeMap::new()), (BTreeMap::new(), 1));
+    }
+
+    #[test]
+    fn test_trials_are_capped() {
+        assert_eq!(cap_trials(None, 3), DEFAULT_TRIALS);
+        assert_eq!(cap_trials(Some(u32::MAX), 3), MAX_TRIALS);
+        assert_eq!(cap_trials(Some(u32::MAX), 1_000_000), 10);
+        assert_eq!(cap_trials(Some(0), 3), 1);
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::data::{Context, Error};
+
+pub(crate) const PREFERENCES_FILE: &str = "preferences.json";
+
+// How much personality goes into a response. The server picks one with /setconfig,
+// and anyone can override it for themselves with /flavor.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    serde::Serialize,
+    serde::Deserialize,
+    poise::ChoiceParameter,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Flavor {
+    // persona prefixes and the demigod's patter
+    #[default]
+    #[name = "Flavorful, with all the demigod's patter"]
+    Flavorful,
+    // just the results
+    #[name = "Terse, just the results"]
+    Terse,
+}
+impl Flavor {
+    pub(crate) const NAMES: &'static [&'static str] = &["flavorful", "terse"];
+
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "flavorful" => Some(Flavor::Flavorful),
+            "terse" => Some(Flavor::Terse),
+            _ => None,
+        }
+    }
+}
+
+// Things each user picks for themselves. Like settings.json, these live in a sidecar
+// next to data.json.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Preferences {
+    // users who haven't picked get the server's flavor
+    #[serde(default)]
+    flavors: BTreeMap<u64, Flavor>,
+}
+impl Preferences {
+    pub(crate) fn flavor(&self, user_id: u64) -> Option<Flavor> {
+        self.flavors.get(&user_id).copied()
+    }
+
+    // `None` goes back to the server's flavor.
+    pub(crate) fn set_flavor(&mut self, user_id: u64, flavor: Option<Flavor>) {
+        match flavor {
+            None => self.flavors.remove(&user_id),
+            Some(flavor) => self.flavors.insert(user_id, flavor),
+        };
+    }
+
+    pub(crate) fn read(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Err(_) => Preferences::default(),
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                println!("Failed to read {}: {}", path.display(), err);
+                Preferences::default()
+            }),
+        }
+    }
+
+    pub(crate) async fn write(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        tokio::fs::write(path, serde_json::to_string(self)?).await?;
+        Ok(())
+    }
+}
+
+// Picks how chatty the bot is with you, just for you.
+#[poise::command(slash_command)]
+pub async fn flavor(
+    ctx: Context<'_>,
+    #[description = "How chatty I should be with you. Leave it out to go with the server's"]
+    style: Option<Flavor>,
+) -> Result<(), Error> {
+    let user_id = ctx.author().id.0;
+    ctx.data()
+        .update_preferences(|p| p.set_flavor(user_id, style))
+        .await?;
+    let response = match style {
+        None => "Back to the server's usual style for you.",
+        Some(Flavor::Flavorful) => "You got it, chum. All the flavor, all the time.",
+        Some(Flavor::Terse) => "Okay. Just the results from now on.",
+    };
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+    Ok(())
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferences_round_trip() {
+        let mut preferences = Preferences::default();
+        preferences.set_flavor(1, Some(Flavor::Terse));
+        preferences.set_flavor(2, Some(Flavor::Flavorful));
+        preferences.set_flavor(2, None);
+        let json = serde_json::to_string(&preferences).unwrap();
+        assert_eq!(json, r#"{"flavors":{"1":"terse"}}"#);
+        let preferences: Preferences = serde_json::from_str(&json).unwrap();
+        assert_eq!(preferences.flavor(1), Some(Flavor::Terse));
+        assert_eq!(preferences.flavor(2), None);
+    }
+}
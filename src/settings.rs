@@ -0,0 +1,660 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use poise::serenity_prelude as serenity;
+
+use crate::data::{Context, Data, Error};
+use crate::dice_common::{Overflow, MAX_MESSAGE_LEN};
+use crate::preferences::Flavor;
+
+pub(crate) const SETTINGS_FILE: &str = "settings.json";
+const DEFAULT_MAINTENANCE_BANNER: &str = "⚠️ Bot under maintenance, results may be delayed";
+const DEFAULT_MAX_IMAGES: u8 = 10;
+const DEFAULT_MAX_PER_CHANNEL: u8 = 2;
+// Any longer between steps and the suspense turns into waiting.
+const MAX_REVEAL_DELAY_MS: u64 = 5000;
+const CONFIG_KEYS: &[&str] = &[
+    "cost_multiplier",
+    "max_images",
+    "maintenance_banner",
+    "max_per_channel",
+    "roll_overflow",
+    "flavor",
+    "reveal_delay_ms",
+    "persona_prefix.<command>",
+];
+// The commands that talk in the demigod's voice, and so can have a persona prefix.
+const PERSONA_COMMANDS: &[&str] = &[
+    "roll",
+    "contest",
+    "check",
+    "share",
+    "fair",
+    "shimmer",
+    "roll_table",
+];
+
+// Settings the admin can change while the bot is running. These are kept in a sidecar
+// next to data.json so that they survive a restart.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Settings {
+    #[serde(default)]
+    pub(crate) maintenance: bool,
+    // Overrides set with /setconfig. When unset, we use the env var or the built in default.
+    #[serde(default)]
+    cost_multiplier: Option<f64>,
+    #[serde(default)]
+    max_images: Option<u8>,
+    #[serde(default)]
+    maintenance_banner: Option<String>,
+    // how many generations can be running in one channel at once
+    #[serde(default)]
+    max_per_channel: Option<u8>,
+    // what /roll does with a roll too big for one message
+    #[serde(default)]
+    roll_overflow: Option<Overflow>,
+    // how chatty to be with users who haven't picked for themselves
+    #[serde(default)]
+    flavor: Option<Flavor>,
+    // how long /roll pauses between each step of revealing a result, off when unset or 0
+    #[serde(default)]
+    reveal_delay_ms: Option<u64>,
+    // something like `Zim:` to start a command's responses with, keyed by command name
+    #[serde(default)]
+    persona_prefixes: BTreeMap<String, String>,
+}
+impl Settings {
+    // How much to charge users relative to what OpenAI charges us, e.g. 1.1 for a 10% markup.
+    pub(crate) fn cost_multiplier(&self) -> f64 {
+        self.cost_multiplier.unwrap_or_else(|| {
+            std::env::var("COST_MULTIPLIER")
+                .ok()
+                .and_then(|m| parse_multiplier(&m).ok())
+                .unwrap_or(1.0)
+        })
+    }
+
+    pub(crate) fn max_images(&self) -> u8 {
+        self.max_images.unwrap_or(DEFAULT_MAX_IMAGES)
+    }
+
+    pub(crate) fn max_per_channel(&self) -> u8 {
+        self.max_per_channel.unwrap_or(DEFAULT_MAX_PER_CHANNEL)
+    }
+
+    pub(crate) fn roll_overflow(&self) -> Overflow {
+        self.roll_overflow.unwrap_or_default()
+    }
+
+    pub(crate) fn flavor(&self) -> Flavor {
+        self.flavor.unwrap_or_default()
+    }
+
+    // None if /roll should just post the result, rather than build up to it.
+    pub(crate) fn reveal_delay(&self) -> Option<std::time::Duration> {
+        match self.reveal_delay_ms {
+            None | Some(0) => None,
+            Some(ms) => Some(std::time::Duration::from_millis(ms)),
+        }
+    }
+
+    // What goes above every reply, None unless maintenance mode is on.
+    pub(crate) fn banner(&self) -> Option<String> {
+        self.maintenance.then(|| self.maintenance_banner())
+    }
+
+    fn maintenance_banner(&self) -> String {
+        self.maintenance_banner
+            .clone()
+            .or_else(|| std::env::var("MAINTENANCE_BANNER").ok())
+            .unwrap_or_else(|| DEFAULT_MAINTENANCE_BANNER.to_string())
+    }
+
+    fn persona_prefix(&self, command: &str) -> Option<&str> {
+        self.persona_prefixes.get(command).map(|p| p.as_str())
+    }
+
+    // Sets one of the CONFIG_KEYS. A value of `default` clears the override.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let reset = value == "default";
+        if let Some(command) = key.strip_prefix("persona_prefix.") {
+            if !PERSONA_COMMANDS.contains(&command) {
+                return Err(format!(
+                    "I can only put a persona prefix on: {}",
+                    PERSONA_COMMANDS.join(", ")
+                ));
+            }
+            if reset {
+                self.persona_prefixes.remove(command);
+            } else if value.is_empty() {
+                return Err(
+                    "A persona prefix can't be empty, use `default` to remove it".to_string(),
+                );
+            } else {
+                self.persona_prefixes
+                    .insert(command.to_string(), value.to_string());
+            }
+            return Ok(());
+        }
+        match key {
+            "cost_multiplier" => {
+                self.cost_multiplier = if reset {
+                    None
+                } else {
+                    Some(parse_multiplier(value)?)
+                }
+            }
+            "max_images" => {
+                self.max_images = if reset {
+                    None
+                } else {
+                    match value.parse::<u8>() {
+                        Ok(max) if (1..=DEFAULT_MAX_IMAGES).contains(&max) => Some(max),
+                        _ => {
+                            return Err(format!(
+                                "max_images has to be a whole number from 1 to {}",
+                                DEFAULT_MAX_IMAGES
+                            ))
+                        }
+                    }
+                }
+            }
+            "max_per_channel" => {
+                self.max_per_channel = if reset {
+                    None
+                } else {
+                    match value.parse::<u8>() {
+                        Ok(max) if max >= 1 => Some(max),
+                        _ => {
+                            return Err(
+                                "max_per_channel has to be a whole number, 1 or more".to_string()
+                            )
+                        }
+                    }
+                }
+            }
+            "roll_overflow" => {
+                self.roll_overflow = if reset {
+                    None
+                } else {
+                    match Overflow::parse(value) {
+                        Some(overflow) => Some(overflow),
+                        None => {
+                            return Err(format!(
+                                "roll_overflow has to be one of: {}",
+                                Overflow::NAMES.join(", ")
+                            ))
+                        }
+                    }
+                }
+            }
+            "flavor" => {
+                self.flavor = if reset {
+                    None
+                } else {
+                    match Flavor::parse(value) {
+                        Some(flavor) => Some(flavor),
+                        None => {
+                            return Err(format!(
+                                "flavor has to be one of: {}",
+                                Flavor::NAMES.join(", ")
+                            ))
+                        }
+                    }
+                }
+            }
+            "reveal_delay_ms" => {
+                self.reveal_delay_ms = if reset {
+                    None
+                } else {
+                    match value.parse::<u64>() {
+                        Ok(ms) if ms <= MAX_REVEAL_DELAY_MS => Some(ms),
+                        _ => {
+                            return Err(format!(
+                                "reveal_delay_ms has to be a whole number from 0 (off) to {}",
+                                MAX_REVEAL_DELAY_MS
+                            ))
+                        }
+                    }
+                }
+            }
+            "maintenance_banner" => {
+                self.maintenance_banner = if reset {
+                    None
+                } else if value.is_empty() {
+                    return Err("maintenance_banner can't be empty".to_string());
+                } else {
+                    Some(value.to_string())
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "I don't know a setting called {}. I know about: {}",
+                    key,
+                    CONFIG_KEYS.join(", ")
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        match std::fs::read_to_string(path) {
+            Err(_) => Settings::default(),
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                println!("Failed to read {}: {}", path.display(), err);
+                Settings::default()
+            }),
+        }
+    }
+
+    pub(crate) async fn write(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        tokio::fs::write(path, serde_json::to_string(self)?).await?;
+        Ok(())
+    }
+}
+
+fn parse_multiplier(value: &str) -> Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(m) if m.is_finite() && m >= 0.0 => Ok(m),
+        _ => Err("cost_multiplier has to be a number, zero or more".to_string()),
+    }
+}
+
+pub(crate) fn is_admin(user: &serenity::User) -> bool {
+    match std::env::var("ADMIN_USER_ID") {
+        Err(_) => false,
+        Ok(id) => id.trim().parse::<u64>().ok() == Some(user.id.0),
+    }
+}
+
+// Lets trusted automation (e.g. a scheduler's bot) skip the per-user limits. It's told
+// apart by its user id, in AUTOMATION_USER_IDS.
+pub(crate) fn is_automation(user: &serenity::User) -> bool {
+    automation_ids(std::env::var("AUTOMATION_USER_IDS").ok().as_deref()).contains(&user.id.0)
+}
+
+// A comma separated list, skipping anything that isn't an id.
+fn automation_ids(ids: Option<&str>) -> Vec<u64> {
+    ids.unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse().ok())
+        .collect()
+}
+
+// Other bots are ignored, unless they're automation.
+pub fn command_check(ctx: Context<'_>) -> poise::BoxFuture<'_, Result<bool, Error>> {
+    Box::pin(async move { Ok(!ctx.author().bot || is_automation(ctx.author())) })
+}
+
+// Lets non-admins know they can't do that. Admin commands should bail out when this is false.
+pub(crate) async fn ensure_admin(ctx: Context<'_>) -> Result<bool, Error> {
+    if is_admin(ctx.author()) {
+        return Ok(true);
+    }
+    ctx.send(|m| m.content("Only the admin can do that.").ephemeral(true))
+        .await?;
+    Ok(false)
+}
+
+#[poise::command(slash_command)]
+pub async fn maintenance(
+    ctx: Context<'_>,
+    #[description = "Whether the bot is under maintenance"] on: bool,
+) -> Result<(), Error> {
+    if !ensure_admin(ctx).await? {
+        return Ok(());
+    }
+    ctx.data().update_settings(|s| s.maintenance = on).await?;
+    let response = if on {
+        "Maintenance mode is on."
+    } else {
+        "Maintenance mode is off."
+    };
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+    Ok(())
+}
+
+// Every key /setconfig takes, with a persona_prefix for each command that has a persona.
+fn keys_matching(partial: &str) -> Vec<String> {
+    let personas = PERSONA_COMMANDS
+        .iter()
+        .map(|command| format!("persona_prefix.{}", command));
+    CONFIG_KEYS
+        .iter()
+        .filter(|key| !key.starts_with("persona_prefix."))
+        .map(|key| key.to_string())
+        .chain(personas)
+        .filter(|key| key.contains(partial.trim()))
+        .collect()
+}
+
+async fn autocomplete_key(_ctx: Context<'_>, partial: &str) -> Vec<String> {
+    keys_matching(partial)
+}
+
+// There's more keys than fit in a description, Discord stops at 100 characters. The
+// autocomplete has them all.
+#[poise::command(slash_command)]
+pub async fn setconfig(
+    ctx: Context<'_>,
+    #[description = "A setting, like roll_overflow, flavor, reveal_delay_ms or max_images. Type to see them all"]
+    #[autocomplete = "autocomplete_key"]
+    key: String,
+    #[description = "The new value, or `default` to go back to the default"] value: String,
+) -> Result<(), Error> {
+    if !ensure_admin(ctx).await? {
+        return Ok(());
+    }
+    let mut result = Ok(());
+    ctx.data()
+        .update_settings(|s| result = s.set(&key, &value))
+        .await?;
+    let response = match result {
+        Err(err) => err,
+        Ok(()) => format!("Set {} to {}.", key, value.trim()),
+    };
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+    Ok(())
+}
+
+// Checks the config files on disk for mistakes without applying any of them.
+#[poise::command(slash_command)]
+pub async fn lint_config(ctx: Context<'_>) -> Result<(), Error> {
+    if !ensure_admin(ctx).await? {
+        return Ok(());
+    }
+    let tables = std::fs::read_to_string(crate::table::TABLES_FILE).ok();
+    let settings = std::fs::read_to_string(SETTINGS_FILE).ok();
+    let problems = lint(tables.as_deref(), settings.as_deref());
+    let response = if problems.is_empty() {
+        "Config looks good!".to_string()
+    } else {
+        format!(
+            "Found {} problems:\n- {}",
+            problems.len(),
+            problems.join("\n- ")
+        )
+    };
+    ctx.send(|m| m.content(response).ephemeral(true)).await?;
+    Ok(())
+}
+
+// Missing files are fine, everything in them is optional.
+fn lint(tables: Option<&str>, settings: Option<&str>) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Some(tables) = tables {
+        problems.extend(crate::table::lint_tables(tables));
+    }
+    if let Some(settings) = settings {
+        if let Err(err) = serde_json::from_str::<Settings>(settings) {
+            problems.push(format!("{}: {}", SETTINGS_FILE, err));
+        }
+    }
+    problems
+}
+
+// Every reply and edit goes through this on its way out (it's poise's reply_callback),
+// so the maintenance banner goes above all of them without each command having to ask.
+pub fn add_banner(ctx: Context<'_>, reply: &mut poise::CreateReply<'_>) {
+    if let Some(banner) = ctx.data().banner() {
+        reply.content = banner_content(&banner, reply.content.take());
+    }
+}
+
+// `content` with `banner` on top. No content is left alone, since for an edit that means
+// keeping what's there. So is content the banner would push past Discord's limit.
+fn banner_content(banner: &str, content: Option<String>) -> Option<String> {
+    let content = content?;
+    let bannered = apply_banner(true, banner, content.clone());
+    if bannered.chars().count() > MAX_MESSAGE_LEN {
+        return Some(content);
+    }
+    Some(bannered)
+}
+
+// For commands that speak as the demigod, so they get their persona prefix, unless
+// `user_id` would rather they didn't.
+pub(crate) async fn with_persona(
+    data: &Data,
+    user_id: u64,
+    command: &str,
+    content: impl Into<String>,
+) -> String {
+    let flavor = flavor_for(data, user_id).await;
+    let settings = data.settings.lock().await;
+    apply_persona(&settings, flavor, command, content.into())
+}
+
+// The user's own flavor if they've picked one, otherwise the server's.
+pub(crate) async fn flavor_for(data: &Data, user_id: u64) -> Flavor {
+    let preference = data.preferences.lock().await.flavor(user_id);
+    let settings = data.settings.lock().await;
+    effective_flavor(preference, &settings)
+}
+
+fn effective_flavor(preference: Option<Flavor>, settings: &Settings) -> Flavor {
+    preference.unwrap_or_else(|| settings.flavor())
+}
+
+fn apply_persona(settings: &Settings, flavor: Flavor, command: &str, content: String) -> String {
+    match (flavor, settings.persona_prefix(command)) {
+        (Flavor::Terse, _) | (_, None) => content,
+        (Flavor::Flavorful, Some(prefix)) => format!("{} {}", prefix, content),
+    }
+}
+
+fn apply_banner(maintenance: bool, banner: &str, content: String) -> String {
+    match (maintenance, content.trim()) {
+        (false, _) => content,
+        // like an embed's reply, which only has content for the banner
+        (true, "") => banner.to_string(),
+        (true, _) => format!("{}\n\n{}", banner, content),
+    }
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preferences::Preferences;
+
+    #[test]
+    fn test_lint_reports_each_kind_of_problem() {
+        let tables = r#"{
+            "overlapping": {"bands": [{"min": 1, "max": 5, "text": "a"}, {"min": 5, "text": "b"}]},
+            "backwards": {"bands": [{"min": 6, "max": 2, "text": "a"}]},
+            "bad_dice": {"dice": "3x6", "bands": [{"min": 1, "text": "a"}]}
+        }"#;
+        let problems = lint(Some(tables), Some(r#"{"maintenance": "yes"}"#));
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].contains("table backwards range 6-2 ends before it starts"));
+        assert!(problems[1].contains("table bad_dice can't roll its dice 3x6"));
+        assert!(problems[2].contains("table overlapping ranges 1-5 and 5+ overlap"));
+        assert!(problems[3].starts_with("settings.json"));
+
+        let problems = lint(Some("[not json"), None);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("tables.json"));
+
+        assert!(lint(None, None).is_empty());
+    }
+
+    #[test]
+    fn test_setconfig() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.max_images(), 10);
+        settings.set("max_images", "4").unwrap();
+        assert_eq!(settings.max_images(), 4);
+        settings.set("cost_multiplier", " 1.25 ").unwrap();
+        assert_eq!(settings.cost_multiplier(), 1.25);
+        settings.set("max_images", "default").unwrap();
+        assert_eq!(settings.max_images(), 10);
+        assert_eq!(settings.roll_overflow(), Overflow::Summary);
+        settings.set("roll_overflow", "Attachment").unwrap();
+        assert_eq!(settings.roll_overflow(), Overflow::Attachment);
+        assert_eq!(settings.reveal_delay(), None);
+        settings.set("reveal_delay_ms", "800").unwrap();
+        assert_eq!(
+            settings.reveal_delay(),
+            Some(std::time::Duration::from_millis(800))
+        );
+        settings.set("reveal_delay_ms", "0").unwrap();
+        assert_eq!(settings.reveal_delay(), None);
+    }
+
+    #[test]
+    fn test_every_key_can_be_found() {
+        let keys = keys_matching("");
+        assert!(keys.contains(&"roll_overflow".to_string()));
+        assert!(keys.contains(&"flavor".to_string()));
+        assert!(keys.contains(&"reveal_delay_ms".to_string()));
+        assert!(keys.contains(&"persona_prefix.shimmer".to_string()));
+        assert!(!keys.iter().any(|key| key.contains('<')));
+        for key in keys.iter() {
+            assert!(Settings::default().set(key, "default").is_ok(), "{}", key);
+        }
+        assert_eq!(keys_matching("overflow"), vec!["roll_overflow".to_string()]);
+
+        // Discord won't register an option with a longer description
+        for parameter in setconfig().parameters {
+            let description = parameter.description.unwrap_or_default();
+            assert!(description.chars().count() <= 100, "{}", description);
+        }
+    }
+
+    #[test]
+    fn test_setconfig_rejects_unknown_keys() {
+        let mut settings = Settings::default();
+        let err = settings.set("cooldown", "5").unwrap_err();
+        assert!(err.contains("I don't know a setting called cooldown"));
+    }
+
+    #[test]
+    fn test_setconfig_rejects_bad_values() {
+        let mut settings = Settings::default();
+        settings.set("max_images", "3").unwrap();
+        assert!(settings.set("max_images", "0").is_err());
+        assert!(settings.set("max_images", "11").is_err());
+        assert!(settings.set("max_images", "lots").is_err());
+        assert!(settings.set("cost_multiplier", "-1").is_err());
+        assert!(settings.set("cost_multiplier", "NaN").is_err());
+        assert!(settings.set("maintenance_banner", " ").is_err());
+        assert!(settings.set("max_per_channel", "0").is_err());
+        assert!(settings.set("roll_overflow", "scroll").is_err());
+        assert!(settings.set("reveal_delay_ms", "5001").is_err());
+        assert!(settings.set("reveal_delay_ms", "-1").is_err());
+        // failed sets leave the old value alone
+        assert_eq!(settings.max_images(), 3);
+    }
+
+    #[test]
+    fn test_persona_prefix() {
+        let mut settings = Settings::default();
+        // no prefix unless one's configured
+        assert_eq!(
+            apply_persona(
+                &settings,
+                Flavor::Flavorful,
+                "roll",
+                "Rolling 2d6".to_string()
+            ),
+            "Rolling 2d6"
+        );
+        settings.set("persona_prefix.roll", " Zim: ").unwrap();
+        assert_eq!(
+            apply_persona(
+                &settings,
+                Flavor::Flavorful,
+                "roll",
+                "Rolling 2d6".to_string()
+            ),
+            "Zim: Rolling 2d6"
+        );
+        assert_eq!(
+            apply_persona(
+                &settings,
+                Flavor::Flavorful,
+                "shimmer",
+                "Rolling d8".to_string()
+            ),
+            "Rolling d8"
+        );
+        settings.set("persona_prefix.roll", "Hypnos says").unwrap();
+        assert_eq!(
+            apply_persona(&settings, Flavor::Flavorful, "roll", "hi".to_string()),
+            "Hypnos says hi"
+        );
+        settings.set("persona_prefix.roll", "default").unwrap();
+        assert_eq!(
+            apply_persona(&settings, Flavor::Flavorful, "roll", "hi".to_string()),
+            "hi"
+        );
+
+        assert!(settings.set("persona_prefix.gen", "Zim:").is_err());
+        assert!(settings.set("persona_prefix.roll", "").is_err());
+    }
+
+    #[test]
+    fn test_terse_users_skip_the_persona() {
+        let mut settings = Settings::default();
+        settings.set("persona_prefix.roll", "Zim:").unwrap();
+        let mut preferences = Preferences::default();
+        preferences.set_flavor(1, Some(Flavor::Terse));
+        preferences.set_flavor(3, Some(Flavor::Flavorful));
+        let roll_for = |user_id: u64, settings: &Settings| {
+            let flavor = effective_flavor(preferences.flavor(user_id), settings);
+            apply_persona(settings, flavor, "roll", "Rolling 2d6".to_string())
+        };
+        assert_eq!(roll_for(1, &settings), "Rolling 2d6");
+        assert_eq!(roll_for(2, &settings), "Zim: Rolling 2d6");
+
+        // a terse server doesn't override someone who asked for the flavor
+        settings.set("flavor", "terse").unwrap();
+        assert_eq!(roll_for(2, &settings), "Rolling 2d6");
+        assert_eq!(roll_for(3, &settings), "Zim: Rolling 2d6");
+        assert!(settings.set("flavor", "spicy").is_err());
+    }
+
+    #[test]
+    fn test_automation_ids() {
+        assert_eq!(automation_ids(Some("123, 456")), vec![123, 456]);
+        assert_eq!(automation_ids(Some("123,,nope")), vec![123]);
+        assert!(automation_ids(Some("")).is_empty());
+        assert!(automation_ids(None).is_empty());
+    }
+
+    #[test]
+    fn test_banner_only_when_in_maintenance() {
+        assert_eq!(
+            apply_banner(true, "Under maintenance", "Total: 7".to_string()),
+            "Under maintenance\n\nTotal: 7"
+        );
+        assert_eq!(
+            apply_banner(false, "Under maintenance", "Total: 7".to_string()),
+            "Total: 7"
+        );
+        assert_eq!(
+            apply_banner(true, "Under maintenance", String::new()),
+            "Under maintenance"
+        );
+    }
+
+    #[test]
+    fn test_banner_content() {
+        assert_eq!(
+            banner_content("Under maintenance", Some("Total: 7".to_string())),
+            Some("Under maintenance\n\nTotal: 7".to_string())
+        );
+        // an edit that only changes the buttons keeps its content
+        assert_eq!(banner_content("Under maintenance", None), None);
+        // and there's no room for it on a message that's already as long as they go
+        let full = "x".repeat(MAX_MESSAGE_LEN - 5);
+        assert_eq!(
+            banner_content("Under maintenance", Some(full.clone())),
+            Some(full)
+        );
+    }
+}
@@ -2,36 +2,45 @@ use rand::Rng;
 use std::fmt::Write;
 
 use crate::data::{Context, Error};
+use crate::dice_common::{say_chunked, too_many_to_show, ParseError, MAX_MESSAGE_LEN};
+use crate::preferences::Flavor;
+use crate::settings::{flavor_for, with_persona};
 
 #[poise::command(slash_command, prefix_command)]
 pub async fn shimmer(
     ctx: Context<'_>,
-    #[description = "The dice you want to roll, like: `d4` or `3d6 1d10` or even just `6 8 10`"]
+    #[description = "The dice you want to roll, like: `d4` or `3d6 1d10`. `d6^` steps up a die, `d8v` down"]
     dice: String,
 ) -> Result<(), Error> {
-    let response = get_response(&dice);
-    ctx.say(response).await?;
+    let flavor = flavor_for(ctx.data(), ctx.author().id.0).await;
+    let response = get_response(&dice, flavor);
+    say_chunked(
+        ctx,
+        &with_persona(ctx.data(), ctx.author().id.0, "shimmer", response).await,
+        false,
+    )
+    .await?;
     Ok(())
 }
 
-fn get_response(dice: &String) -> String {
-    let roll = DiceRollRequest::parse(&dice);
+fn get_response(dice: &String, flavor: Flavor) -> String {
+    let roll = DiceRollRequest::parse(dice);
     let roll = match roll {
         Err(err) => {
-            return err;
+            return err.message(flavor);
         }
         Ok(roll) => roll,
     };
-    let mut roll = roll.roll();
+    let roll = roll.roll(&mut rand::thread_rng());
     let resp = format!(
         "Rolling {}\n\nResult: {}",
         dice,
         roll.to_discord_markdown().trim()
     );
-    if resp.len() > 1950 {
+    if resp.chars().count() > MAX_MESSAGE_LEN {
         format!(
-            "Roll {}?? hoo.. that's a lot. I don't wanna flood the chat here, so, uh, I'll give you the quick summary:\n\n{}",
-            dice,
+            "{}\n\n{}",
+            too_many_to_show(dice, flavor, false),
             roll.short_summary()
         )
     } else {
@@ -39,8 +48,12 @@ fn get_response(dice: &String) -> String {
     }
 }
 
+// The ladder tops out at a d12 today, but if it ever grows (or gets misconfigured into a
+// loop) a single die still can't cascade forever.
+const MAX_SHIMMERS: u8 = 10;
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord)]
-enum Die {
+pub enum Die {
     D4,
     D6,
     D8,
@@ -65,13 +78,13 @@ impl TryFrom<i32> for Die {
 }
 
 impl Die {
-    fn sides(self) -> u64 {
+    pub fn sides(self) -> u64 {
         match self {
             Die::D4 => 4,
             Die::D6 => 6,
             Die::D8 => 8,
             Die::D10 => 10,
-            Die::D12 => 10,
+            Die::D12 => 12,
         }
     }
     fn bump_up(self) -> Die {
@@ -83,21 +96,44 @@ impl Die {
             Die::D12 => Die::D12,
         }
     }
-    fn roll(self) -> Roll {
-        let num = rand::thread_rng().gen_range(1..=self.sides());
-        if num == 1 {
-            return Roll::Glitch(self);
+    fn bump_down(self) -> Die {
+        match self {
+            Die::D4 => Die::D4,
+            Die::D6 => Die::D4,
+            Die::D8 => Die::D6,
+            Die::D10 => Die::D8,
+            Die::D12 => Die::D10,
+        }
+    }
+    fn roll(self, glitch_on: u64, rng: &mut impl Rng) -> Roll {
+        self.roll_from(
+            &mut |die: Die| rng.gen_range(1..=die.sides()),
+            MAX_SHIMMERS,
+            glitch_on,
+        )
+    }
+
+    // `face` picks the number that comes up on each die, so tests can force particular rolls.
+    fn roll_from(
+        self,
+        face: &mut impl FnMut(Die) -> u64,
+        shimmers_left: u8,
+        glitch_on: u64,
+    ) -> Roll {
+        let num = face(self);
+        if num <= glitch_on {
+            return Roll::Glitch(num, self);
         }
         // A D12 is the biggest, it can't shimmer.
         if let Die::D12 = self {
             return Roll::Value(num, self);
         }
-        if num == self.sides() {
+        if num == self.sides() && shimmers_left > 0 {
             // shimmer potential!
             let bigger_die = self.bump_up();
-            let bigger_roll = bigger_die.roll();
+            let bigger_roll = bigger_die.roll_from(face, shimmers_left - 1, glitch_on);
             match bigger_roll {
-                Roll::Glitch(_) => Roll::Value(num, self),
+                Roll::Glitch(..) => Roll::Value(num, self),
                 Roll::Value(val, _) => {
                     if val < num {
                         Roll::Value(num, self)
@@ -144,87 +180,115 @@ enum Roll {
         shimmer_count: u8,
         value: u64,
     },
-    Glitch(Die),
+    Glitch(u64, Die),
 }
 impl Roll {
     fn is_shimmer(self) -> bool {
-        match self {
-            Roll::Shimmer { .. } => true,
-            _ => false,
-        }
+        matches!(self, Roll::Shimmer { .. })
     }
 
     fn is_glitch(self) -> bool {
-        match self {
-            Roll::Glitch(_) => true,
-            _ => false,
-        }
+        matches!(self, Roll::Glitch(..))
     }
 }
 
-struct DiceRollRequest {
+#[derive(Debug)]
+pub struct DiceRollRequest {
     dice: Vec<Die>,
+    glitch_on: u64,
 }
 
 impl DiceRollRequest {
-    fn parse(s: &str) -> Result<Self, String> {
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        DiceRollRequest::parse_glitching_on(s, crate::dice_common::glitch_on())
+    }
+
+    fn parse_glitching_on(s: &str, glitch_on: u64) -> Result<Self, ParseError> {
         let mut dice = Vec::new();
         for s in s.split_whitespace() {
             if s.trim().is_empty() {
                 continue;
             }
-            let (count, die) = DiceRollRequest::get_die_count(s)
-                .ok_or_else(|| format!("Expected {} to be like XdY, e.g. 3d6 or 1d8", s))?;
+            // d6^ steps a die up the ladder before rolling, d8v steps it down
+            let term = s.trim_end_matches(['^', 'v']);
+            let (count, mut die) = DiceRollRequest::get_die_count(term)?;
+            for step in s[term.len()..].chars() {
+                die = match step {
+                    '^' => die.bump_up(),
+                    _ => die.bump_down(),
+                };
+            }
             if count > 1_000_000 {
-                return Err(format!(
-                    "Hey buddy, I'm just a demigod, that's too many dice!",
-                ));
+                return Err(ParseError::TooManyDice);
             }
             for _ in 0..count {
                 dice.push(die);
             }
         }
-        Ok(DiceRollRequest { dice })
+        // a die that can only glitch isn't much use to anyone
+        if let Some(die) = dice.iter().find(|die| die.sides() <= glitch_on) {
+            return Err(ParseError::GlitchOn {
+                threshold: glitch_on,
+                sides: die.sides(),
+            });
+        }
+        Ok(DiceRollRequest { dice, glitch_on })
     }
 
-    fn get_die_count(s: &str) -> Option<(u64, Die)> {
-        if let Ok(sides) = s.trim().parse::<i32>() {
-            return Some((1, sides.try_into().ok()?));
-        }
-        let idx = s.find('d')?;
-        let (count, sides) = (&s[..idx], &s[idx + 1..]);
-        let count: u64 = if count.trim().is_empty() {
-            1
-        } else {
-            count.trim().parse().ok()?
+    fn get_die_count(s: &str) -> Result<(u64, Die), ParseError> {
+        let invalid = || ParseError::InvalidDie(s.to_string());
+        let (count, sides) = match s.find('d') {
+            None => (1, s),
+            Some(idx) => {
+                let count = s[..idx].trim();
+                let count = if count.is_empty() {
+                    1
+                } else {
+                    count.parse().map_err(|_| invalid())?
+                };
+                (count, &s[idx + 1..])
+            }
         };
-        let sides: i32 = sides.trim().parse().ok()?;
-        Some((count, sides.try_into().ok()?))
+        let sides: i32 = sides.trim().parse().map_err(|_| invalid())?;
+        let die = sides
+            .try_into()
+            .map_err(|_| ParseError::UnknownSides(sides))?;
+        Ok((count, die))
     }
 
-    fn roll(self) -> RollResult {
+    // Takes the rng so that tests can seed it. Commands just use `rand::thread_rng()`.
+    pub fn roll(&self, rng: &mut impl Rng) -> RollResult {
         let mut rolls = Vec::new();
-        for die in self.dice {
-            rolls.push(die.roll());
+        for die in self.dice.iter() {
+            rolls.push(die.roll(self.glitch_on, rng));
         }
         RollResult { rolled_die: rolls }
     }
+
+    pub fn dice_count(&self) -> usize {
+        self.dice.len()
+    }
 }
 
-struct RollResult {
+pub struct RollResult {
     rolled_die: Vec<Roll>,
 }
 impl RollResult {
-    fn is_botch(&self) -> bool {
+    pub fn is_botch(&self) -> bool {
         self.rolled_die.iter().all(|r| r.is_glitch())
     }
 
-    fn to_discord_markdown(&mut self) -> String {
+    // How many of the dice shimmered, however far they went.
+    pub fn shimmers(&self) -> usize {
+        self.rolled_die.iter().filter(|r| r.is_shimmer()).count()
+    }
+
+    pub fn to_discord_markdown(&self) -> String {
         let mut s = String::new();
         for roll in self.rolled_die.iter() {
             match roll {
-                Roll::Glitch(die) => {
-                    s.push_str(&format!("**1** (d{}) ", die.sides()));
+                Roll::Glitch(value, die) => {
+                    s.push_str(&format!("**{}** (d{}) ", value, die.sides()));
                 }
                 Roll::Shimmer {
                     initial,
@@ -259,7 +323,7 @@ impl RollResult {
         s
     }
 
-    fn short_summary(&mut self) -> String {
+    pub fn short_summary(&self) -> String {
         let mut s = String::new();
         if self.is_botch() {
             s += "**BOTCH!**";
@@ -305,12 +369,12 @@ impl RollResult {
         s
     }
 
-    fn get_highest_effect(&self) -> FinalResult {
+    pub fn get_highest_effect(&self) -> FinalResult {
         let vals = self
             .rolled_die
             .iter()
             .filter_map(|roll| match roll {
-                Roll::Glitch(_) => None,
+                Roll::Glitch(..) => None,
                 Roll::Shimmer {
                     ultimate, value, ..
                 } => Some((*value, *ultimate)),
@@ -342,21 +406,21 @@ impl RollResult {
         FinalResult::Result { total: val, effect }
     }
 
-    fn get_highest_total(&mut self) -> FinalResult {
-        self.rolled_die.sort_by_key(|roll| match roll {
-            Roll::Glitch(_) => (0, 0),
+    pub fn get_highest_total(&self) -> FinalResult {
+        let mut rolled_die = self.rolled_die.clone();
+        rolled_die.sort_by_key(|roll| match roll {
+            Roll::Glitch(..) => (0, 0),
             Roll::Shimmer {
                 ultimate, value, ..
             } => (*value, -(ultimate.sides() as i128)),
             Roll::Value(v, d) => (*v, -(d.sides() as i128)),
         });
-        let total = self
-            .rolled_die
+        let total = rolled_die
             .iter()
             .rev()
             .take(2)
             .filter_map(|roll| match roll {
-                Roll::Glitch(_) => None,
+                Roll::Glitch(..) => None,
                 Roll::Value(v, _) => Some(*v),
                 Roll::Shimmer { value, .. } => Some(*value),
             })
@@ -364,13 +428,12 @@ impl RollResult {
         if total == 0 {
             return FinalResult::Botch;
         }
-        let effect = self
-            .rolled_die
+        let effect = rolled_die
             .iter()
             .rev()
             .skip(2)
             .filter_map(|d| match d {
-                Roll::Glitch(_) => None,
+                Roll::Glitch(..) => None,
                 Roll::Shimmer { ultimate, .. } => Some(*ultimate),
                 Roll::Value(_, die) => Some(*die),
             })
@@ -381,7 +444,7 @@ impl RollResult {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum FinalResult {
+pub enum FinalResult {
     Botch,
     Result { total: u64, effect: Die },
 }
@@ -410,9 +473,152 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_shimmer_cap() {
+        // every die rolls its max, so everything shimmers as far as it's allowed to
+        let mut max = |die: Die| die.sides();
+        match Die::D4.roll_from(&mut max, 2, 1) {
+            Roll::Shimmer {
+                ultimate,
+                shimmer_count,
+                ..
+            } => {
+                assert_eq!(shimmer_count, 2);
+                assert_eq!(ultimate, Die::D8);
+            }
+            roll => panic!("expected a shimmer, got {:?}", roll),
+        }
+        assert!(matches!(
+            Die::D4.roll_from(&mut max, 0, 1),
+            Roll::Value(4, Die::D4)
+        ));
+        match Die::D4.roll_from(&mut max, MAX_SHIMMERS, 1) {
+            Roll::Shimmer { shimmer_count, .. } => assert!(shimmer_count <= MAX_SHIMMERS),
+            roll => panic!("expected a shimmer, got {:?}", roll),
+        }
+    }
+
+    #[test]
+    fn test_d12_has_twelve_sides() {
+        assert_eq!(Die::D12.sides(), 12);
+        assert_eq!(Die::D12.to_string(), "d12");
+    }
+
+    #[test]
+    fn test_d12_can_roll_over_ten() {
+        let pool = DiceRollRequest::parse("d12 d4").unwrap();
+        let mut max = |die: Die| die.sides();
+        let roll_result = RollResult {
+            rolled_die: pool
+                .dice
+                .into_iter()
+                .map(|die| die.roll_from(&mut max, MAX_SHIMMERS, 1))
+                .collect(),
+        };
+        assert!(matches!(
+            roll_result.rolled_die[0],
+            Roll::Value(12, Die::D12)
+        ));
+        match roll_result.get_highest_total() {
+            FinalResult::Result { total, .. } => assert!(total > 10, "total was {}", total),
+            FinalResult::Botch => panic!("expected a total"),
+        }
+    }
+
+    #[test]
+    fn test_seeded_rolls_are_deterministic() {
+        use rand::SeedableRng;
+        // plenty of d4s, so some of them are bound to shimmer
+        let roll = |seed| {
+            DiceRollRequest::parse("20d4 3d8 d12")
+                .unwrap()
+                .roll(&mut rand::rngs::StdRng::seed_from_u64(seed))
+                .to_discord_markdown()
+        };
+        assert_eq!(roll(7), roll(7));
+        assert!(roll(7).contains("shimmered"));
+    }
+
+    #[test]
+    fn test_glitch_on() {
+        use rand::SeedableRng;
+        let mut two = |_| 2;
+        assert!(matches!(
+            Die::D6.roll_from(&mut two, MAX_SHIMMERS, 2),
+            Roll::Glitch(2, Die::D6)
+        ));
+        assert!(matches!(
+            Die::D6.roll_from(&mut two, MAX_SHIMMERS, 1),
+            Roll::Value(2, Die::D6)
+        ));
+
+        let request = DiceRollRequest::parse_glitching_on("20d6", 2).unwrap();
+        let roll = request.roll(&mut rand::rngs::StdRng::seed_from_u64(42));
+        let markdown = roll.to_discord_markdown();
+        assert!(roll
+            .rolled_die
+            .iter()
+            .any(|r| matches!(r, Roll::Glitch(2, _))));
+        assert!(!roll
+            .rolled_die
+            .iter()
+            .any(|r| matches!(r, Roll::Value(2, _))));
+        assert!(markdown.contains("**2** (d6)"));
+
+        assert_eq!(
+            DiceRollRequest::parse_glitching_on("3d8 d4", 4).unwrap_err(),
+            ParseError::GlitchOn {
+                threshold: 4,
+                sides: 4
+            }
+        );
+    }
+
+    #[test]
+    fn test_stepping_dice() {
+        let request = DiceRollRequest::parse("d6^").unwrap();
+        assert_eq!(request.dice, vec![Die::D8]);
+        let roll = request.roll(&mut rand::thread_rng());
+        assert!(roll.to_discord_markdown().contains("(d8"));
+
+        assert_eq!(DiceRollRequest::parse("d4v").unwrap().dice, vec![Die::D4]);
+        assert_eq!(
+            DiceRollRequest::parse("2d12^").unwrap().dice,
+            vec![Die::D12; 2]
+        );
+        assert_eq!(
+            DiceRollRequest::parse("d8v 6^^").unwrap().dice,
+            vec![Die::D6, Die::D10]
+        );
+        assert_eq!(
+            DiceRollRequest::parse("^").unwrap_err(),
+            ParseError::InvalidDie("".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            DiceRollRequest::parse("2d7").unwrap_err(),
+            ParseError::UnknownSides(7)
+        );
+        assert_eq!(
+            DiceRollRequest::parse("20").unwrap_err(),
+            ParseError::UnknownSides(20)
+        );
+        assert_eq!(
+            DiceRollRequest::parse("3x6").unwrap_err(),
+            ParseError::InvalidDie("3x6".to_string())
+        );
+        assert_eq!(
+            DiceRollRequest::parse("2000000d6").unwrap_err(),
+            ParseError::TooManyDice
+        );
+    }
+
     #[test]
     fn test_get_highest_total() {
-        let mut roll_result = RollResult {
+        let roll_result = RollResult {
             rolled_die: vec![
                 Roll::Value(1, Die::D4),
                 Roll::Value(2, Die::D6),
@@ -428,7 +634,7 @@ mod tests {
             }
         );
 
-        let mut roll_result = RollResult {
+        let roll_result = RollResult {
             rolled_die: vec![
                 Roll::Shimmer {
                     initial: Die::D4,
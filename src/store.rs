@@ -0,0 +1,755 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::data::{Account, CostMap, Error};
+
+// Bump this and teach `DataFile::parse` how to upgrade the previous shape whenever
+// the on-disk format changes.
+const SCHEMA_VERSION: u64 = 1;
+// Same idea for the SQLite tables, kept in `PRAGMA user_version`. Zero is a brand new
+// database.
+const DB_VERSION: i64 = 4;
+
+// Where accounts live between restarts. The JSON file is the default, set HYPNOS_DB to
+// a path to keep them in SQLite instead.
+pub(crate) trait Store: Send {
+    fn get(&self, user_id: u64) -> Result<Option<Account>, Error>;
+    // Saves all of these accounts, or none of them.
+    fn put(&mut self, accounts: &CostMap) -> Result<(), Error>;
+    fn all(&self) -> Result<CostMap, Error>;
+}
+
+// Every account in one file, rewritten whenever anything changes.
+pub(crate) struct JsonStore {
+    path: PathBuf,
+    accounts: CostMap,
+}
+impl JsonStore {
+    // Starts empty if there's no file yet, upgrading it if it's from an older schema. A
+    // file that's there but won't read is an error, since carrying on would write over
+    // everyone's balances with nothing.
+    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let (store, outdated) = JsonStore::load(path)?;
+        if outdated {
+            println!(
+                "Upgrading {} to schema version {}",
+                store.path.display(),
+                SCHEMA_VERSION
+            );
+            store.write(&store.accounts)?;
+        }
+        Ok(store)
+    }
+
+    // Like `open`, but leaves a file from an older schema as it is. For DRY_RUN.
+    pub(crate) fn read(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(JsonStore::load(path)?.0)
+    }
+
+    // The store, and whether the file needs upgrading.
+    fn load(path: impl AsRef<Path>) -> Result<(Self, bool), Error> {
+        let path = path.as_ref().to_path_buf();
+        let (file, outdated) = match std::fs::read_to_string(&path) {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (DataFile::default(), false),
+            Err(err) => return Err(format!("can't read {}: {}", path.display(), err).into()),
+            Ok(contents) => DataFile::parse(&contents)
+                .map_err(|err| format!("can't read {}: {}", path.display(), err))?,
+        };
+        let store = JsonStore {
+            path,
+            accounts: file.accounts,
+        };
+        Ok((store, outdated))
+    }
+
+    // Doesn't look at what's on disk, and won't touch it until something is saved.
+    pub(crate) fn empty(path: impl AsRef<Path>) -> Self {
+        JsonStore {
+            path: path.as_ref().to_path_buf(),
+            accounts: CostMap::new(),
+        }
+    }
+
+    fn write(&self, accounts: &CostMap) -> Result<(), Error> {
+        let serialized = serde_json::to_string(&DataFileRef {
+            schema_version: SCHEMA_VERSION,
+            accounts,
+        })?;
+        write_atomically(&self.path, |file| file.write_all(serialized.as_bytes()))?;
+        Ok(())
+    }
+}
+
+// For DRY_RUN. Reads through to the real store, but anything saved stays in memory, so
+// the bot acts like it's keeping accounts without ever changing them.
+pub(crate) struct DryRunStore {
+    inner: Box<dyn Store>,
+    saved: CostMap,
+}
+impl DryRunStore {
+    pub(crate) fn new(inner: Box<dyn Store>) -> Self {
+        DryRunStore {
+            inner,
+            saved: CostMap::new(),
+        }
+    }
+}
+impl Store for DryRunStore {
+    fn get(&self, user_id: u64) -> Result<Option<Account>, Error> {
+        match self.saved.get(&user_id) {
+            Some(account) => Ok(Some(account.clone())),
+            None => self.inner.get(user_id),
+        }
+    }
+
+    fn put(&mut self, accounts: &CostMap) -> Result<(), Error> {
+        self.saved
+            .extend(accounts.iter().map(|(id, account)| (*id, account.clone())));
+        Ok(())
+    }
+
+    fn all(&self) -> Result<CostMap, Error> {
+        let mut accounts = self.inner.all()?;
+        accounts.extend(self.saved.clone());
+        Ok(accounts)
+    }
+}
+
+// Writes to `<path>.tmp` and then renames it over `path`, so a crash part way through
+// leaves the last good file alone rather than half of a new one.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut std::fs::File) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let tmp = path.with_file_name(name);
+    let result = std::fs::File::create(&tmp).and_then(|mut file| {
+        write(&mut file)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+impl Store for JsonStore {
+    fn get(&self, user_id: u64) -> Result<Option<Account>, Error> {
+        Ok(self.accounts.get(&user_id).cloned())
+    }
+
+    // On disk first, so a failed write doesn't leave memory saying otherwise.
+    fn put(&mut self, accounts: &CostMap) -> Result<(), Error> {
+        let mut updated = self.accounts.clone();
+        for (id, account) in accounts.iter() {
+            updated.insert(*id, account.clone());
+        }
+        self.write(&updated)?;
+        self.accounts = updated;
+        Ok(())
+    }
+
+    fn all(&self) -> Result<CostMap, Error> {
+        Ok(self.accounts.clone())
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct DataFile {
+    accounts: CostMap,
+}
+impl DataFile {
+    // Returns the parsed file, and whether it was written with an older schema.
+    fn parse(contents: &str) -> Result<(Self, bool), Error> {
+        let value: serde_json::Value = serde_json::from_str(contents)?;
+        match value.get("schema_version").and_then(|v| v.as_u64()) {
+            // version 0 didn't have a version, it was just the bare map of accounts
+            None => Ok((
+                DataFile {
+                    accounts: serde_json::from_value(value)?,
+                },
+                true,
+            )),
+            Some(SCHEMA_VERSION) => Ok((serde_json::from_value(value)?, false)),
+            Some(version) => Err(format!("unknown schema version {}", version).into()),
+        }
+    }
+}
+
+// Checks an accounts file field by field, so that a bad import says exactly what's wrong
+// with it rather than stopping at the first thing serde trips over.
+pub(crate) fn validate_data_file(contents: &str) -> Result<CostMap, Vec<String>> {
+    let value: serde_json::Value =
+        serde_json::from_str(contents).map_err(|err| vec![format!("not valid JSON: {}", err)])?;
+    let accounts = match value.get("schema_version") {
+        // version 0 is just the bare map of accounts
+        None => &value,
+        Some(version) if version.as_u64() == Some(SCHEMA_VERSION) => match value.get("accounts") {
+            None => return Err(vec!["accounts: missing".to_string()]),
+            Some(accounts) => accounts,
+        },
+        Some(version) => return Err(vec![format!("unknown schema version {}", version)]),
+    };
+    let accounts = match accounts.as_object() {
+        None => {
+            return Err(vec![
+                "accounts: expected an object of accounts by user id".to_string()
+            ])
+        }
+        Some(accounts) => accounts,
+    };
+    let mut problems = Vec::new();
+    for (id, account) in accounts {
+        if id.parse::<u64>().is_err() {
+            problems.push(format!("accounts.{}: the key has to be a user id", id));
+        }
+        problems.extend(validate_account(&format!("accounts.{}", id), account));
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+    serde_json::from_value(accounts.clone().into()).map_err(|err| vec![err.to_string()])
+}
+
+fn validate_account(path: &str, account: &serde_json::Value) -> Vec<String> {
+    let account = match account.as_object() {
+        None => return vec![format!("{}: expected an account object", path)],
+        Some(account) => account,
+    };
+    let mut problems = Vec::new();
+    match account.get("user") {
+        None => problems.push(format!("{}.user: missing", path)),
+        Some(user) => match user.as_str() {
+            None => problems.push(format!("{}.user: expected a string", path)),
+            Some(user) if user.trim().is_empty() => {
+                problems.push(format!("{}.user: can't be empty", path))
+            }
+            Some(_) => {}
+        },
+    }
+    match account.get("images") {
+        None => problems.push(format!("{}.images: missing", path)),
+        Some(images) if images.as_u64().is_none() => problems.push(format!(
+            "{}.images: expected a whole number, zero or more, got {}",
+            path, images
+        )),
+        Some(_) => {}
+    }
+    if let Some(last_reset) = account.get("last_reset") {
+        let valid = last_reset
+            .as_str()
+            .is_some_and(|s| chrono::DateTime::parse_from_rfc3339(s).is_ok());
+        if !valid {
+            problems.push(format!(
+                "{}.last_reset: expected a date like 2024-01-01T00:00:00Z, got {}",
+                path, last_reset
+            ));
+        }
+    }
+    if let Some(grants) = account.get("grants") {
+        match grants.as_array() {
+            None => problems.push(format!("{}.grants: expected a list of grants", path)),
+            Some(grants) => {
+                for (i, grant) in grants.iter().enumerate() {
+                    let valid = grant
+                        .get("credit_granted_at")
+                        .and_then(|at| at.as_str())
+                        .is_some_and(|at| chrono::DateTime::parse_from_rfc3339(at).is_ok())
+                        && grant.get("unspent").is_some_and(|unspent| unspent.is_i64());
+                    if !valid {
+                        problems.push(format!(
+                            "{}.grants[{}]: expected a credit_granted_at date and a whole number of unspent millicents, got {}",
+                            path, i, grant
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    if let Some(self_limit) = account.get("self_limit") {
+        let valid = self_limit.as_object().is_some_and(|self_limit| {
+            self_limit
+                .get("monthly")
+                .is_none_or(|monthly| monthly.is_null() || monthly.is_i64())
+                && self_limit.get("spent").is_none_or(|spent| spent.is_i64())
+                && self_limit.get("month").is_none_or(|month| {
+                    month
+                        .as_str()
+                        .is_some_and(|s| chrono::DateTime::parse_from_rfc3339(s).is_ok())
+                })
+        });
+        if !valid {
+            problems.push(format!(
+                "{}.self_limit: expected a monthly limit and what's been spent in millicents, and the month as a date, got {}",
+                path, self_limit
+            ));
+        }
+    }
+    // raw_cost came later, so older files don't have it
+    for (field, required) in [("credit", true), ("total_cost", true), ("raw_cost", false)] {
+        match account.get(field) {
+            None if required => problems.push(format!("{}.{}: missing", path, field)),
+            Some(value) if value.as_i64().is_none() => problems.push(format!(
+                "{}.{}: expected a whole number of millicents, got {}",
+                path, field, value
+            )),
+            _ => {}
+        }
+    }
+    problems
+}
+
+#[derive(serde::Serialize)]
+struct DataFileRef<'a> {
+    schema_version: u64,
+    accounts: &'a CostMap,
+}
+
+// One row per account, so a debit only writes the one that changed.
+pub(crate) struct SqliteStore {
+    conn: Connection,
+}
+impl SqliteStore {
+    // The first time, this creates the tables and brings over everything in `legacy`
+    // (the old data.json), if there is one.
+    pub(crate) fn open(path: impl AsRef<Path>, legacy: impl AsRef<Path>) -> Result<Self, Error> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn, legacy.as_ref())?;
+        Ok(SqliteStore { conn })
+    }
+
+    // For DRY_RUN, which can't write to the database even to upgrade it, so it has to be
+    // up to date already. One that isn't there yet is made in memory instead.
+    pub(crate) fn open_read_only(
+        path: impl AsRef<Path>,
+        legacy: impl AsRef<Path>,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            let mut conn = Connection::open_in_memory()?;
+            migrate(&mut conn, legacy.as_ref())?;
+            return Ok(SqliteStore { conn });
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version != DB_VERSION {
+            return Err(format!(
+                "{} is at version {} rather than {}, start once without DRY_RUN to upgrade it",
+                path.display(),
+                version,
+                DB_VERSION
+            )
+            .into());
+        }
+        Ok(SqliteStore { conn })
+    }
+}
+impl Store for SqliteStore {
+    fn get(&self, user_id: u64) -> Result<Option<Account>, Error> {
+        let account = self
+            .conn
+            .query_row(
+                "SELECT user, images, credit, total_cost, raw_cost, last_reset, grants, self_limit FROM accounts WHERE user_id = ?1",
+                params![user_id as i64],
+                |row| row_to_account(row, 0),
+            )
+            .optional()?;
+        Ok(account)
+    }
+
+    fn put(&mut self, accounts: &CostMap) -> Result<(), Error> {
+        let tx = self.conn.transaction()?;
+        insert_accounts(&tx, accounts)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn all(&self) -> Result<CostMap, Error> {
+        let mut statement = self.conn.prepare(
+            "SELECT user_id, user, images, credit, total_cost, raw_cost, last_reset, grants, self_limit FROM accounts",
+        )?;
+        let rows = statement.query_map([], |row| {
+            // ids are stored as i64, the cast back undoes the one going in
+            Ok((row.get::<_, i64>(0)? as u64, row_to_account(row, 1)?))
+        })?;
+        let mut accounts = CostMap::new();
+        for row in rows {
+            let (id, account) = row?;
+            accounts.insert(id, account);
+        }
+        Ok(accounts)
+    }
+}
+
+// The account in the columns from `start` on.
+fn row_to_account(row: &rusqlite::Row, start: usize) -> rusqlite::Result<Account> {
+    Ok(Account {
+        user: row.get(start)?,
+        images: row.get::<_, i64>(start + 1)? as u64,
+        credit: row.get(start + 2)?,
+        total_cost: row.get(start + 3)?,
+        raw_cost: row.get(start + 4)?,
+        // seconds since the epoch, which is also what a never reset account has
+        last_reset: chrono::DateTime::from_timestamp(row.get(start + 5)?, 0).unwrap_or_default(),
+        // JSON, since nothing needs to look inside these from SQL
+        grants: json_column(row, start + 6)?,
+        self_limit: json_column(row, start + 7)?,
+    })
+}
+
+fn json_column<T: serde::de::DeserializeOwned>(
+    row: &rusqlite::Row,
+    i: usize,
+) -> rusqlite::Result<T> {
+    serde_json::from_str(&row.get::<_, String>(i)?).map_err(|err| {
+        rusqlite::Error::FromSqlConversionFailure(i, rusqlite::types::Type::Text, Box::new(err))
+    })
+}
+
+fn insert_accounts(conn: &Connection, accounts: &CostMap) -> Result<(), Error> {
+    let mut statement = conn.prepare(
+        "INSERT OR REPLACE INTO accounts (user_id, user, images, credit, total_cost, raw_cost, last_reset, grants, self_limit)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+    )?;
+    for (id, account) in accounts.iter() {
+        statement.execute(params![
+            *id as i64,
+            account.user,
+            account.images as i64,
+            account.credit,
+            account.total_cost,
+            account.raw_cost,
+            account.last_reset.timestamp(),
+            serde_json::to_string(&account.grants)?,
+            serde_json::to_string(&account.self_limit)?
+        ])?;
+    }
+    Ok(())
+}
+
+fn migrate(conn: &mut Connection, legacy: &Path) -> Result<(), Error> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    match version {
+        DB_VERSION => return Ok(()),
+        version if (0..DB_VERSION).contains(&version) => {}
+        version => return Err(format!("unknown database version {}", version).into()),
+    }
+    // a data.json we can't read is worth stopping for, rather than starting everyone
+    // over from scratch
+    let imported = match std::fs::read_to_string(legacy) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => CostMap::new(),
+        Err(_) if version > 0 => CostMap::new(),
+        Err(err) => return Err(format!("can't read {}: {}", legacy.display(), err).into()),
+        Ok(_) if version > 0 => CostMap::new(),
+        Ok(contents) => validate_data_file(&contents).map_err(|problems| {
+            format!(
+                "can't import {}:\n- {}",
+                legacy.display(),
+                problems.join("\n- ")
+            )
+        })?,
+    };
+    let tx = conn.transaction()?;
+    if version < 1 {
+        tx.execute_batch(
+            "CREATE TABLE accounts (
+                user_id INTEGER PRIMARY KEY,
+                user TEXT NOT NULL,
+                images INTEGER NOT NULL,
+                credit INTEGER NOT NULL,
+                total_cost INTEGER NOT NULL,
+                raw_cost INTEGER NOT NULL
+            )",
+        )?;
+    }
+    if version < 2 {
+        tx.execute_batch("ALTER TABLE accounts ADD COLUMN last_reset INTEGER NOT NULL DEFAULT 0")?;
+    }
+    if version < 3 {
+        tx.execute_batch("ALTER TABLE accounts ADD COLUMN grants TEXT NOT NULL DEFAULT '[]'")?;
+    }
+    if version < 4 {
+        tx.execute_batch("ALTER TABLE accounts ADD COLUMN self_limit TEXT NOT NULL DEFAULT '{}'")?;
+    }
+    insert_accounts(&tx, &imported)?;
+    tx.pragma_update(None, "user_version", DB_VERSION)?;
+    tx.commit()?;
+    if !imported.is_empty() {
+        println!(
+            "Imported {} accounts from {}",
+            imported.len(),
+            legacy.display()
+        );
+    }
+    Ok(())
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("hypnos-store-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn account() -> Account {
+        Account {
+            user: "someone#0001".to_string(),
+            images: 7,
+            credit: -123_456,
+            total_cost: 2_123_456,
+            raw_cost: 1_900_000,
+            last_reset: chrono::DateTime::default(),
+            grants: Vec::new(),
+            self_limit: Default::default(),
+        }
+    }
+
+    // big enough to go negative as an i64
+    const BIG_ID: u64 = u64::MAX - 5;
+
+    fn round_trip(open: impl Fn() -> Box<dyn Store>) {
+        let mut store = open();
+        assert_eq!(store.get(BIG_ID).unwrap(), None);
+        let mut accounts = CostMap::new();
+        accounts.insert(BIG_ID, account());
+        let mut other = Account::named("other".to_string(), crate::data::DEFAULT_CREDIT);
+        other.grant(
+            5_000,
+            chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        );
+        other.self_limit.monthly = Some(10_000);
+        accounts.insert(2, other);
+        store.put(&accounts).unwrap();
+        drop(store);
+
+        let store = open();
+        assert_eq!(store.get(BIG_ID).unwrap(), Some(account()));
+        assert_eq!(store.all().unwrap(), accounts);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let path = temp_path("json");
+        round_trip(|| Box::new(JsonStore::open(&path).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_interrupted_write_keeps_the_old_file() {
+        let path = temp_path("interrupted.json");
+        let mut accounts = CostMap::new();
+        accounts.insert(1234, account());
+        JsonStore::empty(&path).put(&accounts).unwrap();
+        let good = std::fs::read_to_string(&path).unwrap();
+
+        // dies half way through writing
+        let err = write_atomically(&path, |file| {
+            file.write_all(&good.as_bytes()[..good.len() / 2])?;
+            Err(std::io::Error::other("killed"))
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "killed");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), good);
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        assert!(!PathBuf::from(tmp).exists());
+        let store = JsonStore::open(&path).unwrap();
+        assert_eq!(store.get(1234).unwrap(), Some(account()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_put_changes_nothing() {
+        let path = temp_path("failed-put.json");
+        let mut store = JsonStore::empty(&path);
+        store.put(&CostMap::from([(1234, account())])).unwrap();
+        let good = std::fs::read_to_string(&path).unwrap();
+
+        // something's in the way of the temp file, so the write can't happen
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::create_dir(&tmp).unwrap();
+        let mut changed = account();
+        changed.credit += 1;
+        let put = CostMap::from([(1234, changed), (5678, account())]);
+        assert!(store.put(&put).is_err());
+        assert_eq!(store.get(1234).unwrap(), Some(account()));
+        assert_eq!(store.get(5678).unwrap(), None);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), good);
+
+        std::fs::remove_dir(&tmp).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_round_trip() {
+        let path = temp_path("sqlite");
+        let legacy = temp_path("sqlite-legacy");
+        round_trip(|| Box::new(SqliteStore::open(&path, &legacy).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_imports_data_json_once() {
+        let path = temp_path("import");
+        let legacy = temp_path("import-legacy");
+        let mut accounts = CostMap::new();
+        accounts.insert(1234, account());
+        JsonStore::empty(&legacy).put(&accounts).unwrap();
+
+        let store = SqliteStore::open(&path, &legacy).unwrap();
+        assert_eq!(store.all().unwrap(), accounts);
+        drop(store);
+
+        // later changes to data.json don't matter any more
+        JsonStore::empty(&legacy).put(&CostMap::new()).unwrap();
+        let store = SqliteStore::open(&path, &legacy).unwrap();
+        assert_eq!(store.get(1234).unwrap(), Some(account()));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&legacy).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_read_only() {
+        let path = temp_path("read-only");
+        let legacy = temp_path("read-only-legacy");
+        let mut accounts = CostMap::new();
+        accounts.insert(1234, account());
+        JsonStore::empty(&legacy).put(&accounts).unwrap();
+
+        // with no database yet, it's imported into one in memory
+        let store = SqliteStore::open_read_only(&path, &legacy).unwrap();
+        assert_eq!(store.all().unwrap(), accounts);
+        assert!(!path.exists());
+
+        drop(SqliteStore::open(&path, &legacy).unwrap());
+        let store = SqliteStore::open_read_only(&path, &legacy).unwrap();
+        assert_eq!(store.get(1234).unwrap(), Some(account()));
+        drop(store);
+
+        // and one that would need upgrading is left alone
+        let conn = Connection::open(&path).unwrap();
+        conn.pragma_update(None, "user_version", DB_VERSION - 1)
+            .unwrap();
+        drop(conn);
+        assert!(SqliteStore::open_read_only(&path, &legacy).is_err());
+        let conn = Connection::open(&path).unwrap();
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, DB_VERSION - 1);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&legacy).unwrap();
+    }
+
+    #[test]
+    fn test_validate_data_file() {
+        let accounts = validate_data_file(
+            r#"{"schema_version":1,"accounts":{"1234":{"user":"someone#0001","images":7,"credit":-123456,"total_cost":2123456,"raw_cost":1900000}}}"#,
+        )
+        .unwrap();
+        assert_eq!(accounts[&1234], account());
+        // version 0 files, without raw_cost, are fine too
+        let accounts =
+            validate_data_file(r#"{"1":{"user":"a","images":0,"credit":5,"total_cost":0}}"#)
+                .unwrap();
+        assert_eq!(accounts[&1].raw_cost, 0);
+    }
+
+    #[test]
+    fn test_validate_data_file_itemizes_problems() {
+        let problems = validate_data_file(
+            r#"{"schema_version":1,"accounts":{
+                "1":{"images":-3,"credit":"lots","total_cost":0},
+                "2":{"user":"","images":1.5,"total_cost":0,"raw_cost":null},
+                "three":{"user":7,"images":0,"credit":0,"total_cost":0},
+                "4":[]
+            }}"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            problems,
+            vec![
+                "accounts.1.user: missing",
+                "accounts.1.images: expected a whole number, zero or more, got -3",
+                r#"accounts.1.credit: expected a whole number of millicents, got "lots""#,
+                "accounts.2.user: can't be empty",
+                "accounts.2.images: expected a whole number, zero or more, got 1.5",
+                "accounts.2.credit: missing",
+                "accounts.2.raw_cost: expected a whole number of millicents, got null",
+                "accounts.4: expected an account object",
+                "accounts.three: the key has to be a user id",
+                "accounts.three.user: expected a string",
+            ]
+        );
+        assert_eq!(
+            validate_data_file(r#"{"schema_version":1}"#).unwrap_err(),
+            vec!["accounts: missing"]
+        );
+        assert!(validate_data_file("[1, 2").unwrap_err()[0].starts_with("not valid JSON"));
+    }
+
+    #[test]
+    fn test_sqlite_adds_last_reset_to_version_1() {
+        let path = temp_path("v1");
+        let legacy = temp_path("v1-legacy");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE accounts (
+                user_id INTEGER PRIMARY KEY,
+                user TEXT NOT NULL,
+                images INTEGER NOT NULL,
+                credit INTEGER NOT NULL,
+                total_cost INTEGER NOT NULL,
+                raw_cost INTEGER NOT NULL
+            );
+            INSERT INTO accounts VALUES (1234, 'someone#0001', 7, -123456, 2123456, 1900000);
+            PRAGMA user_version = 1;",
+        )
+        .unwrap();
+        drop(conn);
+
+        let mut store = SqliteStore::open(&path, &legacy).unwrap();
+        assert_eq!(store.get(1234).unwrap(), Some(account()));
+        let reset = Account {
+            last_reset: chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            ..account()
+        };
+        store.put(&CostMap::from([(1234, reset.clone())])).unwrap();
+        assert_eq!(store.get(1234).unwrap(), Some(reset));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_unknown_schema_version_is_rejected() {
+        assert!(DataFile::parse(r#"{"schema_version":99,"accounts":{}}"#).is_err());
+    }
+
+    #[test]
+    fn test_unreadable_data_json_is_left_alone() {
+        let path = temp_path("unreadable.json");
+        for contents in [r#"{"schema_version":99,"accounts":{}}"#, "{\"1\": tru"] {
+            std::fs::write(&path, contents).unwrap();
+            assert!(JsonStore::open(&path).is_err());
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), contents);
+        }
+        // a directory where the file should be can't be read either
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        assert!(JsonStore::open(&path).is_err());
+        std::fs::remove_dir(&path).unwrap();
+        // but no file at all is a fresh start
+        assert_eq!(
+            JsonStore::open(&path).unwrap().all().unwrap(),
+            CostMap::new()
+        );
+    }
+}
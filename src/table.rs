@@ -0,0 +1,272 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::data::{Context, Error};
+use crate::dice::DiceRollRequest;
+use crate::preferences::Flavor;
+use crate::settings::{flavor_for, with_persona};
+
+pub(crate) const TABLES_FILE: &str = "tables.json";
+
+#[poise::command(slash_command, prefix_command)]
+pub async fn roll_table(
+    ctx: Context<'_>,
+    #[description = "The name of the outcome table to roll on"] table: String,
+    #[description = "The dice to roll, instead of the table's usual pool"] dice: Option<String>,
+) -> Result<(), Error> {
+    let flavor = flavor_for(ctx.data(), ctx.author().id.0).await;
+    let response = match ctx.data().tables.get(&table) {
+        None => format!("I don't know any table called {}", table),
+        Some(outcome_table) => get_response(&table, outcome_table, dice, flavor),
+    };
+    ctx.say(with_persona(ctx.data(), ctx.author().id.0, "roll_table", response).await)
+        .await?;
+    Ok(())
+}
+
+fn get_response(name: &str, table: &OutcomeTable, dice: Option<String>, flavor: Flavor) -> String {
+    let dice = match dice.or_else(|| table.dice.clone()) {
+        None => {
+            return format!(
+                "The {} table doesn't have a default pool, tell me what dice to roll",
+                name
+            )
+        }
+        Some(dice) => dice,
+    };
+    let roll = match DiceRollRequest::parse(&dice) {
+        Err(err) => return err.message(flavor),
+        Ok(roll) => roll.roll(&mut rand::thread_rng()),
+    };
+    let total = roll.sum();
+    let outcome = table
+        .lookup(total)
+        .unwrap_or("Nothing on the table for that one, the fates are confused");
+    format!(
+        "Rolling {} on {}\n\n{}\nTotal: {}\n\n**{}**",
+        dice,
+        name,
+        roll.dice_markdown().trim(),
+        total,
+        outcome
+    )
+}
+
+// A table that maps the total of a roll onto some text, e.g. 2-6 miss, 7-9 partial, 10+ hit.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub(crate) struct OutcomeTable {
+    #[serde(default)]
+    dice: Option<String>,
+    bands: Vec<Band>,
+}
+
+// An inclusive range of totals. A missing `max` means "and up".
+#[derive(Debug, Clone, serde::Deserialize)]
+struct Band {
+    min: u64,
+    #[serde(default)]
+    max: Option<u64>,
+    text: String,
+}
+impl Band {
+    fn contains(&self, total: u64) -> bool {
+        total >= self.min && self.max.is_none_or(|max| total <= max)
+    }
+
+    fn describe(&self) -> String {
+        match self.max {
+            None => format!("{}+", self.min),
+            Some(max) => format!("{}-{}", self.min, max),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum TableError {
+    Inverted { band: String },
+    Overlap { first: String, second: String },
+    BadDice { dice: String, reason: String },
+}
+impl std::fmt::Display for TableError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TableError::Inverted { band } => write!(f, "range {} ends before it starts", band),
+            TableError::Overlap { first, second } => {
+                write!(f, "ranges {} and {} overlap", first, second)
+            }
+            TableError::BadDice { dice, reason } => {
+                write!(f, "can't roll its dice {}: {}", dice, reason)
+            }
+        }
+    }
+}
+
+impl OutcomeTable {
+    fn lookup(&self, total: u64) -> Option<&str> {
+        self.bands
+            .iter()
+            .find(|band| band.contains(total))
+            .map(|band| band.text.as_str())
+    }
+
+    pub(crate) fn validate(&self) -> Vec<TableError> {
+        let mut errors = Vec::new();
+        if let Some(dice) = &self.dice {
+            if let Err(reason) = DiceRollRequest::parse(dice) {
+                errors.push(TableError::BadDice {
+                    dice: dice.clone(),
+                    reason: reason.to_string(),
+                });
+            }
+        }
+        for band in self.bands.iter() {
+            if band.max.is_some_and(|max| max < band.min) {
+                errors.push(TableError::Inverted {
+                    band: band.describe(),
+                });
+            }
+        }
+        let mut sorted: Vec<&Band> = self.bands.iter().collect();
+        sorted.sort_by_key(|band| band.min);
+        // every pair, not just neighbours, since a wide band can reach past the next one
+        for (i, first) in sorted.iter().enumerate() {
+            for second in sorted[i + 1..].iter() {
+                if first.max.is_none_or(|max| max >= second.min) {
+                    errors.push(TableError::Overlap {
+                        first: first.describe(),
+                        second: second.describe(),
+                    });
+                }
+            }
+        }
+        errors
+    }
+}
+
+pub(crate) type Tables = BTreeMap<String, OutcomeTable>;
+
+pub(crate) fn parse_tables(contents: &str) -> Result<Tables, Error> {
+    Ok(serde_json::from_str(contents)?)
+}
+
+// Everything wrong with a tables file, without loading it.
+pub(crate) fn lint_tables(contents: &str) -> Vec<String> {
+    let tables = match parse_tables(contents) {
+        Err(err) => return vec![format!("{}: {}", TABLES_FILE, err)],
+        Ok(tables) => tables,
+    };
+    let mut problems = Vec::new();
+    for (name, table) in tables.iter() {
+        for err in table.validate() {
+            problems.push(format!("{}: table {} {}", TABLES_FILE, name, err));
+        }
+    }
+    problems
+}
+
+// Tables are optional, so a missing file is just no tables. Broken tables are skipped
+// (loudly) rather than taking the whole bot down.
+pub(crate) fn load_tables(path: impl AsRef<Path>) -> Tables {
+    let path = path.as_ref();
+    let contents = match std::fs::read_to_string(path) {
+        Err(_) => return Tables::new(),
+        Ok(contents) => contents,
+    };
+    let mut tables = match parse_tables(&contents) {
+        Err(err) => {
+            println!("Failed to read {}: {}", path.display(), err);
+            return Tables::new();
+        }
+        Ok(tables) => tables,
+    };
+    tables.retain(|name, table| {
+        let errors = table.validate();
+        for err in errors.iter() {
+            println!("Skipping table {}: {}", name, err);
+        }
+        errors.is_empty()
+    });
+    tables
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pbta() -> OutcomeTable {
+        let tables = parse_tables(
+            r#"{"move": {"dice": "2d6", "bands": [
+                {"min": 2, "max": 6, "text": "miss"},
+                {"min": 7, "max": 9, "text": "partial"},
+                {"min": 10, "text": "hit"}
+            ]}}"#,
+        )
+        .unwrap();
+        tables["move"].clone()
+    }
+
+    #[test]
+    fn test_lookup_boundaries() {
+        let table = pbta();
+        assert!(table.validate().is_empty());
+        assert_eq!(table.lookup(1), None);
+        assert_eq!(table.lookup(2), Some("miss"));
+        assert_eq!(table.lookup(6), Some("miss"));
+        assert_eq!(table.lookup(7), Some("partial"));
+        assert_eq!(table.lookup(9), Some("partial"));
+        assert_eq!(table.lookup(10), Some("hit"));
+        assert_eq!(table.lookup(1000), Some("hit"));
+    }
+
+    #[test]
+    fn test_overlap_detection() {
+        let tables = parse_tables(
+            r#"{"bad": {"bands": [
+                {"min": 7, "text": "hit"},
+                {"min": 2, "max": 7, "text": "miss"},
+                {"min": 9, "max": 8, "text": "backwards"}
+            ]}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            tables["bad"].validate(),
+            vec![
+                TableError::Inverted {
+                    band: "9-8".to_string()
+                },
+                TableError::Overlap {
+                    first: "2-7".to_string(),
+                    second: "7+".to_string()
+                },
+                TableError::Overlap {
+                    first: "7+".to_string(),
+                    second: "9-8".to_string()
+                },
+            ]
+        );
+
+        // a band can overlap ones past its neighbour too
+        let tables = parse_tables(
+            r#"{"wide": {"bands": [
+                {"min": 1, "max": 10, "text": "anything"},
+                {"min": 2, "max": 3, "text": "low"},
+                {"min": 5, "max": 6, "text": "middling"}
+            ]}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            tables["wide"].validate(),
+            vec![
+                TableError::Overlap {
+                    first: "1-10".to_string(),
+                    second: "2-3".to_string()
+                },
+                TableError::Overlap {
+                    first: "1-10".to_string(),
+                    second: "5-6".to_string()
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+// When OpenAI falls over every request fails the same way, so only print each distinct
+// message once per window and summarize how many repeats we swallowed.
+const WINDOW: Duration = Duration::from_secs(60);
+
+static LOG: LazyLock<ThrottledLog> = LazyLock::new(|| ThrottledLog::new(WINDOW));
+
+pub(crate) fn log(message: impl Into<String>) {
+    for line in LOG.record(message.into(), Instant::now()) {
+        println!("{}", line);
+    }
+}
+
+struct ThrottledLog {
+    window: Duration,
+    seen: Mutex<HashMap<String, Seen>>,
+}
+
+struct Seen {
+    logged_at: Instant,
+    suppressed: u64,
+}
+impl Seen {
+    fn summary(&self, message: &str, now: Instant) -> String {
+        format!(
+            "(suppressed {} repeats in the last {}s of: {})",
+            self.suppressed,
+            now.duration_since(self.logged_at).as_secs(),
+            message
+        )
+    }
+}
+
+impl ThrottledLog {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns the lines that should actually be printed for this message.
+    fn record(&self, message: String, now: Instant) -> Vec<String> {
+        let mut seen = self.seen.lock().unwrap();
+        let mut lines = Vec::new();
+        // don't let old messages pile up forever, and don't sit on their repeats until they
+        // come up again either, since they might not
+        seen.retain(|logged, s| {
+            if *logged == message || now.duration_since(s.logged_at) < self.window {
+                return true;
+            }
+            if s.suppressed > 0 {
+                lines.push(s.summary(logged, now));
+            }
+            false
+        });
+        match seen.get_mut(&message) {
+            Some(s) if now.duration_since(s.logged_at) < self.window => {
+                s.suppressed += 1;
+            }
+            Some(s) => {
+                if s.suppressed > 0 {
+                    lines.push(s.summary(&message, now));
+                }
+                s.logged_at = now;
+                s.suppressed = 0;
+                lines.push(message);
+            }
+            None => {
+                seen.insert(
+                    message.clone(),
+                    Seen {
+                        logged_at: now,
+                        suppressed: 0,
+                    },
+                );
+                lines.push(message);
+            }
+        }
+        lines
+    }
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeated_errors_are_summarized() {
+        let log = ThrottledLog::new(Duration::from_secs(10));
+        let start = Instant::now();
+        let mut lines = Vec::new();
+        for i in 0..100 {
+            lines.extend(log.record(
+                "Failed to generate image: 503".to_string(),
+                start + Duration::from_millis(i * 10),
+            ));
+        }
+        assert_eq!(lines, vec!["Failed to generate image: 503".to_string()]);
+
+        let lines = log.record(
+            "Failed to generate image: 503".to_string(),
+            start + Duration::from_secs(11),
+        );
+        assert_eq!(
+            lines,
+            vec![
+                "(suppressed 99 repeats in the last 11s of: Failed to generate image: 503)"
+                    .to_string(),
+                "Failed to generate image: 503".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_distinct_errors_are_all_logged() {
+        let log = ThrottledLog::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert_eq!(log.record("a".to_string(), now).len(), 1);
+        assert_eq!(log.record("b".to_string(), now).len(), 1);
+        assert_eq!(log.record("a".to_string(), now).len(), 0);
+    }
+
+    #[test]
+    fn test_repeats_are_reported_when_a_message_stops() {
+        let log = ThrottledLog::new(Duration::from_secs(10));
+        let start = Instant::now();
+        for _ in 0..3 {
+            log.record("a".to_string(), start);
+        }
+        let lines = log.record("b".to_string(), start + Duration::from_secs(12));
+        assert_eq!(
+            lines,
+            vec![
+                "(suppressed 2 repeats in the last 12s of: a)".to_string(),
+                "b".to_string(),
+            ]
+        );
+        // it's only reported the once
+        let lines = log.record("a".to_string(), start + Duration::from_secs(13));
+        assert_eq!(lines, vec!["a".to_string()]);
+    }
+}
@@ -0,0 +1,85 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+
+use crate::data::Context;
+
+// Discord shows "typing..." for about ten seconds after each trigger, so poke it again a
+// little before that runs out.
+const TYPING_INTERVAL: Duration = Duration::from_secs(8);
+
+// Shows the typing indicator in the command's channel until the returned sender is used or
+// dropped. Dropping it counts, so bailing out early with `?` stops the typing too.
+pub(crate) fn start(ctx: Context<'_>) -> oneshot::Sender<()> {
+    let (done, stop) = oneshot::channel();
+    let http = ctx.serenity_context().http.clone();
+    let channel = ctx.channel_id();
+    tokio::spawn(type_until(
+        move || {
+            let http = http.clone();
+            async move {
+                // not worth failing a command over
+                let _ = channel.broadcast_typing(&http).await;
+            }
+        },
+        TYPING_INTERVAL,
+        stop,
+    ));
+    done
+}
+
+async fn type_until<F, Fut>(mut trigger: F, interval: Duration, mut stop: oneshot::Receiver<()>)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        trigger().await;
+        tokio::select! {
+            _ = &mut stop => return,
+            _ = tokio::time::sleep(interval) => {}
+        }
+    }
+}
+
+// Unit tests module
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_typing_stops_when_done() {
+        let triggers = Arc::new(AtomicU32::new(0));
+        let (done, stop) = oneshot::channel();
+        let counter = triggers.clone();
+        let task = tokio::spawn(type_until(
+            move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                async {}
+            },
+            Duration::from_millis(5),
+            stop,
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        done.send(()).unwrap();
+        task.await.unwrap();
+        let count = triggers.load(Ordering::SeqCst);
+        assert!(count > 1, "only typed {} times", count);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(triggers.load(Ordering::SeqCst), count);
+    }
+
+    #[tokio::test]
+    async fn test_typing_stops_when_dropped() {
+        let (done, stop) = oneshot::channel::<()>();
+        let task = tokio::spawn(type_until(|| async {}, Duration::from_secs(60), stop));
+        drop(done);
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
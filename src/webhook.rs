@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::data::Error;
+
+// Give up on a slow receiver rather than letting deliveries pile up.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What happened with one /gen, for an external dashboard.
+#[derive(Debug, Clone)]
+pub struct GenerationSummary {
+    pub user_id: u64,
+    pub user: String,
+    pub prompt: String,
+    pub requested: u8,
+    pub generated: usize,
+    pub failed: usize,
+    // what the user was charged, after COST_MULTIPLIER
+    pub cost_millicents: u128,
+    pub success: bool,
+}
+
+pub fn payload(summary: &GenerationSummary) -> serde_json::Value {
+    json!({
+        "event": "generation",
+        // as a string, since JSON numbers don't hold a Discord id in a lot of languages
+        "user": {"id": summary.user_id.to_string(), "name": summary.user},
+        "prompt": summary.prompt,
+        "images": {
+            "requested": summary.requested,
+            "generated": summary.generated,
+            "failed": summary.failed,
+        },
+        "cost_millicents": summary.cost_millicents as u64,
+        "success": summary.success,
+    })
+}
+
+// Fires off the summary to GENERATION_WEBHOOK_URL, if there is one. Best effort: it never
+// holds up the command, and failures only make it into the log.
+pub(crate) fn notify(summary: GenerationSummary) {
+    let url = match std::env::var("GENERATION_WEBHOOK_URL") {
+        Ok(url) if !url.trim().is_empty() => url,
+        _ => return,
+    };
+    tokio::spawn(async move {
+        if let Err(err) = deliver(url.trim(), &payload(&summary)).await {
+            crate::throttled_log::log(format!("Failed to deliver generation webhook: {}", err));
+        }
+    });
+}
+
+// Tries twice, then gives up.
+pub async fn deliver(url: &str, payload: &serde_json::Value) -> Result<(), Error> {
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?;
+    match post(&client, url, payload).await {
+        Ok(()) => Ok(()),
+        Err(_) => post(&client, url, payload).await,
+    }
+}
+
+async fn post(
+    client: &reqwest::Client,
+    url: &str,
+    payload: &serde_json::Value,
+) -> Result<(), Error> {
+    client
+        .post(url)
+        .json(payload)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
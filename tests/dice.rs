@@ -0,0 +1,52 @@
+use hypnos::{CortexResult, DiceRollRequest};
+use rand::SeedableRng;
+
+#[test]
+fn test_parse_errors() {
+    assert!(DiceRollRequest::parse("3d8 1d10").is_ok());
+    assert!(DiceRollRequest::parse("3x8").is_err());
+    assert!(DiceRollRequest::parse("").is_err());
+}
+
+#[test]
+fn test_summary_matches_cortex_math() {
+    let request = DiceRollRequest::parse("3d8 1d10 2d4").unwrap();
+    for seed in 0..500 {
+        let roll = request.roll(&mut rand::rngs::StdRng::seed_from_u64(seed));
+        let summary = roll.short_summary();
+        let (effect_total, effect_die, best_total, total_die) =
+            match (roll.get_highest_effect(), roll.get_highest_total()) {
+                (CortexResult::Botch, CortexResult::Botch) => {
+                    assert!(roll.is_botch());
+                    assert_eq!(summary, "**BOTCH!**");
+                    continue;
+                }
+                (
+                    CortexResult::Result {
+                        total: effect_total,
+                        effect: effect_die,
+                    },
+                    CortexResult::Result {
+                        total: best_total,
+                        effect: total_die,
+                    },
+                ) => (effect_total, effect_die, best_total, total_die),
+                _ => panic!("disagreement on botch for seed {}", seed),
+            };
+        // going for the biggest total can only add up to more than going for the effect die
+        assert!(best_total >= effect_total, "seed {}", seed);
+        assert!(effect_die.sides() >= 4);
+        if (effect_total, effect_die) == (best_total, total_die) {
+            assert!(summary.ends_with(&format!("Total: {} (effect {})", best_total, effect_die)));
+        } else {
+            assert!(summary.contains(&format!(
+                "Best effect: {} (effect {})",
+                effect_total, effect_die
+            )));
+            assert!(summary.contains(&format!(
+                "Best total: {} (effect {})",
+                best_total, total_die
+            )));
+        }
+    }
+}
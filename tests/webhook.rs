@@ -0,0 +1,90 @@
+use hypnos::{deliver, payload, GenerationSummary};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+fn summary() -> GenerationSummary {
+    GenerationSummary {
+        user_id: 123456789012345678,
+        user: "someone#0001".to_string(),
+        prompt: "a cat in a hat".to_string(),
+        requested: 4,
+        generated: 3,
+        failed: 1,
+        cost_millicents: 16_000,
+        success: true,
+    }
+}
+
+// Reads one HTTP request off the stream and returns its body.
+async fn read_body(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    loop {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "connection closed before the request was complete");
+        request.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&request).to_string();
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let length = head
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse::<usize>().unwrap())
+                })
+                .unwrap_or(0);
+            if body.len() >= length {
+                return body.to_string();
+            }
+        }
+    }
+}
+
+async fn respond_ok(stream: &mut TcpStream) {
+    stream
+        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_webhook_payload_shape() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let receiver = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let body = read_body(&mut stream).await;
+        respond_ok(&mut stream).await;
+        body
+    });
+
+    deliver(&url, &payload(&summary())).await.unwrap();
+    let received: serde_json::Value = serde_json::from_str(&receiver.await.unwrap()).unwrap();
+    assert_eq!(received["event"], "generation");
+    assert_eq!(received["user"]["id"], "123456789012345678");
+    assert_eq!(received["user"]["name"], "someone#0001");
+    assert_eq!(received["prompt"], "a cat in a hat");
+    assert_eq!(received["images"]["requested"], 4);
+    assert_eq!(received["images"]["generated"], 3);
+    assert_eq!(received["images"]["failed"], 1);
+    assert_eq!(received["cost_millicents"], 16_000);
+    assert_eq!(received["success"], true);
+}
+
+#[tokio::test]
+async fn test_webhook_retries_once() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let receiver = tokio::spawn(async move {
+        // hang up on the first attempt
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_body(&mut stream).await;
+        drop(stream);
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_body(&mut stream).await;
+        respond_ok(&mut stream).await;
+    });
+
+    deliver(&url, &payload(&summary())).await.unwrap();
+    receiver.await.unwrap();
+}